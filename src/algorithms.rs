@@ -74,6 +74,26 @@ impl From<HashAlgorithm> for HashingAlgorithm {
     }
 }
 
+impl TryFrom<HashingAlgorithm> for HashAlgorithm {
+    type Error = AlgorithmError;
+
+    fn try_from(
+        hashing_algorithm: HashingAlgorithm,
+    ) -> Result<Self, Self::Error> {
+        match hashing_algorithm {
+            HashingAlgorithm::Sha1 => Ok(HashAlgorithm::Sha1),
+            HashingAlgorithm::Sha256 => Ok(HashAlgorithm::Sha256),
+            HashingAlgorithm::Sha384 => Ok(HashAlgorithm::Sha384),
+            HashingAlgorithm::Sha512 => Ok(HashAlgorithm::Sha512),
+            HashingAlgorithm::Sm3_256 => Ok(HashAlgorithm::Sm3_256),
+            _ => Err(AlgorithmError::Hash(format!(
+                "Hash algorithm {:?} is not supported by Keylime",
+                hashing_algorithm
+            ))),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum EncryptionAlgorithm {
     Rsa,
@@ -139,6 +159,23 @@ impl SignAlgorithm {
             }
         }
     }
+
+    // The type of the keys signing with this scheme
+    pub fn key_algorithm(self) -> EncryptionAlgorithm {
+        match self {
+            SignAlgorithm::RsaSsa | SignAlgorithm::RsaPss => {
+                EncryptionAlgorithm::Rsa
+            }
+            SignAlgorithm::EcDsa | SignAlgorithm::EcSchnorr => {
+                EncryptionAlgorithm::Ecc
+            }
+        }
+    }
+
+    // Whether a key of the given type can sign with this scheme
+    pub fn is_compatible_with(self, enc_alg: EncryptionAlgorithm) -> bool {
+        self.key_algorithm() == enc_alg
+    }
 }
 
 impl From<SignAlgorithm> for SignatureSchemeAlgorithm {
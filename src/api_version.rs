@@ -0,0 +1,123 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+use crate::common::{APIVersion, API_VERSION};
+use crate::error::{Error, Result};
+use actix_web::HttpRequest;
+use std::str::FromStr;
+
+/// API versions the agent speaks, oldest first. The last one is the current
+/// version, `API_VERSION`.
+pub(crate) static SUPPORTED_API_VERSIONS: &[APIVersion] =
+    &[APIVersion::new(2, 0)];
+
+impl FromStr for APIVersion {
+    type Err = Error;
+
+    /// Parses a version as found in request paths, e.g. "v2.0"
+    fn from_str(s: &str) -> Result<Self> {
+        let malformed =
+            || Error::Other(format!("Malformed API version: {}", s));
+
+        let (major, minor) = s
+            .strip_prefix('v')
+            .and_then(|v| v.split_once('.'))
+            .ok_or_else(malformed)?;
+        let major = major.parse().map_err(|_| malformed())?;
+        let minor = minor.parse().map_err(|_| malformed())?;
+        Ok(APIVersion::new(major, minor))
+    }
+}
+
+impl APIVersion {
+    pub(crate) fn is_supported(&self) -> bool {
+        SUPPORTED_API_VERSIONS.contains(self)
+    }
+}
+
+/// The current API version
+pub(crate) fn current_api_version() -> APIVersion {
+    // SUPPORTED_API_VERSIONS is never empty
+    SUPPORTED_API_VERSIONS[SUPPORTED_API_VERSIONS.len() - 1]
+}
+
+/// Parses and validates the version segment of a request path
+///
+/// Returns an error, to be reported with a 400 response, if the version is
+/// malformed or not supported by the agent.
+pub(crate) fn parse_api_version(segment: &str) -> Result<APIVersion> {
+    let version = APIVersion::from_str(segment)?;
+    if !version.is_supported() {
+        let supported = SUPPORTED_API_VERSIONS
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<String>>()
+            .join(", ");
+        return Err(Error::Other(format!(
+            "API version not supported: {} (supported: {})",
+            version, supported
+        )));
+    }
+    Ok(version)
+}
+
+/// Returns the API version a request was made with, from the first segment
+/// of its path, so that handlers can branch on it
+pub(crate) fn request_api_version(req: &HttpRequest) -> Result<APIVersion> {
+    let segment = req
+        .path()
+        .trim_start_matches('/')
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    parse_api_version(segment)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_parse_api_version() {
+        let version = parse_api_version("v2.0").unwrap(); //#[allow_ci]
+        assert_eq!(version, APIVersion::new(2, 0));
+        assert_eq!(version.to_string(), "v2.0");
+        assert_eq!(version.number(), "2.0");
+
+        // The current version is the last supported one
+        assert_eq!(current_api_version().to_string(), API_VERSION);
+    }
+
+    #[test]
+    fn test_parse_api_version_unsupported() {
+        let err = parse_api_version("v1.0").unwrap_err(); //#[allow_ci]
+        assert_eq!(
+            err.to_string(),
+            "API version not supported: v1.0 (supported: v2.0)"
+        );
+
+        for malformed in &["2.0", "v2", "v2.x", "vx.0", ""] {
+            assert_eq!(
+                parse_api_version(malformed).unwrap_err().to_string(), //#[allow_ci]
+                format!("Malformed API version: {}", malformed)
+            );
+        }
+    }
+
+    #[test]
+    fn test_request_api_version() {
+        let req = TestRequest::get()
+            .uri(&format!("/{}/quotes/identity?nonce=1234", API_VERSION))
+            .to_http_request();
+        assert_eq!(
+            request_api_version(&req).unwrap(), //#[allow_ci]
+            current_api_version()
+        );
+
+        let req = TestRequest::get()
+            .uri("/v1.0/quotes/identity?nonce=1234")
+            .to_http_request();
+        assert!(request_api_version(&req).is_err());
+    }
+}
@@ -0,0 +1,257 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+//! Attestation bundles for offline verification.
+//!
+//! A bundle is a tar archive holding the quote and the measurement data
+//! that came with it, plus a manifest listing the SHA256 digest of every
+//! entry. The manifest is signed with the agent key so that a verifier can
+//! later check that the bundle was not tampered with.
+//!
+//! Bundles are served by the /quotes/bundle endpoint, which takes the same
+//! parameters as the integrity quote request.
+
+use crate::crypto;
+use crate::error::Result;
+use crate::quotes_handler::KeylimeQuote;
+use crate::serialization::{
+    deserialize_as_base64, deserialize_maybe_hex, serialize_as_base64,
+    serialize_maybe_hex,
+};
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkey::{PKey, Private};
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Version of the bundle layout, bumped whenever the manifest changes
+pub(crate) const BUNDLE_VERSION: u32 = 5;
+
+/// Content type of the bundles returned by the agent
+pub(crate) const BUNDLE_CONTENT_TYPE: &str = "application/x-tar";
+
+pub(crate) const MANIFEST_ENTRY: &str = "manifest.json";
+pub(crate) const MANIFEST_SIGNATURE_ENTRY: &str = "manifest.json.sig";
+
+const QUOTE_ENTRY: &str = "quote";
+const PUBKEY_ENTRY: &str = "pubkey.pem";
+const IMA_ENTRY: &str = "ascii_runtime_measurements";
+const MB_ENTRY: &str = "binary_bios_measurements";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct BundleEntry {
+    pub name: String,
+    #[serde(
+        serialize_with = "serialize_as_base64",
+        deserialize_with = "deserialize_as_base64"
+    )]
+    pub sha256: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct BundleManifest {
+    pub version: u32,
+    pub hash_alg: String,
+    pub enc_alg: String,
+    pub sign_alg: String,
+    pub ima_measurement_list_entry: Option<u64>,
+    #[serde(
+        default,
+        serialize_with = "serialize_maybe_hex",
+        deserialize_with = "deserialize_maybe_hex"
+    )]
+    pub boot_aggregate: Option<Vec<u8>>,
+    pub mb_measurement_list_encoding: Option<String>,
+    pub ima_path_filter: Option<String>,
+    pub ima_measurement_list_encoding: Option<String>,
+    pub mb_measurement_list_available: Option<bool>,
+    pub mb_measurement_list_entry: Option<u64>,
+    pub mb_num_entries: Option<u64>,
+    pub entries: Vec<BundleEntry>,
+}
+
+fn append_entry(
+    builder: &mut tar::Builder<Vec<u8>>,
+    name: &str,
+    data: &[u8],
+    mtime: u64,
+) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    builder.append_data(&mut header, name, data)?;
+    Ok(())
+}
+
+/// Assembles the quote and its measurement data into a tar archive, with a
+/// manifest signed with the given agent key.
+///
+/// The manifest signature can be checked with `crypto::asym_verify` against
+/// the agent public key.
+pub(crate) fn build_bundle(
+    quote: &KeylimeQuote,
+    key: &PKey<Private>,
+) -> Result<Vec<u8>> {
+    let mut files: Vec<(&str, &[u8])> =
+        vec![(QUOTE_ENTRY, quote.quote.as_bytes())];
+    if let Some(pubkey) = &quote.pubkey {
+        files.push((PUBKEY_ENTRY, pubkey.as_bytes()));
+    }
+    if let Some(ima) = &quote.ima_measurement_list {
+        files.push((IMA_ENTRY, ima.as_bytes()));
+    }
+    if let Some(mb) = &quote.mb_measurement_list {
+        files.push((MB_ENTRY, mb));
+    }
+
+    let mut entries = Vec::new();
+    for (name, data) in &files {
+        entries.push(BundleEntry {
+            name: name.to_string(),
+            sha256: hash(MessageDigest::sha256(), data)?.to_vec(),
+        });
+    }
+
+    let manifest = serde_json::to_string(&BundleManifest {
+        version: BUNDLE_VERSION,
+        hash_alg: quote.hash_alg.clone(),
+        enc_alg: quote.enc_alg.clone(),
+        sign_alg: quote.sign_alg.clone(),
+        ima_measurement_list_entry: quote.ima_measurement_list_entry,
+        boot_aggregate: quote.boot_aggregate.clone(),
+        mb_measurement_list_encoding: quote
+            .mb_measurement_list_encoding
+            .clone(),
+        ima_path_filter: quote.ima_path_filter.clone(),
+        ima_measurement_list_encoding: quote
+            .ima_measurement_list_encoding
+            .clone(),
+        mb_measurement_list_available: quote.mb_measurement_list_available,
+        mb_measurement_list_entry: quote.mb_measurement_list_entry,
+        mb_num_entries: quote.mb_num_entries,
+        entries,
+    })?;
+    let signature = crypto::asym_sign(key, &manifest)?;
+
+    // Clock before the epoch only affects the timestamp of the entries
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let mut builder = tar::Builder::new(Vec::new());
+    append_entry(&mut builder, MANIFEST_ENTRY, manifest.as_bytes(), mtime)?;
+    append_entry(
+        &mut builder,
+        MANIFEST_SIGNATURE_ENTRY,
+        signature.as_bytes(),
+        mtime,
+    )?;
+    for (name, data) in &files {
+        append_entry(&mut builder, name, data, mtime)?;
+    }
+
+    Ok(builder.into_inner()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithms::HashAlgorithm;
+    use std::collections::HashMap;
+    use std::io::Read;
+
+    fn read_bundle(bundle: &[u8]) -> HashMap<String, Vec<u8>> {
+        let mut archive = tar::Archive::new(bundle);
+        let mut contents = HashMap::new();
+        let entries = archive.entries().unwrap(); //#[allow_ci]
+        for entry in entries {
+            let mut entry = entry.unwrap(); //#[allow_ci]
+            let name = entry.path().unwrap().to_string_lossy().into_owned(); //#[allow_ci]
+            let mut data = Vec::new();
+            let _ = entry.read_to_end(&mut data).unwrap(); //#[allow_ci]
+            let _ = contents.insert(name, data);
+        }
+        contents
+    }
+
+    #[test]
+    fn test_build_bundle() {
+        let key = crypto::rsa_generate(2048).unwrap(); //#[allow_ci]
+        let pubkey = crypto::pkey_pub_from_priv(key.clone()).unwrap(); //#[allow_ci]
+        let quote = KeylimeQuote {
+            quote: "rQUOTE:SIG:PCRS".to_string(),
+            hash_alg: "sha256".to_string(),
+            enc_alg: "rsa".to_string(),
+            sign_alg: "rsassa".to_string(),
+            pubkey: Some(crypto::pkey_pub_to_pem(&pubkey).unwrap()), //#[allow_ci]
+            ima_measurement_list: Some("10 ima-ng entry\n".to_string()),
+            mb_measurement_list: Some(vec![0, 1, 2, 3]),
+            ima_measurement_list_entry: Some(0),
+            boot_aggregate: None,
+            num_entries: Some(1),
+            mb_measurement_list_encoding: None,
+            ima_path_filter: None,
+            ima_measurement_list_encoding: None,
+            banks: None,
+            mb_measurement_list_available: Some(true),
+            mb_measurement_list_entry: None,
+            mb_num_entries: None,
+            quote_parts: None,
+            ima_signature_failures: None,
+        };
+
+        let bundle = build_bundle(&quote, &key).unwrap(); //#[allow_ci]
+        let contents = read_bundle(&bundle);
+
+        let mut names: Vec<&str> =
+            contents.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        assert_eq!(
+            names,
+            [
+                IMA_ENTRY,
+                MB_ENTRY,
+                MANIFEST_ENTRY,
+                MANIFEST_SIGNATURE_ENTRY,
+                PUBKEY_ENTRY,
+                QUOTE_ENTRY
+            ]
+        );
+
+        let manifest =
+            String::from_utf8(contents[MANIFEST_ENTRY].clone()).unwrap(); //#[allow_ci]
+        let signature =
+            String::from_utf8(contents[MANIFEST_SIGNATURE_ENTRY].clone())
+                .unwrap(); //#[allow_ci]
+        let outcome = crypto::asym_verify(
+            &pubkey,
+            &manifest,
+            &signature,
+            HashAlgorithm::Sha256,
+        );
+        assert!(outcome.unwrap().valid); //#[allow_ci]
+
+        // A modified manifest must not verify
+        let tampered = manifest.replace("sha256", "sha1");
+        let outcome = crypto::asym_verify(
+            &pubkey,
+            &tampered,
+            &signature,
+            HashAlgorithm::Sha256,
+        );
+        assert!(!outcome.unwrap().valid); //#[allow_ci]
+
+        // Every entry is listed in the manifest with its digest
+        let manifest: BundleManifest =
+            serde_json::from_str(&manifest).unwrap(); //#[allow_ci]
+        assert_eq!(manifest.version, BUNDLE_VERSION);
+        assert_eq!(manifest.entries.len(), 4);
+        for entry in &manifest.entries {
+            let digest =
+                hash(MessageDigest::sha256(), &contents[&entry.name])
+                    .unwrap(); //#[allow_ci]
+            assert_eq!(entry.sha256, digest.to_vec());
+        }
+    }
+}
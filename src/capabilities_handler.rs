@@ -0,0 +1,129 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+use crate::api_version::SUPPORTED_API_VERSIONS;
+use crate::common::JsonWrapper;
+use crate::quotes_handler::QUOTE_SCHEMA_VERSION;
+use crate::QuoteData;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+// Encodings of the response bodies
+static ENCODINGS: &[&str] = &["json"];
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Capabilities {
+    api_versions: Vec<String>,
+    /// Hash algorithm quotes are made with when the verifier does not ask
+    /// for one, as configured by tpm_hash_alg
+    hash_alg: String,
+    enc_alg: String,
+    sign_alg: String,
+    /// Every hash algorithm quotes can be requested with, from the PCR
+    /// banks of the TPM
+    hash_algs: Vec<String>,
+    sign_schemes: Vec<String>,
+    quote_schema_version: u32,
+    ima: bool,
+    measured_boot: bool,
+    encodings: Vec<String>,
+    revocation_transports: Vec<String>,
+}
+
+impl Capabilities {
+    fn new(data: &QuoteData) -> Self {
+        let mut revocation_transports = vec!["rest".to_string()];
+        if cfg!(feature = "with-zmq") {
+            revocation_transports.push("zmq".to_string());
+        }
+
+        Capabilities {
+            api_versions: SUPPORTED_API_VERSIONS
+                .iter()
+                .map(|v| v.number())
+                .collect(),
+            hash_alg: data.hash_alg.to_string(),
+            enc_alg: data.enc_alg.to_string(),
+            sign_alg: data.sign_alg.to_string(),
+            hash_algs: data.pcr_banks.iter().map(|b| b.to_string()).collect(),
+            sign_schemes: data
+                .ak_sign_algs
+                .iter()
+                .map(|alg| alg.to_string())
+                .collect(),
+            quote_schema_version: QUOTE_SCHEMA_VERSION,
+            ima: data.ima_ml_path.exists(),
+            measured_boot: data.measuredboot_ml_path.exists(),
+            encodings: ENCODINGS.iter().map(|e| e.to_string()).collect(),
+            revocation_transports,
+        }
+    }
+}
+
+// This is the handler for the GET request for the agent capabilities
+pub async fn capabilities(
+    req: HttpRequest,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    info!(
+        "GET invoked from {:?} with uri {}",
+        req.connection_info().peer_addr().unwrap(), //#[allow_ci]
+        req.uri()
+    );
+
+    let response = JsonWrapper::success(Capabilities::new(&data));
+
+    HttpResponse::Ok().json(response)
+}
+
+#[cfg(feature = "testing")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_version::current_api_version;
+    use crate::common::{KeylimeConfig, API_VERSION};
+    use actix_web::{test, web, App};
+
+    #[actix_rt::test]
+    async fn test_capabilities() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/capabilities", API_VERSION),
+                web::get().to(capabilities),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}/capabilities", API_VERSION))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let body: JsonWrapper<Capabilities> =
+            test::read_body_json(resp).await;
+        let caps = body.results;
+        let config = KeylimeConfig::default();
+
+        assert_eq!(caps.api_versions, [current_api_version().number()]);
+        assert_eq!(caps.hash_alg, "sha256");
+        assert_eq!(caps.enc_alg, "rsa");
+        assert_eq!(caps.sign_alg, "rsassa");
+        assert!(caps.hash_algs.contains(&"sha256".to_string()));
+        // The AK only signs with the scheme it was created with
+        assert_eq!(caps.sign_schemes, [config.sign_alg.to_string()]);
+        assert_eq!(caps.quote_schema_version, QUOTE_SCHEMA_VERSION);
+        assert_eq!(caps.ima, quotedata.ima_ml_path.exists());
+        assert_eq!(
+            caps.measured_boot,
+            quotedata.measuredboot_ml_path.exists()
+        );
+        assert_eq!(caps.encodings, ["json"]);
+        assert_eq!(
+            caps.revocation_transports.contains(&"zmq".to_string()),
+            cfg!(feature = "with-zmq")
+        );
+    }
+}
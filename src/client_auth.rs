@@ -0,0 +1,202 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+use crate::common::KeylimeConfig;
+use crate::crypto;
+use crate::error::{Error, Result};
+use actix_tls::accept::openssl::TlsStream;
+use actix_web::{dev::Extensions, rt::net::TcpStream};
+use openssl::{
+    nid::Nid,
+    stack::Stack,
+    x509::{
+        store::{X509Store, X509StoreBuilder},
+        X509StoreContext, X509,
+    },
+};
+use std::any::Any;
+use std::path::Path;
+
+/// Certificate presented by the client of a TLS connection, stored in the
+/// connection data by `on_connect`
+#[derive(Debug, Clone)]
+pub(crate) struct PeerCertificate(pub X509);
+
+/// Stores the certificate presented by the client in the data of the
+/// connection, for the handlers requiring client authentication
+pub(crate) fn on_connect(conn: &dyn Any, ext: &mut Extensions) {
+    if let Some(stream) = conn.downcast_ref::<TlsStream<TcpStream>>() {
+        if let Some(cert) = stream.ssl().peer_certificate() {
+            let _ = ext.insert(PeerCertificate(cert));
+        }
+    }
+}
+
+/// Client certificate requirements of the quote endpoints.
+///
+/// When required, clients must present a certificate issued by the
+/// configured CA, with a common name in the allowed subjects if any are
+/// configured.
+pub(crate) struct ClientAuthPolicy {
+    required: bool,
+    store: Option<X509Store>,
+    allowed_subjects: Vec<String>,
+}
+
+impl std::fmt::Debug for ClientAuthPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientAuthPolicy")
+            .field("required", &self.required)
+            .field("allowed_subjects", &self.allowed_subjects)
+            .finish()
+    }
+}
+
+impl ClientAuthPolicy {
+    pub(crate) fn new(
+        required: bool,
+        ca: Option<X509>,
+        allowed_subjects: Vec<String>,
+    ) -> Result<Self> {
+        let store = match ca {
+            Some(ca) => {
+                let mut builder = X509StoreBuilder::new()?;
+                builder.add_cert(ca)?;
+                Some(builder.build())
+            }
+            None => None,
+        };
+        Ok(ClientAuthPolicy {
+            required,
+            store,
+            allowed_subjects,
+        })
+    }
+
+    /// Policy not requiring client certificates
+    pub(crate) fn disabled() -> Self {
+        ClientAuthPolicy {
+            required: false,
+            store: None,
+            allowed_subjects: Vec::new(),
+        }
+    }
+
+    pub(crate) fn from_config(config: &KeylimeConfig) -> Result<Self> {
+        if !config.require_client_auth {
+            return Ok(Self::disabled());
+        }
+        let ca = crypto::load_x509(Path::new(&config.client_auth_ca))?;
+        Self::new(true, Some(ca), config.client_auth_allowed_subjects.clone())
+    }
+
+    /// Checks the certificate presented by the client. Returns
+    /// `Error::Unauthorized` if it is missing or not issued by the CA, and
+    /// `Error::Forbidden` if its subject is not allowed.
+    pub(crate) fn authorize(&self, cert: Option<&X509>) -> Result<()> {
+        if !self.required {
+            return Ok(());
+        }
+        let cert = cert.ok_or_else(|| {
+            Error::Unauthorized("no client certificate".to_string())
+        })?;
+
+        if let Some(store) = &self.store {
+            let chain = Stack::new()?;
+            let mut ctx = X509StoreContext::new()?;
+            let (verified, error) = ctx.init(store, cert, &chain, |c| {
+                Ok((c.verify_cert()?, c.error()))
+            })?;
+            if !verified {
+                return Err(Error::Unauthorized(format!(
+                    "client certificate verification failed: {}",
+                    error
+                )));
+            }
+        }
+
+        if self.allowed_subjects.is_empty() {
+            return Ok(());
+        }
+        let subject = cert
+            .subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .and_then(|entry| entry.data().as_utf8().ok())
+            .map(|cn| cn.to_string())
+            .unwrap_or_default();
+        if self.allowed_subjects.contains(&subject) {
+            Ok(())
+        } else {
+            Err(Error::Forbidden(format!(
+                "client certificate subject {:?} is not allowed",
+                subject
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cert(cn: &str) -> Result<X509> {
+        let key = crypto::rsa_generate(2048)?;
+        crypto::generate_x509(&key, cn)
+    }
+
+    #[test]
+    fn test_disabled() -> Result<()> {
+        let policy = ClientAuthPolicy::disabled();
+        policy.authorize(None)?;
+        policy.authorize(Some(&cert("verifier")?))
+    }
+
+    #[test]
+    fn test_without_cert() -> Result<()> {
+        let ca = cert("verifier")?;
+        let policy = ClientAuthPolicy::new(true, Some(ca), Vec::new())?;
+        assert!(matches!(
+            policy.authorize(None),
+            Err(Error::Unauthorized(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_valid_cert() -> Result<()> {
+        let ca = cert("verifier")?;
+        let policy = ClientAuthPolicy::new(
+            true,
+            Some(ca.clone()),
+            vec!["tenant".to_string(), "verifier".to_string()],
+        )?;
+        policy.authorize(Some(&ca))
+    }
+
+    #[test]
+    fn test_untrusted_cert() -> Result<()> {
+        let ca = cert("verifier")?;
+        let policy = ClientAuthPolicy::new(true, Some(ca), Vec::new())?;
+        assert!(matches!(
+            policy.authorize(Some(&cert("verifier")?)),
+            Err(Error::Unauthorized(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_subject_not_allowed() -> Result<()> {
+        let ca = cert("intruder")?;
+        let policy = ClientAuthPolicy::new(
+            true,
+            Some(ca.clone()),
+            vec!["verifier".to_string()],
+        )?;
+        assert!(matches!(
+            policy.authorize(Some(&ca)),
+            Err(Error::Forbidden(_))
+        ));
+        Ok(())
+    }
+}
@@ -3,6 +3,9 @@
 
 use crate::algorithms::{EncryptionAlgorithm, HashAlgorithm, SignAlgorithm};
 use crate::error::{Error, Result};
+use crate::ima::ImaSignatureMode;
+use crate::revocation::{RevocationTransport, ScratchCleanup};
+use actix_web::{http::StatusCode, HttpResponse, HttpResponseBuilder};
 use ini::Ini;
 use log::*;
 use serde::{Deserialize, Serialize};
@@ -15,6 +18,7 @@ use std::fs::File;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 use tss_esapi::{structures::PcrSlot, utils::TpmsContext};
 use uuid::Uuid;
 
@@ -26,6 +30,7 @@ pub const STUB_VTPM: bool = false;
 pub const STUB_IMA: bool = true;
 pub const TPM_DATA_PCR: usize = 16;
 pub const IMA_PCR: usize = 10;
+pub const MEASUREDBOOT_PCR: usize = 0;
 pub static DEFAULT_CONFIG: &str = "/etc/keylime.conf";
 pub static RSA_PUBLICKEY_EXPORTABLE: &str = "rsa placeholder";
 pub static TPM_TOOLS_PATH: &str = "/usr/local/bin/";
@@ -43,10 +48,55 @@ pub static TPM_DATA: &str = "tpmdata.json";
 // certificate(s) can be generated by running the tenant with the --cert flag. For more
 // information, check the README: https://github.com/keylime/keylime/#using-keylime-ca
 pub static REV_CERT: &str = "RevocationNotifier-cert.crt";
+// Name of the copy of the revocation certificate fetched from a URL, kept in
+// the work directory
+pub static REV_CERT_CACHE: &str = "revocation_cert_cache.crt";
+pub static REV_CERT_CA: &str = "";
+pub static REV_CERT_CACHE_TTL: &str = "3600";
 pub static REV_ACTIONS_DIR: &str = "/usr/libexec/keylime";
 pub static REV_ACTIONS: &str = "";
+pub static PYTHON_SHIM_PATH: &str = "";
+pub static PYTHON_INTERPRETER: &str = "";
 pub static ALLOW_PAYLOAD_REV_ACTIONS: bool = true;
+pub static REV_ACTION_TIMEOUT: &str = "60";
+pub static REV_ACTION_MAX_ATTEMPTS: &str = "1";
+pub static REV_ACTION_RETRY_DELAY: &str = "1";
+// EX_TEMPFAIL from sysexits.h
+pub static REV_ACTION_RETRY_EXIT_CODES: &str = "75";
+pub static CHECK_REV_ACTIONS_DIR_PERMISSIONS: bool = false;
+pub static ALLOW_REV_ACTION_FAILURES: bool = false;
+pub static REV_ACTIONS_RAW_MESSAGE: bool = false;
+pub static WATCH_REV_CERT: bool = false;
+pub static PERSIST_AK: bool = true;
+pub static REV_ACTIONS_MAX_CONCURRENCY: &str = "1";
+pub static REV_ACTIONS_ALLOWLIST: &str = "";
+pub static REV_ACTIONS_DENYLIST: &str = "";
+pub static REV_ACTION_SCRATCH_CLEANUP: &str = "always";
 pub static ALLOW_INSECURE_PAYLOAD: bool = false;
+pub static REQUIRED_PCRS: &str = "";
+pub static ALLOWED_PCRS: &str = "";
+pub static IMA_ML_MAX_BYTES: &str = "0";
+pub static IMA_SIGNATURE_VERIFICATION: &str = "off";
+pub static IMA_SIGNING_KEYS: &str = "";
+pub static REV_NOTIFICATION_URL: &str = "";
+pub static REV_TRANSPORTS: &str = "";
+pub static REV_NOTIFICATION_CA: &str = "";
+pub static REV_POLL_INTERVAL: &str = "10";
+pub static REV_MAX_AGE: &str = "0";
+pub static REV_SIGNATURE_DIGEST: &str = "sha256";
+pub static IDENTITY_QUOTE_CACHE_TTL: &str = "0";
+pub static NONCE_REUSE_WINDOW: &str = "0";
+pub static QUOTE_RATE_LIMIT: &str = "0";
+pub static QUOTE_RATE_LIMIT_BURST: &str = "10";
+pub static REQUIRE_CLIENT_AUTH: bool = false;
+pub static CLIENT_AUTH_CA: &str = "";
+pub static CLIENT_AUTH_ALLOWED_SUBJECTS: &str = "";
+pub static TPM_TCTI: &str = "";
+pub static TPM_LOCK_TIMEOUT: &str = "30";
+pub static PUSH_ATTESTATION_URL: &str = "";
+pub static PUSH_ATTESTATION_INTERVAL: &str = "60";
+pub static PUSH_ATTESTATION_CA: &str = "";
+pub static PUSH_ATTESTATION_MASK: &str = "0x400";
 
 pub const AGENT_UUID_LEN: usize = 36;
 pub const AUTH_TAG_LEN: usize = 96;
@@ -74,51 +124,197 @@ cfg_if::cfg_if! {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
+)]
 pub(crate) struct APIVersion {
     major: u32,
     minor: u32,
 }
 
+impl APIVersion {
+    pub(crate) const fn new(major: u32, minor: u32) -> Self {
+        APIVersion { major, minor }
+    }
+
+    /// The version number without the "v" prefix of the request paths,
+    /// e.g. "2.0", as reported by the version and capabilities endpoints
+    pub(crate) fn number(&self) -> String {
+        format!("{}.{}", self.major, self.minor)
+    }
+}
+
 impl std::fmt::Display for APIVersion {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "v{}.{}", self.major, self.minor)
     }
 }
 
+/// Status of an API response. It sets both the HTTP status of the response
+/// and the numeric code in its JSON body, so that they always match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ResponseStatus {
+    Success,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    TooManyRequests,
+    InternalServerError,
+    ServiceUnavailable,
+}
+
+impl ResponseStatus {
+    const ALL: [ResponseStatus; 9] = [
+        ResponseStatus::Success,
+        ResponseStatus::BadRequest,
+        ResponseStatus::Unauthorized,
+        ResponseStatus::Forbidden,
+        ResponseStatus::NotFound,
+        ResponseStatus::MethodNotAllowed,
+        ResponseStatus::TooManyRequests,
+        ResponseStatus::InternalServerError,
+        ResponseStatus::ServiceUnavailable,
+    ];
+
+    /// HTTP status code, sent as the code of the JSON body
+    pub(crate) fn code(self) -> u16 {
+        match self {
+            ResponseStatus::Success => 200,
+            ResponseStatus::BadRequest => 400,
+            ResponseStatus::Unauthorized => 401,
+            ResponseStatus::Forbidden => 403,
+            ResponseStatus::NotFound => 404,
+            ResponseStatus::MethodNotAllowed => 405,
+            ResponseStatus::TooManyRequests => 429,
+            ResponseStatus::InternalServerError => 500,
+            ResponseStatus::ServiceUnavailable => 503,
+        }
+    }
+
+    /// Stable reason of the status, for responses without a more specific
+    /// message
+    pub(crate) fn reason(self) -> &'static str {
+        match self {
+            ResponseStatus::Success => "Success",
+            ResponseStatus::BadRequest => "Bad Request",
+            ResponseStatus::Unauthorized => "Unauthorized",
+            ResponseStatus::Forbidden => "Forbidden",
+            ResponseStatus::NotFound => "Not Found",
+            ResponseStatus::MethodNotAllowed => "Method Not Allowed",
+            ResponseStatus::TooManyRequests => "Too Many Requests",
+            ResponseStatus::InternalServerError => "Internal Server Error",
+            ResponseStatus::ServiceUnavailable => "Service Unavailable",
+        }
+    }
+
+    pub(crate) fn http_status(self) -> StatusCode {
+        // The codes are all valid HTTP status codes
+        StatusCode::from_u16(self.code())
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    /// Starts building an HTTP response with this status, e.g. to add
+    /// headers before setting the JSON body
+    pub(crate) fn response(self) -> HttpResponseBuilder {
+        HttpResponse::build(self.http_status())
+    }
+}
+
+impl TryFrom<u16> for ResponseStatus {
+    type Error = String;
+
+    fn try_from(code: u16) -> std::result::Result<Self, Self::Error> {
+        ResponseStatus::ALL
+            .iter()
+            .copied()
+            .find(|status| status.code() == code)
+            .ok_or_else(|| format!("unknown response code {}", code))
+    }
+}
+
+impl Serialize for ResponseStatus {
+    fn serialize<S>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_u16(self.code())
+    }
+}
+
+impl<'de> Deserialize<'de> for ResponseStatus {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let code = u16::deserialize(deserializer)?;
+        ResponseStatus::try_from(code).map_err(serde::de::Error::custom)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) struct JsonWrapper<A> {
-    pub code: u16,
+    /// Serialized as the numeric HTTP status code
+    pub code: ResponseStatus,
     pub status: String,
     pub results: A,
+    /// Machine-readable reason of an error, for clients to branch on
+    /// without parsing the status message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>,
 }
 
 impl JsonWrapper<Value> {
     pub(crate) fn error(
-        code: u16,
+        code: ResponseStatus,
         status: impl ToString,
     ) -> JsonWrapper<Value> {
         JsonWrapper {
             code,
             status: status.to_string(),
             results: json!({}),
+            error_code: None,
+        }
+    }
+
+    pub(crate) fn error_with_code(
+        code: ResponseStatus,
+        error_code: impl ToString,
+        status: impl ToString,
+    ) -> JsonWrapper<Value> {
+        JsonWrapper {
+            error_code: Some(error_code.to_string()),
+            ..JsonWrapper::error(code, status)
         }
     }
 }
 
-impl<'de, A> JsonWrapper<A>
+impl<A> JsonWrapper<A>
 where
-    A: Deserialize<'de> + Serialize + Debug,
+    A: Serialize + Debug,
 {
     pub(crate) fn success(results: A) -> JsonWrapper<A> {
         JsonWrapper {
-            code: 200,
-            status: String::from("Success"),
+            code: ResponseStatus::Success,
+            status: String::from(ResponseStatus::Success.reason()),
             results,
+            error_code: None,
         }
     }
 }
 
+impl<A: Serialize> JsonWrapper<A> {
+    /// Builds the HTTP response carrying this body, with the HTTP status
+    /// matching its code
+    pub(crate) fn into_response(self) -> HttpResponse {
+        self.code.response().json(self)
+    }
+}
+
 // a vector holding keys
 pub type KeySet = Vec<SymmKey>;
 
@@ -181,9 +377,13 @@ impl TpmData {
         Ok(data)
     }
 
+    // The AK context is only readable by the agent. It is written to a
+    // temporary file, created with mode 0600, then moved in place.
     pub(crate) fn store(&self, path: &Path) -> Result<()> {
-        let file = File::create(path)?;
-        serde_json::to_writer_pretty(file, self)?;
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        let file = tempfile::NamedTempFile::new_in(dir)?;
+        serde_json::to_writer_pretty(&file, self)?;
+        let _ = file.persist(path)?;
         Ok(())
     }
 
@@ -210,8 +410,11 @@ pub(crate) struct KeylimeConfig {
     pub sign_alg: SignAlgorithm,
     pub tpm_data: Option<TpmData>,
     pub tpm_data_path: String,
+    pub persist_ak: bool,
     pub run_revocation: bool,
     pub revocation_cert: String,
+    pub revocation_cert_ca: String,
+    pub revocation_cert_cache_ttl: Duration,
     pub revocation_ip: String,
     pub revocation_port: String,
     pub secure_size: String,
@@ -222,12 +425,52 @@ pub(crate) struct KeylimeConfig {
     pub keylime_ca_path: String,
     pub revocation_actions: String,
     pub revocation_actions_dir: String,
+    pub python_shim_path: String,
+    pub python_interpreter: String,
     pub allow_payload_revocation_actions: bool,
+    pub revocation_action_timeout: Duration,
+    pub revocation_action_max_attempts: u32,
+    pub revocation_action_retry_delay: Duration,
+    pub revocation_action_retry_exit_codes: Vec<i32>,
+    pub check_revocation_actions_dir_permissions: bool,
+    pub allow_revocation_action_failures: bool,
+    pub revocation_actions_max_concurrency: usize,
+    pub revocation_actions_raw_message: bool,
+    pub revocation_actions_allowlist: String,
+    pub revocation_actions_denylist: String,
+    pub revocation_action_scratch_cleanup: ScratchCleanup,
+    pub watch_revocation_cert: bool,
+    pub revocation_notification_url: String,
+    pub revocation_notification_ca: String,
+    pub revocation_poll_interval: Duration,
+    pub revocation_transports: Vec<RevocationTransport>,
+    pub revocation_max_age: Duration,
+    pub revocation_signature_digest: HashAlgorithm,
     pub work_dir: String,
     pub ima_ml_path: String,
     pub measuredboot_ml_path: String,
     pub mtls_enabled: bool,
     pub enable_insecure_payload: bool,
+    pub required_pcrs: Vec<u32>,
+    pub allowed_pcrs: Vec<u32>,
+    pub ima_pcr: u32,
+    pub measuredboot_pcr: u32,
+    pub ima_ml_max_bytes: usize,
+    pub ima_signature_verification: ImaSignatureMode,
+    pub ima_signing_keys: String,
+    pub identity_quote_cache_ttl: Duration,
+    pub nonce_reuse_window: Duration,
+    pub quote_rate_limit: f64,
+    pub quote_rate_limit_burst: u32,
+    pub require_client_auth: bool,
+    pub client_auth_ca: String,
+    pub client_auth_allowed_subjects: Vec<String>,
+    pub tpm_tcti: String,
+    pub tpm_lock_timeout: Duration,
+    pub push_attestation_url: String,
+    pub push_attestation_interval: Duration,
+    pub push_attestation_ca: String,
+    pub push_attestation_mask: String,
 }
 
 impl KeylimeConfig {
@@ -246,48 +489,128 @@ impl KeylimeConfig {
             "registrar_port",
             "REGISTRAR_PORT",
         )?;
-        let agent_uuid_config = config_get("cloud_agent", "agent_uuid")?;
+        let agent_uuid_config = config_get_env(
+            "cloud_agent",
+            "agent_uuid",
+            "KEYLIME_AGENT_UUID",
+        )?;
         let agent_uuid = get_uuid(&agent_uuid_config);
         let agent_contact_ip = cloudagent_contact_ip_get();
         let agent_contact_port = cloudagent_contact_port_get()?;
         let hash_alg = HashAlgorithm::try_from(
-            config_get("cloud_agent", "tpm_hash_alg")?.as_str(),
+            config_get_env(
+                "cloud_agent",
+                "tpm_hash_alg",
+                "KEYLIME_TPM_HASH_ALG",
+            )?
+            .as_str(),
         )?;
         let enc_alg = EncryptionAlgorithm::try_from(
-            config_get("cloud_agent", "tpm_encryption_alg")?.as_str(),
+            config_get_env(
+                "cloud_agent",
+                "tpm_encryption_alg",
+                "KEYLIME_TPM_ENCRYPTION_ALG",
+            )?
+            .as_str(),
         )?;
         let sign_alg = SignAlgorithm::try_from(
-            config_get("cloud_agent", "tpm_signing_alg")?.as_str(),
+            config_get_env(
+                "cloud_agent",
+                "tpm_signing_alg",
+                "KEYLIME_TPM_SIGNING_ALG",
+            )?
+            .as_str(),
         )?;
         // There was a typo in Python Keylime and this accounts for having a version
         // of Keylime installed that still has this typo. TODO: Remove later
         let run_revocation = bool::from_str(
-            &config_get("cloud_agent", "listen_notifications")
-                .or_else(|_| {
-                    config_get("cloud_agent", "listen_notfications")
-                })?
-                .to_lowercase(),
+            &config_get_env(
+                "cloud_agent",
+                "listen_notifications",
+                "KEYLIME_LISTEN_NOTIFICATIONS",
+            )
+            .or_else(|_| config_get("cloud_agent", "listen_notfications"))?
+            .to_lowercase(),
+        )?;
+        let revocation_cert = config_get_env(
+            "cloud_agent",
+            "revocation_cert",
+            "KEYLIME_REVOCATION_CERT",
+        )?;
+        let revocation_cert_ca = config_get_env(
+            "cloud_agent",
+            "revocation_cert_ca",
+            "KEYLIME_REVOCATION_CERT_CA",
+        )
+        .or_else::<Error, _>(|_| Ok(String::from(REV_CERT_CA)))?
+        .trim()
+        .to_string();
+        let revocation_cert_cache_ttl = config_get_env(
+            "cloud_agent",
+            "revocation_cert_cache_ttl",
+            "KEYLIME_REVOCATION_CERT_CACHE_TTL",
+        )
+        .or_else::<Error, _>(|_| Ok(String::from(REV_CERT_CACHE_TTL)))?;
+        let revocation_cert_cache_ttl =
+            match revocation_cert_cache_ttl.trim().parse::<u64>() {
+                Ok(secs) => Duration::from_secs(secs),
+                _ => {
+                    return Err(Error::Configuration(format!(
+                        "Invalid revocation_cert_cache_ttl {}: expected a number of seconds",
+                        revocation_cert_cache_ttl
+                    )))
+                }
+            };
+        let revocation_ip = revocation_ip_get()?;
+        let revocation_port = revocation_port_get()?;
+
+        let secure_size = config_get_env(
+            "cloud_agent",
+            "secure_size",
+            "KEYLIME_SECURE_SIZE",
+        )?;
+        let _ = crate::secure_mount::SecureSize::from_str(&secure_size)?;
+        let payload_script = config_get_env(
+            "cloud_agent",
+            "payload_script",
+            "KEYLIME_PAYLOAD_SCRIPT",
+        )?;
+        let dec_payload_filename = config_get_env(
+            "cloud_agent",
+            "dec_payload_file",
+            "KEYLIME_DEC_PAYLOAD_FILE",
+        )?;
+        let key_filename = config_get_env(
+            "cloud_agent",
+            "enc_keyname",
+            "KEYLIME_ENC_KEYNAME",
         )?;
-        let revocation_cert = config_get("cloud_agent", "revocation_cert")?;
-        let revocation_ip = config_get("general", "receive_revocation_ip")?;
-        let revocation_port =
-            config_get("general", "receive_revocation_port")?;
-
-        let secure_size = config_get("cloud_agent", "secure_size")?;
-        let payload_script = config_get("cloud_agent", "payload_script")?;
-        let dec_payload_filename =
-            config_get("cloud_agent", "dec_payload_file")?;
-        let key_filename = config_get("cloud_agent", "enc_keyname")?;
         let extract_payload_zip = bool::from_str(
-            &config_get("cloud_agent", "extract_payload_zip")?.to_lowercase(),
+            &config_get_env(
+                "cloud_agent",
+                "extract_payload_zip",
+                "KEYLIME_EXTRACT_PAYLOAD_ZIP",
+            )?
+            .to_lowercase(),
         )?;
 
         let work_dir =
             config_get_env("cloud_agent", "keylime_dir", "KEYLIME_DIR")
                 .or_else::<Error, _>(|_| Ok(String::from(WORK_DIR)))?;
 
+        let persist_ak = match config_get_env(
+            "cloud_agent",
+            "persist_ak",
+            "KEYLIME_PERSIST_AK",
+        ) {
+            Ok(s) => bool::from_str(&s.to_lowercase())?,
+            Err(_) => PERSIST_AK,
+        };
+
         let tpm_data_path = PathBuf::from(&work_dir).join(TPM_DATA);
-        let tpm_data = if tpm_data_path.exists() {
+        let tpm_data = if !persist_ak {
+            None
+        } else if tpm_data_path.exists() {
             match TpmData::load(&tpm_data_path) {
                 Ok(data) => Some(data),
                 Err(e) => {
@@ -303,42 +626,553 @@ impl KeylimeConfig {
             None
         };
 
-        let mut keylime_ca_path = config_get("cloud_agent", "keylime_ca")?;
+        let mut keylime_ca_path = config_get_env(
+            "cloud_agent",
+            "keylime_ca",
+            "KEYLIME_KEYLIME_CA",
+        )?;
         if keylime_ca_path == "default" {
             keylime_ca_path = Path::new(&work_dir)
                 .join(DEFAULT_CA_PATH)
                 .display()
                 .to_string();
         }
-        let revocation_actions =
-            config_get("cloud_agent", "revocation_actions")
-                .or_else::<Error, _>(|_| Ok(String::from(REV_ACTIONS)))?;
-        let revocation_actions_dir =
-            config_get("cloud_agent", "revocation_actions_dir")
-                .or_else::<Error, _>(|_| Ok(String::from(REV_ACTIONS_DIR)))?;
-        let allow_payload_revocation_actions = match config_get(
+        let revocation_actions = config_get_env(
+            "cloud_agent",
+            "revocation_actions",
+            "KEYLIME_REVOCATION_ACTIONS",
+        )
+        .or_else::<Error, _>(|_| Ok(String::from(REV_ACTIONS)))?;
+        let revocation_actions_dir = config_get_env(
+            "cloud_agent",
+            "revocation_actions_dir",
+            "KEYLIME_REVOCATION_ACTIONS_DIR",
+        )
+        .or_else::<Error, _>(|_| Ok(String::from(REV_ACTIONS_DIR)))?;
+        let python_shim_path = config_get_env(
+            "cloud_agent",
+            "python_shim_path",
+            "KEYLIME_PYTHON_SHIM_PATH",
+        )
+        .or_else::<Error, _>(|_| Ok(String::from(PYTHON_SHIM_PATH)))?;
+        let python_interpreter = config_get_env(
+            "cloud_agent",
+            "python_interpreter",
+            "KEYLIME_PYTHON_INTERPRETER",
+        )
+        .or_else::<Error, _>(|_| Ok(String::from(PYTHON_INTERPRETER)))?;
+        let allow_payload_revocation_actions = match config_get_env(
             "cloud_agent",
             "allow_payload_revocation_actions",
+            "KEYLIME_ALLOW_PAYLOAD_REVOCATION_ACTIONS",
         ) {
             Ok(s) => bool::from_str(&s.to_lowercase())?,
             Err(_) => ALLOW_PAYLOAD_REV_ACTIONS,
         };
+        let revocation_action_timeout = config_get_env(
+            "cloud_agent",
+            "revocation_action_timeout",
+            "KEYLIME_REVOCATION_ACTION_TIMEOUT",
+        )
+        .or_else::<Error, _>(|_| Ok(String::from(REV_ACTION_TIMEOUT)))?;
+        let revocation_action_timeout = match revocation_action_timeout
+            .trim()
+            .parse::<u64>()
+        {
+            Ok(secs) if secs > 0 => Duration::from_secs(secs),
+            _ => {
+                return Err(Error::Configuration(format!(
+                    "Invalid revocation_action_timeout {}: expected a positive number of seconds",
+                    revocation_action_timeout
+                )))
+            }
+        };
+        let revocation_action_max_attempts = config_get_env(
+            "cloud_agent",
+            "revocation_action_max_attempts",
+            "KEYLIME_REVOCATION_ACTION_MAX_ATTEMPTS",
+        )
+        .or_else::<Error, _>(|_| Ok(String::from(REV_ACTION_MAX_ATTEMPTS)))?;
+        let revocation_action_max_attempts =
+            match revocation_action_max_attempts.trim().parse::<u32>() {
+                Ok(attempts) if attempts > 0 => attempts,
+                _ => {
+                    return Err(Error::Configuration(format!(
+                        "Invalid revocation_action_max_attempts {}: expected a positive number",
+                        revocation_action_max_attempts
+                    )))
+                }
+            };
+        let revocation_action_retry_delay = config_get_env(
+            "cloud_agent",
+            "revocation_action_retry_delay",
+            "KEYLIME_REVOCATION_ACTION_RETRY_DELAY",
+        )
+        .or_else::<Error, _>(|_| Ok(String::from(REV_ACTION_RETRY_DELAY)))?;
+        let revocation_action_retry_delay =
+            match revocation_action_retry_delay.trim().parse::<u64>() {
+                Ok(secs) => Duration::from_secs(secs),
+                _ => {
+                    return Err(Error::Configuration(format!(
+                        "Invalid revocation_action_retry_delay {}: expected a number of seconds",
+                        revocation_action_retry_delay
+                    )))
+                }
+            };
+        let revocation_action_retry_exit_codes = parse_exit_code_list(
+            &config_get_env(
+                "cloud_agent",
+                "revocation_action_retry_exit_codes",
+                "KEYLIME_REVOCATION_ACTION_RETRY_EXIT_CODES",
+            )
+            .or_else::<Error, _>(|_| {
+                Ok(String::from(REV_ACTION_RETRY_EXIT_CODES))
+            })?,
+        )?;
+        let check_revocation_actions_dir_permissions = match config_get_env(
+            "cloud_agent",
+            "check_revocation_actions_dir_permissions",
+            "KEYLIME_CHECK_REVOCATION_ACTIONS_DIR_PERMISSIONS",
+        ) {
+            Ok(s) => bool::from_str(&s.to_lowercase())?,
+            Err(_) => CHECK_REV_ACTIONS_DIR_PERMISSIONS,
+        };
+        let allow_revocation_action_failures = match config_get_env(
+            "cloud_agent",
+            "allow_revocation_action_failures",
+            "KEYLIME_ALLOW_REVOCATION_ACTION_FAILURES",
+        ) {
+            Ok(s) => bool::from_str(&s.to_lowercase())?,
+            Err(_) => ALLOW_REV_ACTION_FAILURES,
+        };
+        let revocation_actions_raw_message = match config_get_env(
+            "cloud_agent",
+            "revocation_actions_raw_message",
+            "KEYLIME_REVOCATION_ACTIONS_RAW_MESSAGE",
+        ) {
+            Ok(s) => bool::from_str(&s.to_lowercase())?,
+            Err(_) => REV_ACTIONS_RAW_MESSAGE,
+        };
+        let watch_revocation_cert = match config_get_env(
+            "cloud_agent",
+            "watch_revocation_cert",
+            "KEYLIME_WATCH_REVOCATION_CERT",
+        ) {
+            Ok(s) => bool::from_str(&s.to_lowercase())?,
+            Err(_) => WATCH_REV_CERT,
+        };
+        let revocation_notification_url = config_get_env(
+            "cloud_agent",
+            "revocation_notification_url",
+            "KEYLIME_REVOCATION_NOTIFICATION_URL",
+        )
+        .or_else::<Error, _>(|_| Ok(String::from(REV_NOTIFICATION_URL)))?
+        .trim()
+        .to_string();
+        let revocation_notification_ca = config_get_env(
+            "cloud_agent",
+            "revocation_notification_ca",
+            "KEYLIME_REVOCATION_NOTIFICATION_CA",
+        )
+        .or_else::<Error, _>(|_| Ok(String::from(REV_NOTIFICATION_CA)))?
+        .trim()
+        .to_string();
+        let revocation_poll_interval = config_get_env(
+            "cloud_agent",
+            "revocation_poll_interval",
+            "KEYLIME_REVOCATION_POLL_INTERVAL",
+        )
+        .or_else::<Error, _>(|_| Ok(String::from(REV_POLL_INTERVAL)))?;
+        let revocation_poll_interval = match revocation_poll_interval
+            .trim()
+            .parse::<u64>()
+        {
+            Ok(secs) if secs > 0 => Duration::from_secs(secs),
+            _ => {
+                return Err(Error::Configuration(format!(
+                    "Invalid revocation_poll_interval {}: expected a positive number of seconds",
+                    revocation_poll_interval
+                )))
+            }
+        };
+        let revocation_transports = config_get_env(
+            "cloud_agent",
+            "revocation_transports",
+            "KEYLIME_REVOCATION_TRANSPORTS",
+        )
+        .or_else::<Error, _>(|_| Ok(String::from(REV_TRANSPORTS)))?
+        .split(',')
+        .map(str::trim)
+        .filter(|transport| !transport.is_empty())
+        .map(RevocationTransport::from_str)
+        .collect::<Result<Vec<RevocationTransport>>>()?;
+        let revocation_max_age = config_get_env(
+            "cloud_agent",
+            "revocation_max_age",
+            "KEYLIME_REVOCATION_MAX_AGE",
+        )
+        .or_else::<Error, _>(|_| Ok(String::from(REV_MAX_AGE)))?;
+        let revocation_max_age =
+            match revocation_max_age.trim().parse::<u64>() {
+                Ok(secs) => Duration::from_secs(secs),
+                _ => {
+                    return Err(Error::Configuration(format!(
+                "Invalid revocation_max_age {}: expected a number of seconds",
+                revocation_max_age
+            )))
+                }
+            };
+        let revocation_signature_digest = config_get_env(
+            "cloud_agent",
+            "revocation_signature_digest",
+            "KEYLIME_REVOCATION_SIGNATURE_DIGEST",
+        )
+        .or_else::<Error, _>(|_| Ok(String::from(REV_SIGNATURE_DIGEST)))?;
+        let revocation_signature_digest =
+            match HashAlgorithm::try_from(revocation_signature_digest.trim())
+            {
+                Ok(digest)
+                    if matches!(
+                        digest,
+                        HashAlgorithm::Sha256
+                            | HashAlgorithm::Sha384
+                            | HashAlgorithm::Sha512
+                    ) =>
+                {
+                    digest
+                }
+                _ => {
+                    return Err(Error::Configuration(format!(
+                "Invalid revocation_signature_digest {}: expected sha256, sha384 or sha512",
+                revocation_signature_digest
+            )))
+                }
+            };
+        let revocation_actions_max_concurrency = config_get_env(
+            "cloud_agent",
+            "revocation_actions_max_concurrency",
+            "KEYLIME_REVOCATION_ACTIONS_MAX_CONCURRENCY",
+        )
+        .or_else::<Error, _>(|_| {
+            Ok(String::from(REV_ACTIONS_MAX_CONCURRENCY))
+        })?;
+        let revocation_actions_max_concurrency =
+            match revocation_actions_max_concurrency.trim().parse::<usize>() {
+                Ok(max) if max > 0 => max,
+                _ => {
+                    return Err(Error::Configuration(format!(
+                        "Invalid revocation_actions_max_concurrency {}: expected a positive number",
+                        revocation_actions_max_concurrency
+                    )))
+                }
+            };
+        let revocation_actions_allowlist = config_get_env(
+            "cloud_agent",
+            "revocation_actions_allowlist",
+            "KEYLIME_REVOCATION_ACTIONS_ALLOWLIST",
+        )
+        .or_else::<Error, _>(|_| Ok(String::from(REV_ACTIONS_ALLOWLIST)))?;
+        let revocation_actions_denylist = config_get_env(
+            "cloud_agent",
+            "revocation_actions_denylist",
+            "KEYLIME_REVOCATION_ACTIONS_DENYLIST",
+        )
+        .or_else::<Error, _>(|_| Ok(String::from(REV_ACTIONS_DENYLIST)))?;
+        let revocation_action_scratch_cleanup = ScratchCleanup::from_str(
+            &config_get_env(
+                "cloud_agent",
+                "revocation_action_scratch_cleanup",
+                "KEYLIME_REVOCATION_ACTION_SCRATCH_CLEANUP",
+            )
+            .or_else::<Error, _>(|_| {
+                Ok(String::from(REV_ACTION_SCRATCH_CLEANUP))
+            })?,
+        )?;
         let ima_ml_path = ima_ml_path_get();
         let measuredboot_ml_path = Path::new(MEASUREDBOOT_ML).to_path_buf();
 
-        let mtls_enabled =
-            match config_get("cloud_agent", "mtls_cert_enabled") {
-                Ok(enabled) => bool::from_str(&enabled.to_lowercase())
-                    .or::<Error>(Ok(MTLS_ENABLED))?,
-                Err(_) => true,
+        let mtls_enabled = match config_get_env(
+            "cloud_agent",
+            "mtls_cert_enabled",
+            "KEYLIME_MTLS_CERT_ENABLED",
+        ) {
+            Ok(enabled) => bool::from_str(&enabled.to_lowercase())
+                .or::<Error>(Ok(MTLS_ENABLED))?,
+            Err(_) => true,
+        };
+
+        let enable_insecure_payload = match config_get_env(
+            "cloud_agent",
+            "enable_insecure_payload",
+            "KEYLIME_ENABLE_INSECURE_PAYLOAD",
+        ) {
+            Ok(allowed) => bool::from_str(&allowed.to_lowercase())
+                .or::<Error>(Ok(ALLOW_INSECURE_PAYLOAD))?,
+            Err(_) => false,
+        };
+
+        let required_pcrs = parse_pcr_list(
+            &config_get_env(
+                "cloud_agent",
+                "required_pcrs",
+                "KEYLIME_REQUIRED_PCRS",
+            )
+            .or_else::<Error, _>(|_| Ok(String::from(REQUIRED_PCRS)))?,
+        )?;
+        let allowed_pcrs = parse_pcr_list(
+            &config_get_env(
+                "cloud_agent",
+                "allowed_pcrs",
+                "KEYLIME_ALLOWED_PCRS",
+            )
+            .or_else::<Error, _>(|_| Ok(String::from(ALLOWED_PCRS)))?,
+        )?;
+        if let Some(pcr) = required_pcrs.iter().find(|pcr| {
+            !allowed_pcrs.is_empty() && !allowed_pcrs.contains(pcr)
+        }) {
+            return Err(Error::Configuration(format!(
+                "required PCR {} is not in allowed_pcrs",
+                pcr
+            )));
+        }
+        let ima_pcr = parse_pcr_index(
+            "ima_pcr",
+            &config_get_env("cloud_agent", "ima_pcr", "KEYLIME_IMA_PCR")
+                .or_else::<Error, _>(|_| Ok(IMA_PCR.to_string()))?,
+        )?;
+        let measuredboot_pcr = parse_pcr_index(
+            "measuredboot_pcr",
+            &config_get_env(
+                "cloud_agent",
+                "measuredboot_pcr",
+                "KEYLIME_MEASUREDBOOT_PCR",
+            )
+            .or_else::<Error, _>(|_| Ok(MEASUREDBOOT_PCR.to_string()))?,
+        )?;
+        let ima_ml_max_bytes = config_get_env(
+            "cloud_agent",
+            "ima_ml_max_bytes",
+            "KEYLIME_IMA_ML_MAX_BYTES",
+        )
+        .or_else::<Error, _>(|_| Ok(String::from(IMA_ML_MAX_BYTES)))?;
+        let ima_ml_max_bytes = match ima_ml_max_bytes.trim().parse::<usize>()
+        {
+            Ok(max) => max,
+            _ => {
+                return Err(Error::Configuration(format!(
+                    "Invalid ima_ml_max_bytes {}: expected a number",
+                    ima_ml_max_bytes
+                )))
+            }
+        };
+        let ima_signature_verification = ImaSignatureMode::from_str(
+            &config_get_env(
+                "cloud_agent",
+                "ima_signature_verification",
+                "KEYLIME_IMA_SIGNATURE_VERIFICATION",
+            )
+            .or_else::<Error, _>(|_| {
+                Ok(String::from(IMA_SIGNATURE_VERIFICATION))
+            })?,
+        )?;
+        let ima_signing_keys = config_get_env(
+            "cloud_agent",
+            "ima_signing_keys",
+            "KEYLIME_IMA_SIGNING_KEYS",
+        )
+        .or_else::<Error, _>(|_| Ok(String::from(IMA_SIGNING_KEYS)))?;
+        if ima_signature_verification != ImaSignatureMode::Off
+            && ima_signing_keys.is_empty()
+        {
+            return Err(Error::Configuration(
+                "ima_signing_keys must be set to verify IMA signatures"
+                    .to_string(),
+            ));
+        }
+        let identity_quote_cache_ttl = config_get_env(
+            "cloud_agent",
+            "identity_quote_cache_ttl",
+            "KEYLIME_IDENTITY_QUOTE_CACHE_TTL",
+        )
+        .or_else::<Error, _>(|_| {
+            Ok(String::from(IDENTITY_QUOTE_CACHE_TTL))
+        })?;
+        let identity_quote_cache_ttl =
+            match identity_quote_cache_ttl.trim().parse::<u64>() {
+                Ok(secs) => Duration::from_secs(secs),
+                _ => {
+                    return Err(Error::Configuration(format!(
+                        "Invalid identity_quote_cache_ttl {}: expected a number of seconds",
+                        identity_quote_cache_ttl
+                    )))
+                }
+            };
+
+        let nonce_reuse_window = config_get_env(
+            "cloud_agent",
+            "nonce_reuse_window",
+            "KEYLIME_NONCE_REUSE_WINDOW",
+        )
+        .or_else::<Error, _>(|_| Ok(String::from(NONCE_REUSE_WINDOW)))?;
+        let nonce_reuse_window =
+            match nonce_reuse_window.trim().parse::<u64>() {
+                Ok(secs) => Duration::from_secs(secs),
+                _ => {
+                    return Err(Error::Configuration(format!(
+                "Invalid nonce_reuse_window {}: expected a number of seconds",
+                nonce_reuse_window
+            )))
+                }
             };
 
-        let enable_insecure_payload =
-            match config_get("cloud_agent", "enable_insecure_payload") {
-                Ok(allowed) => bool::from_str(&allowed.to_lowercase())
-                    .or::<Error>(Ok(ALLOW_INSECURE_PAYLOAD))?,
-                Err(_) => false,
+        let quote_rate_limit = config_get_env(
+            "cloud_agent",
+            "quote_rate_limit",
+            "KEYLIME_QUOTE_RATE_LIMIT",
+        )
+        .or_else::<Error, _>(|_| Ok(String::from(QUOTE_RATE_LIMIT)))?;
+        let quote_rate_limit = match quote_rate_limit.trim().parse::<f64>() {
+            Ok(rate) if rate.is_finite() && rate >= 0.0 => rate,
+            _ => {
+                return Err(Error::Configuration(format!(
+                    "Invalid quote_rate_limit {}: expected a number of requests per second",
+                    quote_rate_limit
+                )))
+            }
+        };
+
+        let quote_rate_limit_burst = config_get_env(
+            "cloud_agent",
+            "quote_rate_limit_burst",
+            "KEYLIME_QUOTE_RATE_LIMIT_BURST",
+        )
+        .or_else::<Error, _>(|_| Ok(String::from(QUOTE_RATE_LIMIT_BURST)))?;
+        let quote_rate_limit_burst =
+            match quote_rate_limit_burst.trim().parse::<u32>() {
+                Ok(burst) if burst > 0 => burst,
+                _ => {
+                    return Err(Error::Configuration(format!(
+                        "Invalid quote_rate_limit_burst {}: expected a positive number of requests",
+                        quote_rate_limit_burst
+                    )))
+                }
+            };
+
+        let require_client_auth = match config_get_env(
+            "cloud_agent",
+            "require_client_auth",
+            "KEYLIME_REQUIRE_CLIENT_AUTH",
+        ) {
+            Ok(s) => bool::from_str(&s.to_lowercase())
+                .or::<Error>(Ok(REQUIRE_CLIENT_AUTH))?,
+            Err(_) => REQUIRE_CLIENT_AUTH,
+        };
+        if require_client_auth && !mtls_enabled {
+            return Err(Error::Configuration(
+                "require_client_auth requires mtls_cert_enabled".to_string(),
+            ));
+        }
+        let mut client_auth_ca = config_get_env(
+            "cloud_agent",
+            "client_auth_ca",
+            "KEYLIME_CLIENT_AUTH_CA",
+        )
+        .or_else::<Error, _>(|_| Ok(String::from(CLIENT_AUTH_CA)))?;
+        if client_auth_ca.trim().is_empty() {
+            client_auth_ca = keylime_ca_path.clone();
+        }
+        let client_auth_allowed_subjects = config_get_env(
+            "cloud_agent",
+            "client_auth_allowed_subjects",
+            "KEYLIME_CLIENT_AUTH_ALLOWED_SUBJECTS",
+        )
+        .or_else::<Error, _>(|_| {
+            Ok(String::from(CLIENT_AUTH_ALLOWED_SUBJECTS))
+        })?
+        .split(',')
+        .map(str::trim)
+        .filter(|subject| !subject.is_empty())
+        .map(String::from)
+        .collect::<Vec<String>>();
+
+        let tpm_tcti =
+            config_get_env("cloud_agent", "tpm_tcti", "KEYLIME_TPM_TCTI")
+                .or_else::<Error, _>(|_| Ok(String::from(TPM_TCTI)))?;
+        let tpm_tcti = tpm_tcti.trim().to_string();
+        if !tpm_tcti.is_empty() {
+            let _ = crate::tpm::parse_tcti(&tpm_tcti)?;
+        }
+
+        let tpm_lock_timeout = config_get_env(
+            "cloud_agent",
+            "tpm_lock_timeout",
+            "KEYLIME_TPM_LOCK_TIMEOUT",
+        )
+        .or_else::<Error, _>(|_| Ok(String::from(TPM_LOCK_TIMEOUT)))?;
+        let tpm_lock_timeout = match tpm_lock_timeout.trim().parse::<u64>() {
+            Ok(secs) => Duration::from_secs(secs),
+            _ => {
+                return Err(Error::Configuration(format!(
+                "Invalid tpm_lock_timeout {}: expected a number of seconds",
+                tpm_lock_timeout
+            )))
+            }
+        };
+
+        let push_attestation_url = config_get_env(
+            "cloud_agent",
+            "push_attestation_url",
+            "KEYLIME_PUSH_ATTESTATION_URL",
+        )
+        .or_else::<Error, _>(|_| Ok(String::from(PUSH_ATTESTATION_URL)))?
+        .trim()
+        .to_string();
+        if !push_attestation_url.is_empty()
+            && !push_attestation_url.starts_with("https://")
+        {
+            return Err(Error::Configuration(format!(
+                "Invalid push_attestation_url {}: only https:// URLs are supported",
+                push_attestation_url
+            )));
+        }
+        let push_attestation_interval = config_get_env(
+            "cloud_agent",
+            "push_attestation_interval",
+            "KEYLIME_PUSH_ATTESTATION_INTERVAL",
+        )
+        .or_else::<Error, _>(|_| {
+            Ok(String::from(PUSH_ATTESTATION_INTERVAL))
+        })?;
+        let push_attestation_interval =
+            match push_attestation_interval.trim().parse::<u64>() {
+                Ok(secs) if secs > 0 => Duration::from_secs(secs),
+                _ => {
+                    return Err(Error::Configuration(format!(
+                        "Invalid push_attestation_interval {}: expected a positive number of seconds",
+                        push_attestation_interval
+                    )))
+                }
             };
+        let push_attestation_ca = config_get_env(
+            "cloud_agent",
+            "push_attestation_ca",
+            "KEYLIME_PUSH_ATTESTATION_CA",
+        )
+        .or_else::<Error, _>(|_| Ok(String::from(PUSH_ATTESTATION_CA)))?;
+        let push_attestation_mask = config_get_env(
+            "cloud_agent",
+            "push_attestation_mask",
+            "KEYLIME_PUSH_ATTESTATION_MASK",
+        )
+        .or_else::<Error, _>(|_| Ok(String::from(PUSH_ATTESTATION_MASK)))?
+        .trim()
+        .to_string();
+        if let Err(e) = crate::tpm::read_mask(&push_attestation_mask) {
+            return Err(Error::Configuration(format!(
+                "Invalid push_attestation_mask: {}",
+                e
+            )));
+        }
 
         Ok(KeylimeConfig {
             agent_ip,
@@ -353,8 +1187,11 @@ impl KeylimeConfig {
             sign_alg,
             tpm_data,
             tpm_data_path: tpm_data_path.display().to_string(),
+            persist_ak,
             run_revocation,
             revocation_cert,
+            revocation_cert_ca,
+            revocation_cert_cache_ttl,
             revocation_ip,
             revocation_port,
             secure_size,
@@ -365,12 +1202,52 @@ impl KeylimeConfig {
             keylime_ca_path,
             revocation_actions,
             revocation_actions_dir,
+            python_shim_path,
+            python_interpreter,
             allow_payload_revocation_actions,
+            revocation_action_timeout,
+            revocation_action_max_attempts,
+            revocation_action_retry_delay,
+            revocation_action_retry_exit_codes,
+            check_revocation_actions_dir_permissions,
+            allow_revocation_action_failures,
+            revocation_actions_max_concurrency,
+            revocation_actions_raw_message,
+            revocation_actions_allowlist,
+            revocation_actions_denylist,
+            revocation_action_scratch_cleanup,
+            watch_revocation_cert,
+            revocation_notification_url,
+            revocation_notification_ca,
+            revocation_poll_interval,
+            revocation_transports,
+            revocation_max_age,
+            revocation_signature_digest,
             work_dir,
             ima_ml_path: ima_ml_path.display().to_string(),
             measuredboot_ml_path: measuredboot_ml_path.display().to_string(),
             mtls_enabled,
             enable_insecure_payload,
+            required_pcrs,
+            allowed_pcrs,
+            ima_pcr,
+            measuredboot_pcr,
+            ima_ml_max_bytes,
+            ima_signature_verification,
+            ima_signing_keys,
+            identity_quote_cache_ttl,
+            nonce_reuse_window,
+            quote_rate_limit,
+            quote_rate_limit_burst,
+            require_client_auth,
+            client_auth_ca,
+            client_auth_allowed_subjects,
+            tpm_tcti,
+            tpm_lock_timeout,
+            push_attestation_url,
+            push_attestation_interval,
+            push_attestation_ca,
+            push_attestation_mask,
         })
     }
 }
@@ -395,8 +1272,11 @@ impl Default for KeylimeConfig {
                 .join(TPM_DATA)
                 .display()
                 .to_string(),
+            persist_ak: true,
             run_revocation: true,
             revocation_cert: "default".to_string(),
+            revocation_cert_ca: String::new(),
+            revocation_cert_cache_ttl: Duration::from_secs(3600),
             revocation_ip: "127.0.0.1".to_string(),
             revocation_port: "8992".to_string(),
             secure_size: "1m".to_string(),
@@ -407,12 +1287,52 @@ impl Default for KeylimeConfig {
             keylime_ca_path: DEFAULT_CA_PATH.to_string(),
             revocation_actions: "".to_string(),
             revocation_actions_dir: "/usr/libexec/keylime".to_string(),
+            python_shim_path: String::new(),
+            python_interpreter: String::new(),
             allow_payload_revocation_actions: true,
+            revocation_action_timeout: Duration::from_secs(60),
+            revocation_action_max_attempts: 1,
+            revocation_action_retry_delay: Duration::from_secs(1),
+            revocation_action_retry_exit_codes: vec![75],
+            check_revocation_actions_dir_permissions: false,
+            allow_revocation_action_failures: false,
+            revocation_actions_max_concurrency: 1,
+            revocation_actions_raw_message: false,
+            revocation_actions_allowlist: String::new(),
+            revocation_actions_denylist: String::new(),
+            revocation_action_scratch_cleanup: ScratchCleanup::Always,
+            watch_revocation_cert: false,
+            revocation_notification_url: String::new(),
+            revocation_notification_ca: String::new(),
+            revocation_poll_interval: Duration::from_secs(10),
+            revocation_transports: Vec::new(),
+            revocation_max_age: Duration::from_secs(0),
+            revocation_signature_digest: HashAlgorithm::Sha256,
             work_dir: WORK_DIR.to_string(),
             ima_ml_path: IMA_ML.to_string(),
             measuredboot_ml_path: MEASUREDBOOT_ML.to_string(),
             mtls_enabled: true,
             enable_insecure_payload: false,
+            required_pcrs: Vec::new(),
+            allowed_pcrs: Vec::new(),
+            ima_pcr: IMA_PCR as u32,
+            measuredboot_pcr: MEASUREDBOOT_PCR as u32,
+            ima_ml_max_bytes: 0,
+            ima_signature_verification: ImaSignatureMode::Off,
+            ima_signing_keys: String::new(),
+            identity_quote_cache_ttl: Duration::from_secs(0),
+            nonce_reuse_window: Duration::from_secs(0),
+            quote_rate_limit: 0.0,
+            quote_rate_limit_burst: 10,
+            require_client_auth: false,
+            client_auth_ca: String::new(),
+            client_auth_allowed_subjects: Vec::new(),
+            tpm_tcti: String::new(),
+            tpm_lock_timeout: Duration::from_secs(30),
+            push_attestation_url: String::new(),
+            push_attestation_interval: Duration::from_secs(60),
+            push_attestation_ca: String::new(),
+            push_attestation_mask: PUSH_ATTESTATION_MASK.to_string(),
         }
     }
 }
@@ -544,6 +1464,10 @@ fn config_get(section: &str, key: &str) -> Result<String> {
  * Input: [section] and key and environment variable
  * Return: Returns the matched key
  *
+ * A non-empty environment variable takes precedence over the value in the
+ * configuration file. Values taken from the environment go through the same
+ * parsing and validation as values read from the file.
+ *
  * Example call:
  * let port = common::config_get_env("general","cloudagent_port", "CLOUDAGENT_PORT");
  */
@@ -561,6 +1485,59 @@ fn config_get_env(section: &str, key: &str, env: &str) -> Result<String> {
     }
 }
 
+/*
+ * Input: comma-separated list of process exit codes
+ * Return: Returns the parsed exit codes
+ *
+ * An empty string means an empty list.
+ */
+fn parse_exit_code_list(codes: &str) -> Result<Vec<i32>> {
+    codes
+        .split(',')
+        .map(str::trim)
+        .filter(|code| !code.is_empty())
+        .map(|code| {
+            code.parse::<i32>().map_err(|_| {
+                Error::Configuration(format!(
+                    "Invalid revocation_action_retry_exit_codes: {} is not an exit code",
+                    code
+                ))
+            })
+        })
+        .collect()
+}
+
+/*
+ * Input: comma-separated list of PCR indexes
+ * Return: Returns the parsed PCR indexes
+ *
+ * Used for options listing PCRs, e.g. "0, 10". An empty string means an
+ * empty list. Only PCRs 0-23 are accepted.
+ */
+fn parse_pcr_list(pcrs: &str) -> Result<Vec<u32>> {
+    pcrs.split(',')
+        .map(|pcr| pcr.trim())
+        .filter(|pcr| !pcr.is_empty())
+        .map(|pcr| match pcr.parse::<u32>() {
+            Ok(idx) if idx < 24 => Ok(idx),
+            _ => Err(Error::Configuration(format!(
+                "Invalid PCR {} in PCR list: only PCRs 0-23 are supported",
+                pcr
+            ))),
+        })
+        .collect()
+}
+
+fn parse_pcr_index(option: &str, pcr: &str) -> Result<u32> {
+    match pcr.trim().parse::<u32>() {
+        Ok(idx) if idx < 24 => Ok(idx),
+        _ => Err(Error::Configuration(format!(
+            "Invalid {} {}: only PCRs 0-23 are supported",
+            option, pcr
+        ))),
+    }
+}
+
 /*
  * Input: path directory to be changed owner to root
  *
@@ -592,6 +1569,10 @@ pub(crate) fn chownroot(path: &Path) -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::Mutex;
+
+    // Serializes the tests modifying the process environment
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
 
     #[test]
     fn test_config_get_parameters_exist() {
@@ -601,6 +1582,7 @@ mod tests {
 
     #[test]
     fn test_config_file_get() {
+        let _guard = ENV_LOCK.lock().unwrap(); //#[allow_ci]
         let conf_orig = option_env!("KEYLIME_CONFIG").or(Some("")).unwrap(); //#[allow_ci]
 
         // Test with no environment variable
@@ -614,6 +1596,66 @@ mod tests {
         env::set_var("KEYLIME_CONFIG", conf_orig);
     }
 
+    #[test]
+    fn test_config_env_override() {
+        let _guard = ENV_LOCK.lock().unwrap(); //#[allow_ci]
+        env::set_var(
+            "KEYLIME_CONFIG",
+            concat!(env!("CARGO_MANIFEST_DIR"), "/keylime.conf"),
+        );
+
+        // Value from the file
+        let config = KeylimeConfig::build().unwrap(); //#[allow_ci]
+        assert_eq!(config.revocation_cert, "default");
+        assert!(config.extract_payload_zip);
+
+        // The environment takes precedence over the file
+        env::set_var("KEYLIME_REVOCATION_CERT", "/tmp/revocation.crt");
+        env::set_var("KEYLIME_EXTRACT_PAYLOAD_ZIP", "False");
+        env::set_var("KEYLIME_KEYLIME_CA", "/tmp/cacert.crt");
+        let config = KeylimeConfig::build().unwrap(); //#[allow_ci]
+        assert_eq!(config.revocation_cert, "/tmp/revocation.crt");
+        assert!(!config.extract_payload_zip);
+        assert_eq!(config.keylime_ca_path, "/tmp/cacert.crt");
+
+        // Values from the environment are validated
+        env::set_var("KEYLIME_EXTRACT_PAYLOAD_ZIP", "maybe");
+        assert!(KeylimeConfig::build().is_err());
+        env::remove_var("KEYLIME_EXTRACT_PAYLOAD_ZIP");
+        env::set_var("KEYLIME_TPM_HASH_ALG", "md5");
+        assert!(KeylimeConfig::build().is_err());
+
+        env::remove_var("KEYLIME_TPM_HASH_ALG");
+        env::remove_var("KEYLIME_KEYLIME_CA");
+        env::remove_var("KEYLIME_REVOCATION_CERT");
+        env::remove_var("KEYLIME_CONFIG");
+    }
+
+    #[test]
+    fn test_parse_pcr_list() {
+        assert_eq!(parse_pcr_list("").unwrap(), Vec::<u32>::new()); //#[allow_ci]
+        assert_eq!(parse_pcr_list("0, 10").unwrap(), vec![0, 10]); //#[allow_ci]
+        assert_eq!(parse_pcr_list("10,").unwrap(), vec![10]); //#[allow_ci]
+        assert!(parse_pcr_list("24").is_err());
+        assert!(parse_pcr_list("ten").is_err());
+    }
+
+    #[test]
+    fn test_parse_pcr_index() {
+        assert_eq!(parse_pcr_index("ima_pcr", "10").unwrap(), 10); //#[allow_ci]
+        assert_eq!(parse_pcr_index("ima_pcr", " 23 ").unwrap(), 23); //#[allow_ci]
+        assert!(parse_pcr_index("ima_pcr", "24").is_err());
+        assert!(parse_pcr_index("ima_pcr", "").is_err());
+        assert!(parse_pcr_index("ima_pcr", "-1").is_err());
+    }
+
+    #[test]
+    fn test_parse_exit_code_list() {
+        assert_eq!(parse_exit_code_list("").unwrap(), Vec::<i32>::new()); //#[allow_ci]
+        assert_eq!(parse_exit_code_list("75, 1").unwrap(), vec![75, 1]); //#[allow_ci]
+        assert!(parse_exit_code_list("tempfail").is_err());
+    }
+
     #[test]
     fn test_get_uuid() {
         assert_eq!(get_uuid("openstack"), "openstack");
@@ -632,4 +1674,61 @@ mod tests {
         ))
         .unwrap(); //#[allow_ci]
     }
+
+    #[test]
+    fn test_json_wrapper_error_code() {
+        // The error code is only serialized when set
+        let value = serde_json::to_value(JsonWrapper::error(
+            ResponseStatus::BadRequest,
+            "Bad",
+        ))
+        .expect("unable to serialize");
+        assert_eq!(
+            value,
+            json!({"code": 400, "status": "Bad", "results": {}})
+        );
+
+        let value = serde_json::to_value(JsonWrapper::error_with_code(
+            ResponseStatus::BadRequest,
+            "nonce_too_long",
+            "Bad",
+        ))
+        .expect("unable to serialize");
+        assert_eq!(value["code"], 400);
+        assert_eq!(value["error_code"], "nonce_too_long");
+    }
+
+    #[test]
+    fn test_response_status_code() {
+        for (status, code) in [
+            (ResponseStatus::Success, 200),
+            (ResponseStatus::BadRequest, 400),
+            (ResponseStatus::Unauthorized, 401),
+            (ResponseStatus::Forbidden, 403),
+            (ResponseStatus::NotFound, 404),
+            (ResponseStatus::MethodNotAllowed, 405),
+            (ResponseStatus::TooManyRequests, 429),
+            (ResponseStatus::InternalServerError, 500),
+            (ResponseStatus::ServiceUnavailable, 503),
+        ] {
+            let value = serde_json::to_value(JsonWrapper::error(status, "x"))
+                .expect("unable to serialize");
+            assert_eq!(value["code"], code);
+            assert_eq!(status.http_status().as_u16(), code);
+            if status != ResponseStatus::Success {
+                assert_eq!(
+                    status.http_status().canonical_reason(),
+                    Some(status.reason())
+                );
+            }
+
+            let wrapper: JsonWrapper<Value> =
+                serde_json::from_value(value).expect("unable to deserialize");
+            assert_eq!(wrapper.code, status);
+        }
+        assert!(serde_json::from_value::<JsonWrapper<Value>>(
+            json!({"code": 418, "status": "", "results": {}})
+        )
+        .is_err());
+    }
 }
@@ -3,6 +3,7 @@
 
 use openssl::{
     asn1::Asn1Time,
+    ec::EcKey,
     encrypt::Decrypter,
     hash::MessageDigest,
     memcmp,
@@ -16,18 +17,33 @@ use openssl::{
     x509::store::X509StoreBuilder,
     x509::{X509Name, X509},
 };
+use std::fmt;
 use std::fs;
 use std::path::Path;
 use std::string::String;
 
 use crate::{
-    Error, Result, AES_128_KEY_LEN, AES_256_KEY_LEN, AES_BLOCK_SIZE,
+    algorithms::HashAlgorithm, Error, Result, AES_128_KEY_LEN,
+    AES_256_KEY_LEN, AES_BLOCK_SIZE,
 };
 
+// Marker that starts every PEM encoded block
+const PEM_MARKER: &[u8] = b"-----BEGIN";
+
 // Read a X509 cert or cert chain and outputs the first certificate
+//
+// The certificate may be either PEM or DER encoded. The encoding is sniffed
+// from the content rather than from the file extension.
 pub(crate) fn load_x509(input_cert_path: &Path) -> Result<X509> {
-    let contents = fs::read_to_string(&input_cert_path)?;
-    let mut cert_chain = X509::stack_from_pem(contents.as_bytes())?;
+    let contents = fs::read(input_cert_path)?;
+    if !contents
+        .windows(PEM_MARKER.len())
+        .any(|window| window == PEM_MARKER)
+    {
+        return X509::from_der(&contents).map_err(Error::Crypto);
+    }
+
+    let mut cert_chain = X509::stack_from_pem(&contents)?;
 
     if cert_chain.len() != 1 {
         return Err(Error::Other(
@@ -64,6 +80,12 @@ pub(crate) fn pkey_pub_from_priv(
             .map_err(Error::Crypto)?;
             PKey::from_rsa(rsa).map_err(Error::Crypto)
         }
+        Id::EC => {
+            let ec = privkey.ec_key()?;
+            let ec = EcKey::from_public_key(ec.group(), ec.public_key())
+                .map_err(Error::Crypto)?;
+            PKey::from_ec_key(ec).map_err(Error::Crypto)
+        }
         id => {
             return Err(Error::Other(format!(
                 "pkey_pub_from_priv not yet implemented for key type {:?}",
@@ -160,24 +182,187 @@ pub(crate) fn kdf(
     Ok(hex::encode(&key[..]))
 }
 
+/// Signature scheme a signature was checked with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum VerifyScheme {
+    RsaPss,
+    RsaPkcs1,
+    Ecdsa,
+}
+
+/// Signature algorithm a signature was checked with: the scheme and the
+/// digest of the message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct VerifyAlgorithm {
+    pub scheme: VerifyScheme,
+    pub digest: HashAlgorithm,
+}
+
+impl fmt::Display for VerifyAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = match self.scheme {
+            VerifyScheme::RsaPss => "RSA-PSS",
+            VerifyScheme::RsaPkcs1 => "RSA-PKCS1",
+            VerifyScheme::Ecdsa => "ECDSA",
+        };
+        write!(
+            f,
+            "{} with {}",
+            scheme,
+            self.digest.to_string().to_uppercase()
+        )
+    }
+}
+
+/// Result of a signature verification, along with the algorithms tried, so
+/// that a failure can be told apart from a scheme mismatch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct VerifyOutcome {
+    pub valid: bool,
+    /// Algorithms tried, in order. When the signature is valid, the last
+    /// one verified it.
+    pub tried: Vec<VerifyAlgorithm>,
+}
+
+impl VerifyOutcome {
+    /// The algorithm that verified the signature, if it is valid
+    pub(crate) fn alg(&self) -> Option<VerifyAlgorithm> {
+        if self.valid {
+            self.tried.last().copied()
+        } else {
+            None
+        }
+    }
+}
+
+/// Digests accepted for signature verification
+pub(crate) fn signature_digest(
+    digest: HashAlgorithm,
+) -> Result<MessageDigest> {
+    match digest {
+        HashAlgorithm::Sha256 => Ok(MessageDigest::sha256()),
+        HashAlgorithm::Sha384 => Ok(MessageDigest::sha384()),
+        HashAlgorithm::Sha512 => Ok(MessageDigest::sha512()),
+        other => Err(Error::Other(format!(
+            "Unsupported digest for signature verification: {}",
+            other
+        ))),
+    }
+}
+
+/// Checks signatures against trusted public keys, so that the verification
+/// of revocation messages can be delegated, e.g. to an HSM or a remote KMS
+pub(crate) trait SignatureVerifier {
+    /// Verifies the raw signature of the message with the key, the message
+    /// being hashed with the digest
+    fn verify(
+        &self,
+        keypair: &PKeyRef<Public>,
+        message: &[u8],
+        signature: &[u8],
+        digest: HashAlgorithm,
+    ) -> Result<VerifyOutcome>;
+}
+
+/// Verifies signatures with OpenSSL, as `asym_verify` does
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct OpensslVerifier;
+
+impl SignatureVerifier for OpensslVerifier {
+    fn verify(
+        &self,
+        keypair: &PKeyRef<Public>,
+        message: &[u8],
+        signature: &[u8],
+        digest: HashAlgorithm,
+    ) -> Result<VerifyOutcome> {
+        verify_signature(keypair, message, signature, digest)
+    }
+}
+
 /*
- * Input: Trusted public key, and remote message and signature
- * Output: true if they are verified, otherwise false
+ * Input: Trusted public key, remote message and signature, and the digest
+ *        of the message
+ * Output: whether they are verified, and the algorithms tried
  *
- * Verify a remote message and signature against a local rsa cert
+ * Verify a remote message and signature against a local cert. RSA keys are
+ * verified with RSA-PSS, then with PKCS#1 v1.5 padding, and EC keys with
+ * ECDSA.
  */
 pub(crate) fn asym_verify(
     keypair: &PKeyRef<Public>,
     message: &str,
     signature: &str,
-) -> Result<bool> {
-    let mut verifier = Verifier::new(MessageDigest::sha256(), keypair)?;
-    verifier.set_rsa_padding(Padding::PKCS1_PSS)?;
-    verifier.set_rsa_mgf1_md(MessageDigest::sha256())?;
-    verifier
+    digest: HashAlgorithm,
+) -> Result<VerifyOutcome> {
+    let signature = base64::decode(signature.as_bytes())?;
+    verify_signature(keypair, message.as_bytes(), &signature, digest)
+}
+
+// Verifies a raw signature, see asym_verify
+fn verify_signature(
+    keypair: &PKeyRef<Public>,
+    message: &[u8],
+    signature: &[u8],
+    digest: HashAlgorithm,
+) -> Result<VerifyOutcome> {
+    let md = signature_digest(digest)?;
+    let schemes: &[VerifyScheme] = match keypair.id() {
+        Id::RSA => &[VerifyScheme::RsaPss, VerifyScheme::RsaPkcs1],
+        Id::EC => &[VerifyScheme::Ecdsa],
+        id => {
+            return Err(Error::Other(format!(
+                "Unsupported key type for signature verification: {:?}",
+                id
+            )))
+        }
+    };
+    let mut tried = Vec::new();
+    for &scheme in schemes {
+        let mut verifier = Verifier::new(md, keypair)?;
+        match scheme {
+            VerifyScheme::RsaPss => {
+                verifier.set_rsa_padding(Padding::PKCS1_PSS)?;
+                verifier.set_rsa_mgf1_md(md)?;
+                verifier.set_rsa_pss_saltlen(
+                    openssl::sign::RsaPssSaltlen::MAXIMUM_LENGTH,
+                )?;
+            }
+            VerifyScheme::RsaPkcs1 => {
+                verifier.set_rsa_padding(Padding::PKCS1)?
+            }
+            // The ECDSA signature is DER encoded, nothing to configure
+            VerifyScheme::Ecdsa => {}
+        }
+        verifier.update(message)?;
+        tried.push(VerifyAlgorithm { scheme, digest });
+        if verifier.verify(signature)? {
+            return Ok(VerifyOutcome { valid: true, tried });
+        }
+    }
+    Ok(VerifyOutcome {
+        valid: false,
+        tried,
+    })
+}
+
+/*
+ * Input: Local private key and message
+ * Output: base64 encoded signature
+ *
+ * Sign a message so that it can be verified with asym_verify
+ */
+pub(crate) fn asym_sign(
+    keypair: &PKeyRef<Private>,
+    message: &str,
+) -> Result<String> {
+    let mut signer = Signer::new(MessageDigest::sha256(), keypair)?;
+    signer.set_rsa_padding(Padding::PKCS1_PSS)?;
+    signer.set_rsa_mgf1_md(MessageDigest::sha256())?;
+    signer
         .set_rsa_pss_saltlen(openssl::sign::RsaPssSaltlen::MAXIMUM_LENGTH)?;
-    verifier.update(message.as_bytes())?;
-    Ok(verifier.verify(&base64::decode(signature.as_bytes())?)?)
+    signer.update(message.as_bytes())?;
+    Ok(base64::encode(signer.sign_to_vec()?))
 }
 
 /*
@@ -279,6 +464,7 @@ pub(crate) fn decrypt_aead(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
 
 pub mod testing {
     use super::*;
+    use openssl::ec::EcGroup;
     use openssl::encrypt::Encrypter;
     use std::path::Path;
 
@@ -291,6 +477,14 @@ pub mod testing {
         Ok((public, private))
     }
 
+    pub(crate) fn ec_generate_pair() -> Result<(PKey<Public>, PKey<Private>)>
+    {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let private = PKey::from_ec_key(EcKey::generate(&group)?)?;
+        let public = pkey_pub_from_priv(private.clone())?;
+        Ok((public, private))
+    }
+
     pub(crate) fn pkey_pub_from_pem(pem: &str) -> Result<PKey<Public>> {
         PKey::<Public>::public_key_from_pem(pem.as_bytes())
             .map_err(Error::Crypto)
@@ -537,6 +731,156 @@ mod tests {
 
         let signature = fs::read_to_string(signature_path).unwrap(); //#[allow_ci]
 
-        assert!(asym_verify(&public, &message, &signature).unwrap()) //#[allow_ci]
+        let outcome =
+            asym_verify(&public, &message, &signature, HashAlgorithm::Sha256)
+                .unwrap(); //#[allow_ci]
+        assert!(outcome.valid);
+        assert_eq!(
+            outcome.alg(),
+            Some(VerifyAlgorithm {
+                scheme: VerifyScheme::RsaPss,
+                digest: HashAlgorithm::Sha256,
+            })
+        );
+
+        // A mismatch still reports the algorithms tried
+        let outcome = asym_verify(
+            &public,
+            "tampered",
+            &signature,
+            HashAlgorithm::Sha256,
+        )
+        .unwrap(); //#[allow_ci]
+        assert!(!outcome.valid);
+        assert_eq!(outcome.alg(), None);
+        let tried = outcome
+            .tried
+            .iter()
+            .map(|alg| alg.to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(tried, ["RSA-PSS with SHA256", "RSA-PKCS1 with SHA256"]);
+    }
+
+    #[test]
+    fn test_asym_verify_rsa_schemes() {
+        let test_data =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data");
+        let cert = load_x509(&test_data.join("test-cert.pem")).unwrap(); //#[allow_ci]
+        let public = cert.public_key().unwrap(); //#[allow_ci]
+        let message =
+            fs::read_to_string(test_data.join("test_ok.json")).unwrap(); //#[allow_ci]
+
+        // The revocation message signed by the verifier, with RSA-PSS
+        let signature =
+            fs::read_to_string(test_data.join("revocation.sig")).unwrap(); //#[allow_ci]
+        let outcome =
+            asym_verify(&public, &message, &signature, HashAlgorithm::Sha256)
+                .unwrap(); //#[allow_ci]
+        assert_eq!(
+            outcome.alg().map(|alg| alg.scheme),
+            Some(VerifyScheme::RsaPss)
+        );
+
+        // Signatures with PKCS#1 v1.5 padding and other digests
+        let private = PKey::private_key_from_pem(
+            &fs::read(test_data.join("test-rsa.pem")).unwrap(), //#[allow_ci]
+        )
+        .unwrap(); //#[allow_ci]
+        for (padding, scheme) in &[
+            (Padding::PKCS1_PSS, VerifyScheme::RsaPss),
+            (Padding::PKCS1, VerifyScheme::RsaPkcs1),
+        ] {
+            for digest in &[HashAlgorithm::Sha256, HashAlgorithm::Sha384] {
+                let md = signature_digest(*digest).unwrap(); //#[allow_ci]
+                let mut signer = Signer::new(md, &private).unwrap(); //#[allow_ci]
+                signer.set_rsa_padding(*padding).unwrap(); //#[allow_ci]
+                signer.update(message.as_bytes()).unwrap(); //#[allow_ci]
+                let signature = base64::encode(signer.sign_to_vec().unwrap()); //#[allow_ci]
+
+                let outcome =
+                    asym_verify(&public, &message, &signature, *digest)
+                        .unwrap(); //#[allow_ci]
+                assert_eq!(
+                    outcome.alg(),
+                    Some(VerifyAlgorithm {
+                        scheme: *scheme,
+                        digest: *digest,
+                    })
+                );
+            }
+        }
+
+        // The digest must match the configured one
+        let outcome =
+            asym_verify(&public, &message, &signature, HashAlgorithm::Sha384)
+                .unwrap(); //#[allow_ci]
+        assert!(!outcome.valid);
+        assert!(asym_verify(
+            &public,
+            &message,
+            &signature,
+            HashAlgorithm::Sha1
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_load_x509_der() {
+        let test_data =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data");
+
+        let pem = load_x509(&test_data.join("test-cert.pem")).unwrap(); //#[allow_ci]
+        let der = load_x509(&test_data.join("test-cert.der")).unwrap(); //#[allow_ci]
+        assert_eq!(der.to_der().unwrap(), pem.to_der().unwrap()); //#[allow_ci]
+        assert!(der
+            .public_key()
+            .unwrap() //#[allow_ci]
+            .public_eq(&pem.public_key().unwrap())); //#[allow_ci]
+
+        // Content that is neither PEM nor DER is rejected
+        assert!(load_x509(&test_data.join("test-rsa.sig")).is_err());
+    }
+
+    #[test]
+    fn test_asym_verify_ec() {
+        let test_data =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data");
+
+        let cert = load_x509(&test_data.join("test-cert-ec.pem")).unwrap(); //#[allow_ci]
+        let public = cert.public_key().unwrap(); //#[allow_ci]
+        assert_eq!(public.id(), Id::EC);
+
+        let message =
+            fs::read_to_string(test_data.join("test_ok.json")).unwrap(); //#[allow_ci]
+        let signature =
+            fs::read_to_string(test_data.join("revocation-ec.sig")).unwrap(); //#[allow_ci]
+
+        let outcome =
+            asym_verify(&public, &message, &signature, HashAlgorithm::Sha256)
+                .unwrap(); //#[allow_ci]
+        assert!(outcome.valid);
+        assert_eq!(
+            outcome.alg().map(|alg| alg.to_string()),
+            Some("ECDSA with SHA256".to_string())
+        );
+        let outcome = asym_verify(
+            &public,
+            "tampered",
+            &signature,
+            HashAlgorithm::Sha256,
+        )
+        .unwrap(); //#[allow_ci]
+        assert!(!outcome.valid);
+    }
+
+    #[test]
+    fn test_pkey_pub_from_priv_ec() {
+        let (public, private) = testing::ec_generate_pair().unwrap(); //#[allow_ci]
+        assert_eq!(public.id(), Id::EC);
+        assert!(public.public_eq(&private));
+
+        let pem = pkey_pub_to_pem(&public).unwrap(); //#[allow_ci]
+        let parsed = testing::pkey_pub_from_pem(&pem).unwrap(); //#[allow_ci]
+        assert!(parsed.public_eq(&public));
     }
 }
@@ -4,13 +4,12 @@
 use crate::algorithms;
 use thiserror::Error;
 use tss_esapi::{
-    constants::response_code::Tss2ResponseCodeKind, Error::Tss2Error,
+    constants::response_code::{Tss2ResponseCode, Tss2ResponseCodeKind},
+    Error::Tss2Error,
 };
 
 #[derive(Error, Debug)]
 pub(crate) enum Error {
-    #[error("HttpServer error: {0}")]
-    ActixWeb(#[from] actix_web::Error),
     #[error("TPM Error: {err:?}, kind: {kind:?}, {message}")]
     Tpm {
         err: tss_esapi::Error,
@@ -20,6 +19,11 @@ pub(crate) enum Error {
     #[error("Invalid request")]
     #[allow(unused)]
     InvalidRequest,
+    #[error("{message}")]
+    InvalidParameter {
+        error_code: &'static str,
+        message: String,
+    },
     #[error("Configuration loading error: {0}")]
     Ini(#[from] ini::Error),
     #[error("Infallible: {0}")]
@@ -36,6 +40,10 @@ pub(crate) enum Error {
     Serde(#[from] serde_json::Error),
     #[error("Permission error")]
     Permission,
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
     #[error("Text decoding error: {0}")]
@@ -43,14 +51,35 @@ pub(crate) enum Error {
     #[error("Secure Mount error: {0})")]
     #[allow(unused)]
     SecureMount(String),
-    #[error("TPM in use")]
-    TpmInUse,
+    #[error("Measured boot log error: {0}")]
+    MeasuredBoot(String),
+    #[error("TPM busy for more than {}ms", .0.as_millis())]
+    TpmBusy(std::time::Duration),
+    #[error("Unable to retrieve public key: {0}")]
+    PublicKey(String),
+    #[error("IMA measurement list entry {entry} is {size} bytes, larger than the {max_bytes} bytes limit")]
+    ImaEntryTooLarge {
+        entry: u64,
+        size: usize,
+        max_bytes: usize,
+    },
     #[error("UUID error")]
     Uuid(#[from] uuid::Error),
     #[error("Execution error: {0:?}, {1}")]
     Execution(Option<i32>, String),
     #[error("Error executing script {0}: {1:?}, {2}")]
     Script(String, Option<i32>, String),
+    #[error("Unable to read revocation action list {}: {source}", path.display())]
+    ActionList {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+    #[error("{} of {total} revocation actions failed", failures.len())]
+    RevocationActions {
+        total: usize,
+        succeeded: Vec<crate::revocation::ActionResult>,
+        failures: Vec<Error>,
+    },
     #[error("Number parsing error: {0}")]
     NumParse(#[from] std::num::ParseIntError),
     #[error("Crypto error: {0}")]
@@ -93,33 +122,65 @@ impl Error {
         }
     }
 
-    pub(crate) fn exe_code(&self) -> Result<Option<i32>> {
+    /// Returns the machine-readable code of an invalid request parameter,
+    /// to be reported to the client along with the error message
+    pub(crate) fn error_code(&self) -> Option<&'static str> {
         match self {
-            Error::Execution(code, _) => Ok(code.to_owned()),
-            other => Err(Error::Other(format!(
-                "cannot get execution status code for Error type {}",
-                other
-            ))),
+            Error::InvalidParameter { error_code, .. } => Some(error_code),
+            _ => None,
         }
     }
 
-    pub(crate) fn stderr(&self) -> Result<String> {
+    /// Returns the TPM response code and its kind, if the error was returned
+    /// by the TPM stack
+    pub(crate) fn tpm_rc(
+        &self,
+    ) -> Option<(u32, Option<Tss2ResponseCodeKind>)> {
         match self {
-            Error::Execution(_, stderr) => Ok(stderr.to_owned()),
-            other => Err(Error::Other(format!(
-                "cannot get stderr for Error type {}",
-                other
-            ))),
+            Error::Tpm {
+                err: Tss2Error(rc),
+                kind,
+                ..
+            } => {
+                let code = match rc {
+                    Tss2ResponseCode::Success => 0,
+                    Tss2ResponseCode::FormatZero(code) => code.0,
+                    Tss2ResponseCode::FormatOne(code) => code.0,
+                };
+                Some((code, *kind))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the exit code of a failed command or action, if it exited
+    /// at all. Timeouts, spawn failures and processes killed by a signal
+    /// have no exit code.
+    pub(crate) fn exe_code(&self) -> Option<i32> {
+        match self {
+            Error::Execution(code, _) | Error::Script(_, code, _) => *code,
+            _ => None,
+        }
+    }
+
+    /// Returns the error output of a failed command or action. For other
+    /// errors, the error message is returned instead.
+    pub(crate) fn stderr(&self) -> String {
+        match self {
+            Error::Execution(_, stderr) | Error::Script(_, _, stderr) => {
+                stderr.to_owned()
+            }
+            other => other.to_string(),
         }
     }
 }
 
-impl std::convert::TryFrom<std::process::Output> for Error {
-    type Error = Error;
-    fn try_from(output: std::process::Output) -> Result<Self> {
+impl From<std::process::Output> for Error {
+    fn from(output: std::process::Output) -> Self {
         let code = output.status.code();
-        let stderr = String::from_utf8(output.stderr)?;
-        Ok(Error::Execution(code, stderr))
+        // Error output is only used for reporting, do not fail on it
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        Error::Execution(code, stderr)
     }
 }
 
@@ -137,3 +198,43 @@ impl From<tss_esapi::Error> for Error {
 }
 
 pub(crate) type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tss_esapi::constants::response_code::FormatOneResponseCode;
+
+    #[test]
+    fn test_tpm_rc() {
+        // TPM_RC_SCHEME for the 2nd parameter
+        let err: Error = Tss2Error(Tss2ResponseCode::FormatOne(
+            FormatOneResponseCode(0x2d2),
+        ))
+        .into();
+        assert_eq!(
+            err.tpm_rc(),
+            Some((0x2d2, Some(Tss2ResponseCodeKind::Scheme)))
+        );
+
+        assert_eq!(Error::Other("other".to_string()).tpm_rc(), None);
+    }
+
+    #[test]
+    fn test_exe_code_stderr() {
+        let err = Error::Script(
+            "action".to_string(),
+            Some(2),
+            "failed".to_string(),
+        );
+        assert_eq!(err.exe_code(), Some(2));
+        assert_eq!(err.stderr(), "failed");
+
+        let err = Error::Execution(None, "killed".to_string());
+        assert_eq!(err.exe_code(), None);
+        assert_eq!(err.stderr(), "killed");
+
+        let err = Error::Other("other".to_string());
+        assert_eq!(err.exe_code(), None);
+        assert_eq!(err.stderr(), "other");
+    }
+}
@@ -1,7 +1,7 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright 2021 Keylime Authors
 
-use crate::common::{APIVersion, JsonWrapper, API_VERSION};
+use crate::common::{APIVersion, JsonWrapper, ResponseStatus, API_VERSION};
 use actix_web::{
     body, dev,
     error::{InternalError, JsonPayloadError, PathError, QueryPayloadError},
@@ -12,43 +12,42 @@ use actix_web::{
 use log::*;
 
 pub(crate) async fn app_default(req: HttpRequest) -> impl Responder {
-    let error;
+    let status;
     let response;
     let message;
 
     match req.head().method {
         http::Method::GET => {
-            error = 400;
+            status = ResponseStatus::BadRequest;
             message = format!(
                 "Not Implemented: Use /version or /{}/ interfaces",
                 API_VERSION
             );
-            response = HttpResponse::BadRequest()
-                .json(JsonWrapper::error(error, &message));
+            response = JsonWrapper::error(status, &message).into_response();
         }
         http::Method::POST => {
-            error = 400;
+            status = ResponseStatus::BadRequest;
             message =
                 format!("Not Implemented: Use /{}/ interface", API_VERSION);
-            response = HttpResponse::BadRequest()
-                .json(JsonWrapper::error(error, &message));
+            response = JsonWrapper::error(status, &message).into_response();
         }
         _ => {
-            error = 405;
+            status = ResponseStatus::MethodNotAllowed;
             message = "Method is not supported".to_string();
-            response = HttpResponse::MethodNotAllowed()
+            response = status
+                .response()
                 .insert_header(http::header::Allow(vec![
                     http::Method::GET,
                     http::Method::POST,
                 ]))
-                .json(JsonWrapper::error(error, &message));
+                .json(JsonWrapper::error(status, &message));
         }
     };
 
     warn!(
         "{} returning {} response. {}",
         req.head().method,
-        error,
+        status.code(),
         message
     );
 
@@ -56,40 +55,38 @@ pub(crate) async fn app_default(req: HttpRequest) -> impl Responder {
 }
 
 pub(crate) async fn api_default(req: HttpRequest) -> impl Responder {
-    let error;
+    let status;
     let response;
     let message;
 
     match req.head().method {
         http::Method::GET => {
-            error = 400;
+            status = ResponseStatus::BadRequest;
             message = "Not Implemented: Use /keys/ or /quotes/ interfaces";
-            response = HttpResponse::BadRequest()
-                .json(JsonWrapper::error(error, message));
+            response = JsonWrapper::error(status, message).into_response();
         }
         http::Method::POST => {
-            error = 400;
-            message =
-                "Not Implemented: Use /keys/ or /notifications/ interfaces";
-            response = HttpResponse::BadRequest()
-                .json(JsonWrapper::error(error, message));
+            status = ResponseStatus::BadRequest;
+            message = "Not Implemented: Use /keys/, /quotes/ or /notifications/ interfaces";
+            response = JsonWrapper::error(status, message).into_response();
         }
         _ => {
-            error = 405;
+            status = ResponseStatus::MethodNotAllowed;
             message = "Method is not supported";
-            response = HttpResponse::MethodNotAllowed()
+            response = status
+                .response()
                 .insert_header(http::header::Allow(vec![
                     http::Method::GET,
                     http::Method::POST,
                 ]))
-                .json(JsonWrapper::error(error, message));
+                .json(JsonWrapper::error(status, message));
         }
     };
 
     warn!(
         "{} returning {} response. {}",
         req.head().method,
-        error,
+        status.code(),
         message
     );
 
@@ -97,39 +94,38 @@ pub(crate) async fn api_default(req: HttpRequest) -> impl Responder {
 }
 
 pub(crate) async fn keys_default(req: HttpRequest) -> impl Responder {
-    let error;
+    let status;
     let response;
     let message;
 
     match req.head().method {
         http::Method::GET => {
-            error = 400;
+            status = ResponseStatus::BadRequest;
             message = "URI not supported, only /pubkey and /verify are supported for GET in /keys/ interface";
-            response = HttpResponse::BadRequest()
-                .json(JsonWrapper::error(error, message));
+            response = JsonWrapper::error(status, message).into_response();
         }
         http::Method::POST => {
-            error = 400;
+            status = ResponseStatus::BadRequest;
             message = "URI not supported, only /ukey and /vkey are supported for POST in /keys/ interface";
-            response = HttpResponse::BadRequest()
-                .json(JsonWrapper::error(error, message));
+            response = JsonWrapper::error(status, message).into_response();
         }
         _ => {
-            error = 405;
+            status = ResponseStatus::MethodNotAllowed;
             message = "Method is not supported in /keys/ interface";
-            response = HttpResponse::MethodNotAllowed()
+            response = status
+                .response()
                 .insert_header(http::header::Allow(vec![
                     http::Method::GET,
                     http::Method::POST,
                 ]))
-                .json(JsonWrapper::error(error, message));
+                .json(JsonWrapper::error(status, message));
         }
     };
 
     warn!(
         "{} returning {} response. {}",
         req.head().method,
-        error,
+        status.code(),
         message
     );
 
@@ -137,30 +133,69 @@ pub(crate) async fn keys_default(req: HttpRequest) -> impl Responder {
 }
 
 pub(crate) async fn quotes_default(req: HttpRequest) -> impl Responder {
-    let error;
+    let status;
     let response;
     let message;
 
     match req.head().method {
         http::Method::GET => {
-            error = 400;
-            message = "URI not supported, only /identity and /integrity are supported for GET in /quotes/ interface";
-            response = HttpResponse::BadRequest()
-                .json(JsonWrapper::error(error, message));
+            status = ResponseStatus::BadRequest;
+            message = "URI not supported, only /identity, /integrity and /bundle are supported for GET in /quotes/ interface";
+            response = JsonWrapper::error(status, message).into_response();
+        }
+        http::Method::POST => {
+            status = ResponseStatus::BadRequest;
+            message = "URI not supported, only /batch is supported for POST in /quotes/ interface";
+            response = JsonWrapper::error(status, message).into_response();
         }
         _ => {
-            error = 405;
+            status = ResponseStatus::MethodNotAllowed;
             message = "Method is not supported in /quotes/ interface";
-            response = HttpResponse::MethodNotAllowed()
+            response = status
+                .response()
+                .insert_header(http::header::Allow(vec![
+                    http::Method::GET,
+                    http::Method::POST,
+                ]))
+                .json(JsonWrapper::error(status, message));
+        }
+    };
+
+    warn!(
+        "{} returning {} response. {}",
+        req.head().method,
+        status.code(),
+        message
+    );
+
+    response
+}
+
+pub(crate) async fn agent_default(req: HttpRequest) -> impl Responder {
+    let status;
+    let response;
+    let message;
+
+    match req.head().method {
+        http::Method::GET => {
+            status = ResponseStatus::BadRequest;
+            message = "URI not supported, only /health and /mount are supported for GET in /agent/ interface";
+            response = JsonWrapper::error(status, message).into_response();
+        }
+        _ => {
+            status = ResponseStatus::MethodNotAllowed;
+            message = "Method is not supported in /agent/ interface";
+            response = status
+                .response()
                 .insert_header(http::header::Allow(vec![http::Method::GET]))
-                .json(JsonWrapper::error(error, message));
+                .json(JsonWrapper::error(status, message));
         }
     };
 
     warn!(
         "{} returning {} response. {}",
         req.head().method,
-        error,
+        status.code(),
         message
     );
 
@@ -170,30 +205,30 @@ pub(crate) async fn quotes_default(req: HttpRequest) -> impl Responder {
 pub(crate) async fn notifications_default(
     req: HttpRequest,
 ) -> impl Responder {
-    let error;
+    let status;
     let response;
     let message;
 
     match req.head().method {
         http::Method::POST => {
-            error = 400;
+            status = ResponseStatus::BadRequest;
             message = "URI not supported, only /revocation is supported for POST in /notifications/ interface";
-            response = HttpResponse::BadRequest()
-                .json(JsonWrapper::error(error, message));
+            response = JsonWrapper::error(status, message).into_response();
         }
         _ => {
-            error = 405;
+            status = ResponseStatus::MethodNotAllowed;
             message = "Method is not supported in /notifications/ interface";
-            response = HttpResponse::MethodNotAllowed()
+            response = status
+                .response()
                 .insert_header(http::header::Allow(vec![http::Method::POST]))
-                .json(JsonWrapper::error(error, message));
+                .json(JsonWrapper::error(status, message));
         }
     };
 
     warn!(
         "{} returning {} response. {}",
         req.head().method,
-        error,
+        status.code(),
         message
     );
 
@@ -208,7 +243,7 @@ pub(crate) async fn version_not_supported(
 
     warn!("{} returning 400 response. {}", req.head().method, message);
 
-    HttpResponse::BadRequest().json(JsonWrapper::error(400, message))
+    JsonWrapper::error(ResponseStatus::BadRequest, message).into_response()
 }
 
 pub(crate) fn json_parser_error(
@@ -217,7 +252,8 @@ pub(crate) fn json_parser_error(
 ) -> Error {
     warn!("{} returning 400 response. {}", req.head().method, err);
 
-    let resp = HttpResponse::BadRequest().json(JsonWrapper::error(400, &err));
+    let resp =
+        JsonWrapper::error(ResponseStatus::BadRequest, &err).into_response();
     InternalError::from_response(err, resp).into()
 }
 
@@ -227,14 +263,16 @@ pub(crate) fn query_parser_error(
 ) -> Error {
     warn!("{} returning 400 response. {}", req.head().method, err);
 
-    let resp = HttpResponse::BadRequest().json(JsonWrapper::error(400, &err));
+    let resp =
+        JsonWrapper::error(ResponseStatus::BadRequest, &err).into_response();
     InternalError::from_response(err, resp).into()
 }
 
 pub(crate) fn path_parser_error(err: PathError, req: &HttpRequest) -> Error {
     warn!("{} returning 400 response. {}", req.head().method, err);
 
-    let resp = HttpResponse::BadRequest().json(JsonWrapper::error(400, &err));
+    let resp =
+        JsonWrapper::error(ResponseStatus::BadRequest, &err).into_response();
     InternalError::from_response(err, resp).into()
 }
 
@@ -244,19 +282,15 @@ pub(crate) fn path_parser_error(err: PathError, req: &HttpRequest) -> Error {
 pub(crate) fn wrap_404<B>(
     res: dev::ServiceResponse<B>,
 ) -> Result<ErrorHandlerResponse<body::BoxBody>> {
-    let status = res.status();
-
     warn!(
         "{} returning 404 response. {}",
         res.request().head().method,
-        status.canonical_reason().unwrap_or("Not Found")
+        ResponseStatus::NotFound.reason()
     );
 
+    let status = ResponseStatus::NotFound;
     let response =
-        HttpResponse::build(res.status()).json(JsonWrapper::error(
-            status.as_u16(),
-            status.canonical_reason().unwrap_or("Not Found"),
-        ));
+        JsonWrapper::error(status, status.reason()).into_response();
 
     Ok(ErrorHandlerResponse::Response(dev::ServiceResponse::new(
         res.into_parts().0,
@@ -284,7 +318,7 @@ mod tests {
             let result: JsonWrapper<Value> = test::read_body_json(resp).await;
 
             assert_eq!(result.results, json!({}));
-            assert_eq!(result.code, 400);
+            assert_eq!(result.code, ResponseStatus::BadRequest);
         }
 
         if allow.contains("POST") {
@@ -299,7 +333,7 @@ mod tests {
             let result: JsonWrapper<Value> = test::read_body_json(resp).await;
 
             assert_eq!(result.results, json!({}));
-            assert_eq!(result.code, 400);
+            assert_eq!(result.code, ResponseStatus::BadRequest);
         }
 
         let req = test::TestRequest::delete().uri("/").to_request();
@@ -315,7 +349,7 @@ mod tests {
         let result: JsonWrapper<Value> = test::read_body_json(resp).await;
 
         assert_eq!(result.results, json!({}));
-        assert_eq!(result.code, 405);
+        assert_eq!(result.code, ResponseStatus::MethodNotAllowed);
     }
 
     #[actix_rt::test]
@@ -335,7 +369,12 @@ mod tests {
 
     #[actix_rt::test]
     async fn test_quotes_default() {
-        test_default(web::resource("/").to(quotes_default), "GET").await
+        test_default(web::resource("/").to(quotes_default), "GET, POST").await
+    }
+
+    #[actix_rt::test]
+    async fn test_agent_default() {
+        test_default(web::resource("/").to(agent_default), "GET").await
     }
 
     #[actix_rt::test]
@@ -428,7 +467,7 @@ mod tests {
         assert!(resp.status().is_client_error());
         let result: JsonWrapper<Value> = test::read_body_json(resp).await;
         assert_eq!(result.results, json!({}));
-        assert_eq!(result.code, 400);
+        assert_eq!(result.code, ResponseStatus::BadRequest);
         assert_eq!(result.status, "API version not supported: v500.440");
 
         // Test JSON parsing error
@@ -441,7 +480,7 @@ mod tests {
         assert!(resp.status().is_client_error());
         let result: JsonWrapper<Value> = test::read_body_json(resp).await;
         assert_eq!(result.results, json!({}));
-        assert_eq!(result.code, 400);
+        assert_eq!(result.code, ResponseStatus::BadRequest);
         assert!(result.status.contains("Json deserialize error"));
 
         // Test Query parsing error
@@ -453,7 +492,7 @@ mod tests {
         assert!(resp.status().is_client_error());
         let result: JsonWrapper<Value> = test::read_body_json(resp).await;
         assert_eq!(result.results, json!({}));
-        assert_eq!(result.code, 400);
+        assert_eq!(result.code, ResponseStatus::BadRequest);
         assert!(result.status.contains("Query deserialize error"));
 
         // Test Path parsing error
@@ -465,7 +504,7 @@ mod tests {
         assert!(resp.status().is_client_error());
         let result: JsonWrapper<Value> = test::read_body_json(resp).await;
         assert_eq!(result.results, json!({}));
-        assert_eq!(result.code, 400);
+        assert_eq!(result.code, ResponseStatus::BadRequest);
         assert!(result.status.contains("Path deserialize error"));
 
         // Test not found
@@ -474,7 +513,7 @@ mod tests {
         assert!(resp.status().is_client_error());
         let result: JsonWrapper<Value> = test::read_body_json(resp).await;
         assert_eq!(result.results, json!({}));
-        assert_eq!(result.code, 404);
+        assert_eq!(result.code, ResponseStatus::NotFound);
         assert!(result.status.contains("Not Found"));
     }
 }
@@ -0,0 +1,108 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+use crate::common::{JsonWrapper, ResponseStatus};
+use crate::{tpm, QuoteData, Result};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// Longest time the health check waits for the TPM, including the time
+/// spent waiting for a quote in progress to release it
+pub(crate) const HEALTH_TPM_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Health {
+    status: String,
+    tpm_latency_ms: u64,
+}
+
+// Runs a TPM capability read, the cheapest command exercising the TPM.
+//
+// The lock is polled rather than waited for, so that health checks never
+// queue up behind quotes, and give up once the timeout expires.
+fn probe_tpm(data: &QuoteData, timeout: Duration) -> Result<()> {
+    let mut context = tpm::lock_context_timeout(&data.tpmcontext, timeout)?;
+    let _ = tpm::get_pcr_banks(&mut context)?;
+    Ok(())
+}
+
+// This is the handler for the GET request for the agent health. It checks
+// that the TPM answers, not only that the HTTP server is up.
+pub async fn health(
+    req: HttpRequest,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    // Health checks are frequent, so they are only logged when debugging
+    debug!("GET invoked with uri {}", req.uri());
+
+    let start = Instant::now();
+    let probe_data = data.clone();
+    let probe = tokio::time::timeout(
+        HEALTH_TPM_TIMEOUT,
+        tokio::task::spawn_blocking(move || {
+            probe_tpm(&probe_data, HEALTH_TPM_TIMEOUT)
+        }),
+    )
+    .await;
+    let result = match probe {
+        Ok(Ok(result)) => result.map_err(|e| e.to_string()),
+        Ok(Err(e)) => Err(format!("TPM probe failed: {}", e)),
+        Err(_) => Err(format!(
+            "TPM did not respond within {}ms",
+            HEALTH_TPM_TIMEOUT.as_millis()
+        )),
+    };
+
+    match result {
+        Ok(()) => HttpResponse::Ok().json(JsonWrapper::success(Health {
+            status: "ok".to_string(),
+            tpm_latency_ms: start.elapsed().as_millis() as u64,
+        })),
+        Err(e) => {
+            warn!("GET health returning 503 response. {}", e);
+            JsonWrapper::error(ResponseStatus::ServiceUnavailable, e)
+                .into_response()
+        }
+    }
+}
+
+#[cfg(feature = "testing")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::API_VERSION;
+    use actix_web::{test, web, App};
+
+    #[actix_rt::test]
+    async fn test_health() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/agent/health", API_VERSION),
+                web::get().to(health),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}/agent/health", API_VERSION))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let body: JsonWrapper<Health> = test::read_body_json(resp).await;
+        assert_eq!(body.results.status, "ok");
+    }
+
+    #[actix_rt::test]
+    async fn test_health_tpm_busy() {
+        let quotedata = QuoteData::fixture().unwrap(); //#[allow_ci]
+
+        // A quote holding the TPM for longer than the timeout
+        let _guard = tpm::lock_context(&quotedata).unwrap(); //#[allow_ci]
+        let err =
+            probe_tpm(&quotedata, Duration::from_millis(50)).unwrap_err(); //#[allow_ci]
+        assert_eq!(err.to_string(), "TPM busy for more than 50ms");
+    }
+}
@@ -1,32 +1,77 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright 2021 Keylime Authors
 
+use crate::crypto;
+use crate::error::Error as KeylimeError;
 use log::*;
+use openssl::{
+    ecdsa::EcdsaSig,
+    pkey::{Id, PKey, Public},
+    rsa::Padding,
+};
 use std::{
     collections::HashSet,
-    fs::File,
-    io::{prelude::*, Error, SeekFrom},
+    fs::{self, File},
+    io::{prelude::*, BufReader, SeekFrom},
     path::Path,
+    str::FromStr,
+    sync::{Mutex, MutexGuard, PoisonError},
 };
 
 /// IMAMeasurementList models the IMA measurement lists's last two known
-/// numbers of entries in the log and filesizes at that point
+/// numbers of entries in the log and filesizes at that point, along with
+/// the first entry of the log last read to notice it was reset
 #[derive(Debug)]
 pub(crate) struct ImaMeasurementList {
     entries: HashSet<(u64, u64)>,
+    first_entry: Option<String>,
+    /// Number of times the state was reset, so that offsets found in a log
+    /// read before a reset are not recorded afterwards
+    generation: u64,
+}
+
+/// Measurement list read from a given entry
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct MeasurementListRead {
+    pub ml: Option<String>,
+    /// Entry the list was read from
+    pub nth_entry: Option<u64>,
+    /// Number of entries in the log
+    pub num_entries: Option<u64>,
+    /// Whether the entries before the requested one are no longer in the
+    /// log, so that the list was read from the first entry instead
+    pub rotated: bool,
 }
 
-pub type IMAError = Result<(Option<String>, Option<u64>, Option<u64>), Error>;
+pub type IMAError = Result<MeasurementListRead, KeylimeError>;
 
 impl ImaMeasurementList {
     pub(crate) fn new() -> ImaMeasurementList {
         ImaMeasurementList {
             entries: HashSet::new(),
+            first_entry: None,
+            generation: 0,
         }
     }
 
-    fn reset(&mut self) {
+    pub(crate) fn reset(&mut self) {
         self.entries = HashSet::new();
+        self.first_entry = None;
+        self.generation += 1;
+    }
+
+    // Detects whether the log was replaced since it was last read, which
+    // happens when the first entry, holding the boot aggregate, changed. The
+    // offsets known are then reset. The size of the log cannot tell, as
+    // securityfs reports a size of 0 for it.
+    fn check_rotation(&mut self, first_entry: &str) -> bool {
+        let rotated =
+            matches!(&self.first_entry, Some(entry) if entry != first_entry);
+        if rotated {
+            self.reset();
+        }
+        self.first_entry = Some(first_entry.to_string());
+        rotated
     }
 
     fn update(&mut self, num_entries: u64, filesize: u64) -> Option<bool> {
@@ -48,34 +93,76 @@ impl ImaMeasurementList {
     }
 }
 
+// Locks the IMA state. The state only caches the offsets of the entries in
+// the log, and every update leaves them valid, so a poisoned lock is simply
+// recovered.
+fn lock_state(
+    ima_ml: &Mutex<ImaMeasurementList>,
+) -> MutexGuard<'_, ImaMeasurementList> {
+    ima_ml.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
 /// Read the IMA measurement list starting from a given entry.
 /// The entry may be of any value 0 <= entry <= entries_in_log where
-/// entries_in_log + 1 indicates that the client wants to read the next entry
-/// once available. If the entry is outside this range, the function will
-/// automatically read from the 0-th entry.
+/// entries_in_log indicates that the client wants to read the next entry
+/// once available. A list read from an entry past that is empty.
 /// This function returns the measurement list and the entry from where it
 /// was read and the current number of entries in the file.
+///
+/// If the log was reset since it was last read, e.g. on a reboot the
+/// verifier did not notice, the list is read from the 0-th entry and
+/// flagged as rotated, so that the verifier restarts from there instead of
+/// mixing entries of different logs.
+///
+/// If max_entries is not 0, at most max_entries entries are returned, so
+/// that the list can be read in fixed-size chunks. If max_bytes is not 0,
+/// the returned list only holds the whole entries fitting in max_bytes. The
+/// entries left out are returned when reading again from the first entry
+/// that was not returned. If the first entry alone is larger than
+/// max_bytes, an `ImaEntryTooLarge` error is returned.
+///
+/// The state is only locked to look up and record offsets, not while the
+/// log is read, so that concurrent requests read the log in parallel. Only
+/// whole entries are read, so entries being appended to the log while it is
+/// read are left for the next read.
 pub(crate) fn read_measurement_list(
-    ima_ml: &mut ImaMeasurementList,
+    ima_ml: &Mutex<ImaMeasurementList>,
     filename: &Path,
     nth_entry: u64,
+    max_entries: usize,
+    max_bytes: usize,
 ) -> IMAError {
     if !Path::new(filename).exists() {
-        let _ = ima_ml.reset();
+        lock_state(ima_ml).reset();
         warn!("IMA measurement list not available: {}", filename.display());
-        return Ok((None, None, None));
+        return Ok(MeasurementListRead::default());
     }
 
-    let mut nth_entry = nth_entry;
+    let mut file = File::open(filename)?;
+    let mut first_entry = String::new();
+    let _ = BufReader::new(&file).read_line(&mut first_entry)?;
 
-    // Try to find the closest entry to the nth_entry
-    let (mut num_entries, filesize) = ima_ml.find(nth_entry);
+    let (rotated, nth_entry, (mut num_entries, filesize), generation) = {
+        let mut state = lock_state(ima_ml);
+        let rotated = state.check_rotation(&first_entry);
+        // The entries the verifier already read are gone
+        let nth_entry = if rotated { 0 } else { nth_entry };
+        // Try to find the closest entry to the nth_entry
+        (rotated, nth_entry, state.find(nth_entry), state.generation)
+    };
+    if rotated {
+        warn!(
+            "IMA measurement list {} was reset since it was last read",
+            filename.display()
+        );
+    }
 
     let mut ml = None;
     let mut filedata = String::new();
-    let mut file = File::open(filename)?;
     let _ = file.seek(SeekFrom::Start(filesize))?;
     let _ = file.read_to_string(&mut filedata)?;
+    // An entry still being written is not complete yet
+    filedata.truncate(filedata.rfind('\n').map_or(0, |idx| idx + 1));
     let mut offset: usize = 0;
 
     loop {
@@ -91,25 +178,542 @@ pub(crate) fn read_measurement_list(
         num_entries += 1;
     }
 
-    let _ = ima_ml.update(num_entries, filesize + offset as u64);
+    {
+        // The offsets are those of the log as it was before a reset by a
+        // concurrent read, if any, so they are only valid if there was none
+        let mut state = lock_state(ima_ml);
+        if state.generation == generation {
+            let _ = state.update(num_entries, filesize + offset as u64);
+        }
+    }
+
+    // The requested entry is past the end of the log: the list is empty,
+    // and the number of entries tells the verifier where the log is at
+    let ml = limit_entries(ml.unwrap_or_default(), max_entries);
+    let ml = truncate_entries(ml, max_bytes).ok_or_else(|| {
+        KeylimeError::ImaEntryTooLarge {
+            entry: nth_entry,
+            size: ml.find('\n').map_or(ml.len(), |idx| idx + 1),
+            max_bytes,
+        }
+    })?;
+    Ok(MeasurementListRead {
+        ml: Some(String::from(ml)),
+        nth_entry: Some(nth_entry),
+        num_entries: Some(num_entries),
+        rotated,
+    })
+}
+
+// Keeps the first max_entries entries of the measurement list. A limit of 0
+// means no limit.
+fn limit_entries(ml: &str, max_entries: usize) -> &str {
+    if max_entries == 0 {
+        return ml;
+    }
+    match ml.match_indices('\n').nth(max_entries - 1) {
+        Some((idx, _)) => &ml[..=idx],
+        None => ml,
+    }
+}
+
+// Truncates the measurement list to the whole entries fitting in max_bytes,
+// so that an entry is never split. A limit of 0 means no limit. Returns
+// None if not even the first entry fits.
+fn truncate_entries(ml: &str, max_bytes: usize) -> Option<&str> {
+    if max_bytes == 0 || ml.len() <= max_bytes {
+        return Some(ml);
+    }
+    ml.as_bytes()[..max_bytes]
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .map(|idx| &ml[..=idx])
+}
+
+/// Entry of the IMA measurement list, in the ASCII format of
+/// ascii_runtime_measurements:
+///
+/// `<PCR> <template hash> <template name> <file digest> <path> [<fields>]`
+///
+/// Formatting an entry gives back the line it was parsed from, so that a
+/// list can be filtered without changing the entries that are kept.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ImaEntry {
+    pub pcr: u32,
+    pub template_hash: Vec<u8>,
+    pub template_name: String,
+    /// File digest, as `<algorithm>:<hex>`, or as hex for the ima template
+    pub digest: String,
+    pub path: String,
+    /// Fields following the path: the signature of ima-sig entries or the
+    /// buffer of ima-buf entries
+    pub extra: Option<String>,
+}
+
+impl FromStr for ImaEntry {
+    type Err = KeylimeError;
+
+    fn from_str(line: &str) -> crate::error::Result<Self> {
+        let malformed = || {
+            KeylimeError::Other(format!(
+                "Malformed IMA measurement list entry: {}",
+                line
+            ))
+        };
 
-    match ml {
-        None => read_measurement_list(ima_ml, filename, 0),
-        Some(slice) => Ok((
-            Some(String::from(slice)),
-            Some(nth_entry),
-            Some(num_entries),
+        // The PCR is right-aligned on two characters
+        let mut fields = line.trim_start().splitn(5, ' ');
+        let pcr = fields
+            .next()
+            .and_then(|pcr| pcr.parse().ok())
+            .ok_or_else(malformed)?;
+        let template_hash = fields
+            .next()
+            .and_then(|hash| hex::decode(hash).ok())
+            .ok_or_else(malformed)?;
+        let template_name = fields.next().ok_or_else(malformed)?;
+        let digest = fields.next().ok_or_else(malformed)?;
+        let rest = fields.next().ok_or_else(malformed)?;
+
+        let (path, extra) = match template_name {
+            "ima-sig" => match split_signature(rest) {
+                (path, Some(_)) => (path, Some(&rest[path.len() + 1..])),
+                (path, None) => (path, None),
+            },
+            "ima-buf" => match rest.rsplit_once(' ') {
+                Some((name, buf)) => (name, Some(buf)),
+                None => return Err(malformed()),
+            },
+            _ => (rest, None),
+        };
+
+        Ok(ImaEntry {
+            pcr,
+            template_hash,
+            template_name: template_name.to_string(),
+            digest: digest.to_string(),
+            path: path.to_string(),
+            extra: extra.map(String::from),
+        })
+    }
+}
+
+impl std::fmt::Display for ImaEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:2} {} {} {} {}",
+            self.pcr,
+            hex::encode(&self.template_hash),
+            self.template_name,
+            self.digest,
+            self.path
+        )?;
+        if let Some(extra) = &self.extra {
+            write!(f, " {}", extra)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses the entries of a measurement list, as returned by
+/// read_measurement_list
+pub(crate) fn parse_measurement_list(
+    ml: &str,
+) -> crate::error::Result<Vec<ImaEntry>> {
+    ml.lines().map(ImaEntry::from_str).collect()
+}
+
+/// Formats entries back into a measurement list, one entry per line
+pub(crate) fn format_measurement_list(entries: &[ImaEntry]) -> String {
+    entries.iter().map(|entry| format!("{}\n", entry)).collect()
+}
+
+// Whether the path matches the filter: a glob, where `*` matches any
+// characters and `?` a single one, or otherwise a prefix
+fn path_matches(path: &str, filter: &str) -> bool {
+    if !filter.contains(&['*', '?'][..]) {
+        return path.starts_with(filter);
+    }
+
+    let path: Vec<char> = path.chars().collect();
+    let filter: Vec<char> = filter.chars().collect();
+    let (mut p, mut f) = (0, 0);
+    // Position after the last `*` in the filter, and in the path where it
+    // started matching
+    let mut backtrack = None;
+    while p < path.len() {
+        match filter.get(f) {
+            Some('*') => {
+                backtrack = Some((f + 1, p));
+                f += 1;
+            }
+            Some(c) if *c == '?' || *c == path[p] => {
+                p += 1;
+                f += 1;
+            }
+            _ => match backtrack {
+                Some((star_f, star_p)) => {
+                    f = star_f;
+                    p = star_p + 1;
+                    backtrack = Some((star_f, star_p + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    filter[f..].iter().all(|c| *c == '*')
+}
+
+/// Keeps the entries of the measurement list whose path matches the filter,
+/// a prefix or a glob. The entries kept are unchanged. Entries that cannot
+/// be parsed are logged and left out, rather than failing the whole list.
+pub(crate) fn filter_measurement_list(ml: &str, filter: &str) -> String {
+    let mut entries = Vec::new();
+    for line in ml.lines() {
+        match ImaEntry::from_str(line) {
+            Ok(entry) if path_matches(&entry.path, filter) => {
+                entries.push(entry)
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!("Leaving out IMA entry from filtered list: {}", e)
+            }
+        }
+    }
+    format_measurement_list(&entries)
+}
+
+/// How the signatures carried by the IMA measurement list are verified
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ImaSignatureMode {
+    /// Signatures are not verified
+    Off,
+    /// Entries failing verification are logged
+    Log,
+    /// Entries failing verification are logged and reported in the quote
+    Mark,
+}
+
+impl FromStr for ImaSignatureMode {
+    type Err = KeylimeError;
+
+    fn from_str(s: &str) -> crate::error::Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "off" => Ok(ImaSignatureMode::Off),
+            "log" => Ok(ImaSignatureMode::Log),
+            "mark" => Ok(ImaSignatureMode::Mark),
+            other => Err(KeylimeError::Configuration(format!(
+                "Invalid ima_signature_verification {}: expected off, log or mark",
+                other
+            ))),
+        }
+    }
+}
+
+// Type of the IMA signatures, as found in the security.ima xattr
+const IMA_XATTR_DIGSIG: u8 = 0x03;
+// Version of the signatures made over the file digest
+const IMA_DIGSIG_VERSION_2: u8 = 2;
+// Size of the signature header: type, version, hash algorithm, key id and
+// signature size
+const IMA_DIGSIG_HEADER_LEN: usize = 9;
+
+/// Signature of an IMA measurement list entry, in the signature_v2_hdr
+/// format used by the kernel
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ImaSignature {
+    pub hash_algo: u8,
+    pub keyid: [u8; 4],
+    pub signature: Vec<u8>,
+}
+
+impl ImaSignature {
+    fn parse(bytes: &[u8]) -> Option<ImaSignature> {
+        if bytes.len() < IMA_DIGSIG_HEADER_LEN
+            || bytes[0] != IMA_XATTR_DIGSIG
+            || bytes[1] != IMA_DIGSIG_VERSION_2
+        {
+            return None;
+        }
+        let size = u16::from_be_bytes([bytes[7], bytes[8]]) as usize;
+        let signature = &bytes[IMA_DIGSIG_HEADER_LEN..];
+        if signature.len() != size {
+            return None;
+        }
+        Some(ImaSignature {
+            hash_algo: bytes[2],
+            keyid: [bytes[3], bytes[4], bytes[5], bytes[6]],
+            signature: signature.to_vec(),
+        })
+    }
+}
+
+// Splits the path and the signature of an ima-sig entry. The path may hold
+// spaces, so the signature is the last field, if it decodes as one.
+fn split_signature(rest: &str) -> (&str, Option<ImaSignature>) {
+    match rest.rsplit_once(' ') {
+        Some((path, sig)) => match hex::decode(sig)
+            .ok()
+            .and_then(|sig| ImaSignature::parse(&sig))
+        {
+            Some(sig) => (path, Some(sig)),
+            None => (rest, None),
+        },
+        None => (rest, None),
+    }
+}
+
+// Name and DER encoded DigestInfo prefix of the hash algorithms IMA
+// signatures can be made with, by their number in the kernel hash_algo enum
+fn ima_hash_algo(hash_algo: u8) -> Option<(&'static str, &'static [u8])> {
+    match hash_algo {
+        2 => Some((
+            "sha1",
+            &[
+                0x30, 0x21, 0x30, 0x09, 0x06, 0x05, 0x2b, 0x0e, 0x03, 0x02,
+                0x1a, 0x05, 0x00, 0x04, 0x14,
+            ],
         )),
+        4 => Some((
+            "sha256",
+            &[
+                0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01,
+                0x65, 0x03, 0x04, 0x02, 0x01, 0x05, 0x00, 0x04, 0x20,
+            ],
+        )),
+        5 => Some((
+            "sha384",
+            &[
+                0x30, 0x41, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01,
+                0x65, 0x03, 0x04, 0x02, 0x02, 0x05, 0x00, 0x04, 0x30,
+            ],
+        )),
+        6 => Some((
+            "sha512",
+            &[
+                0x30, 0x51, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01,
+                0x65, 0x03, 0x04, 0x02, 0x03, 0x05, 0x00, 0x04, 0x40,
+            ],
+        )),
+        _ => None,
+    }
+}
+
+/// Fields of an ima-sig measurement list entry:
+///
+/// `<PCR> <template hash> ima-sig <algorithm>:<file digest> <path> [<signature>]`
+///
+/// The signature is only present for files carrying one in their
+/// security.ima xattr.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ImaSigEntry<'a> {
+    pub digest_alg: &'a str,
+    pub digest: Vec<u8>,
+    pub path: &'a str,
+    pub signature: Option<ImaSignature>,
+}
+
+impl<'a> ImaSigEntry<'a> {
+    /// Parses an entry of the ima-sig template. Returns None for entries of
+    /// other templates, and an error for malformed ima-sig entries.
+    pub(crate) fn parse(
+        line: &'a str,
+    ) -> Option<crate::error::Result<ImaSigEntry<'a>>> {
+        let mut fields = line.splitn(5, ' ');
+        let _pcr = fields.next()?;
+        let _template_hash = fields.next()?;
+        if fields.next()? != "ima-sig" {
+            return None;
+        }
+
+        Some(Self::parse_fields(line, fields.next(), fields.next()))
+    }
+
+    // Parses the template fields following the template name
+    fn parse_fields(
+        line: &str,
+        digest: Option<&'a str>,
+        rest: Option<&'a str>,
+    ) -> crate::error::Result<ImaSigEntry<'a>> {
+        let malformed = || {
+            KeylimeError::Other(format!(
+                "Malformed ima-sig measurement list entry: {}",
+                line
+            ))
+        };
+        let (digest_alg, digest) = digest
+            .and_then(|d| d.split_once(':'))
+            .ok_or_else(malformed)?;
+        let digest = hex::decode(digest).map_err(|_| malformed())?;
+        let rest = rest.ok_or_else(malformed)?;
+
+        let (path, signature) = split_signature(rest);
+
+        Ok(ImaSigEntry {
+            digest_alg,
+            digest,
+            path,
+            signature,
+        })
+    }
+
+    // Verifies the signature of the file digest with the key
+    fn verify_with(&self, key: &PKey<Public>) -> bool {
+        let signature = match &self.signature {
+            Some(signature) => signature,
+            None => return false,
+        };
+        let prefix = match ima_hash_algo(signature.hash_algo) {
+            Some((name, prefix)) if name == self.digest_alg => prefix,
+            _ => return false,
+        };
+
+        match key.id() {
+            Id::RSA => {
+                let rsa = match key.rsa() {
+                    Ok(rsa) => rsa,
+                    Err(_) => return false,
+                };
+                let mut decrypted = vec![0; rsa.size() as usize];
+                match rsa.public_decrypt(
+                    &signature.signature,
+                    &mut decrypted,
+                    Padding::PKCS1,
+                ) {
+                    Ok(len) => {
+                        decrypted.truncate(len);
+                        decrypted.len() == prefix.len() + self.digest.len()
+                            && decrypted.starts_with(prefix)
+                            && decrypted.ends_with(&self.digest)
+                    }
+                    Err(_) => false,
+                }
+            }
+            Id::EC => {
+                match (key.ec_key(), EcdsaSig::from_der(&signature.signature))
+                {
+                    (Ok(ec), Ok(sig)) => {
+                        sig.verify(&self.digest, &ec).unwrap_or(false)
+                    }
+                    _ => false,
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Verifies the signatures of the ima-sig entries of the measurement list
+/// against the keys of the certificates IMA files are signed with, so that
+/// tampering with the local log is noticed before it is sent to the verifier.
+///
+/// Entries without a signature and entries of other templates are not
+/// verified. Appended signatures of the ima-modsig template are not supported.
+#[derive(Debug)]
+pub(crate) struct ImaSignatureVerifier {
+    mode: ImaSignatureMode,
+    keys: Vec<PKey<Public>>,
+}
+
+impl ImaSignatureVerifier {
+    /// Loads the certificate, or the certificates of the directory, found at
+    /// keyring. Nothing is loaded when verification is off.
+    pub(crate) fn new(
+        mode: ImaSignatureMode,
+        keyring: &Path,
+    ) -> crate::error::Result<Self> {
+        let mut keys = Vec::new();
+        if mode != ImaSignatureMode::Off {
+            let files = if keyring.is_dir() {
+                let mut files = fs::read_dir(keyring)?
+                    .map(|entry| entry.map(|e| e.path()))
+                    .collect::<std::io::Result<Vec<_>>>()?;
+                files.retain(|path| path.is_file());
+                files.sort();
+                files
+            } else {
+                vec![keyring.to_path_buf()]
+            };
+
+            for file in files {
+                match crypto::load_x509(&file).and_then(|cert| {
+                    cert.public_key().map_err(KeylimeError::Crypto)
+                }) {
+                    Ok(key) => keys.push(key),
+                    Err(e) => warn!(
+                        "Could not load IMA signing certificate {}: {}",
+                        file.display(),
+                        e
+                    ),
+                }
+            }
+
+            if keys.is_empty() {
+                return Err(KeylimeError::Configuration(format!(
+                    "No IMA signing certificate could be loaded from {}",
+                    keyring.display()
+                )));
+            }
+        }
+        Ok(ImaSignatureVerifier { mode, keys })
+    }
+
+    /// Verifies the entries of the measurement list, read from entry
+    /// first_entry of the log, logging the entries failing verification.
+    /// In mark mode, the numbers of these entries in the log are returned,
+    /// so that they are reported along with the list. They are not left out,
+    /// as the verifier could not replay the list anymore.
+    ///
+    /// Malformed ima-sig entries are logged and skipped: the verifier
+    /// rejects them anyway when replaying the list.
+    pub(crate) fn verify(
+        &self,
+        ml: &str,
+        first_entry: u64,
+    ) -> Option<Vec<u64>> {
+        if self.mode == ImaSignatureMode::Off {
+            return None;
+        }
+
+        let mut failures = Vec::new();
+        for (number, line) in (first_entry..).zip(ml.lines()) {
+            let entry = match ImaSigEntry::parse(line) {
+                Some(Ok(entry)) => entry,
+                Some(Err(e)) => {
+                    warn!("Skipping IMA entry {}: {}", number, e);
+                    continue;
+                }
+                None => continue,
+            };
+            if entry.signature.is_some()
+                && !self.keys.iter().any(|key| entry.verify_with(key))
+            {
+                warn!(
+                    "IMA signature verification failed for entry {}: {}",
+                    number, entry.path
+                );
+                failures.push(number);
+            }
+        }
+
+        match self.mode {
+            ImaSignatureMode::Mark => Some(failures),
+            _ => None,
+        }
     }
 }
 
 mod tests {
     use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::thread;
     use tempfile::NamedTempFile;
 
     #[test]
     fn read_measurement_list_test() {
-        let mut ima_ml = ImaMeasurementList::new();
+        let ima_ml = Mutex::new(ImaMeasurementList::new());
 
         let filedata = "0-entry\n1-entry\n2-entry\n";
         let mut tf = NamedTempFile::new().unwrap(); //#[allow_ci]
@@ -117,25 +721,456 @@ mod tests {
         tf.flush();
 
         // Request the 2nd entry, which is available
-        let (ml, nth_entry, num_entries) =
-            read_measurement_list(&mut ima_ml, tf.path(), 2).unwrap(); //#[allow_ci]
+        let MeasurementListRead {
+            ml,
+            nth_entry,
+            num_entries,
+            ..
+        } = read_measurement_list(&ima_ml, tf.path(), 2, 0, 0).unwrap(); //#[allow_ci]
         assert_eq!(num_entries, Some(3));
         assert_eq!(nth_entry, Some(2));
         assert_eq!(ml.unwrap().find("2-entry").unwrap(), 0); //#[allow_ci]
 
         // Request the 3rd entry, which is not available yet, thus we get an empty list
-        let (ml, nth_entry, num_entries) =
-            read_measurement_list(&mut ima_ml, tf.path(), 3).unwrap(); //#[allow_ci]
+        let MeasurementListRead {
+            ml,
+            nth_entry,
+            num_entries,
+            ..
+        } = read_measurement_list(&ima_ml, tf.path(), 3, 0, 0).unwrap(); //#[allow_ci]
         assert_eq!(num_entries, Some(3));
         assert_eq!(nth_entry, Some(3));
         assert_eq!(ml.unwrap().len(), 0); //#[allow_ci]
 
-        // Request the 4th entry, which is beyond the next entry; since this is wrong,
-        // we expect the entire list now.
-        let (ml, nth_entry, num_entries) =
-            read_measurement_list(&mut ima_ml, tf.path(), 4).unwrap(); //#[allow_ci]
+        // Request the 4th entry, which is beyond the next entry: the list
+        // is empty rather than read again from the start
+        let MeasurementListRead {
+            ml,
+            nth_entry,
+            num_entries,
+            rotated,
+        } = read_measurement_list(&ima_ml, tf.path(), 4, 0, 0).unwrap(); //#[allow_ci]
         assert_eq!(num_entries, Some(3));
+        assert_eq!(nth_entry, Some(4));
+        assert!(!rotated);
+        assert_eq!(ml.as_deref(), Some(""));
+    }
+
+    #[test]
+    fn read_measurement_list_rotation_test() {
+        let ima_ml = Mutex::new(ImaMeasurementList::new());
+
+        let mut tf = NamedTempFile::new().unwrap(); //#[allow_ci]
+        tf.write_all(b"0-entry\n1-entry\n2-entry\n3-entry\n")
+            .unwrap(); //#[allow_ci]
+        tf.flush().unwrap(); //#[allow_ci]
+
+        let read =
+            read_measurement_list(&ima_ml, tf.path(), 3, 0, 0).unwrap(); //#[allow_ci]
+        assert_eq!(read.ml.as_deref(), Some("3-entry\n"));
+        assert!(!read.rotated);
+
+        // The log is replaced by a shorter one, as after a reboot, which is
+        // noticed by its first entry holding a new boot aggregate: the
+        // verifier restarts from the first entry even though the requested
+        // entry is still there
+        fs::write(tf.path(), "0-other\n1-other\n2-other\n").unwrap(); //#[allow_ci]
+        let read =
+            read_measurement_list(&ima_ml, tf.path(), 2, 0, 0).unwrap(); //#[allow_ci]
+        assert!(read.rotated);
+        assert_eq!(read.nth_entry, Some(0));
+        assert_eq!(read.num_entries, Some(3));
+        assert_eq!(read.ml.as_deref(), Some("0-other\n1-other\n2-other\n"));
+
+        // Reading the new log further is not a rotation
+        let read =
+            read_measurement_list(&ima_ml, tf.path(), 2, 0, 0).unwrap(); //#[allow_ci]
+        assert!(!read.rotated);
+        assert_eq!(read.ml.as_deref(), Some("2-other\n"));
+
+        // Likewise for a log replaced by a longer one
+        fs::write(tf.path(), "0-new\n1-new\n2-new\n3-new\n4-new\n").unwrap(); //#[allow_ci]
+        let read =
+            read_measurement_list(&ima_ml, tf.path(), 3, 0, 0).unwrap(); //#[allow_ci]
+        assert!(read.rotated);
+        assert_eq!(read.nth_entry, Some(0));
+        assert_eq!(read.num_entries, Some(5));
+    }
+
+    #[test]
+    fn read_measurement_list_max_bytes_test() {
+        let ima_ml = Mutex::new(ImaMeasurementList::new());
+
+        let filedata = "0-entry\n1-entry\n2-entry\n";
+        let mut tf = NamedTempFile::new().unwrap(); //#[allow_ci]
+        tf.write_all(filedata.as_bytes());
+        tf.flush();
+
+        // Only the first two entries (16 bytes) fit in the limit
+        let MeasurementListRead {
+            ml,
+            nth_entry,
+            num_entries,
+            ..
+        } = read_measurement_list(&ima_ml, tf.path(), 0, 0, 20).unwrap(); //#[allow_ci]
+        let ml = ml.unwrap(); //#[allow_ci]
+        assert!(ml.len() <= 20);
+        assert_eq!(ml, "0-entry\n1-entry\n");
         assert_eq!(nth_entry, Some(0));
-        assert_eq!(ml.unwrap().find("0-entry").unwrap(), 0); //#[allow_ci]
+        assert_eq!(num_entries, Some(3));
+
+        // Reading from the first entry left out returns the rest
+        let MeasurementListRead { ml, nth_entry, .. } =
+            read_measurement_list(&ima_ml, tf.path(), 2, 0, 20).unwrap(); //#[allow_ci]
+        assert_eq!(ml.unwrap(), "2-entry\n"); //#[allow_ci]
+        assert_eq!(nth_entry, Some(2));
+
+        // The cap is hit in the middle of the list: the verifier resumes
+        // from the entry following the ones returned
+        let MeasurementListRead {
+            ml,
+            nth_entry,
+            num_entries,
+            ..
+        } = read_measurement_list(&ima_ml, tf.path(), 1, 0, 10).unwrap(); //#[allow_ci]
+        assert_eq!(ml.unwrap(), "1-entry\n"); //#[allow_ci]
+        assert_eq!(nth_entry, Some(1));
+        assert_eq!(num_entries, Some(3));
+
+        // An entry is never split: an entry larger than the limit on its
+        // own is an error
+        let err =
+            read_measurement_list(&ima_ml, tf.path(), 2, 0, 5).unwrap_err(); //#[allow_ci]
+        assert!(matches!(
+            err,
+            KeylimeError::ImaEntryTooLarge {
+                entry: 2,
+                size: 8,
+                max_bytes: 5
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "IMA measurement list entry 2 is 8 bytes, larger than the 5 bytes limit"
+        );
+    }
+
+    #[test]
+    fn read_measurement_list_max_entries_test() {
+        let ima_ml = Mutex::new(ImaMeasurementList::new());
+
+        let filedata = "0-entry\n1-entry\n2-entry\n3-entry\n4-entry\n";
+        let mut tf = NamedTempFile::new().unwrap(); //#[allow_ci]
+        tf.write_all(filedata.as_bytes());
+        tf.flush();
+
+        // Page through the list two entries at a time
+        let MeasurementListRead { ml, nth_entry, .. } =
+            read_measurement_list(&ima_ml, tf.path(), 0, 2, 0).unwrap(); //#[allow_ci]
+        assert_eq!(ml.unwrap(), "0-entry\n1-entry\n"); //#[allow_ci]
+        assert_eq!(nth_entry, Some(0));
+
+        let MeasurementListRead { ml, nth_entry, .. } =
+            read_measurement_list(&ima_ml, tf.path(), 2, 2, 0).unwrap(); //#[allow_ci]
+        assert_eq!(ml.unwrap(), "2-entry\n3-entry\n"); //#[allow_ci]
+        assert_eq!(nth_entry, Some(2));
+
+        // The last page is partial
+        let MeasurementListRead { ml, nth_entry, .. } =
+            read_measurement_list(&ima_ml, tf.path(), 4, 2, 0).unwrap(); //#[allow_ci]
+        assert_eq!(ml.unwrap(), "4-entry\n"); //#[allow_ci]
+        assert_eq!(nth_entry, Some(4));
+
+        // Past the last entry, the list is empty until new entries are
+        // measured
+        let MeasurementListRead { ml, nth_entry, .. } =
+            read_measurement_list(&ima_ml, tf.path(), 5, 2, 0).unwrap(); //#[allow_ci]
+        assert_eq!(ml.unwrap(), ""); //#[allow_ci]
+        assert_eq!(nth_entry, Some(5));
+
+        // Both limits apply, whichever is reached first
+        let MeasurementListRead { ml, .. } =
+            read_measurement_list(&ima_ml, tf.path(), 0, 3, 10).unwrap(); //#[allow_ci]
+        assert_eq!(ml.unwrap(), "0-entry\n"); //#[allow_ci]
+    }
+
+    #[test]
+    fn read_measurement_list_concurrent_test() {
+        let ima_ml = Arc::new(Mutex::new(ImaMeasurementList::new()));
+        // The first entry is there from the start, as the boot aggregate
+        let mut tf = NamedTempFile::new().unwrap(); //#[allow_ci]
+        tf.write_all(b"0-entry\n").unwrap(); //#[allow_ci]
+        let path = tf.path().to_path_buf();
+        let written = Arc::new(AtomicU64::new(1));
+
+        // The log grows while it is read
+        let writer = {
+            let path = path.clone();
+            let written = written.clone();
+            thread::spawn(move || {
+                let mut file =
+                    fs::OpenOptions::new().append(true).open(&path).unwrap(); //#[allow_ci]
+                for i in 1..500 {
+                    file.write_all(format!("{}-entry\n", i).as_bytes())
+                        .unwrap(); //#[allow_ci]
+                    written.store(i + 1, Ordering::SeqCst);
+                }
+            })
+        };
+
+        let readers = (0..8)
+            .map(|reader| {
+                let ima_ml = ima_ml.clone();
+                let path = path.clone();
+                let written = written.clone();
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        let available = written.load(Ordering::SeqCst);
+                        let nth = (reader * 100 + i) * 7 % (available + 1);
+                        let read =
+                            read_measurement_list(&ima_ml, &path, nth, 0, 0)
+                                .unwrap(); //#[allow_ci]
+
+                        // The list starts at the requested entry and goes
+                        // up to the number of entries reported, whatever
+                        // the offsets recorded by the other readers
+                        assert!(!read.rotated);
+                        assert_eq!(read.nth_entry, Some(nth));
+                        let num_entries = read.num_entries.unwrap(); //#[allow_ci]
+                        assert!(num_entries >= available);
+                        let expected = (nth..num_entries)
+                            .map(|entry| format!("{}-entry\n", entry))
+                            .collect::<String>();
+                        assert_eq!(read.ml.unwrap(), expected); //#[allow_ci]
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        writer.join().unwrap(); //#[allow_ci]
+        for reader in readers {
+            reader.join().unwrap(); //#[allow_ci]
+        }
+    }
+
+    fn ima_sig_ml() -> String {
+        fs::read_to_string(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("test-data/ima/ascii_runtime_measurements_ima_sig"),
+        )
+        .unwrap() //#[allow_ci]
+    }
+
+    fn test_cert() -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data/test-cert.pem")
+    }
+
+    #[test]
+    fn parse_ima_sig_entry_test() {
+        let ml = ima_sig_ml();
+        let lines: Vec<&str> = ml.lines().collect();
+
+        // Other templates are not parsed
+        assert!(ImaSigEntry::parse(lines[0]).is_none());
+
+        let entry = ImaSigEntry::parse(lines[1]).unwrap().unwrap(); //#[allow_ci]
+        assert_eq!(entry.digest_alg, "sha256");
+        assert_eq!(entry.digest.len(), 32);
+        assert_eq!(entry.path, "/usr/bin/signed");
+        let signature = entry.signature.unwrap(); //#[allow_ci]
+        assert_eq!(signature.hash_algo, 4);
+        assert_eq!(signature.keyid, [0xc6, 0x23, 0x21, 0x7a]);
+        assert_eq!(signature.signature.len(), 256);
+
+        let entry = ImaSigEntry::parse(lines[3]).unwrap().unwrap(); //#[allow_ci]
+        assert_eq!(entry.path, "/usr/bin/unsigned");
+        assert!(entry.signature.is_none());
+
+        // Paths may hold spaces
+        let entry = ImaSigEntry::parse(
+            "10 0000 ima-sig sha256:00ff /usr/bin/with space",
+        )
+        .unwrap() //#[allow_ci]
+        .unwrap(); //#[allow_ci]
+        assert_eq!(entry.path, "/usr/bin/with space");
+
+        assert!(ImaSigEntry::parse("10 0000 ima-sig sha256:zz /bin/sh")
+            .unwrap() //#[allow_ci]
+            .is_err());
+        assert!(ImaSigEntry::parse("10 0000 ima-sig").unwrap().is_err()); //#[allow_ci]
+    }
+
+    #[test]
+    fn verify_ima_signatures_test() {
+        let ml = ima_sig_ml();
+
+        // In log mode, the failures are only logged
+        let verifier =
+            ImaSignatureVerifier::new(ImaSignatureMode::Log, &test_cert())
+                .unwrap(); //#[allow_ci]
+        assert_eq!(verifier.verify(&ml, 0), None);
+
+        // In mark mode, the tampered entry is reported by its number in the
+        // log
+        let verifier =
+            ImaSignatureVerifier::new(ImaSignatureMode::Mark, &test_cert())
+                .unwrap(); //#[allow_ci]
+        assert_eq!(verifier.verify(&ml, 0), Some(vec![2]));
+        assert_eq!(verifier.verify(&ml, 10), Some(vec![12]));
+
+        // Malformed entries are skipped
+        let malformed = format!("{}10 0000 ima-sig\n", ml);
+        assert_eq!(verifier.verify(&malformed, 0), Some(vec![2]));
+
+        // A signature made with another key is not valid
+        let other = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/test-cert-ec.pem");
+        let verifier =
+            ImaSignatureVerifier::new(ImaSignatureMode::Mark, &other)
+                .unwrap(); //#[allow_ci]
+        assert_eq!(verifier.verify(&ml, 0), Some(vec![1, 2]));
+    }
+
+    #[test]
+    fn ima_signature_verifier_keyring_test() {
+        // Nothing is loaded when verification is off
+        let verifier = ImaSignatureVerifier::new(
+            ImaSignatureMode::Off,
+            Path::new("/nonexistent"),
+        )
+        .unwrap(); //#[allow_ci]
+        assert!(verifier.keys.is_empty());
+
+        assert!(matches!(
+            ImaSignatureVerifier::new(
+                ImaSignatureMode::Log,
+                Path::new("/nonexistent")
+            ),
+            Err(KeylimeError::Configuration(_))
+        ));
+
+        // Certificates are loaded from a directory
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let _ = fs::copy(test_cert(), dir.path().join("ima.pem")).unwrap(); //#[allow_ci]
+        let verifier =
+            ImaSignatureVerifier::new(ImaSignatureMode::Log, dir.path())
+                .unwrap(); //#[allow_ci]
+        assert_eq!(verifier.keys.len(), 1);
+
+        assert!(matches!(
+            ImaSignatureMode::from_str("enforce"),
+            Err(KeylimeError::Configuration(_))
+        ));
+        assert_eq!(
+            ImaSignatureMode::from_str("Mark").unwrap(), //#[allow_ci]
+            ImaSignatureMode::Mark
+        );
+    }
+
+    #[test]
+    fn parse_measurement_list_round_trip_test() {
+        let test_data =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data/ima");
+        for fixture in &[
+            "ascii_runtime_measurements",
+            "ascii_runtime_measurements_ima_sig",
+        ] {
+            let ml = fs::read_to_string(test_data.join(fixture)).unwrap(); //#[allow_ci]
+            let entries = parse_measurement_list(&ml).unwrap(); //#[allow_ci]
+            assert_eq!(entries.len(), ml.lines().count());
+            assert_eq!(format_measurement_list(&entries), ml);
+        }
+    }
+
+    #[test]
+    fn parse_ima_entry_test() {
+        let ml = ima_sig_ml();
+        let entries = parse_measurement_list(&ml).unwrap(); //#[allow_ci]
+
+        assert_eq!(entries[0].pcr, 10);
+        assert_eq!(entries[0].template_name, "ima-ng");
+        assert_eq!(entries[0].template_hash.len(), 20);
+        assert_eq!(entries[0].path, "boot_aggregate");
+        assert_eq!(entries[0].extra, None);
+
+        // The signature of ima-sig entries is kept apart from the path
+        assert_eq!(entries[1].template_name, "ima-sig");
+        assert_eq!(entries[1].path, "/usr/bin/signed");
+        assert!(entries[1].extra.as_ref().unwrap().starts_with("030204")); //#[allow_ci]
+        assert_eq!(entries[3].path, "/usr/bin/unsigned");
+        assert_eq!(entries[3].extra, None);
+
+        // Filtering keeps the other entries unchanged
+        let kept: Vec<ImaEntry> = entries
+            .into_iter()
+            .filter(|entry| !entry.path.starts_with("/usr/bin/t"))
+            .collect();
+        let expected: String = ml
+            .split_inclusive('\n')
+            .filter(|line| !line.contains("/usr/bin/tampered"))
+            .collect();
+        assert_eq!(format_measurement_list(&kept), expected);
+
+        // PCRs are right-aligned, paths may hold spaces, and ima-buf
+        // entries carry a buffer
+        for line in &[
+            " 9 0000 ima-ng sha256:00ff /with space",
+            "10 0000 ima-buf sha256:00ff kexec-cmdline 726f6f74",
+            "10 0000 ima 00ff /bin/sh",
+        ] {
+            let entry = ImaEntry::from_str(line).unwrap(); //#[allow_ci]
+            assert_eq!(&entry.to_string(), line);
+        }
+        assert_eq!(
+            ImaEntry::from_str(" 9 0000 ima-ng sha256:00ff /with space")
+                .unwrap() //#[allow_ci]
+                .path,
+            "/with space"
+        );
+
+        for malformed in &["", "10 0000 ima-ng sha1:00", "x 0000 ima-ng a b"]
+        {
+            assert!(ImaEntry::from_str(malformed).is_err());
+        }
+    }
+
+    #[test]
+    fn filter_measurement_list_test() {
+        let ml = ima_sig_ml();
+
+        // Prefix
+        let filtered = filter_measurement_list(&ml, "/usr/bin/s");
+        assert_eq!(filtered.lines().count(), 1);
+        assert!(ml.contains(&filtered));
+        assert!(filtered.ends_with("\n"));
+
+        // Glob
+        let filtered = filter_measurement_list(&ml, "/usr/*/*signed");
+        let paths: Vec<String> = parse_measurement_list(&filtered)
+            .unwrap() //#[allow_ci]
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect();
+        assert_eq!(paths, vec!["/usr/bin/signed", "/usr/bin/unsigned"]);
+
+        let filtered = filter_measurement_list(&ml, "/nonexistent");
+        assert_eq!(filtered, "");
+
+        // Entries that cannot be parsed are left out
+        let malformed = format!("10 0000 ima-ng\n{}", ml);
+        assert_eq!(filter_measurement_list(&malformed, "*"), ml);
+    }
+
+    #[test]
+    fn path_matches_test() {
+        assert!(path_matches("/usr/bin/sh", "/usr/"));
+        assert!(!path_matches("/bin/sh", "/usr/"));
+        assert!(path_matches("/usr/bin/sh", "/usr/*/sh"));
+        assert!(path_matches("/usr/lib/x/y.so", "*.so"));
+        assert!(path_matches("/bin/sh", "/bin/s?"));
+        assert!(!path_matches("/bin/sh", "/bin/s?h"));
+        assert!(path_matches("/a/b/a/b/c", "*a/b/c"));
+        assert!(!path_matches("/usr/bin/sh", "*.so"));
+        assert!(path_matches("", "*"));
     }
 }
@@ -4,8 +4,8 @@
 use crate::crypto;
 use crate::{
     common::{
-        JsonWrapper, KeySet, SymmKey, AES_BLOCK_SIZE, AGENT_UUID_LEN,
-        AUTH_TAG_LEN,
+        JsonWrapper, KeySet, ResponseStatus, SymmKey, AES_BLOCK_SIZE,
+        AGENT_UUID_LEN, AUTH_TAG_LEN,
     },
     Error, QuoteData, Result,
 };
@@ -198,10 +198,11 @@ pub async fn pubkey(
         }
         Err(e) => {
             debug!("Unable to retrieve public key: {:?}", e);
-            HttpResponse::InternalServerError().json(JsonWrapper::error(
-                500,
+            JsonWrapper::error(
+                ResponseStatus::InternalServerError,
                 "Unable to retrieve public key".to_string(),
-            ))
+            )
+            .into_response()
         }
     }
 }
@@ -215,19 +216,23 @@ pub async fn verify(
         warn!(
             "GET key challenge returning 400 response. No challenge provided"
         );
-        return HttpResponse::BadRequest()
-            .json(JsonWrapper::error(400, "No challenge provided."));
+        return JsonWrapper::error(
+            ResponseStatus::BadRequest,
+            "No challenge provided.",
+        )
+        .into_response();
     }
 
     if !param.challenge.chars().all(char::is_alphanumeric) {
         warn!("GET key challenge returning 400 response. Parameters should be strictly alphanumeric: {}", param.challenge);
-        return HttpResponse::BadRequest().json(JsonWrapper::error(
-            400,
+        return JsonWrapper::error(
+            ResponseStatus::BadRequest,
             format!(
                 "Parameters should be strictly alphanumeric: {}",
                 param.challenge
             ),
-        ));
+        )
+        .into_response();
     }
 
     let key_arc = Arc::clone(&data.payload_symm_key);
@@ -235,10 +240,11 @@ pub async fn verify(
 
     if key.is_none() {
         warn!("GET key challenge returning 400 response. Bootstrap key not available");
-        return HttpResponse::BadRequest().json(JsonWrapper::error(
-            400,
+        return JsonWrapper::error(
+            ResponseStatus::BadRequest,
             "Bootstrap key not yet available.",
-        ));
+        )
+        .into_response();
     }
 
     let key = key.as_ref().unwrap(); //#[allow_ci]
@@ -253,10 +259,11 @@ pub async fn verify(
         }
         Err(e) => {
             warn!("GET key challenge failed: {:?}", e);
-            HttpResponse::InternalServerError().json(JsonWrapper::error(
-                500,
+            JsonWrapper::error(
+                ResponseStatus::InternalServerError,
                 "GET key challenge failed".to_string(),
-            ))
+            )
+            .into_response()
         }
     }
 }
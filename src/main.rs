@@ -34,22 +34,38 @@
 #![allow(unused, missing_docs)]
 
 mod algorithms;
+mod api_version;
+mod bundle;
+mod capabilities_handler;
+mod client_auth;
 mod common;
 mod crypto;
 mod error;
 mod errors_handler;
+mod health_handler;
 mod ima;
 mod keys_handler;
+mod measured_boot;
+mod metrics;
+mod mount_handler;
+mod nonce_tracker;
 mod notifications_handler;
+mod push_attestation;
+mod quote_cache;
 mod quotes_handler;
+mod rate_limit;
 mod registrar_agent;
 mod revocation;
+mod revocation_handler;
+mod revocation_history;
 mod secure_mount;
 mod serialization;
 mod tpm;
 mod version_handler;
 
-use actix_web::{dev::Service, http, middleware, rt, web, App, HttpServer};
+use actix_web::{
+    dev::Service, http, middleware, rt, web, App, HttpMessage, HttpServer,
+};
 use clap::{App as ClapApp, Arg};
 use common::*;
 use compress_tools::*;
@@ -69,8 +85,14 @@ use std::{
     sync::{Arc, Condvar, Mutex},
     time::Duration,
 };
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
 use tss_esapi::{
-    handles::KeyHandle, interface_types::algorithm::AsymmetricAlgorithm,
+    handles::KeyHandle,
+    interface_types::algorithm::AsymmetricAlgorithm,
+    structures::{Name, PcrSlot},
     Context,
 };
 use uuid::Uuid;
@@ -85,6 +107,7 @@ static NOTFOUND: &[u8] = b"Not Found";
 #[derive(Debug)]
 pub struct QuoteData {
     tpmcontext: Mutex<Context>,
+    tpm_lock_timeout: Duration,
     priv_key: PKey<Private>,
     pub_key: PKey<Public>,
     ak_handle: KeyHandle,
@@ -97,16 +120,27 @@ pub struct QuoteData {
     hash_alg: algorithms::HashAlgorithm,
     enc_alg: algorithms::EncryptionAlgorithm,
     sign_alg: algorithms::SignAlgorithm,
+    ak_sign_algs: Vec<algorithms::SignAlgorithm>,
     agent_uuid: String,
-    revocation_cert: PathBuf,
-    revocation_actions: String,
-    revocation_actions_dir: PathBuf,
-    allow_payload_revocation_actions: bool,
+    revocation_cert: Arc<revocation::RevocationCert>,
+    revocation_options: Arc<revocation::RevocationOptions>,
     secure_size: String,
     work_dir: PathBuf,
     ima_ml_path: PathBuf,
     measuredboot_ml_path: PathBuf,
     ima_ml: Mutex<ImaMeasurementList>,
+    required_pcrs: Vec<u32>,
+    allowed_pcrs: Vec<u32>,
+    ima_pcr: PcrSlot,
+    measuredboot_pcr: PcrSlot,
+    ima_ml_max_bytes: usize,
+    ima_signatures: ima::ImaSignatureVerifier,
+    pcr_banks: Vec<algorithms::HashAlgorithm>,
+    identity_quotes: quote_cache::QuoteCache,
+    used_nonces: nonce_tracker::NonceTracker,
+    quote_rate_limiter: rate_limit::RateLimiter,
+    client_auth: client_auth::ClientAuthPolicy,
+    revocation_history: Arc<revocation_history::RevocationHistory>,
 }
 
 // Parameters are based on Python codebase:
@@ -307,7 +341,10 @@ async fn worker(
     symm_key: Arc<Mutex<Option<SymmKey>>>,
     symm_key_cvar: Arc<Condvar>,
     payload: Arc<Mutex<Vec<u8>>>,
+    revocation_cert: Arc<revocation::RevocationCert>,
+    revocation_history: Arc<revocation_history::RevocationHistory>,
     config: KeylimeConfig,
+    shutdown: watch::Receiver<bool>,
 ) -> Result<()> {
     // Only run payload scripts if mTLS is enabled or 'enable_insecure_payload' option is set
     if config.mtls_enabled || config.enable_insecure_payload {
@@ -317,15 +354,174 @@ async fn worker(
         warn!("agent mTLS is disabled, and unless 'enable_insecure_payload' is set to 'True', payloads cannot be deployed'");
     }
 
-    // If with-zmq feature is enabled, run the service listening for ZeroMQ messages
-    #[cfg(feature = "with-zmq")]
+    // Listen for revocation messages on the enabled transports, ZeroMQ or
+    // polling an URL, depending on the features and the configuration
     if config.run_revocation {
-        return revocation::run_revocation_service(&config).await;
+        return revocation::run_revocation_transports(
+            &config,
+            &revocation_cert,
+            &revocation_history,
+            shutdown,
+        )
+        .await;
     }
 
     Ok(())
 }
 
+// The REST API served under the path of the given version
+fn api_scope(version: &APIVersion) -> actix_web::Scope {
+    web::scope(&format!("/{}", version))
+        .service(
+            web::resource("/capabilities")
+                .route(web::get().to(capabilities_handler::capabilities)),
+        )
+        .service(
+            web::scope("/agent")
+                .service(
+                    web::resource("/health")
+                        .route(web::get().to(health_handler::health)),
+                )
+                .service(
+                    web::resource("/mount")
+                        .route(web::get().to(mount_handler::mount)),
+                )
+                .service(web::resource("/revocation/last").route(
+                    web::get().to(revocation_handler::last_revocation),
+                ))
+                .default_service(web::to(errors_handler::agent_default)),
+        )
+        .service(
+            web::scope("/keys")
+                .service(
+                    web::resource("/pubkey")
+                        .route(web::get().to(keys_handler::pubkey)),
+                )
+                .service(
+                    web::resource("/ukey")
+                        .route(web::post().to(keys_handler::u_key)),
+                )
+                .service(
+                    web::resource("/verify")
+                        .route(web::get().to(keys_handler::verify)),
+                )
+                .service(
+                    web::resource("/vkey")
+                        .route(web::post().to(keys_handler::v_key)),
+                )
+                .default_service(web::to(errors_handler::keys_default)),
+        )
+        .service(
+            web::scope("/notifications")
+                .service(
+                    web::resource("/revocation").route(
+                        web::post().to(notifications_handler::revocation),
+                    ),
+                )
+                .default_service(web::to(
+                    errors_handler::notifications_default,
+                )),
+        )
+        .service(
+            web::scope("/quotes")
+                .service(
+                    web::resource("/identity")
+                        .route(web::get().to(quotes_handler::identity)),
+                )
+                .service(
+                    web::resource("/integrity")
+                        .route(web::get().to(quotes_handler::integrity)),
+                )
+                .service(
+                    web::resource("/bundle").route(
+                        web::get().to(quotes_handler::integrity_bundle),
+                    ),
+                )
+                .service(
+                    web::resource("/batch")
+                        .route(web::post().to(quotes_handler::batch)),
+                )
+                .default_service(web::to(errors_handler::quotes_default)),
+        )
+        .default_service(web::to(errors_handler::api_default))
+}
+
+// Waits for SIGTERM or SIGINT, which request the agent to shut down
+async fn shutdown_signal() -> Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = sigterm.recv() => info!("Received SIGTERM, shutting down"),
+        res = tokio::signal::ctrl_c() => {
+            res?;
+            info!("Received SIGINT, shutting down");
+        }
+    }
+    Ok(())
+}
+
+// Reuses the AK persisted in the TPM data, if any, or creates a new one. When
+// persist_ak is enabled, a new AK is stored so that it survives restarts.
+fn load_or_create_ak(
+    ctx: &mut Context,
+    ek_handle: KeyHandle,
+    config: &KeylimeConfig,
+) -> Result<(KeyHandle, Name, Vec<u8>)> {
+    // Try to load persistent TPM data
+    let tpm_data = config.tpm_data.clone().and_then(|data|
+        match data.valid(config.hash_alg, config.sign_alg) {
+            true => Some(data),
+            false => {
+                warn!(
+                    "Not using old {} because it is not valid with current configuration",
+                    TPM_DATA
+                );
+                None
+            },
+        }
+    );
+
+    // Try to reuse old AK from TpmData
+    let old_ak =
+        tpm_data.and_then(|data| match tpm::load_ak(ctx, data.ak_context) {
+            Ok(ak_data) => {
+                info!("Loaded old AK context from {}", TPM_DATA);
+                Some(ak_data)
+            }
+            Err(e) => {
+                warn!(
+                    "Loading old AK context from {} failed: {}",
+                    TPM_DATA, e
+                );
+                None
+            }
+        });
+
+    // Use old AK or generate a new one and update the TpmData
+    match old_ak {
+        Some(data) => Ok(data),
+        None => {
+            info!("Generating new AK");
+            let new_ak = tpm::create_ak(
+                ctx,
+                ek_handle,
+                config.hash_alg.into(),
+                config.sign_alg.into(),
+            )?;
+            // Only updating tpmdata.json if a new AK was used
+            if config.persist_ak {
+                info!("Storing updated TPM data in {}", TPM_DATA);
+                TpmData {
+                    ak_hash_alg: config.hash_alg,
+                    ak_sign_alg: config.sign_alg,
+                    ak_context: tpm::store_ak(ctx, new_ak.0)?,
+                }
+                .store(Path::new(&config.tpm_data_path))?;
+            }
+            Ok(new_ak)
+        }
+    }
+}
+
 #[actix_web::main]
 async fn main() -> Result<()> {
     // Print --help information
@@ -337,7 +533,11 @@ async fn main() -> Result<()> {
         .get_matches();
 
     pretty_env_logger::init();
-    let mut ctx = tpm::get_tpm2_ctx()?;
+
+    // Load config
+    let config = KeylimeConfig::build()?;
+
+    let mut ctx = tpm::get_tpm2_ctx(&config.tpm_tcti)?;
 
     //  Retrieve the TPM Vendor, this allows us to warn if someone is using a
     // Software TPM ("SW")
@@ -349,9 +549,6 @@ async fn main() -> Result<()> {
 
     info!("Starting server with API version {}...", API_VERSION);
 
-    // Load config
-    let config = KeylimeConfig::build()?;
-
     // The agent cannot run when a payload script is defined, but mTLS is disabled and insecure
     // payloads are not explicitly enabled
     if !&config.mtls_enabled
@@ -365,73 +562,41 @@ async fn main() -> Result<()> {
     }
 
     // Verify if the python shim is installed in the expected location
-    let python_shim =
-        PathBuf::from(&config.revocation_actions_dir).join("shim.py");
-    if !python_shim.exists() {
-        error!("Could not find python shim at {}", python_shim.display());
+    let python_shim = revocation::PythonShim::from_config(&config);
+    let python_shim_path = revocation::resolve_python_shim(
+        Path::new(&config.revocation_actions_dir),
+        python_shim.path.as_deref(),
+    );
+    if !python_shim_path.exists() {
+        error!(
+            "Could not find python shim at {}",
+            python_shim_path.display()
+        );
         return Err(Error::Configuration(format!(
             "Could not find python shim at {}",
-            python_shim.display()
+            python_shim_path.display()
         )));
     }
+    if let Some(interpreter) = &python_shim.interpreter {
+        if !interpreter.exists() {
+            error!(
+                "Could not find python interpreter at {}",
+                interpreter.display()
+            );
+            return Err(Error::Configuration(format!(
+                "Could not find python interpreter at {}",
+                interpreter.display()
+            )));
+        }
+    }
 
     // Gather EK values and certs
     let (ek_handle, ek_cert, ek_tpm2b_pub) =
         tpm::create_ek(&mut ctx, config.enc_alg.into())?;
 
-    // Try to load persistent TPM data
-    let tpm_data = config.tpm_data.clone().and_then(|data|
-        match data.valid(config.hash_alg, config.sign_alg) {
-            true => Some(data),
-            false => {
-                warn!(
-                    "Not using old {} because it is not valid with current configuration",
-                    TPM_DATA
-                );
-                None
-            },
-        }
-    );
-
-    // Try to reuse old AK from TpmData
-    let old_ak = tpm_data.and_then(|data| {
-        match tpm::load_ak(&mut ctx, data.ak_context) {
-            Ok(ak_data) => {
-                info!("Loaded old AK context from {}", TPM_DATA);
-                Some(ak_data)
-            }
-            Err(e) => {
-                warn!(
-                    "Loading old AK context from {} failed: {}",
-                    TPM_DATA, e
-                );
-                None
-            }
-        }
-    });
-
-    // Use old AK or generate a new one and update the TpmData
-    let (ak_handle, ak_name, ak_tpm2b_pub) = match old_ak {
-        Some(data) => data,
-        None => {
-            info!("Generating new AK");
-            let new_ak = tpm::create_ak(
-                &mut ctx,
-                ek_handle,
-                config.hash_alg.into(),
-                config.sign_alg.into(),
-            )?;
-            // Only updating tpmdata.json if a new AK was used
-            info!("Storing updated TPM data in {}", TPM_DATA);
-            TpmData {
-                ak_hash_alg: config.hash_alg,
-                ak_sign_alg: config.sign_alg,
-                ak_context: tpm::store_ak(&mut ctx, new_ak.0)?,
-            }
-            .store(Path::new(&config.tpm_data_path))?;
-            new_ak
-        }
-    };
+    let (ak_handle, ak_name, ak_tpm2b_pub) =
+        load_or_create_ak(&mut ctx, ek_handle, &config)?;
+    let ak_sign_algs = tpm::ak_sign_algs(&mut ctx, ak_handle)?;
 
     info!("Agent UUID: {}", config.agent_uuid);
 
@@ -511,16 +676,31 @@ async fn main() -> Result<()> {
     let symm_key_cvar = Arc::clone(&symm_key_cvar_arc);
     let payload = Arc::clone(&encr_payload_arc);
 
-    let revocation_cert = revocation::get_revocation_cert_path(&config)?;
+    let revocation_cert =
+        Arc::new(revocation::RevocationCert::from_config(&config).await?);
+    if config.watch_revocation_cert {
+        let _ = revocation::watch_revocation_cert(&revocation_cert)?;
+    }
     let actions_dir =
         Path::new(&config.revocation_actions_dir).canonicalize()?;
+    if config.check_revocation_actions_dir_permissions {
+        revocation::check_actions_dir_permissions(&actions_dir)?;
+    }
     let work_dir = Path::new(&config.work_dir).canonicalize()?;
+    // Revocations are processed both by the REST API and the worker. The
+    // history saved on the last shutdown keeps protecting against replays.
+    let revocation_history =
+        Arc::new(revocation_history::RevocationHistory::load(&work_dir)?);
     let ima_ml_path = Path::new(&config.ima_ml_path).to_path_buf();
     let measuredboot_ml_path =
         Path::new(&config.measuredboot_ml_path).to_path_buf();
 
+    // The PCR banks are reported to verifiers by the capabilities endpoint
+    let pcr_banks = tpm::get_pcr_banks(&mut ctx)?;
+
     let quotedata = web::Data::new(QuoteData {
         tpmcontext: Mutex::new(ctx),
+        tpm_lock_timeout: config.tpm_lock_timeout,
         priv_key: nk_priv,
         pub_key: nk_pub,
         ak_handle,
@@ -533,112 +713,104 @@ async fn main() -> Result<()> {
         hash_alg: config.hash_alg,
         enc_alg: config.enc_alg,
         sign_alg: config.sign_alg,
+        ak_sign_algs,
         agent_uuid: config.agent_uuid.clone(),
-        revocation_cert,
-        revocation_actions: config.revocation_actions.clone(),
-        revocation_actions_dir: actions_dir,
-        allow_payload_revocation_actions: config
-            .allow_payload_revocation_actions,
+        revocation_cert: revocation_cert.clone(),
+        revocation_options: Arc::new(revocation::RevocationOptions {
+            actions_dir,
+            python_shim,
+            work_dir: work_dir.clone(),
+            ..revocation::RevocationOptions::from_config(&config)
+        }),
         secure_size: config.secure_size.clone(),
-        work_dir,
+        work_dir: work_dir.clone(),
         ima_ml_path,
         measuredboot_ml_path,
         ima_ml: Mutex::new(ImaMeasurementList::new()),
+        required_pcrs: config.required_pcrs.clone(),
+        allowed_pcrs: config.allowed_pcrs.clone(),
+        ima_pcr: tpm::pcr_slot(config.ima_pcr)?,
+        measuredboot_pcr: tpm::pcr_slot(config.measuredboot_pcr)?,
+        ima_ml_max_bytes: config.ima_ml_max_bytes,
+        ima_signatures: ima::ImaSignatureVerifier::new(
+            config.ima_signature_verification,
+            Path::new(&config.ima_signing_keys),
+        )?,
+        pcr_banks,
+        identity_quotes: quote_cache::QuoteCache::new(
+            config.identity_quote_cache_ttl,
+        ),
+        used_nonces: nonce_tracker::NonceTracker::new(
+            config.nonce_reuse_window,
+        ),
+        quote_rate_limiter: rate_limit::RateLimiter::new(
+            config.quote_rate_limit,
+            config.quote_rate_limit_burst,
+        ),
+        client_auth: client_auth::ClientAuthPolicy::from_config(&config)?,
+        revocation_history: revocation_history.clone(),
     });
-
-    let actix_server =
-        HttpServer::new(move || {
-            App::new()
-                .wrap(middleware::ErrorHandlers::new().handler(
-                    http::StatusCode::NOT_FOUND,
-                    errors_handler::wrap_404,
-                ))
-                .wrap(middleware::Logger::new(
-                    "%r from %a result %s (took %D ms)",
-                ))
-                .wrap_fn(|req, srv| {
-                    info!(
-                        "{} invoked from {:?} with uri {}",
-                        req.head().method,
-                        req.connection_info().peer_addr().unwrap(), //#[allow_ci]
-                        req.uri()
-                    );
-                    srv.call(req)
-                })
-                .app_data(quotedata.clone())
-                .app_data(
-                    web::JsonConfig::default()
-                        .error_handler(errors_handler::json_parser_error),
-                )
-                .app_data(
-                    web::QueryConfig::default()
-                        .error_handler(errors_handler::query_parser_error),
-                )
-                .app_data(
-                    web::PathConfig::default()
-                        .error_handler(errors_handler::path_parser_error),
-                )
-                .service(
-                    web::scope(&format!("/{}", API_VERSION))
-                        .service(
-                            web::scope("/keys")
-                                .service(web::resource("/pubkey").route(
-                                    web::get().to(keys_handler::pubkey),
-                                ))
-                                .service(web::resource("/ukey").route(
-                                    web::post().to(keys_handler::u_key),
-                                ))
-                                .service(web::resource("/verify").route(
-                                    web::get().to(keys_handler::verify),
-                                ))
-                                .service(web::resource("/vkey").route(
-                                    web::post().to(keys_handler::v_key),
-                                ))
-                                .default_service(web::to(
-                                    errors_handler::keys_default,
-                                )),
-                        )
-                        .service(
-                            web::scope("/notifications")
-                                .service(web::resource("/revocation").route(
-                                    web::post().to(
-                                        notifications_handler::revocation,
-                                    ),
-                                ))
-                                .default_service(web::to(
-                                    errors_handler::notifications_default,
-                                )),
-                        )
-                        .service(
-                            web::scope("/quotes")
-                                .service(web::resource("/identity").route(
-                                    web::get().to(quotes_handler::identity),
-                                ))
-                                .service(web::resource("/integrity").route(
-                                    web::get().to(quotes_handler::integrity),
-                                ))
-                                .default_service(web::to(
-                                    errors_handler::quotes_default,
-                                )),
-                        )
-                        .default_service(web::to(
-                            errors_handler::api_default,
-                        )),
-                )
-                .service(
-                    web::resource("/version")
-                        .route(web::get().to(version_handler::version)),
-                )
-                .service(
-                    web::resource(r"/v{major:\d+}.{minor:\d+}{tail}*")
-                        .to(errors_handler::version_not_supported),
-                )
-                .default_service(web::to(errors_handler::app_default))
-        })
-        // Disable default signal handlers.  See:
-        // https://github.com/actix/actix-web/issues/2739
-        // for details.
-        .disable_signals();
+    let push_data = quotedata.clone();
+
+    let actix_server = HttpServer::new(move || {
+        App::new()
+            .wrap(middleware::ErrorHandlers::new().handler(
+                http::StatusCode::NOT_FOUND,
+                errors_handler::wrap_404,
+            ))
+            .wrap(middleware::Logger::new(
+                "%r from %a result %s (took %D ms)",
+            ))
+            .wrap_fn(|req, srv| {
+                info!(
+                    "{} invoked from {:?} with uri {}",
+                    req.head().method,
+                    req.connection_info().peer_addr().unwrap(), //#[allow_ci]
+                    req.uri()
+                );
+                // Expose the client certificate to the handlers
+                if let Some(cert) =
+                    req.conn_data::<client_auth::PeerCertificate>().cloned()
+                {
+                    let _ = req.extensions_mut().insert(cert);
+                }
+                srv.call(req)
+            })
+            .app_data(quotedata.clone())
+            .app_data(
+                web::JsonConfig::default()
+                    .error_handler(errors_handler::json_parser_error),
+            )
+            .app_data(
+                web::QueryConfig::default()
+                    .error_handler(errors_handler::query_parser_error),
+            )
+            .app_data(
+                web::PathConfig::default()
+                    .error_handler(errors_handler::path_parser_error),
+            )
+            .configure(|cfg| {
+                // Each supported API version is served, so that the
+                // handlers can adapt their responses to the version
+                for version in api_version::SUPPORTED_API_VERSIONS {
+                    let _ = cfg.service(api_scope(version));
+                }
+            })
+            .service(
+                web::resource("/version")
+                    .route(web::get().to(version_handler::version)),
+            )
+            .service(
+                web::resource(r"/v{major:\d+}.{minor:\d+}{tail}*")
+                    .to(errors_handler::version_not_supported),
+            )
+            .default_service(web::to(errors_handler::app_default))
+    })
+    .on_connect(client_auth::on_connect)
+    // Disable default signal handlers.  See:
+    // https://github.com/actix/actix-web/issues/2739
+    // for details.
+    .disable_signals();
 
     let server;
     if config.mtls_enabled && ssl_context.is_some() {
@@ -664,15 +836,49 @@ async fn main() -> Result<()> {
         );
     };
 
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
     let server_handle = server.handle();
     let server_task = rt::spawn(server).map_err(Error::from);
-    let worker_task =
-        rt::spawn(worker(symm_key, symm_key_cvar, payload, config.clone()))
-            .map_err(Error::from);
+    let worker_task = rt::spawn(worker(
+        symm_key,
+        symm_key_cvar,
+        payload,
+        revocation_cert.clone(),
+        revocation_history.clone(),
+        config.clone(),
+        shutdown_rx.clone(),
+    ))
+    .map_err(Error::from);
+    let push_task = rt::spawn(push_attestation::run_push_attestation(
+        push_data,
+        config.clone(),
+        shutdown_rx,
+    ))
+    .map_err(Error::from);
+
+    // On shutdown, the revocation service finishes processing the current
+    // message, if any, and the server the requests in flight
+    let stop_handle = server_handle.clone();
+    let _signal_task = rt::spawn(async move {
+        match shutdown_signal().await {
+            Ok(()) => {
+                let _ = shutdown_tx.send(true);
+                stop_handle.stop(true).await;
+            }
+            Err(e) => warn!("Unable to handle shutdown signals: {}", e),
+        }
+    });
 
-    let result = try_join!(server_task, worker_task);
+    let result = try_join!(server_task, worker_task, push_task);
     server_handle.stop(true).await;
-    result.map(|_| ())
+
+    // No revocation is processed anymore, save what the next start needs
+    // to detect replays
+    let flushed = revocation_history.flush(&work_dir);
+    if let Err(e) = &flushed {
+        error!("Unable to save the revocation history: {}", e);
+    }
+    result.map(|_| ()).and(flushed)
 }
 
 /*
@@ -699,12 +905,14 @@ mod testing {
     impl QuoteData {
         pub(crate) fn fixture() -> Result<Self> {
             let test_config = KeylimeConfig::default();
-            let mut ctx = tpm::get_tpm2_ctx()?;
+            let mut ctx = tpm::get_tpm2_ctx(&test_config.tpm_tcti)?;
 
             // Gather EK and AK key values and certs
             let (ek_handle, ek_cert, ek_tpm2b_pub) =
                 tpm::create_ek(&mut ctx, test_config.enc_alg.into())?;
 
+            let pcr_banks = tpm::get_pcr_banks(&mut ctx)?;
+
             let (ak_handle, ak_name, ak_tpm2b_pub) = tpm::create_ak(
                 &mut ctx,
                 ek_handle,
@@ -730,8 +938,9 @@ mod testing {
             let symm_key_cvar = Arc::clone(&symm_key_cvar_arc);
             let payload = Arc::clone(&encr_payload_arc);
 
-            let revocation_cert =
-                revocation::get_revocation_cert_path(&test_config)?;
+            let revocation_cert = Arc::new(revocation::RevocationCert::new(
+                revocation::local_revocation_cert_path(&test_config)?,
+            ));
 
             let actions_dir =
                 Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions/");
@@ -748,6 +957,7 @@ mod testing {
 
             Ok(QuoteData {
                 tpmcontext: Mutex::new(ctx),
+                tpm_lock_timeout: test_config.tpm_lock_timeout,
                 priv_key: nk_priv,
                 pub_key: nk_pub,
                 ak_handle,
@@ -760,17 +970,78 @@ mod testing {
                 hash_alg: algorithms::HashAlgorithm::Sha256,
                 enc_alg: algorithms::EncryptionAlgorithm::Rsa,
                 sign_alg: algorithms::SignAlgorithm::RsaSsa,
-                agent_uuid: test_config.agent_uuid,
+                ak_sign_algs: vec![algorithms::SignAlgorithm::RsaSsa],
+                agent_uuid: test_config.agent_uuid.clone(),
                 revocation_cert,
-                revocation_actions: String::from(""),
-                revocation_actions_dir: actions_dir,
-                allow_payload_revocation_actions: test_config
-                    .allow_payload_revocation_actions,
+                revocation_options: Arc::new(revocation::RevocationOptions {
+                    config_actions: String::from(""),
+                    actions_dir,
+                    work_dir: work_dir.clone(),
+                    ..revocation::RevocationOptions::from_config(&test_config)
+                }),
                 secure_size: test_config.secure_size,
                 work_dir,
                 ima_ml_path,
                 measuredboot_ml_path: measuredboot_ml_path.to_path_buf(),
                 ima_ml: Mutex::new(ImaMeasurementList::new()),
+                required_pcrs: test_config.required_pcrs,
+                allowed_pcrs: test_config.allowed_pcrs,
+                ima_pcr: tpm::pcr_slot(test_config.ima_pcr)?,
+                measuredboot_pcr: tpm::pcr_slot(
+                    test_config.measuredboot_pcr,
+                )?,
+                ima_ml_max_bytes: test_config.ima_ml_max_bytes,
+                ima_signatures: ima::ImaSignatureVerifier::new(
+                    test_config.ima_signature_verification,
+                    Path::new(&test_config.ima_signing_keys),
+                )?,
+                pcr_banks,
+                identity_quotes: quote_cache::QuoteCache::new(
+                    test_config.identity_quote_cache_ttl,
+                ),
+                used_nonces: nonce_tracker::NonceTracker::new(
+                    test_config.nonce_reuse_window,
+                ),
+                quote_rate_limiter: rate_limit::RateLimiter::new(
+                    test_config.quote_rate_limit,
+                    test_config.quote_rate_limit_burst,
+                ),
+                client_auth: client_auth::ClientAuthPolicy::disabled(),
+                revocation_history: Arc::new(
+                    revocation_history::RevocationHistory::default(),
+                ),
+            })
+        }
+
+        // Same as fixture(), but with EC keys and an AK signing with ECDSA
+        pub(crate) fn fixture_ecc() -> Result<Self> {
+            let quotedata = Self::fixture()?;
+
+            let ak_handle = {
+                let mut ctx = tpm::lock_context(&quotedata)?;
+                let (ek_handle, _, _) = tpm::create_ek(
+                    &mut ctx,
+                    algorithms::EncryptionAlgorithm::Ecc.into(),
+                )?;
+                let (ak_handle, _, _) = tpm::create_ak(
+                    &mut ctx,
+                    ek_handle,
+                    quotedata.hash_alg.into(),
+                    algorithms::SignAlgorithm::EcDsa.into(),
+                )?;
+                ak_handle
+            };
+
+            let (nk_pub, nk_priv) = crypto::testing::ec_generate_pair()?;
+
+            Ok(QuoteData {
+                priv_key: nk_priv,
+                pub_key: nk_pub,
+                ak_handle,
+                enc_alg: algorithms::EncryptionAlgorithm::Ecc,
+                sign_alg: algorithms::SignAlgorithm::EcDsa,
+                ak_sign_algs: vec![algorithms::SignAlgorithm::EcDsa],
+                ..quotedata
             })
         }
     }
@@ -816,4 +1087,48 @@ echo hello > test-output
         .unwrap(); //#[allow_ci]
         assert!(dir.path().join("test-output").exists());
     }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_load_or_create_ak() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let tpm_data_path = dir.path().join(TPM_DATA);
+        let mut config = KeylimeConfig {
+            persist_ak: true,
+            tpm_data_path: tpm_data_path.display().to_string(),
+            ..KeylimeConfig::default()
+        };
+
+        let mut ctx = tpm::get_tpm2_ctx(&config.tpm_tcti).unwrap(); //#[allow_ci]
+        let (ek_handle, _, _) =
+            tpm::create_ek(&mut ctx, config.enc_alg.into()).unwrap(); //#[allow_ci]
+
+        // A new AK is created and persisted, readable only by the agent
+        let (_, ak_name, ak_pub) =
+            load_or_create_ak(&mut ctx, ek_handle, &config).unwrap(); //#[allow_ci]
+        let mode = fs::metadata(&tpm_data_path)
+            .unwrap() //#[allow_ci]
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // The same AK is recovered on the next startup
+        config.tpm_data = Some(TpmData::load(&tpm_data_path).unwrap()); //#[allow_ci]
+        let (_, loaded_name, loaded_pub) =
+            load_or_create_ak(&mut ctx, ek_handle, &config).unwrap(); //#[allow_ci]
+        assert_eq!(loaded_name, ak_name);
+        assert_eq!(loaded_pub, ak_pub);
+
+        // Without persist_ak nothing is stored
+        fs::remove_file(&tpm_data_path).unwrap(); //#[allow_ci]
+        let config = KeylimeConfig {
+            persist_ak: false,
+            tpm_data: None,
+            ..config
+        };
+        let (_, new_name, _) =
+            load_or_create_ak(&mut ctx, ek_handle, &config).unwrap(); //#[allow_ci]
+        assert_ne!(new_name, ak_name);
+        assert!(!tpm_data_path.exists());
+    }
 }
@@ -0,0 +1,396 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+use crate::algorithms::HashAlgorithm;
+use crate::error::{Error, Result};
+use openssl::hash::{Hasher, MessageDigest};
+
+// Event type of events that are logged but not extended into a PCR
+const EV_NO_ACTION: u32 = 0x3;
+
+// Signature of the first event of a crypto agile event log
+const SPEC_ID_SIGNATURE: &[u8] = b"Spec ID Event03\0";
+
+// Signature of the event setting the locality PCR 0 was initialized from
+const STARTUP_LOCALITY_SIGNATURE: &[u8] = b"StartupLocality\0";
+
+// Size of the digest in SHA1 format events (TCG_PCClientPCREvent)
+const SHA1_DIGEST_SIZE: usize = 20;
+
+// The boot aggregate covers PCRs 0-7
+const BOOT_AGGREGATE_PCRS: usize = 8;
+
+// Reads the little endian fields of the event log
+struct EventReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> EventReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        EventReader { data, offset: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.offset >= self.data.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .offset
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| {
+                Error::MeasuredBoot(format!(
+                    "unexpected end of event log at offset {}",
+                    self.offset
+                ))
+            })?;
+        let bytes = &self.data[self.offset..end];
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+}
+
+// An event with the digest for the selected hash algorithm, if present
+struct Event<'a> {
+    pcr: u32,
+    event_type: u32,
+    digest: Option<&'a [u8]>,
+    data: &'a [u8],
+}
+
+fn message_digest(hash_alg: HashAlgorithm) -> Result<MessageDigest> {
+    match hash_alg {
+        HashAlgorithm::Sha1 => Ok(MessageDigest::sha1()),
+        HashAlgorithm::Sha256 => Ok(MessageDigest::sha256()),
+        HashAlgorithm::Sha384 => Ok(MessageDigest::sha384()),
+        HashAlgorithm::Sha512 => Ok(MessageDigest::sha512()),
+        HashAlgorithm::Sm3_256 => Err(Error::MeasuredBoot(format!(
+            "hash algorithm {} is not supported for the boot aggregate",
+            hash_alg
+        ))),
+    }
+}
+
+// TPM_ALG_ID of the hash algorithm, as used in the event log
+fn tpm_alg_id(hash_alg: HashAlgorithm) -> u16 {
+    match hash_alg {
+        HashAlgorithm::Sha1 => 0x0004,
+        HashAlgorithm::Sha256 => 0x000B,
+        HashAlgorithm::Sha384 => 0x000C,
+        HashAlgorithm::Sha512 => 0x000D,
+        HashAlgorithm::Sm3_256 => 0x0012,
+    }
+}
+
+// Reads an event in the SHA1 format (TCG_PCClientPCREvent)
+fn read_sha1_event<'a>(reader: &mut EventReader<'a>) -> Result<Event<'a>> {
+    let pcr = reader.u32()?;
+    let event_type = reader.u32()?;
+    let digest = reader.bytes(SHA1_DIGEST_SIZE)?;
+    let size = reader.u32()? as usize;
+    let data = reader.bytes(size)?;
+    Ok(Event {
+        pcr,
+        event_type,
+        digest: Some(digest),
+        data,
+    })
+}
+
+// Reads an event in the crypto agile format (TCG_PCR_EVENT2). The digest
+// sizes come from the Spec ID event, as (algorithm ID, size) pairs.
+fn read_agile_event<'a>(
+    reader: &mut EventReader<'a>,
+    digest_sizes: &[(u16, u16)],
+    alg_id: u16,
+) -> Result<Event<'a>> {
+    let pcr = reader.u32()?;
+    let event_type = reader.u32()?;
+    let count = reader.u32()?;
+    let mut digest = None;
+    for _ in 0..count {
+        let id = reader.u16()?;
+        let size = digest_sizes
+            .iter()
+            .find(|(known, _)| *known == id)
+            .map(|(_, size)| *size)
+            .ok_or_else(|| {
+                Error::MeasuredBoot(format!(
+                    "digest algorithm 0x{:04x} not listed in the Spec ID event",
+                    id
+                ))
+            })?;
+        let bytes = reader.bytes(size as usize)?;
+        if id == alg_id {
+            digest = Some(bytes);
+        }
+    }
+    let size = reader.u32()? as usize;
+    let data = reader.bytes(size)?;
+    Ok(Event {
+        pcr,
+        event_type,
+        digest,
+        data,
+    })
+}
+
+// Parses the digest sizes out of the Spec ID event data
+// (TCG_EfiSpecIdEvent)
+fn read_spec_id(data: &[u8]) -> Result<Vec<(u16, u16)>> {
+    let mut reader = EventReader::new(data);
+    let _ = reader.bytes(SPEC_ID_SIGNATURE.len())?;
+    // platformClass, specVersionMinor, specVersionMajor, specErrata and
+    // uintnSize
+    let _ = reader.bytes(8)?;
+    let count = reader.u32()?;
+    let mut digest_sizes = Vec::new();
+    for _ in 0..count {
+        digest_sizes.push((reader.u16()?, reader.u16()?));
+    }
+    Ok(digest_sizes)
+}
+
+/// Replays the measured boot event log and returns the resulting values of
+/// PCRs 0-7 for the given hash algorithm.
+///
+/// Both the crypto agile format and the legacy SHA1 only format are
+/// supported. The latter can only be replayed with SHA1.
+pub(crate) fn replay_boot_pcrs(
+    log: &[u8],
+    hash_alg: HashAlgorithm,
+) -> Result<Vec<Vec<u8>>> {
+    let md = message_digest(hash_alg)?;
+    let alg_id = tpm_alg_id(hash_alg);
+    let mut pcrs = vec![vec![0u8; md.size()]; BOOT_AGGREGATE_PCRS];
+
+    let mut reader = EventReader::new(log);
+
+    // The first event is always in the SHA1 format. For crypto agile logs
+    // it is the Spec ID event listing the digest algorithms used.
+    let first = read_sha1_event(&mut reader)?;
+    let digest_sizes = if first.event_type == EV_NO_ACTION
+        && first.data.starts_with(SPEC_ID_SIGNATURE)
+    {
+        Some(read_spec_id(first.data)?)
+    } else {
+        if hash_alg != HashAlgorithm::Sha1 {
+            return Err(Error::MeasuredBoot(format!(
+                "event log only contains SHA1 digests, cannot replay {}",
+                hash_alg
+            )));
+        }
+        extend(&mut pcrs, &first, md)?;
+        None
+    };
+
+    while !reader.is_empty() {
+        let event = match &digest_sizes {
+            Some(digest_sizes) => {
+                read_agile_event(&mut reader, digest_sizes, alg_id)?
+            }
+            None => read_sha1_event(&mut reader)?,
+        };
+
+        if event.event_type != EV_NO_ACTION {
+            extend(&mut pcrs, &event, md)?;
+            continue;
+        }
+
+        // PCR 0 starts from the locality the TPM was started from
+        if event.pcr == 0
+            && event.data.starts_with(STARTUP_LOCALITY_SIGNATURE)
+        {
+            let locality = *event
+                .data
+                .get(STARTUP_LOCALITY_SIGNATURE.len())
+                .ok_or_else(|| {
+                    Error::MeasuredBoot(
+                        "StartupLocality event without locality".to_string(),
+                    )
+                })?;
+            if let Some(last) = pcrs[0].last_mut() {
+                *last = locality;
+            }
+        }
+    }
+
+    Ok(pcrs)
+}
+
+fn extend(
+    pcrs: &mut [Vec<u8>],
+    event: &Event,
+    md: MessageDigest,
+) -> Result<()> {
+    let pcr = match pcrs.get_mut(event.pcr as usize) {
+        Some(pcr) => pcr,
+        // Only PCRs 0-7 are replayed
+        None => return Ok(()),
+    };
+    let digest = event.digest.ok_or_else(|| {
+        Error::MeasuredBoot(format!(
+            "event extending PCR {} has no digest for the selected algorithm",
+            event.pcr
+        ))
+    })?;
+
+    let mut hasher = Hasher::new(md)?;
+    hasher.update(pcr)?;
+    hasher.update(digest)?;
+    *pcr = hasher.finish()?.to_vec();
+    Ok(())
+}
+
+/// Returns the events of the measured boot event log starting from the
+/// event at index `first`, along with the number of events returned. At
+/// most `count` events are returned, or all the remaining ones if it is 0.
+///
+/// The log is parsed to find the event boundaries, so that the range never
+/// ends in the middle of an event. The range is empty if the log has no
+/// event at index `first`.
+pub(crate) fn event_range(
+    log: &[u8],
+    first: usize,
+    count: usize,
+) -> Result<(&[u8], usize)> {
+    let mut reader = EventReader::new(log);
+    let mut digest_sizes: Option<Vec<(u16, u16)>> = None;
+    let mut start = None;
+    let mut index = 0;
+
+    while !reader.is_empty() {
+        if count > 0 && index == first.saturating_add(count) {
+            break;
+        }
+        if index == first {
+            start = Some(reader.offset);
+        }
+
+        // Only the structure of the events matters here, not their digests
+        let event = match &digest_sizes {
+            Some(digest_sizes) => {
+                read_agile_event(&mut reader, digest_sizes, 0)?
+            }
+            None => read_sha1_event(&mut reader)?,
+        };
+        if index == 0
+            && event.event_type == EV_NO_ACTION
+            && event.data.starts_with(SPEC_ID_SIGNATURE)
+        {
+            digest_sizes = Some(read_spec_id(event.data)?);
+        }
+        index += 1;
+    }
+
+    match start {
+        Some(start) => Ok((&log[start..reader.offset], index - first)),
+        None => Ok((&[], 0)),
+    }
+}
+
+/// Computes the boot aggregate from the measured boot event log: the hash of
+/// the concatenation of PCRs 0-7, as replayed from the log.
+pub(crate) fn boot_aggregate(
+    log: &[u8],
+    hash_alg: HashAlgorithm,
+) -> Result<Vec<u8>> {
+    let pcrs = replay_boot_pcrs(log, hash_alg)?;
+
+    let mut hasher = Hasher::new(message_digest(hash_alg)?)?;
+    for pcr in &pcrs {
+        hasher.update(pcr)?;
+    }
+    Ok(hasher.finish()?.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    fn read_log() -> Vec<u8> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/measured_boot/binary_bios_measurements");
+        fs::read(path).unwrap() //#[allow_ci]
+    }
+
+    #[test]
+    fn test_boot_aggregate() {
+        let log = read_log();
+
+        assert_eq!(
+            hex::encode(
+                boot_aggregate(&log, HashAlgorithm::Sha256).unwrap() //#[allow_ci]
+            ),
+            "e72aae27d2edc003778c0671859e21f32bc462fa04b6130258bee30817c6ba59"
+        );
+        assert_eq!(
+            hex::encode(
+                boot_aggregate(&log, HashAlgorithm::Sha1).unwrap() //#[allow_ci]
+            ),
+            "4db9b0f2fe959802c9f326c67b13a6a2fcf667ae"
+        );
+
+        // No SHA384 digests in the log
+        assert!(boot_aggregate(&log, HashAlgorithm::Sha384).is_err());
+    }
+
+    #[test]
+    fn test_event_range() {
+        let log = read_log();
+
+        let (all, num_events) = event_range(&log, 0, 0).unwrap(); //#[allow_ci]
+        assert_eq!(all, log.as_slice());
+        assert!(num_events > 2);
+
+        // The log splits at event boundaries: the head parses on its own,
+        // and the tail resumes right after it
+        let (head, head_events) = event_range(&log, 0, 2).unwrap(); //#[allow_ci]
+        assert_eq!(head_events, 2);
+        assert_eq!(event_range(head, 0, 0).unwrap(), (head, 2)); //#[allow_ci]
+        let (tail, tail_events) = event_range(&log, 2, 0).unwrap(); //#[allow_ci]
+        assert_eq!(tail_events, num_events - 2);
+        assert_eq!([head, tail].concat(), log);
+
+        // A single event from the middle of the log
+        let (event, _) = event_range(&log, 2, 1).unwrap(); //#[allow_ci]
+        assert!(tail.starts_with(event));
+        assert!(event.len() < tail.len());
+
+        // Past the end of the log
+        assert_eq!(
+            event_range(&log, num_events, 0).unwrap(), //#[allow_ci]
+            (&[][..], 0)
+        );
+        assert_eq!(
+            event_range(&log, num_events - 1, 5).unwrap().1, //#[allow_ci]
+            1
+        );
+
+        // A truncated event fails the range reaching it, while the events
+        // before it can still be read
+        assert!(event_range(&log[..log.len() - 1], 0, 0).is_err());
+        assert!(event_range(&log[..log.len() - 1], 0, 2).is_ok());
+    }
+
+    #[test]
+    fn test_boot_aggregate_truncated() {
+        let log = read_log();
+        assert!(boot_aggregate(&log[..log.len() - 1], HashAlgorithm::Sha256)
+            .is_err());
+    }
+}
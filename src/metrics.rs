@@ -0,0 +1,149 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+/// Upper bounds, in seconds, of the revocation action duration histogram
+/// buckets. Durations above the last bound are only counted in the total.
+pub(crate) const ACTION_DURATION_BUCKETS: [f64; 7] =
+    [0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0];
+
+/// Metrics of the revocation actions run by the agent
+pub(crate) static REVOCATION_ACTION_METRICS: ActionMetrics =
+    ActionMetrics::new();
+
+/// Durations of the runs of an action
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct DurationHistogram {
+    pub count: u64,
+    pub sum: Duration,
+    pub max: Duration,
+    /// Number of runs that took at most the matching bound of
+    /// `ACTION_DURATION_BUCKETS`
+    pub buckets: [u64; ACTION_DURATION_BUCKETS.len()],
+}
+
+impl DurationHistogram {
+    fn observe(&mut self, duration: Duration) {
+        self.count += 1;
+        self.sum += duration;
+        self.max = self.max.max(duration);
+        let secs = duration.as_secs_f64();
+        for (bound, count) in
+            ACTION_DURATION_BUCKETS.iter().zip(self.buckets.iter_mut())
+        {
+            if secs <= *bound {
+                *count += 1;
+            }
+        }
+    }
+}
+
+/// Counters of an action, whether it was pre-installed or provided by the
+/// tenant payload
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct ActionStats {
+    /// Number of times the action was run
+    pub runs: u64,
+    /// Number of runs that failed, including the action not being found,
+    /// timing out or exiting with an error
+    pub failures: u64,
+    pub durations: DurationHistogram,
+}
+
+/// In-process registry of revocation action metrics, keyed by action name
+///
+/// The registry is only accumulated; it is meant to be read by a metrics
+/// endpoint.
+#[derive(Debug)]
+pub(crate) struct ActionMetrics {
+    actions: Mutex<Vec<(String, ActionStats)>>,
+}
+
+impl ActionMetrics {
+    pub(crate) const fn new() -> Self {
+        ActionMetrics {
+            actions: Mutex::new(Vec::new()),
+        }
+    }
+
+    // The registry only holds counters, so a poisoned lock is simply
+    // recovered
+    fn actions(&self) -> MutexGuard<'_, Vec<(String, ActionStats)>> {
+        self.actions.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Records a run of the action
+    pub(crate) fn record(
+        &self,
+        action: &str,
+        duration: Duration,
+        success: bool,
+    ) {
+        let mut actions = self.actions();
+        let stats = match actions.iter().position(|(name, _)| name == action)
+        {
+            Some(index) => &mut actions[index].1,
+            None => {
+                actions.push((action.to_string(), ActionStats::default()));
+                let last = actions.len() - 1;
+                &mut actions[last].1
+            }
+        };
+        stats.runs += 1;
+        if !success {
+            stats.failures += 1;
+        }
+        stats.durations.observe(duration);
+    }
+
+    /// Returns the counters of an action, if it was ever run
+    pub(crate) fn get(&self, action: &str) -> Option<ActionStats> {
+        self.actions()
+            .iter()
+            .find(|(name, _)| name == action)
+            .map(|(_, stats)| stats.clone())
+    }
+
+    /// Returns the counters of all the actions run, sorted by name
+    pub(crate) fn snapshot(&self) -> Vec<(String, ActionStats)> {
+        let mut actions = self.actions().clone();
+        actions.sort_by(|(a, _), (b, _)| a.cmp(b));
+        actions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_action_metrics() {
+        let metrics = ActionMetrics::new();
+        assert!(metrics.get("action").is_none());
+
+        metrics.record("action", Duration::from_millis(50), true);
+        metrics.record("action", Duration::from_secs(2), false);
+        metrics.record("other", Duration::from_secs(120), true);
+
+        let stats = metrics.get("action").unwrap(); //#[allow_ci]
+        assert_eq!(stats.runs, 2);
+        assert_eq!(stats.failures, 1);
+        assert_eq!(stats.durations.count, 2);
+        assert_eq!(stats.durations.sum, Duration::from_millis(2050));
+        assert_eq!(stats.durations.max, Duration::from_secs(2));
+        assert_eq!(stats.durations.buckets, [1, 1, 1, 2, 2, 2, 2]);
+
+        // Durations above the last bound are not in any bucket
+        let stats = metrics.get("other").unwrap(); //#[allow_ci]
+        assert_eq!(stats.durations.buckets, [0; 7]);
+
+        let names = metrics
+            .snapshot()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<String>>();
+        assert_eq!(names, vec!["action", "other"]);
+    }
+}
@@ -0,0 +1,115 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+use crate::common::{JsonWrapper, ResponseStatus};
+use crate::secure_mount::{self, MountUsage, SecureSize};
+use crate::QuoteData;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct MountInfo {
+    path: String,
+    secure_size: String,
+    #[serde(flatten)]
+    usage: MountUsage,
+}
+
+// This is the handler for the GET request for the secure mount status. It
+// only reports the mount, and never mounts it.
+pub async fn mount(
+    req: HttpRequest,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    info!(
+        "GET invoked from {:?} with uri {}",
+        req.connection_info().peer_addr().unwrap(), //#[allow_ci]
+        req.uri()
+    );
+
+    // The size is reported normalized, as passed to the mount. It was
+    // validated on startup.
+    let secure_size = SecureSize::from_str(&data.secure_size)
+        .map(|size| size.to_string())
+        .unwrap_or_else(|_| data.secure_size.clone());
+
+    match secure_mount::mount_status(&data.work_dir) {
+        Ok(Some((path, usage))) => {
+            HttpResponse::Ok().json(JsonWrapper::success(MountInfo {
+                path: path.display().to_string(),
+                secure_size,
+                usage,
+            }))
+        }
+        Ok(None) => {
+            let message = format!(
+                "Secure mount {} is not mounted",
+                secure_mount::secure_dir(&data.work_dir).display()
+            );
+            warn!("GET returning 503 response. {}", message);
+            JsonWrapper::error(ResponseStatus::ServiceUnavailable, message)
+                .into_response()
+        }
+        Err(e) => {
+            warn!("GET returning 500 response. {}", e);
+            JsonWrapper::error(
+                ResponseStatus::InternalServerError,
+                e.to_string(),
+            )
+            .into_response()
+        }
+    }
+}
+
+#[cfg(feature = "testing")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::API_VERSION;
+    use actix_web::{http, test, web, App};
+
+    #[actix_rt::test]
+    async fn test_mount() {
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let quotedata = web::Data::new(QuoteData {
+            work_dir: work_dir.path().to_path_buf(),
+            ..QuoteData::fixture().unwrap() //#[allow_ci]
+        });
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/agent/mount", API_VERSION),
+                web::get().to(mount),
+            ))
+            .await;
+
+        // Not mounted yet
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}/agent/mount", API_VERSION))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+
+        let secure_dir =
+            secure_mount::mount(work_dir.path(), &quotedata.secure_size)
+                .unwrap(); //#[allow_ci]
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}/agent/mount", API_VERSION))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let body: JsonWrapper<MountInfo> = test::read_body_json(resp).await;
+        let info = body.results;
+        assert_eq!(info.path, secure_dir.display().to_string());
+        assert_eq!(
+            info.secure_size,
+            SecureSize::from_str(&quotedata.secure_size)
+                .unwrap() //#[allow_ci]
+                .to_string()
+        );
+        assert!(info.usage.size > 0);
+    }
+}
@@ -0,0 +1,135 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+use std::collections::VecDeque;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Maximum number of nonces remembered. Once it is reached, new nonces are
+/// refused until the oldest one leaves the window, as forgetting it would
+/// let it be quoted again.
+pub(crate) const NONCE_TRACKER_SIZE: usize = 1024;
+
+/// Why a nonce cannot be quoted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum NonceRejected {
+    /// It was already used within the window
+    Reused,
+    /// The tracker is full of nonces within the window; the oldest one
+    /// leaves it after the given time
+    Full(Duration),
+}
+
+/// Nonces recently quoted, so that a quote request reusing one within the
+/// window can be rejected rather than producing a quote that could be
+/// replayed.
+///
+/// A window of 0 disables the tracking.
+#[derive(Debug)]
+pub(crate) struct NonceTracker {
+    window: Duration,
+    entries: Mutex<VecDeque<(Vec<u8>, Instant)>>,
+}
+
+impl NonceTracker {
+    pub(crate) fn new(window: Duration) -> Self {
+        NonceTracker {
+            window,
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    // The tracker holds no state that a panic could leave inconsistent, so
+    // a poisoned lock is simply recovered
+    fn entries(&self) -> MutexGuard<'_, VecDeque<(Vec<u8>, Instant)>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Records the nonce as used. It is refused if it was already used
+    /// within the window, in which case it must not be quoted again, or if
+    /// no more nonces can be remembered.
+    pub(crate) fn record(&self, nonce: &[u8]) -> Result<(), NonceRejected> {
+        if self.window.is_zero() {
+            return Ok(());
+        }
+
+        let mut entries = self.entries();
+        let window = self.window;
+        entries.retain(|(_, used)| used.elapsed() < window);
+
+        if entries.iter().any(|(seen, _)| seen == nonce) {
+            return Err(NonceRejected::Reused);
+        }
+        if let Some((_, oldest)) = entries.front() {
+            if entries.len() >= NONCE_TRACKER_SIZE {
+                return Err(NonceRejected::Full(
+                    window.saturating_sub(oldest.elapsed()),
+                ));
+            }
+        }
+        entries.push_back((nonce.to_vec(), Instant::now()));
+        Ok(())
+    }
+
+    /// Forgets a recorded nonce, whose quote could not be generated, so
+    /// that the request can be retried with the same nonce
+    pub(crate) fn forget(&self, nonce: &[u8]) {
+        self.entries().retain(|(seen, _)| seen != nonce);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_nonce_tracker() {
+        let tracker = NonceTracker::new(Duration::from_secs(60));
+        assert_eq!(tracker.record(b"nonce1"), Ok(()));
+        assert_eq!(tracker.record(b"nonce1"), Err(NonceRejected::Reused));
+        assert_eq!(tracker.record(b"nonce2"), Ok(()));
+
+        // A forgotten nonce can be used again
+        tracker.forget(b"nonce2");
+        assert_eq!(tracker.record(b"nonce2"), Ok(()));
+
+        // Once full, new nonces are refused rather than forgetting the
+        // oldest ones within the window
+        for i in 2..NONCE_TRACKER_SIZE {
+            assert_eq!(tracker.record(format!("{}", i).as_bytes()), Ok(()));
+        }
+        assert_eq!(tracker.entries().len(), NONCE_TRACKER_SIZE);
+        match tracker.record(b"nonce3") {
+            Err(NonceRejected::Full(wait)) => {
+                assert!(wait <= Duration::from_secs(60))
+            }
+            other => panic!("unexpected result: {:?}", other), //#[allow_ci]
+        }
+        assert_eq!(tracker.record(b"nonce1"), Err(NonceRejected::Reused));
+    }
+
+    #[test]
+    fn test_nonce_tracker_window() {
+        let tracker = NonceTracker::new(Duration::from_millis(100));
+        assert_eq!(tracker.record(b"nonce"), Ok(()));
+        assert_eq!(tracker.record(b"nonce"), Err(NonceRejected::Reused));
+
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(tracker.record(b"nonce"), Ok(()));
+
+        // Nonces leaving the window make room for new ones
+        for i in 1..NONCE_TRACKER_SIZE {
+            assert_eq!(tracker.record(format!("{}", i).as_bytes()), Ok(()));
+        }
+        assert!(tracker.record(b"other").is_err());
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(tracker.record(b"other"), Ok(()));
+
+        // A window of 0 disables the tracking
+        let tracker = NonceTracker::new(Duration::from_secs(0));
+        assert_eq!(tracker.record(b"nonce"), Ok(()));
+        assert_eq!(tracker.record(b"nonce"), Ok(()));
+        assert!(tracker.entries().is_empty());
+    }
+}
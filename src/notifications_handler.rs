@@ -1,11 +1,10 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright 2021 Keylime Authors
 
-use crate::{common::KeylimeConfig, revocation, Error, QuoteData, Result};
+use crate::{revocation, Error, QuoteData, Result};
 use actix_web::{web, HttpRequest, HttpResponse, Responder};
 use log::*;
 use serde::{Deserialize, Serialize};
-use std::path::{Path, PathBuf};
 
 #[derive(Serialize, Deserialize, Debug)]
 struct KeylimeRevocation {
@@ -22,22 +21,17 @@ pub async fn revocation(
     info!("Received revocation");
 
     let json_body = serde_json::from_slice(&body.to_vec())?;
-    let revocation_cert = &data.revocation_cert;
-    let secure_size = &data.secure_size;
-    let revocation_actions = &data.revocation_actions;
-    let actions_dir = PathBuf::from(&data.revocation_actions_dir);
-    let payload_actions_allowed = data.allow_payload_revocation_actions;
-    let work_dir = PathBuf::from(&data.work_dir);
-
-    let result = revocation::process_revocation(
+    let result = revocation::process_revocation_blocking(
         json_body,
-        revocation_cert,
-        secure_size,
-        revocation_actions,
-        &actions_dir,
-        payload_actions_allowed,
-        &work_dir,
-    )?;
+        data.revocation_cert.clone(),
+        data.revocation_options.clone(),
+        data.revocation_history.clone(),
+    )
+    .await
+    .map_err(|e| {
+        warn!("Unable to process revocation message: {}", e);
+        e
+    })?;
 
     HttpResponse::Ok().await
 }
@@ -45,32 +39,46 @@ pub async fn revocation(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::common::{KeylimeConfig, API_VERSION};
-    use actix_web::{test, web, App};
+    use crate::common::{JsonWrapper, KeylimeConfig, API_VERSION};
+    use crate::revocation_handler;
+    use crate::revocation_history::RevocationStatus;
+    use actix_web::{http, test, web, App};
     use serde_json::json;
-    use std::{fs, path::Path};
+    use std::{fs, path::Path, sync::Arc};
 
     #[cfg(feature = "testing")]
     #[actix_rt::test]
     async fn test_revocation() {
-        let revocation_cert = Path::new(env!("CARGO_MANIFEST_DIR"))
-            .join("test-data/test-cert.pem");
-
-        let revocation_actions_dir =
-            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions");
+        let revocation_cert = Arc::new(revocation::RevocationCert::new(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("test-data/test-cert.pem"),
+        ));
 
         let quotedata = web::Data::new(QuoteData {
             revocation_cert,
-            revocation_actions_dir,
             ..QuoteData::fixture().unwrap() //#[allow_ci]
         });
 
-        let mut app =
-            test::init_service(App::new().app_data(quotedata.clone()).route(
-                &format!("/{}/notifications/revocation", API_VERSION),
-                web::post().to(revocation),
-            ))
-            .await;
+        let mut app = test::init_service(
+            App::new()
+                .app_data(quotedata.clone())
+                .route(
+                    &format!("/{}/notifications/revocation", API_VERSION),
+                    web::post().to(revocation),
+                )
+                .route(
+                    &format!("/{}/agent/revocation/last", API_VERSION),
+                    web::get().to(revocation_handler::last_revocation),
+                ),
+        )
+        .await;
+
+        // Nothing was processed yet
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}/agent/revocation/last", API_VERSION))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::NOT_FOUND);
 
         let sig_path = Path::new(env!("CARGO_MANIFEST_DIR"))
             .join("test-data/revocation.sig");
@@ -93,5 +101,20 @@ mod tests {
 
         let resp = test::call_service(&app, req).await;
         assert!(resp.status().is_success());
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}/agent/revocation/last", API_VERSION))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let body: JsonWrapper<RevocationStatus> =
+            test::read_body_json(resp).await;
+        assert_eq!(body.results.msg_type, "revocation");
+        assert_eq!(
+            body.results.agent_id,
+            "d432fbb3-d2f1-4a97-9ef7-75bd81c00000"
+        );
+        assert!(body.results.success);
     }
 }
@@ -0,0 +1,384 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+use crate::common::{JsonWrapper, KeylimeConfig};
+use crate::error::{Error, Result};
+use crate::quotes_handler::{
+    build_integrity_quote, Integ, IntegrityQuoteParams, KeylimeQuote,
+    SchemaQuote, QUOTE_SCHEMA_VERSION,
+};
+use crate::revocation::{https_client, wait_for_shutdown, Backoff};
+use crate::QuoteData;
+use actix_web::web;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Number of attempts to push a quote before giving up on it. The next
+/// quote is generated at the next interval anyway.
+pub(crate) const PUSH_MAX_ATTEMPTS: u32 = 5;
+
+// Delay before the first retry of a failed push, doubled after each attempt
+const PUSH_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// Timeout of the requests pushing quotes
+const PUSH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Body of the requests pushing a quote to the verifier
+#[derive(Serialize, Debug)]
+struct PushedQuote<'a> {
+    agent_id: &'a str,
+    nonce: &'a str,
+    quote: SchemaQuote<'a>,
+}
+
+/// Challenge sent by the verifier for the next pushed quote
+#[derive(Deserialize, Debug)]
+struct PushChallenge {
+    nonce: String,
+}
+
+/// Asks the verifier for the nonce of the next quote, with a GET request on
+/// the push URL. A quote over a nonce chosen by the verifier proves that it
+/// was generated after the challenge, so that it cannot be replayed.
+pub(crate) async fn fetch_challenge(
+    client: &reqwest::Client,
+    url: &str,
+    agent_id: &str,
+) -> Result<String> {
+    let resp = client
+        .get(url)
+        .query(&[("agent_id", agent_id)])
+        .send()
+        .await?;
+    if !resp.status().is_success() {
+        return Err(Error::Other(format!(
+            "received {} from {}",
+            resp.status(),
+            url
+        )));
+    }
+    let challenge: JsonWrapper<PushChallenge> = resp.json().await?;
+    Ok(challenge.results.nonce)
+}
+
+// Generates the quote to push, the same way as for an integrity quote
+// request for the IMA entries starting from ima_ml_entry
+async fn generate_quote(
+    data: &web::Data<QuoteData>,
+    mask: &str,
+    nonce: &str,
+    ima_ml_entry: u64,
+) -> Result<KeylimeQuote> {
+    let param = Integ {
+        nonce: nonce.to_string(),
+        nonce_encoding: None,
+        mask: mask.to_string(),
+        extra_pcrs: None,
+        partial: "1".to_string(),
+        ima_ml_entry: Some(ima_ml_entry.to_string()),
+        ima_ml_count: None,
+        mb_ml_encoding: None,
+        mb_entry: None,
+        mb_ml_count: None,
+        ima_ml_encoding: None,
+        ima_path_filter: None,
+        sign_scheme: None,
+        hash_alg: None,
+        quote_parts: None,
+        schema_version: None,
+    };
+    build_integrity_quote(&IntegrityQuoteParams::parse(&param, data)?, data)
+        .await
+}
+
+/// Posts a quote to the verifier, retrying with an exponential backoff when
+/// the request fails or the verifier answers with a server error. Client
+/// errors are not retried, as the same request would fail again.
+pub(crate) async fn push_quote(
+    client: &reqwest::Client,
+    url: &str,
+    body: &impl Serialize,
+    mut backoff: Backoff,
+    max_attempts: u32,
+) -> Result<()> {
+    let mut attempt = 1;
+    loop {
+        let error = match client.post(url).json(body).send().await {
+            Ok(resp) if resp.status().is_success() => return Ok(()),
+            Ok(resp) if !resp.status().is_server_error() => {
+                return Err(Error::Other(format!(
+                    "received {} from {}",
+                    resp.status(),
+                    url
+                )))
+            }
+            Ok(resp) => {
+                format!("received {} from {}", resp.status(), url)
+            }
+            Err(e) => e.to_string(),
+        };
+        if attempt >= max_attempts {
+            return Err(Error::Other(format!(
+                "{} after {} attempts",
+                error, attempt
+            )));
+        }
+        let delay = backoff.next_delay();
+        warn!(
+            "Unable to push quote: {}, retrying in {}ms",
+            error,
+            delay.as_millis()
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Pushes an integrity quote to `push_attestation_url` every
+/// `push_attestation_interval`, for verifiers unable to reach the agent.
+/// Each quote is over a nonce fetched from the verifier just before.
+///
+/// The quotes carry the IMA entries added since the last quote pushed
+/// successfully, the first one carrying the whole list. A quote that cannot
+/// be challenged, generated or pushed is skipped, and the next one carries
+/// its entries.
+///
+/// The task runs until `true` is sent on `shutdown`. It returns at once if
+/// push attestation is disabled.
+pub(crate) async fn run_push_attestation(
+    data: web::Data<QuoteData>,
+    config: KeylimeConfig,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let url = &config.push_attestation_url;
+    if url.is_empty() {
+        return Ok(());
+    }
+    let client = https_client(&config.push_attestation_ca, PUSH_TIMEOUT)?;
+
+    info!(
+        "Pushing quotes to {} every {}s",
+        url,
+        config.push_attestation_interval.as_secs()
+    );
+
+    let mut ima_ml_entry = 0;
+    let mut interval =
+        tokio::time::interval(config.push_attestation_interval);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = wait_for_shutdown(&mut shutdown) => break,
+        }
+
+        let nonce = tokio::select! {
+            result = fetch_challenge(&client, url, &data.agent_uuid) => {
+                match result {
+                    Ok(nonce) => nonce,
+                    Err(e) => {
+                        warn!("Unable to get the challenge of the quote to push: {}", e);
+                        continue;
+                    }
+                }
+            }
+            _ = wait_for_shutdown(&mut shutdown) => break,
+        };
+        let quote = match generate_quote(
+            &data,
+            &config.push_attestation_mask,
+            &nonce,
+            ima_ml_entry,
+        )
+        .await
+        {
+            Ok(quote) => quote,
+            Err(e) => {
+                warn!("Unable to generate the quote to push: {}", e);
+                continue;
+            }
+        };
+        let next_entry = quote
+            .ima_measurement_list_entry
+            .map(|entry| entry + quote.num_entries.unwrap_or(0));
+        let schema_quote = match quote.to_schema(QUOTE_SCHEMA_VERSION) {
+            Ok(schema_quote) => schema_quote,
+            Err(e) => {
+                warn!("Unable to serialize the quote to push: {}", e);
+                continue;
+            }
+        };
+        let body = PushedQuote {
+            agent_id: &data.agent_uuid,
+            nonce: &nonce,
+            quote: schema_quote,
+        };
+
+        let backoff =
+            Backoff::new(PUSH_RETRY_DELAY, config.push_attestation_interval);
+        tokio::select! {
+            result = push_quote(&client, url, &body, backoff, PUSH_MAX_ATTEMPTS) => {
+                match result {
+                    Ok(()) => {
+                        debug!("Pushed quote to {}", url);
+                        if let Some(entry) = next_entry {
+                            ima_ml_entry = entry;
+                        }
+                    }
+                    Err(e) => warn!("Unable to push quote: {}", e),
+                }
+            }
+            _ = wait_for_shutdown(&mut shutdown) => break,
+        }
+    }
+
+    info!("Push attestation stopped");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use wiremock::matchers::{method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn backoff() -> Backoff {
+        Backoff::new(Duration::from_millis(10), Duration::from_millis(10))
+    }
+
+    #[actix_rt::test]
+    async fn test_push_quote_retried() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/quotes"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/quotes"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let body = json!({"agent_id": "agent", "nonce": "1234567890"});
+        let url = format!("{}/quotes", server.uri());
+        push_quote(&reqwest::Client::new(), &url, &body, backoff(), 3)
+            .await
+            .unwrap(); //#[allow_ci]
+
+        let requests = server.received_requests().await.unwrap(); //#[allow_ci]
+        assert_eq!(requests.len(), 2);
+        for request in requests {
+            let pushed: Value =
+                serde_json::from_slice(&request.body).unwrap(); //#[allow_ci]
+            assert_eq!(pushed, body);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_fetch_challenge() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/quotes"))
+            .and(query_param("agent_id", "agent"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "code": 200,
+                "status": "Success",
+                "results": {"nonce": "1234567890ABCDEF"},
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let url = format!("{}/quotes", server.uri());
+        let nonce = fetch_challenge(&reqwest::Client::new(), &url, "agent")
+            .await
+            .unwrap(); //#[allow_ci]
+        assert_eq!(nonce, "1234567890ABCDEF");
+
+        // Without a challenge, no quote is pushed
+        let url = format!("{}/other", server.uri());
+        assert!(fetch_challenge(&reqwest::Client::new(), &url, "agent")
+            .await
+            .is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_push_quote_gives_up() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(3)
+            .mount(&server)
+            .await;
+        let body = json!({});
+        let err = push_quote(
+            &reqwest::Client::new(),
+            &server.uri(),
+            &body,
+            backoff(),
+            3,
+        )
+        .await
+        .unwrap_err(); //#[allow_ci]
+        assert!(err.to_string().ends_with("after 3 attempts"));
+
+        // Client errors are not retried
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(400))
+            .expect(1)
+            .mount(&server)
+            .await;
+        assert!(push_quote(
+            &reqwest::Client::new(),
+            &server.uri(),
+            &body,
+            backoff(),
+            3
+        )
+        .await
+        .is_err());
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_push_generated_quote() {
+        let data = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+
+        let nonce = "1234567890ABCDEF";
+        let quote = generate_quote(&data, "0x400", nonce, 0).await.unwrap(); //#[allow_ci]
+        let body = PushedQuote {
+            agent_id: &data.agent_uuid,
+            nonce,
+            quote: quote.to_schema(QUOTE_SCHEMA_VERSION).unwrap(), //#[allow_ci]
+        };
+        push_quote(
+            &reqwest::Client::new(),
+            &server.uri(),
+            &body,
+            backoff(),
+            1,
+        )
+        .await
+        .unwrap(); //#[allow_ci]
+
+        let requests = server.received_requests().await.unwrap(); //#[allow_ci]
+        let pushed: Value =
+            serde_json::from_slice(&requests[0].body).unwrap(); //#[allow_ci]
+        assert_eq!(pushed["agent_id"], data.agent_uuid.as_str());
+        assert_eq!(pushed["nonce"], nonce);
+        assert!(pushed["quote"]["quote"].as_str().unwrap().starts_with('r')); //#[allow_ci]
+        assert!(pushed["quote"]["ima_measurement_list"].is_string());
+    }
+}
@@ -0,0 +1,206 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+use crate::algorithms::{HashAlgorithm, SignAlgorithm};
+use crate::quotes_handler::KeylimeQuote;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Maximum number of quotes kept in the cache
+pub(crate) const QUOTE_CACHE_SIZE: usize = 32;
+
+/// What a cached quote was requested for. A quote is only served again for
+/// the very same nonce, signing scheme and PCR banks.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct QuoteCacheKey {
+    pub nonce: Vec<u8>,
+    pub sign_alg: SignAlgorithm,
+    pub banks: Vec<HashAlgorithm>,
+}
+
+#[derive(Debug)]
+struct CachedQuote {
+    key: QuoteCacheKey,
+    created: Instant,
+    quote: KeylimeQuote,
+}
+
+/// Time-bounded cache of identity quotes, so that verifiers repeating a
+/// request do not wait for the TPM again.
+///
+/// A TTL of 0 disables the cache.
+#[derive(Debug)]
+pub(crate) struct QuoteCache {
+    ttl: Duration,
+    entries: Mutex<VecDeque<CachedQuote>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl QuoteCache {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        QuoteCache {
+            ttl,
+            entries: Mutex::new(VecDeque::new()),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        !self.ttl.is_zero()
+    }
+
+    // The cache holds no state that a panic could leave inconsistent, so a
+    // poisoned lock is simply recovered
+    fn entries(&self) -> std::sync::MutexGuard<'_, VecDeque<CachedQuote>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns the quote cached for the key, if it has not expired yet
+    pub(crate) fn get(&self, key: &QuoteCacheKey) -> Option<KeylimeQuote> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let mut entries = self.entries();
+        let ttl = self.ttl;
+        entries.retain(|entry| entry.created.elapsed() < ttl);
+
+        match entries.iter().find(|entry| entry.key == *key) {
+            Some(entry) => {
+                let _ = self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(entry.quote.clone())
+            }
+            None => {
+                let _ = self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Caches the quote for the key, evicting the oldest quote when the
+    /// cache is full
+    pub(crate) fn insert(&self, key: QuoteCacheKey, quote: &KeylimeQuote) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut entries = self.entries();
+        entries.retain(|entry| entry.key != key);
+        if entries.len() >= QUOTE_CACHE_SIZE {
+            let _ = entries.pop_front();
+        }
+        entries.push_back(CachedQuote {
+            key,
+            created: Instant::now(),
+            quote: quote.clone(),
+        });
+    }
+
+    /// Number of requests served from the cache
+    pub(crate) fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Number of requests that needed a new quote from the TPM
+    pub(crate) fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn quote(quote: &str) -> KeylimeQuote {
+        KeylimeQuote {
+            quote: quote.to_string(),
+            hash_alg: "sha256".to_string(),
+            enc_alg: "rsa".to_string(),
+            sign_alg: "rsassa".to_string(),
+            pubkey: None,
+            ima_measurement_list: None,
+            mb_measurement_list: None,
+            ima_measurement_list_entry: None,
+            boot_aggregate: None,
+            num_entries: None,
+            mb_measurement_list_encoding: None,
+            ima_path_filter: None,
+            ima_measurement_list_encoding: None,
+            banks: None,
+            mb_measurement_list_available: None,
+            mb_measurement_list_entry: None,
+            mb_num_entries: None,
+            quote_parts: None,
+            ima_signature_failures: None,
+        }
+    }
+
+    fn key(nonce: &str) -> QuoteCacheKey {
+        QuoteCacheKey {
+            nonce: nonce.as_bytes().to_vec(),
+            sign_alg: SignAlgorithm::RsaSsa,
+            banks: vec![HashAlgorithm::Sha256],
+        }
+    }
+
+    #[test]
+    fn test_quote_cache() {
+        let cache = QuoteCache::new(Duration::from_secs(60));
+        assert!(cache.get(&key("nonce1")).is_none());
+
+        cache.insert(key("nonce1"), &quote("rQUOTE1"));
+        assert_eq!(cache.get(&key("nonce1")).unwrap().quote, "rQUOTE1"); //#[allow_ci]
+
+        // Never served for another nonce, scheme or bank
+        assert!(cache.get(&key("nonce2")).is_none());
+        assert!(cache
+            .get(&QuoteCacheKey {
+                sign_alg: SignAlgorithm::RsaPss,
+                ..key("nonce1")
+            })
+            .is_none());
+        assert!(cache
+            .get(&QuoteCacheKey {
+                banks: vec![HashAlgorithm::Sha384],
+                ..key("nonce1")
+            })
+            .is_none());
+
+        assert_eq!(cache.hits(), 1);
+        assert_eq!(cache.misses(), 4);
+    }
+
+    #[test]
+    fn test_quote_cache_bounded() {
+        let cache = QuoteCache::new(Duration::from_secs(60));
+        for i in 0..=QUOTE_CACHE_SIZE {
+            cache.insert(key(&format!("nonce{}", i)), &quote("rQUOTE"));
+        }
+        assert_eq!(cache.entries().len(), QUOTE_CACHE_SIZE);
+
+        // The oldest quote was evicted
+        assert!(cache.get(&key("nonce0")).is_none());
+        assert!(cache.get(&key("nonce1")).is_some());
+    }
+
+    #[test]
+    fn test_quote_cache_ttl() {
+        let cache = QuoteCache::new(Duration::from_millis(100));
+        cache.insert(key("nonce"), &quote("rQUOTE"));
+        assert!(cache.get(&key("nonce")).is_some());
+
+        thread::sleep(Duration::from_millis(200));
+        assert!(cache.get(&key("nonce")).is_none());
+        assert!(cache.entries().is_empty());
+
+        // A TTL of 0 disables the cache
+        let cache = QuoteCache::new(Duration::from_secs(0));
+        cache.insert(key("nonce"), &quote("rQUOTE"));
+        assert!(cache.get(&key("nonce")).is_none());
+    }
+}
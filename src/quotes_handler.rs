@@ -1,32 +1,73 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright 2021 Keylime Authors
 
-use crate::{tpm, Error as KeylimeError, QuoteData};
+use crate::{tpm, Error as KeylimeError, QuoteData, Result};
 
-use crate::common::JsonWrapper;
+use crate::algorithms::{HashAlgorithm, SignAlgorithm};
+use crate::api_version::request_api_version;
+use crate::bundle;
+use crate::client_auth::PeerCertificate;
+use crate::common::{JsonWrapper, ResponseStatus};
 use crate::crypto;
-use crate::ima::read_measurement_list;
-use crate::serialization::serialize_maybe_base64;
-use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use crate::ima::{filter_measurement_list, read_measurement_list};
+use crate::measured_boot;
+use crate::nonce_tracker::NonceRejected;
+use crate::quote_cache::QuoteCacheKey;
+use crate::serialization::{
+    deserialize_as_base64_url, deserialize_maybe_base64,
+    deserialize_maybe_hex, serialize_maybe_base64, serialize_maybe_hex,
+    to_python_json,
+};
+use actix_web::{
+    http::header, web, HttpMessage, HttpRequest, HttpResponse, Responder,
+};
+use flate2::{write::GzEncoder, Compression};
 use log::*;
+use serde::de::{value::StrDeserializer, IntoDeserializer};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::fs::{read, read_to_string};
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use std::time::Duration;
 use tss_esapi::structures::PcrSlot;
+use uuid::Uuid;
 
 #[derive(Deserialize)]
 pub struct Ident {
-    nonce: String,
+    pub(crate) nonce: String,
+    pub(crate) nonce_encoding: Option<String>,
+    pub(crate) sign_scheme: Option<String>,
+    pub(crate) hash_alg: Option<String>,
+    pub(crate) banks: Option<String>,
+    pub(crate) partial: Option<String>,
+    pub(crate) quote_parts: Option<bool>,
+    pub(crate) schema_version: Option<u32>,
 }
 
 #[derive(Deserialize)]
 pub struct Integ {
-    nonce: String,
-    mask: String,
-    partial: String,
-    ima_ml_entry: Option<String>,
+    pub(crate) nonce: String,
+    pub(crate) nonce_encoding: Option<String>,
+    pub(crate) mask: String,
+    pub(crate) extra_pcrs: Option<String>,
+    pub(crate) partial: String,
+    pub(crate) ima_ml_entry: Option<String>,
+    pub(crate) ima_ml_count: Option<usize>,
+    pub(crate) mb_ml_encoding: Option<String>,
+    pub(crate) mb_entry: Option<String>,
+    pub(crate) mb_ml_count: Option<usize>,
+    pub(crate) ima_ml_encoding: Option<String>,
+    pub(crate) ima_path_filter: Option<String>,
+    pub(crate) sign_scheme: Option<String>,
+    pub(crate) hash_alg: Option<String>,
+    pub(crate) quote_parts: Option<bool>,
+    pub(crate) schema_version: Option<u32>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) struct KeylimeQuote {
     pub quote: String, // 'r' + quote + sig + pcrblob
     pub hash_alg: String,
@@ -36,6 +77,496 @@ pub(crate) struct KeylimeQuote {
     pub ima_measurement_list: Option<String>,
     pub mb_measurement_list: Option<Vec<u8>>,
     pub ima_measurement_list_entry: Option<u64>,
+    /// Hex encoded on the wire, as PCR values conventionally are
+    #[serde(
+        default,
+        serialize_with = "serialize_maybe_hex",
+        deserialize_with = "deserialize_maybe_hex"
+    )]
+    pub boot_aggregate: Option<Vec<u8>>,
+    /// Number of IMA entries in ima_measurement_list, so that the verifier
+    /// resumes from ima_measurement_list_entry + num_entries
+    pub num_entries: Option<u64>,
+    /// Encoding of mb_measurement_list, if any other than the raw log
+    pub mb_measurement_list_encoding: Option<String>,
+    /// Path filter ima_measurement_list was restricted to, if any. A
+    /// filtered list cannot be replayed against the IMA PCR.
+    pub ima_path_filter: Option<String>,
+    /// Encoding of ima_measurement_list, if compressed. The compressed list
+    /// is sent base64 encoded.
+    pub ima_measurement_list_encoding: Option<String>,
+    /// PCR values of each bank covered by the quote, when the verifier
+    /// asked for specific banks
+    pub banks: Option<Vec<QuoteBank>>,
+    /// Whether the measured boot log could be read, when PCR 0 was quoted,
+    /// so that a missing log is not mistaken for a platform without one
+    pub mb_measurement_list_available: Option<bool>,
+    /// Event mb_measurement_list starts from, when the verifier asked for
+    /// the measured boot log from a given event
+    pub mb_measurement_list_entry: Option<u64>,
+    /// Number of events in mb_measurement_list, so that the verifier
+    /// resumes from mb_measurement_list_entry + mb_num_entries
+    pub mb_num_entries: Option<u64>,
+    /// Components packed in quote, when the verifier asked for them
+    pub quote_parts: Option<QuoteParts>,
+    /// Entries of ima_measurement_list failing signature verification, by
+    /// their number in the log, when ima_signature_verification is mark
+    pub ima_signature_failures: Option<Vec<u64>>,
+}
+
+/// PCR values read from one of the banks covered by a quote, keyed by PCR
+/// index and hex encoded
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct QuoteBank {
+    pub hash_alg: String,
+    pub pcrs: BTreeMap<u32, String>,
+}
+
+/// Components of a quote string, each base64 encoded: the attestation
+/// structure, its signature and the PCR selection and values
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct QuoteParts {
+    pub quote_b64: String,
+    pub sig_b64: String,
+    pub pcr_b64: String,
+}
+
+impl QuoteParts {
+    /// Packs the components the way Keylime expects them in the quote
+    /// field: 'r' followed by the components separated by ':'
+    pub(crate) fn to_quote_string(&self) -> String {
+        format!("r{}:{}:{}", self.quote_b64, self.sig_b64, self.pcr_b64)
+    }
+
+    /// Splits a quote string packed by `to_quote_string`
+    pub(crate) fn from_quote_string(quote: &str) -> Result<Self> {
+        let invalid = || {
+            KeylimeError::Other(format!("Malformed quote string: {}", quote))
+        };
+        let mut split =
+            quote.strip_prefix('r').ok_or_else(invalid)?.split(':');
+        let mut next = || split.next().map(String::from).ok_or_else(invalid);
+        let parts = QuoteParts {
+            quote_b64: next()?,
+            sig_b64: next()?,
+            pcr_b64: next()?,
+        };
+        if split.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(parts)
+    }
+}
+
+/// Latest version of the quote response schema, used when the request does
+/// not ask for a specific one
+pub(crate) const QUOTE_SCHEMA_VERSION: u32 = 12;
+
+/// Encoding of a measurement log sent as is
+pub(crate) const ML_ENCODING_NONE: &str = "none";
+/// Encoding of a measurement log compressed with gzip
+pub(crate) const ML_ENCODING_GZIP: &str = "gzip";
+/// Encoding of a measurement log compressed with zstd
+pub(crate) const ML_ENCODING_ZSTD: &str = "zstd";
+
+/// How a measurement log, measured boot or IMA, is encoded in the quote
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MlEncoding {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl MlEncoding {
+    /// Name reported in the log encoding field. The raw log is not
+    /// flagged, as older verifiers do not know about the field.
+    pub(crate) fn name(&self) -> Option<&'static str> {
+        match self {
+            MlEncoding::None => None,
+            MlEncoding::Gzip => Some(ML_ENCODING_GZIP),
+            MlEncoding::Zstd => Some(ML_ENCODING_ZSTD),
+        }
+    }
+
+    pub(crate) fn encode(&self, data: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        match self {
+            MlEncoding::None => Ok(data),
+            MlEncoding::Gzip => {
+                let mut encoder =
+                    GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(&data)?;
+                encoder.finish()
+            }
+            MlEncoding::Zstd => zstd::encode_all(data.as_slice(), 0),
+        }
+    }
+}
+
+// Fields added to KeylimeQuote after the first schema version, with the
+// schema version that introduced them. New optional fields must be listed
+// here so that verifiers asking for an older schema do not receive them.
+static QUOTE_SCHEMA_FIELDS: &[(&str, u32)] = &[
+    ("mb_measurement_list", 2),
+    ("ima_measurement_list_entry", 2),
+    ("boot_aggregate", 3),
+    ("num_entries", 4),
+    ("mb_measurement_list_encoding", 5),
+    ("ima_path_filter", 6),
+    ("ima_measurement_list_encoding", 7),
+    ("banks", 8),
+    ("mb_measurement_list_available", 9),
+    ("mb_measurement_list_entry", 10),
+    ("mb_num_entries", 10),
+    ("quote_parts", 11),
+    ("ima_signature_failures", 12),
+];
+
+impl KeylimeQuote {
+    /// View of the quote serializing only the fields that belong to the
+    /// given schema version
+    pub(crate) fn to_schema(
+        &self,
+        schema_version: u32,
+    ) -> Result<SchemaQuote<'_>> {
+        if schema_version == 0 || schema_version > QUOTE_SCHEMA_VERSION {
+            return Err(KeylimeError::Other(format!(
+                "Unsupported quote schema version {} (supported: 1-{})",
+                schema_version, QUOTE_SCHEMA_VERSION
+            )));
+        }
+        Ok(SchemaQuote {
+            quote: self,
+            schema_version,
+        })
+    }
+}
+
+/// Quote restricted to the fields of a schema version. It is serialized
+/// straight to the output, without building a JSON value of the whole
+/// quote and its measurement logs first.
+#[derive(Debug)]
+pub(crate) struct SchemaQuote<'a> {
+    quote: &'a KeylimeQuote,
+    schema_version: u32,
+}
+
+impl SchemaQuote<'_> {
+    fn includes(&self, field: &str) -> bool {
+        QUOTE_SCHEMA_FIELDS.iter().all(|(name, version)| {
+            *name != field || *version <= self.schema_version
+        })
+    }
+}
+
+// Serializes an optional digest as serialize_maybe_hex does
+struct MaybeHex<'a>(&'a Option<Vec<u8>>);
+
+impl Serialize for MaybeHex<'_> {
+    fn serialize<S>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serialize_maybe_hex(self.0, serializer)
+    }
+}
+
+impl Serialize for SchemaQuote<'_> {
+    fn serialize<S>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeMap;
+
+        // The quote is destructured, so that a field added to KeylimeQuote
+        // does not build until it is listed here. Fields are written in
+        // the order they are declared, as the derived implementation does.
+        // Fields with a serialize_with attribute are listed with the
+        // wrapper serializing them the same way.
+        macro_rules! serialize_fields {
+            ($($field:ident $(as $with:ident)?),* $(,)?) => {{
+                let KeylimeQuote { $($field),* } = self.quote;
+                let mut map = serializer.serialize_map(None)?;
+                $(
+                    $(let $field = &$with($field);)?
+                    if self.includes(stringify!($field)) {
+                        map.serialize_entry(stringify!($field), $field)?;
+                    }
+                )*
+                map.end()
+            }};
+        }
+
+        serialize_fields!(
+            quote,
+            hash_alg,
+            enc_alg,
+            sign_alg,
+            pubkey,
+            ima_measurement_list,
+            mb_measurement_list,
+            ima_measurement_list_entry,
+            boot_aggregate as MaybeHex,
+            num_entries,
+            mb_measurement_list_encoding,
+            ima_path_filter,
+            ima_measurement_list_encoding,
+            banks,
+            mb_measurement_list_available,
+            mb_measurement_list_entry,
+            mb_num_entries,
+            quote_parts,
+            ima_signature_failures,
+        )
+    }
+}
+
+// Quote in the shape the Python agent sends it, with the fields in the same
+// order. Fields that are not set are left out rather than null, and the
+// measured boot log is base64 encoded.
+#[derive(Serialize, Deserialize)]
+struct LegacyQuote {
+    quote: String,
+    hash_alg: String,
+    enc_alg: String,
+    sign_alg: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pubkey: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ima_measurement_list: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ima_measurement_list_entry: Option<u64>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_maybe_base64",
+        deserialize_with = "deserialize_maybe_base64"
+    )]
+    mb_measurement_list: Option<Vec<u8>>,
+}
+
+impl KeylimeQuote {
+    /// Serializes the quote exactly as the Python agent would, for
+    /// verifiers expecting its wire format.
+    ///
+    /// Fields the Python agent does not know about are left out. Quotes
+    /// with encoded or filtered measurement lists cannot be represented,
+    /// as the verifier would misread the lists.
+    pub(crate) fn to_legacy_json(&self) -> Result<String> {
+        if self.mb_measurement_list_encoding.is_some()
+            || self.ima_measurement_list_encoding.is_some()
+            || self.ima_path_filter.is_some()
+        {
+            return Err(KeylimeError::Other(
+                "Quotes with encoded or filtered measurement lists cannot be serialized in the legacy format"
+                    .to_string(),
+            ));
+        }
+
+        let legacy = LegacyQuote {
+            quote: self.quote.clone(),
+            hash_alg: self.hash_alg.clone(),
+            enc_alg: self.enc_alg.clone(),
+            sign_alg: self.sign_alg.clone(),
+            pubkey: self.pubkey.clone(),
+            ima_measurement_list: self.ima_measurement_list.clone(),
+            ima_measurement_list_entry: self.ima_measurement_list_entry,
+            mb_measurement_list: self.mb_measurement_list.clone(),
+        };
+        Ok(to_python_json(&legacy)?)
+    }
+
+    /// Parses a quote sent by the Python agent
+    pub(crate) fn from_legacy_json(json: &str) -> Result<Self> {
+        let legacy: LegacyQuote = serde_json::from_str(json)?;
+        Ok(KeylimeQuote {
+            quote: legacy.quote,
+            hash_alg: legacy.hash_alg,
+            enc_alg: legacy.enc_alg,
+            sign_alg: legacy.sign_alg,
+            pubkey: legacy.pubkey,
+            ima_measurement_list: legacy.ima_measurement_list,
+            mb_measurement_list: legacy.mb_measurement_list,
+            ima_measurement_list_entry: legacy.ima_measurement_list_entry,
+            boot_aggregate: None,
+            num_entries: None,
+            mb_measurement_list_encoding: None,
+            ima_path_filter: None,
+            ima_measurement_list_encoding: None,
+            banks: None,
+            mb_measurement_list_available: None,
+            mb_measurement_list_entry: None,
+            mb_num_entries: None,
+            quote_parts: None,
+            ima_signature_failures: None,
+        })
+    }
+}
+
+/// Header the verifier can send to correlate its logs with the agent ones
+pub(crate) const REQUEST_ID_HEADER: &str = "X-Request-ID";
+
+// Longest request id taken from REQUEST_ID_HEADER
+const REQUEST_ID_MAX_LEN: usize = 64;
+
+/// Context of a quote request, prefixed to the log lines about it so that
+/// they can be correlated across a fleet of agents
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RequestContext {
+    pub agent_uuid: String,
+    pub request_id: String,
+}
+
+impl RequestContext {
+    /// Uses the request id sent by the verifier in `REQUEST_ID_HEADER`, if
+    /// any, or generates one otherwise. Ids that could garble the log line
+    /// are replaced by a generated one.
+    pub(crate) fn new(req: &HttpRequest, agent_uuid: &str) -> Self {
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| {
+                !id.is_empty()
+                    && id.len() <= REQUEST_ID_MAX_LEN
+                    && id.chars().all(|c| {
+                        c.is_ascii_alphanumeric()
+                            || matches!(c, '-' | '_' | '.')
+                    })
+            })
+            .map(String::from)
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        RequestContext {
+            agent_uuid: agent_uuid.to_string(),
+            request_id,
+        }
+    }
+}
+
+impl std::fmt::Display for RequestContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[agent={} request={}]", self.agent_uuid, self.request_id)
+    }
+}
+
+// Returns the whole seconds to send in a Retry-After header for the wait
+fn retry_after_secs(wait: Duration) -> u64 {
+    wait.as_secs()
+        .saturating_add(u64::from(wait.subsec_nanos() > 0))
+}
+
+// Returns a 429 response if the client asked for more quotes than allowed by
+// quote_rate_limit, counting `quotes` for this request. Requests without a
+// known peer address share a single limit.
+fn rate_limited(
+    req: &HttpRequest,
+    data: &QuoteData,
+    ctx: &RequestContext,
+    quotes: u32,
+) -> Option<HttpResponse> {
+    let client = req
+        .peer_addr()
+        .map(|addr| addr.ip())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    let wait = data.quote_rate_limiter.check(client, quotes).err()?;
+    let retry_after = retry_after_secs(wait);
+
+    let message = format!(
+        "Too many quote requests from {}, retry in {}s",
+        client, retry_after
+    );
+    warn!("{} Get quote returning 429 response. {}", ctx, message);
+    Some(
+        ResponseStatus::TooManyRequests
+            .response()
+            .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+            .json(JsonWrapper::error(
+                ResponseStatus::TooManyRequests,
+                message,
+            )),
+    )
+}
+
+// Returns a 401 or 403 response if the client did not present a certificate
+// accepted by the client authentication policy
+fn client_unauthorized(
+    req: &HttpRequest,
+    data: &QuoteData,
+    ctx: &RequestContext,
+) -> Option<HttpResponse> {
+    let extensions = req.extensions();
+    let cert = extensions.get::<PeerCertificate>().map(|cert| &cert.0);
+    match data.client_auth.authorize(cert) {
+        Ok(()) => None,
+        Err(KeylimeError::Forbidden(message)) => {
+            warn!("{} Get quote returning 403 response. {}", ctx, message);
+            Some(
+                JsonWrapper::error(ResponseStatus::Forbidden, message)
+                    .into_response(),
+            )
+        }
+        Err(e) => {
+            warn!("{} Get quote returning 401 response. {}", ctx, e);
+            Some(
+                JsonWrapper::error(
+                    ResponseStatus::Unauthorized,
+                    e.to_string(),
+                )
+                .into_response(),
+            )
+        }
+    }
+}
+
+// Returns a 400 response if the nonce was already quoted within
+// nonce_reuse_window, or a 503 response if too many nonces are within the
+// window to remember another one. Only requests about to be quoted record
+// their nonce, so that invalid requests do not use it up, and requests
+// whose quote cannot be generated forget it.
+fn nonce_rejected(
+    data: &QuoteData,
+    nonce: &[u8],
+    ctx: &RequestContext,
+) -> Option<HttpResponse> {
+    match data.used_nonces.record(nonce).err()? {
+        NonceRejected::Reused => {
+            warn!("{} Get quote returning 400 response. nonce reused", ctx);
+            Some(
+                JsonWrapper::error_with_code(
+                    ResponseStatus::BadRequest,
+                    "nonce_reused",
+                    "nonce reused",
+                )
+                .into_response(),
+            )
+        }
+        NonceRejected::Full(wait) => {
+            let retry_after = retry_after_secs(wait);
+            let message = format!(
+                "Too many nonces quoted within the reuse window, retry in {}s",
+                retry_after
+            );
+            warn!("{} Get quote returning 503 response. {}", ctx, message);
+            Some(
+                ResponseStatus::ServiceUnavailable
+                    .response()
+                    .insert_header((
+                        header::RETRY_AFTER,
+                        retry_after.to_string(),
+                    ))
+                    .json(JsonWrapper::error_with_code(
+                        ResponseStatus::ServiceUnavailable,
+                        "nonce_tracker_full",
+                        message,
+                    )),
+            )
+        }
+    }
 }
 
 // This is a Quote request from the tenant, which does not check
@@ -46,239 +577,3594 @@ pub async fn identity(
     param: web::Query<Ident>,
     data: web::Data<QuoteData>,
 ) -> impl Responder {
-    // nonce can only be in alphanumerical format
-    if !param.nonce.chars().all(char::is_alphanumeric) {
-        warn!("Get quote returning 400 response. Parameters should be strictly alphanumeric: {}", param.nonce);
-        return HttpResponse::BadRequest().json(JsonWrapper::error(
-            400,
-            format!(
-                "Parameters should be strictly alphanumeric: {}",
-                param.nonce
+    let ctx = RequestContext::new(&req, &data.agent_uuid);
+    if let Some(response) = client_unauthorized(&req, &data, &ctx) {
+        return response;
+    }
+    if let Some(response) = rate_limited(&req, &data, &ctx, 1) {
+        return response;
+    }
+
+    // The API version is available to handle differences in the response
+    // shape between versions
+    let api_version = match request_api_version(&req) {
+        Ok(version) => version,
+        Err(e) => {
+            warn!("{} Get quote returning 400 response. {}", ctx, e);
+            return JsonWrapper::error_with_code(
+                ResponseStatus::BadRequest,
+                "unsupported_api_version",
+                e.to_string(),
+            )
+            .into_response();
+        }
+    };
+    debug!("{} Quote requested with API version {}", ctx, api_version);
+
+    let params = match IdentityQuoteParams::parse(&param, &data) {
+        Ok(params) => params,
+        Err(e) => return invalid_params_response(&e, &ctx),
+    };
+
+    if let Some(response) = nonce_rejected(&data, &params.nonce, &ctx) {
+        return response;
+    }
+
+    debug!("{} Calling Identity Quote with nonce: {}", ctx, param.nonce);
+
+    let quote = match build_identity_quote(&params, &data).await {
+        Ok(quote) => quote,
+        Err(e) => {
+            debug!("{} Unable to retrieve quote: {:?}", ctx, e);
+            data.used_nonces.forget(&params.nonce);
+            return quote_error_response(&e, &ctx);
+        }
+    };
+
+    let schema_quote = match quote.to_schema(params.schema_version) {
+        Ok(schema_quote) => schema_quote,
+        Err(e) => {
+            debug!("{} Unable to serialize quote: {:?}", ctx, e);
+            return JsonWrapper::error(
+                ResponseStatus::InternalServerError,
+                "Unable to retrieve quote".to_string(),
+            )
+            .into_response();
+        }
+    };
+
+    let response = JsonWrapper::success(schema_quote);
+    info!("{} GET identity quote returning 200 response", ctx);
+    HttpResponse::Ok().json(response)
+}
+
+// Checks an integrity quote request and builds the quote, returning along
+// with it the validated parameters, or the error response
+async fn integrity_quote(
+    req: &HttpRequest,
+    param: &Integ,
+    data: &web::Data<QuoteData>,
+    ctx: &RequestContext,
+) -> std::result::Result<(KeylimeQuote, IntegrityQuoteParams), HttpResponse> {
+    if let Some(response) = client_unauthorized(req, data, ctx) {
+        return Err(response);
+    }
+    if let Some(response) = rate_limited(req, data, ctx, 1) {
+        return Err(response);
+    }
+
+    // The API version is available to handle differences in the response
+    // shape between versions
+    let api_version = match request_api_version(req) {
+        Ok(version) => version,
+        Err(e) => {
+            warn!("{} Get quote returning 400 response. {}", ctx, e);
+            return Err(JsonWrapper::error_with_code(
+                ResponseStatus::BadRequest,
+                "unsupported_api_version",
+                e.to_string(),
+            )
+            .into_response());
+        }
+    };
+    debug!("{} Quote requested with API version {}", ctx, api_version);
+
+    let params = match IntegrityQuoteParams::parse(param, data) {
+        Ok(params) => params,
+        Err(e) => return Err(invalid_params_response(&e, ctx)),
+    };
+
+    if let Some(response) = nonce_rejected(data, &params.nonce, ctx) {
+        return Err(response);
+    }
+
+    debug!(
+        "{} Calling Integrity Quote with nonce: {}, mask: {}",
+        ctx, param.nonce, param.mask
+    );
+
+    match build_integrity_quote(&params, data).await {
+        Ok(quote) => Ok((quote, params)),
+        Err(e) => {
+            debug!("{} Unable to retrieve quote: {:?}", ctx, e);
+            data.used_nonces.forget(&params.nonce);
+            Err(quote_error_response(&e, ctx))
+        }
+    }
+}
+
+// This is a request for an integrity quote packed with its measurement
+// data into a bundle, to be verified offline. It takes the same parameters
+// as the integrity quote request, and returns a tar archive whose manifest
+// is signed with the NK, see bundle::build_bundle.
+pub async fn integrity_bundle(
+    req: HttpRequest,
+    param: web::Query<Integ>,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    let ctx = RequestContext::new(&req, &data.agent_uuid);
+    let (quote, _) = match integrity_quote(&req, &param, &data, &ctx).await {
+        Ok(quote) => quote,
+        Err(response) => return response,
+    };
+
+    match bundle::build_bundle(&quote, &data.priv_key) {
+        Ok(archive) => {
+            info!("{} GET integrity bundle returning 200 response", ctx);
+            HttpResponse::Ok()
+                .content_type(bundle::BUNDLE_CONTENT_TYPE)
+                .body(archive)
+        }
+        Err(e) => {
+            debug!("{} Unable to build bundle: {:?}", ctx, e);
+            JsonWrapper::error(
+                ResponseStatus::InternalServerError,
+                "Unable to build bundle".to_string(),
+            )
+            .into_response()
+        }
+    }
+}
+
+// This is a Quote request from the cloud verifier, which will check
+// integrity measurement. The PCRs included in the Quote will be specified
+// by the mask. It should return this data:
+// { QuoteAIK(nonce, 16:H(NK_pub), xi:yi), NK_pub}
+// where xi:yi are additional PCRs to be included in the quote, given as a
+// list of indices in extra_pcrs.
+pub async fn integrity(
+    req: HttpRequest,
+    param: web::Query<Integ>,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    let ctx = RequestContext::new(&req, &data.agent_uuid);
+    let (quote, params) =
+        match integrity_quote(&req, &param, &data, &ctx).await {
+            Ok(quote) => quote,
+            Err(response) => return response,
+        };
+
+    let schema_quote = match quote.to_schema(params.schema_version) {
+        Ok(schema_quote) => schema_quote,
+        Err(e) => {
+            debug!("{} Unable to serialize quote: {:?}", ctx, e);
+            return JsonWrapper::error(
+                ResponseStatus::InternalServerError,
+                "Unable to retrieve quote".to_string(),
+            )
+            .into_response();
+        }
+    };
+
+    let response = JsonWrapper::success(schema_quote);
+    info!("{} GET integrity quote returning 200 response", ctx);
+    HttpResponse::Ok().json(response)
+}
+
+// Response returned when the parameters of a quote request are invalid,
+// with the error code of the parameter at fault
+fn invalid_params_response(
+    e: &KeylimeError,
+    ctx: &RequestContext,
+) -> HttpResponse {
+    warn!("{} Get quote returning 400 response. {}", ctx, e);
+    JsonWrapper::error_with_code(
+        ResponseStatus::BadRequest,
+        e.error_code().unwrap_or("invalid_request"),
+        e.to_string(),
+    )
+    .into_response()
+}
+
+// Error message returned when the quote could not be generated. If the
+// failure came from the TPM, the response code is included so that the
+// failing operation can be diagnosed from the verifier side.
+fn quote_error_message(e: &KeylimeError) -> String {
+    // The quote itself may have succeeded
+    if let KeylimeError::PublicKey(_) = e {
+        return "Unable to retrieve public key".to_string();
+    }
+    // The IMA list limit needs to be raised by the operator, so tell the
+    // verifier why the quote cannot be served
+    if let KeylimeError::ImaEntryTooLarge { .. } = e {
+        return format!("Unable to retrieve quote: {}", e);
+    }
+    match e.tpm_rc() {
+        Some((rc, Some(kind))) => format!(
+            "Unable to retrieve quote: TPM error {:#x} ({:?})",
+            rc, kind
+        ),
+        Some((rc, None)) => {
+            format!("Unable to retrieve quote: TPM error {:#x}", rc)
+        }
+        None => "Unable to retrieve quote".to_string(),
+    }
+}
+
+// Response returned when the quote could not be generated: 503 if the TPM
+// was held by other requests for too long, 500 otherwise
+fn quote_error_response(
+    e: &KeylimeError,
+    ctx: &RequestContext,
+) -> HttpResponse {
+    if let KeylimeError::TpmBusy(_) = e {
+        warn!("{} Get quote returning 503 response. {}", ctx, e);
+        return JsonWrapper::error(
+            ResponseStatus::ServiceUnavailable,
+            "TPM busy",
+        )
+        .into_response();
+    }
+    JsonWrapper::error(
+        ResponseStatus::InternalServerError,
+        quote_error_message(e),
+    )
+    .into_response()
+}
+
+/// Returns the nonce to quote, decoded according to the requested encoding:
+///
+/// * `raw` (default): the nonce is used as is and can only be alphanumeric
+/// * `base64`: the nonce is base64, for binary nonces. The URL-safe alphabet
+///   is expected, as the nonce is sent in the URL, but the standard one is
+///   accepted too
+///
+/// In both cases the nonce size is limited to `tpm::MAX_NONCE_SIZE` bytes.
+pub(crate) fn quote_nonce(
+    nonce: &str,
+    encoding: Option<&str>,
+) -> Result<Vec<u8>> {
+    let nonce = match encoding {
+        None | Some("raw") => {
+            if !nonce.chars().all(char::is_alphanumeric) {
+                return Err(KeylimeError::InvalidParameter {
+                    error_code: "invalid_nonce",
+                    message: format!(
+                        "Parameters should be strictly alphanumeric: {}",
+                        nonce
+                    ),
+                });
+            }
+            nonce.as_bytes().to_vec()
+        }
+        Some("base64") => {
+            let nonce: StrDeserializer<'_, serde::de::value::Error> =
+                nonce.into_deserializer();
+            deserialize_as_base64_url(nonce).map_err(|e| {
+                KeylimeError::InvalidParameter {
+                    error_code: "invalid_nonce",
+                    message: format!("Nonce is not valid base64: {}", e),
+                }
+            })?
+        }
+        Some(other) => {
+            return Err(KeylimeError::InvalidParameter {
+                error_code: "unsupported_nonce_encoding",
+                message: format!(
+                    "Unsupported nonce encoding {} (supported: raw, base64)",
+                    other
+                ),
+            })
+        }
+    };
+
+    if nonce.len() > tpm::MAX_NONCE_SIZE {
+        return Err(KeylimeError::InvalidParameter {
+            error_code: "nonce_too_long",
+            message: format!(
+                "Nonce is too long (max size {}): {}",
+                tpm::MAX_NONCE_SIZE,
+                nonce.len()
+            ),
+        });
+    }
+
+    Ok(nonce)
+}
+
+/// Returns whether the NK public key is included in a quote, according to
+/// the `partial` parameter, with the same meaning for both quote types:
+///
+/// * `0`: the quote is complete, including the public key
+/// * `1`: the quote is partial, without the public key, which the verifier
+///   already has
+///
+/// The parameter is required for integrity quotes. Identity quotes include
+/// the public key when it is not given.
+pub(crate) fn quote_includes_pubkey(partial: Option<&str>) -> Result<bool> {
+    match partial {
+        None | Some("0") => Ok(true),
+        Some("1") => Ok(false),
+        Some(_) => Err(KeylimeError::InvalidParameter {
+            error_code: "partial_invalid",
+            message: "uri must contain key 'partial' and value '0' or '1'"
+                .to_string(),
+        }),
+    }
+}
+
+/// Returns the NK public key to send along with a quote, if it is included.
+///
+/// Failures are reported as `KeylimeError::PublicKey`, so that they can be
+/// told apart from failures to generate the quote.
+pub(crate) fn quote_pubkey(
+    include: bool,
+    data: &QuoteData,
+) -> Result<Option<String>> {
+    if !include {
+        return Ok(None);
+    }
+    crypto::pkey_pub_to_pem(&data.pub_key)
+        .map(Some)
+        .map_err(|e| KeylimeError::PublicKey(e.to_string()))
+}
+
+/// Returns the signing scheme to use for a quote: the requested one, if any,
+/// or the one configured for the AK otherwise.
+///
+/// The requested scheme must be one the AK can sign with, as the TPM refuses
+/// to quote with a scheme other than the one the AK was created with, unless
+/// the AK was created without a scheme.
+pub(crate) fn quote_sign_alg(
+    sign_scheme: Option<&str>,
+    data: &QuoteData,
+) -> Result<SignAlgorithm> {
+    let sign_alg = match sign_scheme {
+        None => return Ok(data.sign_alg),
+        Some(scheme) => SignAlgorithm::try_from(scheme)?,
+    };
+
+    if !data.ak_sign_algs.contains(&sign_alg) {
+        return Err(KeylimeError::Other(format!(
+            "Signing scheme {} cannot be used with the {} AK",
+            sign_alg, data.enc_alg
+        )));
+    }
+
+    Ok(sign_alg)
+}
+
+/// Returns the PCRs to quote: the ones selected by the mask, along with the
+/// additional ones listed in `extra_pcrs`, if any, in ascending order.
+///
+/// Errors are invalid parameters, flagging the mask or the additional PCRs.
+pub(crate) fn quote_pcrs(
+    mask: &str,
+    extra_pcrs: Option<&str>,
+) -> Result<Vec<PcrSlot>> {
+    let invalid = |error_code, message| KeylimeError::InvalidParameter {
+        error_code,
+        message,
+    };
+
+    // mask can only be in alphanumerical format
+    if !mask.chars().all(char::is_alphanumeric) {
+        return Err(invalid(
+            "invalid_mask",
+            format!("mask should be strictly alphanumeric: {}", mask),
+        ));
+    }
+    let mut pcrs = tpm::read_mask(mask)
+        .map_err(|e| invalid("invalid_mask", e.to_string()))?;
+
+    if let Some(extra_pcrs) = extra_pcrs {
+        let extra_pcrs = tpm::read_pcr_indices(extra_pcrs)
+            .map_err(|e| invalid("invalid_extra_pcrs", e.to_string()))?;
+        for pcr in extra_pcrs {
+            if !pcrs.contains(&pcr) {
+                pcrs.push(pcr);
+            }
+        }
+        pcrs.sort_by_key(|&pcr| u32::from(pcr));
+    }
+    Ok(pcrs)
+}
+
+/// Returns the PCRs to quote, as `quote_pcrs`, once checked against the
+/// configuration: they must include all the required PCRs, and none
+/// outside of the allowed ones.
+pub(crate) fn quote_integrity_pcrs(
+    mask: &str,
+    extra_pcrs: Option<&str>,
+    data: &QuoteData,
+) -> Result<Vec<PcrSlot>> {
+    let pcrs = quote_pcrs(mask, extra_pcrs)?;
+    let list = |pcrs: Vec<u32>| {
+        pcrs.iter()
+            .map(|pcr| pcr.to_string())
+            .collect::<Vec<String>>()
+            .join(", ")
+    };
+
+    let missing = tpm::missing_pcrs(&pcrs, &data.required_pcrs);
+    if !missing.is_empty() {
+        return Err(KeylimeError::InvalidParameter {
+            error_code: "missing_required_pcrs",
+            message: format!(
+                "mask is missing required PCRs: {}",
+                list(missing)
             ),
+        });
+    }
+
+    let forbidden = tpm::forbidden_pcrs(&pcrs, &data.allowed_pcrs);
+    if !forbidden.is_empty() {
+        return Err(KeylimeError::InvalidParameter {
+            error_code: "forbidden_pcrs",
+            message: format!("PCRs are not allowed: {}", list(forbidden)),
+        });
+    }
+
+    Ok(pcrs)
+}
+
+/// Returns the PCR bank to quote: the requested one, if any, or the one
+/// configured for the agent otherwise.
+///
+/// The requested bank must be allocated on the TPM; there is no fallback to
+/// the configured one, as the verifier policy would not match.
+pub(crate) fn quote_hash_alg(
+    hash_alg: Option<&str>,
+    data: &QuoteData,
+) -> Result<HashAlgorithm> {
+    let hash_alg = match hash_alg {
+        None => return Ok(data.hash_alg),
+        Some(alg) => HashAlgorithm::try_from(alg)?,
+    };
+
+    if !data.pcr_banks.contains(&hash_alg) {
+        let banks = data
+            .pcr_banks
+            .iter()
+            .map(|bank| bank.to_string())
+            .collect::<Vec<String>>()
+            .join(", ");
+        return Err(KeylimeError::Other(format!(
+            "PCR bank {} is not available on the TPM (available: {})",
+            hash_alg, banks
+        )));
+    }
+
+    Ok(hash_alg)
+}
+
+/// Returns the PCR banks the verifier asked to quote, if any, in the order
+/// they were listed.
+///
+/// Each bank must be available on the TPM and listed once. As the banks
+/// replace the single hash_alg, both cannot be given.
+pub(crate) fn quote_pcr_banks(
+    banks: Option<&str>,
+    hash_alg: Option<&str>,
+    schema_version: u32,
+    data: &QuoteData,
+) -> Result<Option<Vec<HashAlgorithm>>> {
+    let banks = match banks {
+        None => return Ok(None),
+        Some(banks) => banks,
+    };
+    if hash_alg.is_some() {
+        return Err(KeylimeError::Other(
+            "banks and hash_alg cannot be both given".to_string(),
+        ));
+    }
+    if schema_version < 8 {
+        return Err(KeylimeError::Other(
+            "banks requires quote schema version 8 or later".to_string(),
+        ));
+    }
+
+    let mut quoted = Vec::new();
+    for bank in banks.split(',') {
+        let bank = quote_hash_alg(Some(bank), data)?;
+        if quoted.contains(&bank) {
+            return Err(KeylimeError::Other(format!(
+                "PCR bank {} is listed more than once",
+                bank
+            )));
+        }
+        quoted.push(bank);
+    }
+    Ok(Some(quoted))
+}
+
+/// Returns the index of the first IMA entry to return: the requested one
+/// for iterative attestation, or 0 for the whole list.
+///
+/// A malformed index is an error rather than a request for the whole list,
+/// as the verifier would otherwise process the returned entries from the
+/// wrong position.
+pub(crate) fn quote_ima_ml_entry(entry: Option<&str>) -> Result<u64> {
+    match entry {
+        None => Ok(0),
+        Some(idx) => idx.parse::<u64>().map_err(|e| {
+            KeylimeError::Other(format!(
+                "ima_ml_entry should be a non-negative integer: {} ({})",
+                idx, e
+            ))
+        }),
+    }
+}
+
+/// Returns whether the components of the quote string are to be sent
+/// separately, in quote_parts.
+///
+/// They are only sent when asked for, and only with a schema version
+/// knowing about them.
+pub(crate) fn quote_includes_parts(
+    quote_parts: Option<bool>,
+    schema_version: u32,
+) -> Result<bool> {
+    let quote_parts = quote_parts.unwrap_or(false);
+    if quote_parts && schema_version < 11 {
+        return Err(KeylimeError::Other(
+            "quote_parts requires quote schema version 11 or later"
+                .to_string(),
+        ));
+    }
+    Ok(quote_parts)
+}
+
+/// Parses the event of the measured boot log the verifier asks the log
+/// from, if any.
+///
+/// The whole log is sent unless asked otherwise, and a range of events only
+/// with a schema version flagging it in the response.
+pub(crate) fn quote_mb_entry(
+    entry: Option<&str>,
+    schema_version: u32,
+) -> Result<Option<u64>> {
+    let entry = match entry {
+        None => return Ok(None),
+        Some(entry) => entry.parse::<u64>().map_err(|e| {
+            KeylimeError::Other(format!(
+                "mb_entry should be a non-negative integer: {} ({})",
+                entry, e
+            ))
+        })?,
+    };
+    if schema_version < 10 {
+        return Err(KeylimeError::Other(
+            "mb_entry requires quote schema version 10 or later".to_string(),
+        ));
+    }
+    Ok(Some(entry))
+}
+
+// Parses the encoding asked for a measurement log
+fn quote_ml_encoding(
+    encoding: Option<&str>,
+    log: &str,
+) -> Result<MlEncoding> {
+    match encoding {
+        None | Some(ML_ENCODING_NONE) => Ok(MlEncoding::None),
+        Some(ML_ENCODING_GZIP) => Ok(MlEncoding::Gzip),
+        Some(ML_ENCODING_ZSTD) => Ok(MlEncoding::Zstd),
+        Some(other) => Err(KeylimeError::Other(format!(
+            "Unsupported {} encoding {} (supported: {}, {}, {})",
+            log, other, ML_ENCODING_NONE, ML_ENCODING_GZIP, ML_ENCODING_ZSTD
+        ))),
+    }
+}
+
+/// Returns how the measured boot log is to be encoded.
+///
+/// The log is sent uncompressed unless the verifier asks for an encoding,
+/// as older verifiers do not know about mb_measurement_list_encoding.
+pub(crate) fn quote_mb_ml_encoding(
+    encoding: Option<&str>,
+) -> Result<MlEncoding> {
+    quote_ml_encoding(encoding, "measured boot log")
+}
+
+/// Returns how the IMA measurement list is to be encoded.
+///
+/// The list is sent as plain text unless the verifier asks for an
+/// encoding, and only with a schema version flagging it in the response.
+pub(crate) fn quote_ima_ml_encoding(
+    encoding: Option<&str>,
+    schema_version: u32,
+) -> Result<MlEncoding> {
+    let ima_encoding = quote_ml_encoding(encoding, "IMA measurement list")?;
+    if ima_encoding != MlEncoding::None && schema_version < 7 {
+        return Err(KeylimeError::Other(
+            "ima_ml_encoding requires quote schema version 7 or later"
+                .to_string(),
         ));
     }
+    Ok(ima_encoding)
+}
+
+/// Checks the filter restricting the IMA entries returned, if any.
+///
+/// Filtering changes the list the verifier replays, so it is only done
+/// when asked for, and only with a schema version flagging it in the
+/// response.
+pub(crate) fn quote_ima_path_filter(
+    filter: Option<&str>,
+    schema_version: u32,
+) -> Result<()> {
+    match filter {
+        None => Ok(()),
+        Some("") => Err(KeylimeError::Other(
+            "ima_path_filter must not be empty".to_string(),
+        )),
+        Some(_) if schema_version < 6 => Err(KeylimeError::Other(
+            "ima_path_filter requires quote schema version 6 or later"
+                .to_string(),
+        )),
+        Some(_) => Ok(()),
+    }
+}
+
+// Flags the errors of a parameter check as invalid parameters with the
+// given error code, unless they already carry their own
+fn invalid_param(
+    error_code: &'static str,
+) -> impl Fn(KeylimeError) -> KeylimeError {
+    move |e| match e {
+        KeylimeError::InvalidParameter { .. } => e,
+        e => KeylimeError::InvalidParameter {
+            error_code,
+            message: e.to_string(),
+        },
+    }
+}
+
+/// Returns the quote schema version to answer with: the requested one, if
+/// any, or the latest one otherwise.
+pub(crate) fn quote_schema_version(version: Option<u32>) -> Result<u32> {
+    match version.unwrap_or(QUOTE_SCHEMA_VERSION) {
+        version if version == 0 || version > QUOTE_SCHEMA_VERSION => {
+            Err(KeylimeError::InvalidParameter {
+                error_code: "unsupported_schema_version",
+                message: format!(
+                    "Unsupported quote schema version {} (supported: 1-{})",
+                    version, QUOTE_SCHEMA_VERSION
+                ),
+            })
+        }
+        version => Ok(version),
+    }
+}
+
+/// Parameters of an identity quote request, once validated
+#[derive(Debug, Clone)]
+pub(crate) struct IdentityQuoteParams {
+    pub(crate) nonce: Vec<u8>,
+    pub(crate) include_pubkey: bool,
+    pub(crate) schema_version: u32,
+    pub(crate) sign_alg: SignAlgorithm,
+    /// The PCR banks to quote, either asked for with banks or the single
+    /// hash_alg one
+    pub(crate) banks: Vec<HashAlgorithm>,
+    /// Whether the banks were asked for, and the quote is broken down by
+    /// bank in the response
+    pub(crate) banks_requested: bool,
+    pub(crate) include_parts: bool,
+}
+
+impl IdentityQuoteParams {
+    /// Validates the parameters of an identity quote request. Errors are
+    /// invalid parameters carrying the error code of the response.
+    pub(crate) fn parse(param: &Ident, data: &QuoteData) -> Result<Self> {
+        let nonce =
+            quote_nonce(&param.nonce, param.nonce_encoding.as_deref())?;
+        let include_pubkey = quote_includes_pubkey(param.partial.as_deref())?;
+        let schema_version = quote_schema_version(param.schema_version)?;
+        let sign_alg = quote_sign_alg(param.sign_scheme.as_deref(), data)
+            .map_err(invalid_param("invalid_sign_scheme"))?;
+        let hash_alg = quote_hash_alg(param.hash_alg.as_deref(), data)
+            .map_err(invalid_param("invalid_hash_alg"))?;
+        let requested_banks = quote_pcr_banks(
+            param.banks.as_deref(),
+            param.hash_alg.as_deref(),
+            schema_version,
+            data,
+        )
+        .map_err(invalid_param("invalid_banks"))?;
+        let include_parts =
+            quote_includes_parts(param.quote_parts, schema_version)
+                .map_err(invalid_param("invalid_quote_parts"))?;
+
+        Ok(IdentityQuoteParams {
+            nonce,
+            include_pubkey,
+            schema_version,
+            sign_alg,
+            banks_requested: requested_banks.is_some(),
+            banks: requested_banks.unwrap_or_else(|| vec![hash_alg]),
+            include_parts,
+        })
+    }
+}
+
+/// Parameters of an integrity quote request, once validated
+#[derive(Debug, Clone)]
+pub(crate) struct IntegrityQuoteParams {
+    pub(crate) nonce: Vec<u8>,
+    pub(crate) pcrs: Vec<PcrSlot>,
+    pub(crate) include_pubkey: bool,
+    pub(crate) schema_version: u32,
+    pub(crate) sign_alg: SignAlgorithm,
+    pub(crate) hash_alg: HashAlgorithm,
+    pub(crate) ima_ml_entry: u64,
+    pub(crate) ima_ml_count: usize,
+    pub(crate) ima_encoding: MlEncoding,
+    pub(crate) ima_path_filter: Option<String>,
+    pub(crate) mb_entry: Option<u64>,
+    pub(crate) mb_ml_count: usize,
+    pub(crate) mb_encoding: MlEncoding,
+    pub(crate) include_parts: bool,
+}
+
+impl IntegrityQuoteParams {
+    /// Validates the parameters of an integrity quote request. Errors are
+    /// invalid parameters carrying the error code of the response.
+    pub(crate) fn parse(param: &Integ, data: &QuoteData) -> Result<Self> {
+        let nonce =
+            quote_nonce(&param.nonce, param.nonce_encoding.as_deref())?;
+        let pcrs = quote_integrity_pcrs(
+            &param.mask,
+            param.extra_pcrs.as_deref(),
+            data,
+        )?;
+        let include_pubkey = quote_includes_pubkey(Some(&param.partial))?;
+        let schema_version = quote_schema_version(param.schema_version)?;
+        let sign_alg = quote_sign_alg(param.sign_scheme.as_deref(), data)
+            .map_err(invalid_param("invalid_sign_scheme"))?;
+        let hash_alg = quote_hash_alg(param.hash_alg.as_deref(), data)
+            .map_err(invalid_param("invalid_hash_alg"))?;
+        let ima_ml_entry = quote_ima_ml_entry(param.ima_ml_entry.as_deref())
+            .map_err(invalid_param("invalid_ima_ml_entry"))?;
+        let mb_entry =
+            quote_mb_entry(param.mb_entry.as_deref(), schema_version)
+                .map_err(invalid_param("invalid_mb_entry"))?;
+        let mb_encoding =
+            quote_mb_ml_encoding(param.mb_ml_encoding.as_deref())
+                .map_err(invalid_param("invalid_mb_ml_encoding"))?;
+        let ima_encoding = quote_ima_ml_encoding(
+            param.ima_ml_encoding.as_deref(),
+            schema_version,
+        )
+        .map_err(invalid_param("invalid_ima_ml_encoding"))?;
+        quote_ima_path_filter(
+            param.ima_path_filter.as_deref(),
+            schema_version,
+        )
+        .map_err(invalid_param("invalid_ima_path_filter"))?;
+        let include_parts =
+            quote_includes_parts(param.quote_parts, schema_version)
+                .map_err(invalid_param("invalid_quote_parts"))?;
+
+        Ok(IntegrityQuoteParams {
+            nonce,
+            pcrs,
+            include_pubkey,
+            schema_version,
+            sign_alg,
+            hash_alg,
+            ima_ml_entry,
+            ima_ml_count: param.ima_ml_count.unwrap_or(0),
+            ima_encoding,
+            ima_path_filter: param.ima_path_filter.clone(),
+            mb_entry,
+            mb_ml_count: param.mb_ml_count.unwrap_or(0),
+            mb_encoding,
+            include_parts,
+        })
+    }
+}
+
+/// Builds the identity quote: a TPM quote over the nonce and PCR 16, which
+/// has the NK public key digest extended into it, along with the NK public
+/// key unless partial is "1". The quote is reused for identical requests
+/// within the identity quote cache TTL.
+pub(crate) async fn build_identity_quote(
+    params: &IdentityQuoteParams,
+    data: &web::Data<QuoteData>,
+) -> Result<KeylimeQuote> {
+    let key = QuoteCacheKey {
+        nonce: params.nonce.clone(),
+        sign_alg: params.sign_alg,
+        banks: params.banks.clone(),
+    };
+    let mut quote = match data.identity_quotes.get(&key) {
+        Some(quote) => {
+            debug!("Returning cached identity quote");
+            quote
+        }
+        None => {
+            let quote = tpm::quote_async(
+                key.nonce.clone(),
+                None,
+                data.clone(),
+                params.sign_alg,
+                key.banks.clone(),
+            )
+            .await?;
+            data.identity_quotes.insert(key, &quote);
+            quote
+        }
+    };
+    quote.pubkey = quote_pubkey(params.include_pubkey, data)?;
+
+    // The breakdown by bank is only sent when banks were asked for, so
+    // that the response is unchanged for other verifiers
+    if !params.banks_requested {
+        quote.banks = None;
+    }
+    if params.include_parts {
+        quote.quote_parts =
+            Some(QuoteParts::from_quote_string(&quote.quote)?);
+    }
+    Ok(quote)
+}
+
+// Reads the measured boot log, along with the boot aggregate computed from
+// it for quick comparison by the verifier. Failures are not fatal: the
+// verifier decides whether the log is required.
+fn read_measured_boot(
+    path: &Path,
+    hash_alg: HashAlgorithm,
+) -> (Option<Vec<u8>>, Option<Vec<u8>>) {
+    let ml = match read(path) {
+        Ok(ml) => ml,
+        Err(e) => {
+            warn!("TPM2 event log not available: {}", path.display());
+            return (None, None);
+        }
+    };
+
+    let boot_aggregate = match measured_boot::boot_aggregate(&ml, hash_alg) {
+        Ok(aggregate) => Some(aggregate),
+        Err(e) => {
+            warn!("Unable to compute the boot aggregate: {}", e);
+            None
+        }
+    };
+
+    (Some(ml), boot_aggregate)
+}
+
+// Logs included in an integrity quote, along with the fields describing
+// them
+#[derive(Debug, Default)]
+struct QuoteLogs {
+    ima_measurement_list: Option<String>,
+    ima_measurement_list_entry: Option<u64>,
+    ima_measurement_list_encoding: Option<String>,
+    num_entries: Option<u64>,
+    mb_measurement_list: Option<Vec<u8>>,
+    mb_measurement_list_encoding: Option<String>,
+    mb_measurement_list_available: Option<bool>,
+    mb_measurement_list_entry: Option<u64>,
+    mb_num_entries: Option<u64>,
+    boot_aggregate: Option<Vec<u8>>,
+    ima_signature_failures: Option<Vec<u64>>,
+}
+
+// Reads the logs of the PCRs included in the integrity quote. Reading
+// files that can be large and verifying the IMA signatures blocks, so the
+// measured boot log and the IMA measurement list are each read in their own
+// blocking task, concurrently. The first error is returned as soon as it
+// occurs; the other task is then detached and its result dropped.
+async fn read_quote_logs(
+    params: &IntegrityQuoteParams,
+    data: &web::Data<QuoteData>,
+) -> Result<QuoteLogs> {
+    let mb_read = async {
+        let params = params.clone();
+        let data = data.clone();
+        tokio::task::spawn_blocking(move || {
+            read_measured_boot_log(&params, &data)
+        })
+        .await?
+    };
+    let ima_read = async {
+        let params = params.clone();
+        let data = data.clone();
+        tokio::task::spawn_blocking(move || read_ima_log(&params, &data))
+            .await?
+    };
+    let (mb, ima) = tokio::try_join!(mb_read, ima_read)?;
+
+    Ok(QuoteLogs {
+        mb_measurement_list: mb.mb_measurement_list,
+        mb_measurement_list_encoding: mb.mb_measurement_list_encoding,
+        mb_measurement_list_available: mb.mb_measurement_list_available,
+        mb_measurement_list_entry: mb.mb_measurement_list_entry,
+        mb_num_entries: mb.mb_num_entries,
+        boot_aggregate: mb.boot_aggregate,
+        ..ima
+    })
+}
+
+// Reads the measured boot log, if the measured boot PCR is included in the
+// mask. Only the measured boot fields of the logs are set.
+fn read_measured_boot_log(
+    params: &IntegrityQuoteParams,
+    data: &QuoteData,
+) -> Result<QuoteLogs> {
+    let mut logs = QuoteLogs::default();
+
+    // The verifier can ask for the events starting from a given one, and
+    // limit their number with mb_ml_count, to fetch the log incrementally.
+    if tpm::check_mask(&params.pcrs, &data.measuredboot_pcr) {
+        let (ml, boot_aggregate) =
+            read_measured_boot(&data.measuredboot_ml_path, params.hash_alg);
+        // The boot aggregate is computed from the whole log
+        let (ml, num_events) = match (ml, params.mb_entry) {
+            (Some(ml), Some(entry)) => {
+                let first = usize::try_from(entry).unwrap_or(usize::MAX);
+                let (range, num_events) = measured_boot::event_range(
+                    &ml,
+                    first,
+                    params.mb_ml_count,
+                )?;
+                (Some(range.to_vec()), Some(num_events as u64))
+            }
+            (ml, _) => (ml, None),
+        };
+        let ml = ml.map(|ml| params.mb_encoding.encode(ml)).transpose()?;
+
+        logs.mb_measurement_list_available = Some(ml.is_some());
+        logs.mb_measurement_list_encoding = match &ml {
+            Some(_) => params.mb_encoding.name().map(String::from),
+            None => None,
+        };
+        logs.mb_measurement_list = ml;
+        logs.boot_aggregate = boot_aggregate;
+        logs.mb_num_entries = num_events;
+        logs.mb_measurement_list_entry = num_events.and(params.mb_entry);
+    }
+    Ok(logs)
+}
+
+// Reads the IMA measurement list, if the IMA PCR is included in the mask.
+// Only the IMA fields of the logs are set.
+fn read_ima_log(
+    params: &IntegrityQuoteParams,
+    data: &QuoteData,
+) -> Result<QuoteLogs> {
+    let mut logs = QuoteLogs::default();
+
+    if !tpm::check_mask(&params.pcrs, &data.ima_pcr) {
+        return Ok(logs);
+    }
+
+    // If an index was provided, the request is for the entries starting from the given index
+    // (iterative attestation). Otherwise the request is for the whole list.
+    // The verifier can also limit the number of entries returned with
+    // ima_ml_count, and page through the list.
+    let nth_entry = params.ima_ml_entry;
+    let ima_read = read_measurement_list(
+        &data.ima_ml,
+        &data.ima_ml_path,
+        nth_entry,
+        params.ima_ml_count,
+        data.ima_ml_max_bytes,
+    )?;
+    // When the log was reset, the list is returned from the first entry,
+    // which tells the verifier to restart from there
+    if ima_read.rotated {
+        warn!(
+            "IMA measurement list entry {} requested, but the log was reset; returning it from the first entry",
+            nth_entry
+        );
+    }
+    logs.ima_measurement_list_entry = ima_read.nth_entry;
+
+    // The list may have been truncated, so count the entries returned
+    // rather than using the number of entries in the log
+    logs.num_entries = ima_read
+        .ml
+        .as_ref()
+        .map(|ml| ml.matches('\n').count() as u64);
+
+    // Entries failing signature verification are reported by their number
+    // in the log
+    logs.ima_signature_failures = ima_read.ml.as_ref().and_then(|ml| {
+        data.ima_signatures
+            .verify(ml, ima_read.nth_entry.unwrap_or(nth_entry))
+    });
+
+    // The entries left out by the path filter are still counted, so that
+    // the verifier reads the next ones afterwards
+    let ml = match (&params.ima_path_filter, ima_read.ml) {
+        (Some(filter), Some(ml)) => {
+            Some(filter_measurement_list(&ml, filter))
+        }
+        (_, ml) => ml,
+    };
+
+    // The list is compressed last, once its entries are final. Compressed
+    // data is not valid UTF-8, so it is sent base64 encoded.
+    let ima_encoding = params.ima_encoding;
+    match (ima_encoding.name(), ml) {
+        (Some(name), Some(ml)) => {
+            logs.ima_measurement_list =
+                Some(base64::encode(ima_encoding.encode(ml.into_bytes())?));
+            logs.ima_measurement_list_encoding = Some(name.to_string());
+        }
+        (_, ml) => logs.ima_measurement_list = ml,
+    }
+    Ok(logs)
+}
+
+/// Builds the integrity quote: a TPM quote over the nonce and the PCRs
+/// selected by the mask and extra_pcrs, along with the IMA measurement list
+/// and the measured boot log if their PCRs (ima_pcr and measuredboot_pcr)
+/// are selected. The NK public key is included only if partial is "0".
+pub(crate) async fn build_integrity_quote(
+    params: &IntegrityQuoteParams,
+    data: &web::Data<QuoteData>,
+) -> Result<KeylimeQuote> {
+    // If partial="0", include the public key in the quote
+    let pubkey = quote_pubkey(params.include_pubkey, data)?;
+
+    // Generate the ID quote.
+    let id_quote = tpm::quote_async(
+        params.nonce.clone(),
+        Some(params.pcrs.clone()),
+        data.clone(),
+        params.sign_alg,
+        vec![params.hash_alg],
+    )
+    .await?;
+
+    complete_integrity_quote(id_quote, pubkey, params, data).await
+}
+
+// Completes a TPM quote over the PCRs of an integrity quote request with
+// the logs of those PCRs and the fields describing them
+async fn complete_integrity_quote(
+    id_quote: KeylimeQuote,
+    pubkey: Option<String>,
+    params: &IntegrityQuoteParams,
+    data: &web::Data<QuoteData>,
+) -> Result<KeylimeQuote> {
+    // Reading and encoding the logs and verifying the IMA signatures can
+    // take a while, so they are read once the quote is generated
+    let logs = read_quote_logs(params, data).await?;
+
+    let quote_parts = if params.include_parts {
+        Some(QuoteParts::from_quote_string(&id_quote.quote)?)
+    } else {
+        None
+    };
+
+    // Generate the final quote based on the ID quote
+    Ok(KeylimeQuote {
+        pubkey,
+        ima_measurement_list: logs.ima_measurement_list,
+        mb_measurement_list: logs.mb_measurement_list,
+        ima_measurement_list_entry: logs.ima_measurement_list_entry,
+        boot_aggregate: logs.boot_aggregate,
+        num_entries: logs.num_entries,
+        mb_measurement_list_encoding: logs.mb_measurement_list_encoding,
+        mb_measurement_list_available: logs.mb_measurement_list_available,
+        mb_measurement_list_entry: logs.mb_measurement_list_entry,
+        mb_num_entries: logs.mb_num_entries,
+        quote_parts,
+        ima_signature_failures: logs.ima_signature_failures,
+        ima_path_filter: params.ima_path_filter.clone(),
+        ima_measurement_list_encoding: logs.ima_measurement_list_encoding,
+        banks: None,
+        ..id_quote
+    })
+}
+
+/// Largest number of quotes in a batch request. The TPM is held for the
+/// whole batch, so it is kept small enough not to starve other requests.
+pub(crate) const QUOTE_BATCH_MAX_SIZE: usize = 16;
+
+/// Entry of a batch quote request. Entries with a mask are integrity quotes
+/// of the PCRs it selects, carrying the IMA and measured boot logs as the
+/// integrity quote requests do. The others are identity quotes.
+#[derive(Deserialize)]
+pub(crate) struct BatchQuoteEntry {
+    pub(crate) nonce: String,
+    pub(crate) nonce_encoding: Option<String>,
+    pub(crate) mask: Option<String>,
+    pub(crate) partial: Option<String>,
+}
+
+/// Result of an entry of a batch quote request: the quote, or an error
+/// object shaped like the response of a failed quote request
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub(crate) enum BatchQuoteResult {
+    Quote(Box<KeylimeQuote>),
+    Error(JsonWrapper<serde_json::Value>),
+}
+
+// Accepted entry of a batch quote request: the quote to generate, and the
+// parameters to complete it with once generated
+struct BatchQuoteRequest {
+    request: tpm::QuoteRequest,
+    include_pubkey: bool,
+    integrity: Option<IntegrityQuoteParams>,
+}
+
+// Parses and validates an entry of a batch quote request. Errors are all
+// invalid parameters, so that they carry an error code for the entry.
+fn batch_quote_request(
+    entry: serde_json::Value,
+    data: &QuoteData,
+) -> Result<BatchQuoteRequest> {
+    let entry: BatchQuoteEntry =
+        serde_json::from_value(entry).map_err(|e| {
+            KeylimeError::InvalidParameter {
+                error_code: "invalid_request",
+                message: format!("Malformed quote request: {}", e),
+            }
+        })?;
+
+    // Entries with a mask are validated as integrity quote requests
+    let (nonce, include_pubkey, integrity) = match entry.mask {
+        Some(mask) => {
+            let param = Integ {
+                nonce: entry.nonce,
+                nonce_encoding: entry.nonce_encoding,
+                mask,
+                extra_pcrs: None,
+                partial: entry.partial.unwrap_or_else(|| "0".to_string()),
+                ima_ml_entry: None,
+                ima_ml_count: None,
+                mb_ml_encoding: None,
+                mb_entry: None,
+                mb_ml_count: None,
+                ima_ml_encoding: None,
+                ima_path_filter: None,
+                sign_scheme: None,
+                hash_alg: None,
+                quote_parts: None,
+                schema_version: None,
+            };
+            let params = IntegrityQuoteParams::parse(&param, data)?;
+            (params.nonce.clone(), params.include_pubkey, Some(params))
+        }
+        None => {
+            let nonce =
+                quote_nonce(&entry.nonce, entry.nonce_encoding.as_deref())?;
+            let include_pubkey =
+                quote_includes_pubkey(entry.partial.as_deref())?;
+            (nonce, include_pubkey, None)
+        }
+    };
+
+    match data.used_nonces.record(&nonce) {
+        Ok(()) => {}
+        Err(NonceRejected::Reused) => {
+            return Err(KeylimeError::InvalidParameter {
+                error_code: "nonce_reused",
+                message: "nonce reused".to_string(),
+            })
+        }
+        Err(NonceRejected::Full(_)) => {
+            return Err(KeylimeError::InvalidParameter {
+                error_code: "nonce_tracker_full",
+                message: "Too many nonces quoted within the reuse window"
+                    .to_string(),
+            })
+        }
+    }
+
+    let request = match &integrity {
+        Some(params) => tpm::QuoteRequest {
+            nonce,
+            mask: Some(params.pcrs.clone()),
+            sign_alg: params.sign_alg,
+            banks: vec![params.hash_alg],
+        },
+        None => tpm::QuoteRequest {
+            nonce,
+            mask: None,
+            sign_alg: data.sign_alg,
+            banks: vec![data.hash_alg],
+        },
+    };
+    Ok(BatchQuoteRequest {
+        request,
+        include_pubkey,
+        integrity,
+    })
+}
+
+// This is a batch of quote requests from a verifier, generated with the TPM
+// held once for the whole batch. The body is a JSON array of entries with
+// nonce, mask and partial, as in the query of the single quote requests,
+// and the response is an array with the result of each entry, in order.
+//
+// Malformed or failing entries get an error object in place of their
+// quote, without failing the rest of the batch. Entries with a mask are
+// integrity quotes, completed with the logs of their PCRs once the TPM is
+// released. Each entry counts as a quote request for the rate limit.
+pub async fn batch(
+    req: HttpRequest,
+    body: web::Json<Vec<serde_json::Value>>,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    let ctx = RequestContext::new(&req, &data.agent_uuid);
+    if let Some(response) = client_unauthorized(&req, &data, &ctx) {
+        return response;
+    }
+    let entries = body.into_inner();
+    if entries.is_empty() || entries.len() > QUOTE_BATCH_MAX_SIZE {
+        let message = format!(
+            "Batch must contain between 1 and {} quote requests: {}",
+            QUOTE_BATCH_MAX_SIZE,
+            entries.len()
+        );
+        warn!(
+            "{} Post quote batch returning 400 response. {}",
+            ctx, message
+        );
+        return JsonWrapper::error_with_code(
+            ResponseStatus::BadRequest,
+            "invalid_batch_size",
+            message,
+        )
+        .into_response();
+    }
+
+    // Each entry is a quote, counted as a single quote request
+    if let Some(response) =
+        rate_limited(&req, &data, &ctx, entries.len() as u32)
+    {
+        return response;
+    }
+
+    let mut results = Vec::with_capacity(entries.len());
+    let mut requests = Vec::new();
+    let mut accepted = Vec::new();
+    for entry in entries {
+        match batch_quote_request(entry, &data) {
+            Ok(BatchQuoteRequest {
+                request,
+                include_pubkey,
+                integrity,
+            }) => {
+                accepted.push((
+                    request.nonce.clone(),
+                    include_pubkey,
+                    integrity,
+                ));
+                requests.push(request);
+                results.push(None);
+            }
+            Err(e) => {
+                debug!("{} Rejecting batch quote request: {}", ctx, e);
+                let error_code = e.error_code().unwrap_or("invalid_request");
+                let status = if error_code == "nonce_tracker_full" {
+                    ResponseStatus::ServiceUnavailable
+                } else {
+                    ResponseStatus::BadRequest
+                };
+                results.push(Some(BatchQuoteResult::Error(
+                    JsonWrapper::error_with_code(
+                        status,
+                        error_code,
+                        e.to_string(),
+                    ),
+                )));
+            }
+        }
+    }
+
+    debug!("{} Calling batch of {} quotes", ctx, requests.len());
+
+    let quotes = if requests.is_empty() {
+        Vec::new()
+    } else {
+        match tpm::quote_batch_async(requests, data.clone()).await {
+            Ok(quotes) => quotes,
+            Err(e) => {
+                debug!("{} Unable to retrieve quotes: {:?}", ctx, e);
+                for (nonce, _, _) in &accepted {
+                    data.used_nonces.forget(nonce);
+                }
+                return quote_error_response(&e, &ctx);
+            }
+        }
+    };
+
+    // Fill the entries that were not rejected with their quote, in order
+    let mut quotes = quotes.into_iter().zip(accepted);
+    let mut filled = Vec::with_capacity(results.len());
+    for result in results {
+        if let Some(result) = result {
+            filled.push(result);
+            continue;
+        }
+        let quote = match quotes.next() {
+            Some((quote, (nonce, include_pubkey, integrity))) => {
+                let quote = match (quote, integrity) {
+                    (Ok(quote), Some(params)) => {
+                        async {
+                            let pubkey = quote_pubkey(include_pubkey, &data)?;
+                            complete_integrity_quote(
+                                quote, pubkey, &params, &data,
+                            )
+                            .await
+                        }
+                        .await
+                    }
+                    (quote, _) => quote.and_then(|mut quote| {
+                        quote.pubkey = quote_pubkey(include_pubkey, &data)?;
+                        quote.banks = None;
+                        Ok(quote)
+                    }),
+                };
+                if quote.is_err() {
+                    data.used_nonces.forget(&nonce);
+                }
+                quote
+            }
+            None => {
+                Err(KeylimeError::Other("Missing quote in batch".to_string()))
+            }
+        };
+        filled.push(match quote {
+            Ok(quote) => BatchQuoteResult::Quote(Box::new(quote)),
+            Err(e) => {
+                debug!("{} Unable to retrieve quote: {:?}", ctx, e);
+                BatchQuoteResult::Error(JsonWrapper::error(
+                    ResponseStatus::InternalServerError,
+                    quote_error_message(&e),
+                ))
+            }
+        });
+    }
+
+    info!("{} POST quote batch returning 200 response", ctx);
+    HttpResponse::Ok().json(JsonWrapper::success(filled))
+}
+
+#[cfg(feature = "testing")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_auth::ClientAuthPolicy;
+    use crate::nonce_tracker::NonceTracker;
+    use crate::quote_cache::QuoteCache;
+    use crate::rate_limit::RateLimiter;
+    use crate::{common::API_VERSION, crypto::testing::pkey_pub_from_pem};
+    use actix_web::{http, test, web, App};
+    use std::time::Duration;
+
+    #[actix_rt::test]
+    async fn test_identity() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/identity", API_VERSION),
+                web::get().to(identity),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/identity?nonce=1234567890ABCDEFHIJ",
+                API_VERSION,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<KeylimeQuote> =
+            test::read_body_json(resp).await;
+        assert_eq!(result.results.hash_alg.as_str(), "sha256");
+        assert_eq!(result.results.enc_alg.as_str(), "rsa");
+        assert_eq!(result.results.sign_alg.as_str(), "rsassa");
+        assert!(
+            pkey_pub_from_pem(&result.results.pubkey.unwrap()) //#[allow_ci]
+                .unwrap() //#[allow_ci]
+                .public_eq(&quotedata.pub_key)
+        );
+        assert!(result.results.quote.starts_with('r'));
+
+        let mut context = tpm::lock_context(&quotedata).unwrap(); //#[allow_ci]
+        tpm::testing::check_quote(
+            &mut context,
+            quotedata.ak_handle,
+            &result.results.quote,
+            b"1234567890ABCDEFHIJ",
+        )
+        .expect("unable to verify quote");
+    }
+
+    #[actix_rt::test]
+    async fn test_identity_quote_parts() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/identity", API_VERSION),
+                web::get().to(identity),
+            ))
+            .await;
+
+        // Not included unless requested
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/identity?nonce=1234567890ABCDEFHIJ",
+                API_VERSION,
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let result: JsonWrapper<KeylimeQuote> =
+            test::read_body_json(resp).await;
+        assert!(result.results.quote_parts.is_none());
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/identity?nonce=1234567890ABCDEFHIJ&quote_parts=true",
+                API_VERSION,
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let result: JsonWrapper<KeylimeQuote> =
+            test::read_body_json(resp).await;
+        let parts = result.results.quote_parts.unwrap(); //#[allow_ci]
+        assert_eq!(
+            format!(
+                "r{}:{}:{}",
+                parts.quote_b64, parts.sig_b64, parts.pcr_b64
+            ),
+            result.results.quote
+        );
+
+        // Older schemas cannot carry the parts
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/identity?nonce=1234567890ABCDEFHIJ&quote_parts=true&schema_version=10",
+                API_VERSION,
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status().as_u16(), 400);
+        let result: JsonWrapper<serde_json::Value> =
+            test::read_body_json(resp).await;
+        assert_eq!(result.code, ResponseStatus::BadRequest);
+        assert_eq!(result.error_code.as_deref(), Some("invalid_quote_parts"));
+    }
+
+    #[actix_rt::test]
+    async fn test_identity_partial() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/identity", API_VERSION),
+                web::get().to(identity),
+            ))
+            .await;
+
+        // partial has the same meaning as for integrity quotes
+        for (partial, has_pubkey) in [("0", true), ("1", false)] {
+            let req = test::TestRequest::get()
+                .uri(&format!(
+                    "/{}/quotes/identity?nonce=1234567890ABCDEFHIJ&partial={}",
+                    API_VERSION, partial
+                ))
+                .to_request();
+
+            let resp = test::call_service(&app, req).await;
+            assert!(resp.status().is_success());
+
+            let result: JsonWrapper<KeylimeQuote> =
+                test::read_body_json(resp).await;
+            assert_eq!(result.results.pubkey.is_some(), has_pubkey);
+        }
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/identity?nonce=1234567890ABCDEFHIJ&partial=2",
+                API_VERSION,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status().as_u16(), 400);
+
+        let result: JsonWrapper<serde_json::Value> =
+            test::read_body_json(resp).await;
+        assert_eq!(result.error_code.as_deref(), Some("partial_invalid"));
+    }
+
+    #[actix_rt::test]
+    async fn test_legacy_json() {
+        let fixture = read_to_string(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("test-data/python-agent-quote.json"),
+        )
+        .unwrap(); //#[allow_ci]
+
+        let quote = KeylimeQuote::from_legacy_json(&fixture).unwrap(); //#[allow_ci]
+        assert!(quote.quote.starts_with('r'));
+        assert_eq!(quote.hash_alg, "sha256");
+        assert!(quote.pubkey.is_some());
+        assert_eq!(quote.ima_measurement_list_entry, Some(0));
+        assert_eq!(
+            quote.mb_measurement_list.as_ref().map(Vec::len),
+            Some(64)
+        );
+        assert_eq!(quote.to_legacy_json().unwrap(), fixture); //#[allow_ci]
+
+        // Fields added since are left out
+        let quote = KeylimeQuote {
+            boot_aggregate: Some(vec![0; 32]),
+            num_entries: Some(2),
+            mb_measurement_list_available: Some(true),
+            mb_measurement_list_entry: None,
+            mb_num_entries: None,
+            quote_parts: None,
+            banks: Some(vec![QuoteBank {
+                hash_alg: "sha256".to_string(),
+                pcrs: BTreeMap::new(),
+            }]),
+            ..quote
+        };
+        assert_eq!(quote.to_legacy_json().unwrap(), fixture); //#[allow_ci]
+
+        let quote = KeylimeQuote {
+            ima_measurement_list_encoding: Some(ML_ENCODING_GZIP.to_string()),
+            ..quote
+        };
+        assert!(quote.to_legacy_json().is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_schema_quote() {
+        let quote = KeylimeQuote {
+            quote: "rAQID:BAUG:BwgJ".to_string(),
+            hash_alg: "sha256".to_string(),
+            enc_alg: "rsa".to_string(),
+            sign_alg: "rsassa".to_string(),
+            pubkey: None,
+            ima_measurement_list: Some("ml".to_string()),
+            mb_measurement_list: Some(vec![0, 1, 255]),
+            ima_measurement_list_entry: Some(0),
+            boot_aggregate: Some(vec![0; 32]),
+            num_entries: Some(1),
+            mb_measurement_list_encoding: None,
+            ima_path_filter: None,
+            ima_measurement_list_encoding: None,
+            banks: Some(Vec::new()),
+            mb_measurement_list_available: Some(true),
+            mb_measurement_list_entry: Some(0),
+            mb_num_entries: Some(3),
+            quote_parts: None,
+            ima_signature_failures: None,
+        };
+
+        // The latest schema has all the fields, as derived
+        assert_eq!(
+            serde_json::to_string(
+                &quote.to_schema(QUOTE_SCHEMA_VERSION).unwrap() //#[allow_ci]
+            )
+            .unwrap(), //#[allow_ci]
+            serde_json::to_string(&quote).unwrap() //#[allow_ci]
+        );
+
+        // Older schemas leave the newer fields out
+        let value =
+            serde_json::to_value(quote.to_schema(1).unwrap()).unwrap(); //#[allow_ci]
+        let mut fields = value
+            .as_object()
+            .unwrap() //#[allow_ci]
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<&str>>();
+        fields.sort_unstable();
+        assert_eq!(
+            fields,
+            [
+                "enc_alg",
+                "hash_alg",
+                "ima_measurement_list",
+                "pubkey",
+                "quote",
+                "sign_alg"
+            ]
+        );
+        let value =
+            serde_json::to_value(quote.to_schema(3).unwrap()).unwrap(); //#[allow_ci]
+        assert_eq!(
+            value["mb_measurement_list"],
+            serde_json::json!([0, 1, 255])
+        );
+        assert_eq!(value["boot_aggregate"], "0".repeat(64));
+        assert!(value.get("num_entries").is_none());
+
+        assert!(quote.to_schema(0).is_err());
+        assert!(quote.to_schema(QUOTE_SCHEMA_VERSION + 1).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_quote_error_message() {
+        // A failure to get the public key is told apart from a failure to
+        // generate the quote
+        assert_eq!(
+            quote_error_message(&KeylimeError::PublicKey(
+                "invalid key".to_string()
+            )),
+            "Unable to retrieve public key"
+        );
+        assert_eq!(
+            quote_error_message(&KeylimeError::Other(
+                "quote failed".to_string()
+            )),
+            "Unable to retrieve quote"
+        );
+
+        assert!(quote_includes_pubkey(None).unwrap()); //#[allow_ci]
+        assert!(quote_includes_pubkey(Some("0")).unwrap()); //#[allow_ci]
+        assert!(!quote_includes_pubkey(Some("1")).unwrap()); //#[allow_ci]
+        assert_eq!(
+            quote_includes_pubkey(Some("yes"))
+                .unwrap_err() //#[allow_ci]
+                .error_code(),
+            Some("partial_invalid")
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_identity_banks() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/identity", API_VERSION),
+                web::get().to(identity),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/identity?nonce=1234567890ABCDEFHIJ&banks=sha1,sha256",
+                API_VERSION,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<KeylimeQuote> =
+            test::read_body_json(resp).await;
+        assert_eq!(result.results.hash_alg.as_str(), "sha1");
+        let banks = result.results.banks.unwrap(); //#[allow_ci]
+        let names = banks
+            .iter()
+            .map(|bank| bank.hash_alg.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(names, vec!["sha1", "sha256"]);
+        for bank in &banks {
+            // The NK digest is extended into PCR 16 of each bank
+            let hash_alg =
+                HashAlgorithm::try_from(bank.hash_alg.as_str()).unwrap(); //#[allow_ci]
+            assert_eq!(
+                bank.pcrs.get(&16),
+                Some(
+                    &tpm::testing::expected_pcr16(
+                        &quotedata.pub_key,
+                        hash_alg
+                    )
+                    .unwrap() //#[allow_ci]
+                )
+            );
+        }
+
+        let mut context = tpm::lock_context(&quotedata).unwrap(); //#[allow_ci]
+        tpm::testing::check_quote(
+            &mut context,
+            quotedata.ak_handle,
+            &result.results.quote,
+            b"1234567890ABCDEFHIJ",
+        )
+        .expect("unable to verify quote");
+        drop(context);
+
+        // Without banks, the breakdown is not sent
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/identity?nonce=1234567890ABCDEFHIJ",
+                API_VERSION,
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let result: JsonWrapper<KeylimeQuote> =
+            test::read_body_json(resp).await;
+        assert!(result.results.banks.is_none());
+
+        for query in &[
+            "banks=sha1,sha1",
+            "banks=sha256,md5",
+            "banks=sha256&hash_alg=sha256",
+            "banks=sha256&schema_version=7",
+        ] {
+            let req = test::TestRequest::get()
+                .uri(&format!(
+                    "/{}/quotes/identity?nonce=1234567890ABCDEFHIJ&{}",
+                    API_VERSION, query,
+                ))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+            let result: JsonWrapper<serde_json::Value> =
+                test::read_body_json(resp).await;
+            assert_eq!(result.error_code.as_deref(), Some("invalid_banks"));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_request_context() {
+        let req = test::TestRequest::get()
+            .insert_header((REQUEST_ID_HEADER, "verifier-42.1"))
+            .to_http_request();
+        let ctx = RequestContext::new(&req, "agent");
+        assert_eq!(ctx.to_string(), "[agent=agent request=verifier-42.1]");
+
+        // Ids that could garble the log line are replaced
+        for id in &["", "a b", "id;other", &"a".repeat(65)] {
+            let req = test::TestRequest::get()
+                .insert_header((REQUEST_ID_HEADER, *id))
+                .to_http_request();
+            let ctx = RequestContext::new(&req, "agent");
+            assert_ne!(ctx.request_id, *id);
+            assert!(Uuid::parse_str(&ctx.request_id).is_ok());
+        }
+
+        // Requests without an id get a new one each
+        let req = test::TestRequest::get().to_http_request();
+        assert_ne!(
+            RequestContext::new(&req, "agent").request_id,
+            RequestContext::new(&req, "agent").request_id
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_identity_request_id() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/identity", API_VERSION),
+                web::get().to(identity),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/identity?nonce=1234567890ABCDEFHIJ",
+                API_VERSION,
+            ))
+            .insert_header((REQUEST_ID_HEADER, "verifier-42"))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/integrity", API_VERSION),
+                web::get().to(integrity),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408400&partial=1",
+                API_VERSION,
+            ))
+            .insert_header((REQUEST_ID_HEADER, "verifier-43"))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+    }
+
+    #[actix_rt::test]
+    async fn test_identity_ecc() {
+        let quotedata = web::Data::new(QuoteData::fixture_ecc().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/identity", API_VERSION),
+                web::get().to(identity),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/identity?nonce=1234567890ABCDEFHIJ",
+                API_VERSION,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<KeylimeQuote> =
+            test::read_body_json(resp).await;
+        assert_eq!(result.results.enc_alg.as_str(), "ecc");
+        assert_eq!(result.results.sign_alg.as_str(), "ecdsa");
+        assert!(
+            pkey_pub_from_pem(&result.results.pubkey.unwrap()) //#[allow_ci]
+                .unwrap() //#[allow_ci]
+                .public_eq(&quotedata.pub_key)
+        );
+
+        let mut context = tpm::lock_context(&quotedata).unwrap(); //#[allow_ci]
+        tpm::testing::check_quote(
+            &mut context,
+            quotedata.ak_handle,
+            &result.results.quote,
+            b"1234567890ABCDEFHIJ",
+        )
+        .expect("unable to verify quote");
+    }
+
+    #[actix_rt::test]
+    async fn test_identity_cached() {
+        let quotedata = web::Data::new(QuoteData {
+            identity_quotes: QuoteCache::new(Duration::from_secs(60)),
+            ..QuoteData::fixture().unwrap() //#[allow_ci]
+        });
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/identity", API_VERSION),
+                web::get().to(identity),
+            ))
+            .await;
+
+        let mut quotes = Vec::new();
+        for nonce in ["1234567890ABCDEFHIJ", "1234567890ABCDEFHIJ", "KLMNOP"]
+        {
+            let req = test::TestRequest::get()
+                .uri(&format!(
+                    "/{}/quotes/identity?nonce={}",
+                    API_VERSION, nonce,
+                ))
+                .to_request();
+
+            let resp = test::call_service(&app, req).await;
+            assert!(resp.status().is_success());
+            let result: JsonWrapper<KeylimeQuote> =
+                test::read_body_json(resp).await;
+            quotes.push(result.results.quote);
+        }
+
+        // The TPM was only asked once for the repeated nonce
+        assert_eq!(quotes[0], quotes[1]);
+        assert_ne!(quotes[0], quotes[2]);
+        assert_eq!(quotedata.identity_quotes.misses(), 2);
+        assert_eq!(quotedata.identity_quotes.hits(), 1);
+
+        let mut context = tpm::lock_context(&quotedata).unwrap(); //#[allow_ci]
+        tpm::testing::check_quote(
+            &mut context,
+            quotedata.ak_handle,
+            &quotes[2],
+            b"KLMNOP",
+        )
+        .expect("unable to verify quote");
+    }
+
+    #[actix_rt::test]
+    async fn test_nonce_reused() {
+        let quotedata = web::Data::new(QuoteData {
+            used_nonces: NonceTracker::new(Duration::from_secs(60)),
+            ..QuoteData::fixture().unwrap() //#[allow_ci]
+        });
+        let mut app = test::init_service(
+            App::new()
+                .app_data(quotedata.clone())
+                .route(
+                    &format!("/{}/quotes/identity", API_VERSION),
+                    web::get().to(identity),
+                )
+                .route(
+                    &format!("/{}/quotes/integrity", API_VERSION),
+                    web::get().to(integrity),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/identity?nonce=1234567890ABCDEFHIJ",
+                API_VERSION,
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        // The nonce cannot be quoted again, whatever the quote type
+        for uri in [
+            format!(
+                "/{}/quotes/identity?nonce=1234567890ABCDEFHIJ",
+                API_VERSION,
+            ),
+            format!(
+                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408400&partial=1",
+                API_VERSION,
+            ),
+        ] {
+            let req = test::TestRequest::get().uri(&uri).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+            let result: JsonWrapper<serde_json::Value> =
+                test::read_body_json(resp).await;
+            assert_eq!(result.error_code.as_deref(), Some("nonce_reused"));
+            assert_eq!(result.status, "nonce reused");
+        }
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}/quotes/identity?nonce=KLMNOP", API_VERSION,))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+    }
+
+    #[actix_rt::test]
+    async fn test_client_auth() {
+        let key = crypto::rsa_generate(2048).unwrap(); //#[allow_ci]
+        let verifier = crypto::generate_x509(&key, "verifier").unwrap(); //#[allow_ci]
+        let tenant = crypto::generate_x509(&key, "tenant").unwrap(); //#[allow_ci]
+        let quotedata = web::Data::new(QuoteData {
+            client_auth: ClientAuthPolicy::new(
+                true,
+                Some(verifier.clone()),
+                vec!["verifier".to_string()],
+            )
+            .unwrap(), //#[allow_ci]
+            ..QuoteData::fixture().unwrap() //#[allow_ci]
+        });
+        let mut app = test::init_service(
+            App::new()
+                .app_data(quotedata.clone())
+                .route(
+                    &format!("/{}/quotes/identity", API_VERSION),
+                    web::get().to(identity),
+                )
+                .route(
+                    &format!("/{}/quotes/integrity", API_VERSION),
+                    web::get().to(integrity),
+                ),
+        )
+        .await;
+
+        for uri in [
+            format!(
+                "/{}/quotes/identity?nonce=1234567890ABCDEFHIJ",
+                API_VERSION,
+            ),
+            format!(
+                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408400&partial=1",
+                API_VERSION,
+            ),
+        ] {
+            // Without a client certificate
+            let req = test::TestRequest::get().uri(&uri).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
+
+            // With a certificate not issued by the CA
+            let req = test::TestRequest::get().uri(&uri).to_request();
+            let _ = req
+                .extensions_mut()
+                .insert(PeerCertificate(tenant.clone()));
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
+
+            // With a valid client certificate
+            let req = test::TestRequest::get().uri(&uri).to_request();
+            let _ = req
+                .extensions_mut()
+                .insert(PeerCertificate(verifier.clone()));
+            let resp = test::call_service(&app, req).await;
+            assert!(resp.status().is_success());
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_identity_concurrent() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/identity", API_VERSION),
+                web::get().to(identity),
+            ))
+            .await;
+
+        // The quotes wait for the TPM on blocking threads, so the requests
+        // are all served while the runtime keeps running
+        let requests = (0..8).map(|i| {
+            let req = test::TestRequest::get()
+                .uri(&format!(
+                    "/{}/quotes/identity?nonce=CONCURRENT{}",
+                    API_VERSION, i
+                ))
+                .to_request();
+            test::call_service(&app, req)
+        });
+        let responses = tokio::time::timeout(
+            Duration::from_secs(60),
+            futures::future::join_all(requests),
+        )
+        .await
+        .expect("concurrent identity quotes timed out");
+
+        for resp in responses {
+            assert!(resp.status().is_success());
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_identity_tpm_busy() {
+        let quotedata = web::Data::new(QuoteData {
+            tpm_lock_timeout: Duration::from_millis(100),
+            ..QuoteData::fixture().unwrap() //#[allow_ci]
+        });
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/identity", API_VERSION),
+                web::get().to(identity),
+            ))
+            .await;
+
+        // A request holding the TPM for longer than the deadline
+        let guard = tpm::lock_context(&quotedata).unwrap(); //#[allow_ci]
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/identity?nonce=1234567890ABCDEFHIJ",
+                API_VERSION,
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+        let result: JsonWrapper<serde_json::Value> =
+            test::read_body_json(resp).await;
+        assert_eq!(result.status, "TPM busy");
+
+        // Once released, the TPM is available again
+        drop(guard);
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/identity?nonce=KLMNOPQRSTUVWXYZ",
+                API_VERSION,
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+    }
+
+    #[actix_rt::test]
+    async fn test_quote_rate_limit() {
+        let quotedata = web::Data::new(QuoteData {
+            quote_rate_limiter: RateLimiter::new(0.001, 2),
+            ..QuoteData::fixture().unwrap() //#[allow_ci]
+        });
+        let mut app = test::init_service(
+            App::new()
+                .app_data(quotedata.clone())
+                .route(
+                    &format!("/{}/quotes/identity", API_VERSION),
+                    web::get().to(identity),
+                )
+                .route(
+                    &format!("/{}/quotes/integrity", API_VERSION),
+                    web::get().to(integrity),
+                ),
+        )
+        .await;
+
+        let client = "192.0.2.1:4242".parse().unwrap(); //#[allow_ci]
+        let uris = [
+            format!("/{}/quotes/identity?nonce=1234567890", API_VERSION),
+            format!(
+                "/{}/quotes/integrity?nonce=1234567890&mask=0x408400&partial=0",
+                API_VERSION
+            ),
+            format!("/{}/quotes/identity?nonce=1234567890", API_VERSION),
+        ];
+        let mut statuses = Vec::new();
+        for uri in &uris {
+            let req = test::TestRequest::get()
+                .uri(uri)
+                .peer_addr(client)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            statuses.push(resp.status());
+            if resp.status() == http::StatusCode::TOO_MANY_REQUESTS {
+                assert!(resp.headers().contains_key(header::RETRY_AFTER));
+                let result: JsonWrapper<serde_json::Value> =
+                    test::read_body_json(resp).await;
+                assert_eq!(result.code, ResponseStatus::TooManyRequests);
+            }
+        }
+
+        // Both quote endpoints share the client burst of 2 requests
+        assert!(statuses[0].is_success());
+        assert!(statuses[1].is_success());
+        assert_eq!(statuses[2], http::StatusCode::TOO_MANY_REQUESTS);
+
+        // Other clients are not limited
+        let req = test::TestRequest::get()
+            .uri(&uris[0])
+            .peer_addr("192.0.2.2:4242".parse().unwrap()) //#[allow_ci]
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+    }
+
+    #[actix_rt::test]
+    async fn test_quote_nonce() {
+        // Encoded as "+/8=" with the standard alphabet
+        for nonce in ["-_8", "-_8=", "+/8=", "+/8"] {
+            assert_eq!(
+                quote_nonce(nonce, Some("base64")).unwrap(), //#[allow_ci]
+                [0xfb, 0xff]
+            );
+        }
+        assert_eq!(quote_nonce("abc", None).unwrap(), b"abc"); //#[allow_ci]
+
+        for (nonce, encoding) in [("-_8*", "base64"), ("-_8", "raw")] {
+            let err = quote_nonce(nonce, Some(encoding)).unwrap_err(); //#[allow_ci]
+            assert!(matches!(
+                err,
+                KeylimeError::InvalidParameter {
+                    error_code: "invalid_nonce",
+                    ..
+                }
+            ));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_identity_base64_nonce() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/identity", API_VERSION),
+                web::get().to(identity),
+            ))
+            .await;
+
+        // A binary 20 bytes nonce, using the URL-safe alphabet
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/identity?nonce=-_-_AQIDBAUGBwgJCgsMDQ4PEBE%3D&nonce_encoding=base64",
+                API_VERSION,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<KeylimeQuote> =
+            test::read_body_json(resp).await;
+        let nonce = [
+            0xfb, 0xff, 0xbf, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14,
+            15, 16, 17,
+        ];
+        let mut context = tpm::lock_context(&quotedata).unwrap(); //#[allow_ci]
+        tpm::testing::check_quote(
+            &mut context,
+            quotedata.ak_handle,
+            &result.results.quote,
+            &nonce,
+        )
+        .expect("unable to verify quote");
+        drop(context);
+
+        // The size limit applies to the decoded nonce
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/identity?nonce={}&nonce_encoding=base64",
+                API_VERSION,
+                base64::encode_config(
+                    [0u8; tpm::MAX_NONCE_SIZE + 1],
+                    base64::URL_SAFE_NO_PAD
+                ),
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status().as_u16(), 400);
+
+        let result: JsonWrapper<serde_json::Value> =
+            test::read_body_json(resp).await;
+        assert_eq!(result.error_code.as_deref(), Some("nonce_too_long"));
+
+        // Only the raw encoding is restricted to alphanumeric characters
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/identity?nonce=-_-_AQIDBAUGBwgJCgsMDQ4PEBE",
+                API_VERSION,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status().as_u16(), 400);
+
+        let result: JsonWrapper<serde_json::Value> =
+            test::read_body_json(resp).await;
+        assert_eq!(result.error_code.as_deref(), Some("invalid_nonce"));
+    }
+
+    #[actix_rt::test]
+    async fn test_integrity_pre() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/integrity", API_VERSION),
+                web::get().to(integrity),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408400&vmask=0x808000&partial=0",
+                API_VERSION,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<KeylimeQuote> =
+            test::read_body_json(resp).await;
+        assert_eq!(result.results.hash_alg.as_str(), "sha256");
+        assert_eq!(result.results.enc_alg.as_str(), "rsa");
+        assert_eq!(result.results.sign_alg.as_str(), "rsassa");
+        assert!(
+            pkey_pub_from_pem(&result.results.pubkey.unwrap()) //#[allow_ci]
+                .unwrap() //#[allow_ci]
+                .public_eq(&quotedata.pub_key)
+        );
+
+        let ima_ml_path = &quotedata.ima_ml_path;
+        let ima_ml = read_to_string(ima_ml_path).unwrap(); //#[allow_ci]
+        assert_eq!(
+            result.results.num_entries,
+            Some(ima_ml.lines().count() as u64)
+        );
+        assert_eq!(
+            result.results.ima_measurement_list.unwrap().as_str(), //#[allow_ci]
+            ima_ml
+        );
+        assert!(result.results.quote.starts_with('r'));
+
+        let mut context = tpm::lock_context(&quotedata).unwrap(); //#[allow_ci]
+        tpm::testing::check_quote(
+            &mut context,
+            quotedata.ak_handle,
+            &result.results.quote,
+            b"1234567890ABCDEFHIJ",
+        )
+        .expect("unable to verify quote");
+    }
+
+    #[actix_rt::test]
+    async fn test_integrity_schema_version() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/integrity", API_VERSION),
+                web::get().to(integrity),
+            ))
+            .await;
+
+        // Fields introduced after schema version 1 are omitted
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408400&partial=0&ima_ml_entry=1&schema_version=1",
+                API_VERSION,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<serde_json::Value> =
+            test::read_body_json(resp).await;
+        let fields = result.results.as_object().unwrap(); //#[allow_ci]
+        assert!(fields.contains_key("quote"));
+        assert!(fields.contains_key("ima_measurement_list"));
+        assert!(!fields.contains_key("mb_measurement_list"));
+        assert!(!fields.contains_key("ima_measurement_list_entry"));
+        assert!(!fields.contains_key("num_entries"));
+
+        // The latest schema is used by default
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408400&partial=0&ima_ml_entry=1",
+                API_VERSION,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<serde_json::Value> =
+            test::read_body_json(resp).await;
+        let fields = result.results.as_object().unwrap(); //#[allow_ci]
+        assert!(fields.contains_key("mb_measurement_list"));
+        assert!(fields.contains_key("ima_measurement_list_entry"));
+        assert!(fields.contains_key("num_entries"));
+
+        // Unknown schema versions are rejected
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408400&partial=0&schema_version={}",
+                API_VERSION,
+                QUOTE_SCHEMA_VERSION + 1,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status().as_u16(), 400);
+    }
+
+    #[actix_rt::test]
+    async fn test_integrity_ima_ml_count() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/integrity", API_VERSION),
+                web::get().to(integrity),
+            ))
+            .await;
+
+        let ima_ml = read_to_string(&quotedata.ima_ml_path).unwrap(); //#[allow_ci]
+        let lines: Vec<&str> = ima_ml.lines().collect();
+
+        // Read the second page of two entries
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408400&partial=1&ima_ml_entry=2&ima_ml_count=2",
+                API_VERSION,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<KeylimeQuote> =
+            test::read_body_json(resp).await;
+        assert_eq!(result.results.ima_measurement_list_entry, Some(2));
+        assert_eq!(result.results.num_entries, Some(2));
+        assert_eq!(
+            result.results.ima_measurement_list,
+            Some(format!("{}\n{}\n", lines[2], lines[3]))
+        );
+
+        // Reading from the next entry to be measured returns an empty list
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408400&partial=1&ima_ml_entry={}&ima_ml_count=2",
+                API_VERSION,
+                lines.len(),
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<KeylimeQuote> =
+            test::read_body_json(resp).await;
+        assert_eq!(result.results.ima_measurement_list.as_deref(), Some(""));
+        assert_eq!(result.results.num_entries, Some(0));
+    }
+
+    #[actix_rt::test]
+    async fn test_integrity_ima_ml_entry() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/integrity", API_VERSION),
+                web::get().to(integrity),
+            ))
+            .await;
+
+        // A malformed index is not taken as a request for the whole list
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408400&partial=1&ima_ml_entry=abc",
+                API_VERSION,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status().as_u16(), 400);
+
+        let result: JsonWrapper<serde_json::Value> =
+            test::read_body_json(resp).await;
+        assert!(result.status.contains("ima_ml_entry"));
+        assert_eq!(
+            result.error_code.as_deref(),
+            Some("invalid_ima_ml_entry")
+        );
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408400&partial=1&ima_ml_entry=1",
+                API_VERSION,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<KeylimeQuote> =
+            test::read_body_json(resp).await;
+        assert_eq!(result.results.ima_measurement_list_entry, Some(1));
+    }
+
+    #[actix_rt::test]
+    async fn test_integrity_ima_path_filter() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/integrity", API_VERSION),
+                web::get().to(integrity),
+            ))
+            .await;
+
+        let ima_ml = read_to_string(&quotedata.ima_ml_path).unwrap(); //#[allow_ci]
+        let lines: Vec<&str> = ima_ml.lines().collect();
+        let expected: Vec<&str> = lines
+            .iter()
+            .copied()
+            .filter(|line| line.ends_with(" /bin/sh"))
+            .collect();
+        assert_eq!(expected.len(), 1);
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408400&partial=1&ima_path_filter=/bin/sh",
+                API_VERSION,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<KeylimeQuote> =
+            test::read_body_json(resp).await;
+        assert_eq!(
+            result.results.ima_measurement_list,
+            Some(format!("{}\n", expected[0]))
+        );
+        assert_eq!(
+            result.results.ima_path_filter.as_deref(),
+            Some("/bin/sh")
+        );
+
+        // The cursor still covers the whole log
+        assert_eq!(result.results.ima_measurement_list_entry, Some(0));
+        assert_eq!(result.results.num_entries, Some(lines.len() as u64));
+
+        // The filter must be flagged in the response
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408400&partial=1&ima_path_filter=/bin/sh&schema_version=5",
+                API_VERSION,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_integrity_ima_ml_encoding() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/integrity", API_VERSION),
+                web::get().to(integrity),
+            ))
+            .await;
+
+        let ima_ml = read_to_string(&quotedata.ima_ml_path).unwrap(); //#[allow_ci]
+
+        for (query, reported) in [
+            ("", None),
+            ("&ima_ml_encoding=none", None),
+            ("&ima_ml_encoding=gzip", Some(ML_ENCODING_GZIP)),
+            ("&ima_ml_encoding=zstd", Some(ML_ENCODING_ZSTD)),
+        ] {
+            let req = test::TestRequest::get()
+                .uri(&format!(
+                    "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408400&partial=1{}",
+                    API_VERSION, query,
+                ))
+                .to_request();
+
+            let resp = test::call_service(&app, req).await;
+            assert!(resp.status().is_success());
+
+            let result: JsonWrapper<KeylimeQuote> =
+                test::read_body_json(resp).await;
+            assert_eq!(
+                result.results.ima_measurement_list_encoding.as_deref(),
+                reported
+            );
+            assert_eq!(
+                result.results.num_entries,
+                Some(ima_ml.lines().count() as u64)
+            );
+
+            let ml = result.results.ima_measurement_list.unwrap(); //#[allow_ci]
+            let decoded = match reported {
+                None => ml,
+                Some(_) => {
+                    let compressed = base64::decode(ml).unwrap(); //#[allow_ci]
+                    let decompressed = if reported == Some(ML_ENCODING_GZIP) {
+                        let mut decompressed = Vec::new();
+                        let _ = GzDecoder::new(compressed.as_slice())
+                            .read_to_end(&mut decompressed)
+                            .unwrap(); //#[allow_ci]
+                        decompressed
+                    } else {
+                        zstd::decode_all(compressed.as_slice()).unwrap() //#[allow_ci]
+                    };
+                    String::from_utf8(decompressed).unwrap() //#[allow_ci]
+                }
+            };
+            assert_eq!(decoded, ima_ml);
+        }
+
+        // The encoding must be flagged in the response, and known
+        for query in &[
+            "&ima_ml_encoding=gzip&schema_version=6",
+            "&ima_ml_encoding=lzma",
+        ] {
+            let req = test::TestRequest::get()
+                .uri(&format!(
+                    "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408400&partial=1{}",
+                    API_VERSION, query,
+                ))
+                .to_request();
+
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+            let result: JsonWrapper<serde_json::Value> =
+                test::read_body_json(resp).await;
+            assert_eq!(
+                result.error_code.as_deref(),
+                Some("invalid_ima_ml_encoding")
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_integrity_poisoned_lock() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/integrity", API_VERSION),
+                web::get().to(integrity),
+            ))
+            .await;
+        let uri = format!(
+            "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408400&partial=1",
+            API_VERSION,
+        );
+
+        // The IMA state is recovered from a panic
+        let data = quotedata.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = data.ima_ml.lock();
+            panic!("poisoning the IMA lock"); //#[allow_ci]
+        })
+        .join();
+        assert!(quotedata.ima_ml.is_poisoned());
+
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        // Likewise for the TPM context
+        let data = quotedata.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = data.tpmcontext.lock();
+            panic!("poisoning the TPM lock"); //#[allow_ci]
+        })
+        .join();
+        assert!(quotedata.tpmcontext.is_poisoned());
+
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        // The locks stay poisoned, and keep being recovered
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert!(quotedata.ima_ml.is_poisoned());
+        assert!(quotedata.tpmcontext.is_poisoned());
+    }
+
+    #[actix_rt::test]
+    async fn test_integrity_hash_alg() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/integrity", API_VERSION),
+                web::get().to(integrity),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408400&partial=1&hash_alg=sha384",
+                API_VERSION,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+
+        // Not every TPM has the SHA384 bank allocated
+        if quotedata.pcr_banks.contains(&HashAlgorithm::Sha384) {
+            assert!(resp.status().is_success());
+            let result: JsonWrapper<KeylimeQuote> =
+                test::read_body_json(resp).await;
+            assert_eq!(result.results.hash_alg.as_str(), "sha384");
+        } else {
+            assert_eq!(resp.status().as_u16(), 400);
+            let result: JsonWrapper<serde_json::Value> =
+                test::read_body_json(resp).await;
+            assert!(result.status.contains("PCR bank sha384"));
+        }
+
+        // Unknown algorithms are rejected
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408400&partial=1&hash_alg=md5",
+                API_VERSION,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status().as_u16(), 400);
+    }
+
+    #[actix_rt::test]
+    async fn test_identity_tpm_error() {
+        // The AK only signs with RSASSA, but is claimed to sign with RSAPSS
+        // too, so that the request is passed on to the TPM
+        let quotedata = QuoteData::fixture().unwrap(); //#[allow_ci]
+        let quotedata = web::Data::new(QuoteData {
+            ak_sign_algs: vec![SignAlgorithm::RsaSsa, SignAlgorithm::RsaPss],
+            ..quotedata
+        });
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/identity", API_VERSION),
+                web::get().to(identity),
+            ))
+            .await;
+
+        // The TPM rejects the scheme
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/identity?nonce=1234567890ABCDEFHIJ&sign_scheme=rsapss",
+                API_VERSION,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status().as_u16(), 500);
+
+        let result: JsonWrapper<serde_json::Value> =
+            test::read_body_json(resp).await;
+        assert!(result.status.contains("TPM error 0x"));
+        assert!(result.status.contains("Scheme"));
+    }
+
+    #[actix_rt::test]
+    async fn test_integrity_bundle() {
+        use std::io::Read;
+
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/bundle", API_VERSION),
+                web::get().to(integrity_bundle),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/bundle?nonce=1234567890ABCDEFHIJ&mask=0x408400&partial=0",
+                API_VERSION,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(
+            resp.headers().get(http::header::CONTENT_TYPE).unwrap(), //#[allow_ci]
+            bundle::BUNDLE_CONTENT_TYPE
+        );
+
+        let body = test::read_body(resp).await;
+        let mut archive = tar::Archive::new(body.as_ref());
+        let mut contents = BTreeMap::new();
+        let entries = archive.entries().expect("unable to read bundle");
+        for entry in entries {
+            let mut entry = entry.unwrap(); //#[allow_ci]
+            let name = entry.path().unwrap().to_string_lossy().into_owned(); //#[allow_ci]
+            let mut data = String::new();
+            let _ = entry.read_to_string(&mut data).unwrap(); //#[allow_ci]
+            let _ = contents.insert(name, data);
+        }
+
+        // The manifest is signed with the NK
+        let outcome = crypto::asym_verify(
+            &quotedata.pub_key,
+            &contents[bundle::MANIFEST_ENTRY],
+            &contents[bundle::MANIFEST_SIGNATURE_ENTRY],
+            HashAlgorithm::Sha256,
+        )
+        .unwrap(); //#[allow_ci]
+        assert!(outcome.valid);
+
+        let ima_ml = read_to_string(&quotedata.ima_ml_path).unwrap(); //#[allow_ci]
+        assert_eq!(contents["ascii_runtime_measurements"], ima_ml);
+
+        let mut context = tpm::lock_context(&quotedata).unwrap(); //#[allow_ci]
+        tpm::testing::check_quote(
+            &mut context,
+            quotedata.ak_handle,
+            &contents["quote"],
+            b"1234567890ABCDEFHIJ",
+        )
+        .expect("unable to verify quote");
+    }
+
+    #[actix_rt::test]
+    async fn test_integrity_post() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/integrity", API_VERSION),
+                web::get().to(integrity),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408400&vmask=0x808000&partial=1",
+                API_VERSION,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<KeylimeQuote> =
+            test::read_body_json(resp).await;
+        assert_eq!(result.results.hash_alg.as_str(), "sha256");
+        assert_eq!(result.results.enc_alg.as_str(), "rsa");
+        assert_eq!(result.results.sign_alg.as_str(), "rsassa");
+
+        let ima_ml_path = &quotedata.ima_ml_path;
+        let ima_ml = read_to_string(&ima_ml_path).unwrap(); //#[allow_ci]
+        assert_eq!(
+            result.results.num_entries,
+            Some(ima_ml.lines().count() as u64)
+        );
+        assert_eq!(
+            result.results.ima_measurement_list.unwrap().as_str(), //#[allow_ci]
+            ima_ml
+        );
+        assert!(result.results.quote.starts_with('r'));
+
+        let mut context = tpm::lock_context(&quotedata).unwrap(); //#[allow_ci]
+        tpm::testing::check_quote(
+            &mut context,
+            quotedata.ak_handle,
+            &result.results.quote,
+            b"1234567890ABCDEFHIJ",
+        )
+        .expect("unable to verify quote");
+    }
+
+    #[actix_rt::test]
+    async fn test_integrity_missing_required_pcr() {
+        let quotedata = web::Data::new(QuoteData {
+            required_pcrs: vec![0, 10],
+            ..QuoteData::fixture().unwrap() //#[allow_ci]
+        });
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/integrity", API_VERSION),
+                web::get().to(integrity),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408001&vmask=0x808000&partial=0",
+                API_VERSION,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status().as_u16(), 400);
+
+        let result: JsonWrapper<serde_json::Value> =
+            test::read_body_json(resp).await;
+        assert_eq!(result.status, "mask is missing required PCRs: 10");
+        assert_eq!(
+            result.error_code.as_deref(),
+            Some("missing_required_pcrs")
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_integrity_forbidden_pcr() {
+        let quotedata = web::Data::new(QuoteData {
+            allowed_pcrs: vec![0, 10, 15],
+            ..QuoteData::fixture().unwrap() //#[allow_ci]
+        });
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/integrity", API_VERSION),
+                web::get().to(integrity),
+            ))
+            .await;
+
+        // Only allowed PCRs, and PCR 16 binding the NK which always is
+        for mask in ["0x8401", "0x18401"] {
+            let req = test::TestRequest::get()
+                .uri(&format!(
+                    "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask={}&partial=1",
+                    API_VERSION, mask,
+                ))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert!(resp.status().is_success());
+        }
+
+        // Application PCR 23 selected by the mask or as an additional PCR
+        for query in ["mask=0x800401", "mask=0x401&extra_pcrs=23"] {
+            let req = test::TestRequest::get()
+                .uri(&format!(
+                    "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&{}&partial=1",
+                    API_VERSION, query,
+                ))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status().as_u16(), 400);
+
+            let result: JsonWrapper<serde_json::Value> =
+                test::read_body_json(resp).await;
+            assert_eq!(result.code, ResponseStatus::BadRequest);
+            assert_eq!(result.status, "PCRs are not allowed: 23");
+            assert_eq!(result.error_code.as_deref(), Some("forbidden_pcrs"));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_integrity_error_code() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/integrity", API_VERSION),
+                web::get().to(integrity),
+            ))
+            .await;
+
+        let long_nonce = "a".repeat(tpm::MAX_NONCE_SIZE + 1);
+        for (query, error_code) in [
+            (
+                format!("nonce={}&mask=0x408400&partial=0", long_nonce),
+                "nonce_too_long",
+            ),
+            (
+                "nonce=1234567890&mask=0x408400&partial=2".to_string(),
+                "partial_invalid",
+            ),
+        ] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/{}/quotes/integrity?{}", API_VERSION, query))
+                .to_request();
+
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status().as_u16(), 400);
+
+            // The numeric code and the message are still returned
+            let result: JsonWrapper<serde_json::Value> =
+                test::read_body_json(resp).await;
+            assert_eq!(result.code, ResponseStatus::BadRequest);
+            assert!(!result.status.is_empty());
+            assert_eq!(result.error_code.as_deref(), Some(error_code));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_integrity_invalid_mask() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/integrity", API_VERSION),
+                web::get().to(integrity),
+            ))
+            .await;
+
+        // Not a hexadecimal number, and PCR 24, which does not exist
+        for (mask, error) in [
+            ("0x", "is not a hexadecimal number"),
+            ("0x1000000", "only pcrs 0-23"),
+        ] {
+            let req = test::TestRequest::get()
+                .uri(&format!(
+                    "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask={}&partial=0",
+                    API_VERSION, mask,
+                ))
+                .to_request();
+
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status().as_u16(), 400);
+
+            let result: JsonWrapper<serde_json::Value> =
+                test::read_body_json(resp).await;
+            assert!(result.status.contains(error));
+            assert_eq!(result.error_code.as_deref(), Some("invalid_mask"));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_integrity_mb_unavailable() {
+        let quotedata = web::Data::new(QuoteData {
+            measuredboot_ml_path: Path::new(
+                "/nonexistent/binary_bios_measurements",
+            )
+            .to_path_buf(),
+            ..QuoteData::fixture().unwrap() //#[allow_ci]
+        });
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/integrity", API_VERSION),
+                web::get().to(integrity),
+            ))
+            .await;
+
+        // PCR 0 is quoted, but the log cannot be read
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408001&partial=1",
+                API_VERSION,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<KeylimeQuote> =
+            test::read_body_json(resp).await;
+        assert!(result.results.mb_measurement_list.is_none());
+        assert_eq!(result.results.mb_measurement_list_available, Some(false));
+
+        // Verifiers asking for an older schema do not get the flag
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408001&partial=1&schema_version=8",
+                API_VERSION,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<serde_json::Value> =
+            test::read_body_json(resp).await;
+        let fields = result.results.as_object().unwrap(); //#[allow_ci]
+        assert!(!fields.contains_key("mb_measurement_list_available"));
+
+        // Without PCR 0, the log is not expected at all
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408400&partial=1",
+                API_VERSION,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<KeylimeQuote> =
+            test::read_body_json(resp).await;
+        assert!(result.results.mb_measurement_list_available.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_integrity_non_default_pcrs() {
+        let measuredboot_ml_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/measured_boot/binary_bios_measurements");
+        let quotedata = web::Data::new(QuoteData {
+            measuredboot_ml_path,
+            ima_pcr: PcrSlot::Slot11,
+            measuredboot_pcr: PcrSlot::Slot1,
+            ..QuoteData::fixture().unwrap() //#[allow_ci]
+        });
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/integrity", API_VERSION),
+                web::get().to(integrity),
+            ))
+            .await;
+
+        // PCRs 0 and 10 no longer select the logs
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x401&partial=1",
+                API_VERSION,
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let result: JsonWrapper<KeylimeQuote> =
+            test::read_body_json(resp).await;
+        assert!(result.results.ima_measurement_list.is_none());
+        assert!(result.results.mb_measurement_list.is_none());
+
+        // PCRs 1 and 11 do
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x802&partial=1",
+                API_VERSION,
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let result: JsonWrapper<KeylimeQuote> =
+            test::read_body_json(resp).await;
+        let ima_ml = read_to_string(&quotedata.ima_ml_path).unwrap(); //#[allow_ci]
+        assert_eq!(
+            result.results.ima_measurement_list.as_deref(),
+            Some(ima_ml.as_str())
+        );
+        assert!(result.results.mb_measurement_list.is_some());
+    }
+
+    #[actix_rt::test]
+    async fn test_integrity_extra_pcrs() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/integrity", API_VERSION),
+                web::get().to(integrity),
+            ))
+            .await;
+
+        // PCRs 15 and 22 from the mask, and 7 and 10 in addition
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408400&extra_pcrs=7,10&partial=1",
+                API_VERSION,
+            ))
+            .to_request();
 
-    if param.nonce.len() > tpm::MAX_NONCE_SIZE {
-        warn!("Get quote returning 400 response. Nonce is too long (max size {}): {}",
-              tpm::MAX_NONCE_SIZE,
-              param.nonce.len()
-        );
-        return HttpResponse::BadRequest().json(JsonWrapper::error(
-            400,
-            format!(
-                "Nonce is too long (max size {}): {}",
-                tpm::MAX_NONCE_SIZE,
-                param.nonce
-            ),
-        ));
-    }
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
 
-    debug!("Calling Identity Quote with nonce: {}", param.nonce);
+        let result: JsonWrapper<KeylimeQuote> =
+            test::read_body_json(resp).await;
+        let (_, _, pcrsel, _) =
+            tpm::testing::decode_quote_string(&result.results.quote).unwrap(); //#[allow_ci]
+        let selected = pcrsel.get_selections()[0].selected();
+        for pcr in [
+            PcrSlot::Slot7,
+            PcrSlot::Slot10,
+            PcrSlot::Slot15,
+            PcrSlot::Slot22,
+        ] {
+            assert!(selected.contains(&pcr));
+        }
 
-    let mut quote =
-        match tpm::quote(param.nonce.as_bytes(), None, data.clone()) {
-            Ok(quote) => quote,
-            Err(e) => {
-                debug!("Unable to retrieve quote: {:?}", e);
-                return HttpResponse::InternalServerError().json(
-                    JsonWrapper::error(
-                        500,
-                        "Unable to retrieve quote".to_string(),
-                    ),
-                );
-            }
-        };
+        let mut context = tpm::lock_context(&quotedata).unwrap(); //#[allow_ci]
+        tpm::testing::check_quote(
+            &mut context,
+            quotedata.ak_handle,
+            &result.results.quote,
+            b"1234567890ABCDEFHIJ",
+        )
+        .expect("unable to verify quote");
+        drop(context);
 
-    match crypto::pkey_pub_to_pem(&data.pub_key) {
-        Ok(pubkey) => quote.pubkey = Some(pubkey),
-        Err(e) => {
-            debug!("Unable to retrieve public key for quote: {:?}", e);
-            return HttpResponse::InternalServerError().json(
-                JsonWrapper::error(
-                    500,
-                    "Unable to retrieve quote".to_string(),
-                ),
+        // PCR 24 does not exist, and each index must be a number
+        for (extra_pcrs, error) in [
+            ("7,24", "only pcrs 0-23"),
+            ("7,abc", "is not a PCR index"),
+            ("", "is not a PCR index"),
+        ] {
+            let req = test::TestRequest::get()
+                .uri(&format!(
+                    "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408400&extra_pcrs={}&partial=1",
+                    API_VERSION, extra_pcrs,
+                ))
+                .to_request();
+
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status().as_u16(), 400);
+
+            let result: JsonWrapper<serde_json::Value> =
+                test::read_body_json(resp).await;
+            assert!(result.status.contains(error));
+            assert_eq!(
+                result.error_code.as_deref(),
+                Some("invalid_extra_pcrs")
             );
         }
     }
 
-    let response = JsonWrapper::success(quote);
-    info!("GET identity quote returning 200 response");
-    HttpResponse::Ok().json(response)
-}
+    #[actix_rt::test]
+    async fn test_build_identity_quote() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let param = Ident {
+            nonce: "1234567890ABCDEFHIJ".to_string(),
+            nonce_encoding: None,
+            sign_scheme: None,
+            hash_alg: None,
+            banks: None,
+            partial: None,
+            quote_parts: None,
+            schema_version: None,
+        };
 
-// This is a Quote request from the cloud verifier, which will check
-// integrity measurement. The PCRs included in the Quote will be specified
-// by the mask. It should return this data:
-// { QuoteAIK(nonce, 16:H(NK_pub), xi:yi), NK_pub}
-// where xi:yi are additional PCRs to be included in the quote.
-pub async fn integrity(
-    req: HttpRequest,
-    param: web::Query<Integ>,
-    data: web::Data<QuoteData>,
-) -> impl Responder {
-    // nonce, mask, vmask can only be in alphanumerical format
-    if !param.nonce.chars().all(char::is_alphanumeric) {
-        warn!("Get quote returning 400 response. Parameters should be strictly alphanumeric: {}", param.nonce);
-        return HttpResponse::BadRequest().json(JsonWrapper::error(
-            400,
-            format!("nonce should be strictly alphanumeric: {}", param.nonce),
-        ));
+        let quote = build_identity_quote(
+            &IdentityQuoteParams::parse(&param, &quotedata)
+                .expect("invalid parameters"),
+            &quotedata,
+        )
+        .await
+        .expect("unable to build identity quote");
+        assert_eq!(quote.hash_alg.as_str(), "sha256");
+        assert!(pkey_pub_from_pem(&quote.pubkey.unwrap()) //#[allow_ci]
+            .unwrap() //#[allow_ci]
+            .public_eq(&quotedata.pub_key));
+        assert!(quote.ima_measurement_list.is_none());
+
+        let mut context = tpm::lock_context(&quotedata).unwrap(); //#[allow_ci]
+        tpm::testing::check_quote(
+            &mut context,
+            quotedata.ak_handle,
+            &quote.quote,
+            b"1234567890ABCDEFHIJ",
+        )
+        .expect("unable to verify quote");
     }
 
-    if !param.mask.chars().all(char::is_alphanumeric) {
-        warn!("Get quote returning 400 response. Parameters should be strictly alphanumeric: {}", param.mask);
-        return HttpResponse::BadRequest().json(JsonWrapper::error(
-            400,
-            format!("mask should be strictly alphanumeric: {}", param.mask),
-        ));
+    #[actix_rt::test]
+    async fn test_build_integrity_quote() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let param = Integ {
+            nonce: "1234567890ABCDEFHIJ".to_string(),
+            nonce_encoding: None,
+            mask: "0x408400".to_string(),
+            extra_pcrs: None,
+            partial: "1".to_string(),
+            ima_ml_entry: None,
+            ima_ml_count: None,
+            mb_ml_encoding: None,
+            mb_entry: None,
+            mb_ml_count: None,
+            ima_ml_encoding: None,
+            ima_path_filter: None,
+            sign_scheme: None,
+            hash_alg: None,
+            quote_parts: None,
+            schema_version: None,
+        };
+
+        let quote = build_integrity_quote(
+            &IntegrityQuoteParams::parse(&param, &quotedata)
+                .expect("invalid parameters"),
+            &quotedata,
+        )
+        .await
+        .expect("unable to build integrity quote");
+        assert!(quote.pubkey.is_none());
+        assert!(quote.mb_measurement_list.is_none());
+
+        let ima_ml = read_to_string(&quotedata.ima_ml_path).unwrap(); //#[allow_ci]
+        assert_eq!(
+            quote.ima_measurement_list.unwrap().as_str(), //#[allow_ci]
+            ima_ml
+        );
+        assert_eq!(quote.ima_measurement_list_entry, Some(0));
+
+        let mut context = tpm::lock_context(&quotedata).unwrap(); //#[allow_ci]
+        tpm::testing::check_quote(
+            &mut context,
+            quotedata.ak_handle,
+            &quote.quote,
+            b"1234567890ABCDEFHIJ",
+        )
+        .expect("unable to verify quote");
     }
 
-    if param.nonce.len() > tpm::MAX_NONCE_SIZE {
-        warn!("Get quote returning 400 response. Nonce is too long (max size {}): {}",
-              tpm::MAX_NONCE_SIZE,
-              param.nonce.len()
+    #[actix_rt::test]
+    async fn test_build_integrity_quote_measured_boot() {
+        let measuredboot_ml_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/measured_boot/binary_bios_measurements");
+        let quotedata = web::Data::new(QuoteData {
+            measuredboot_ml_path: measuredboot_ml_path.clone(),
+            ..QuoteData::fixture().unwrap() //#[allow_ci]
+        });
+        // PCRs 0 and 10, so that both lists are read
+        let param = Integ {
+            nonce: "1234567890ABCDEFHIJ".to_string(),
+            nonce_encoding: None,
+            mask: "0x401".to_string(),
+            extra_pcrs: None,
+            partial: "1".to_string(),
+            ima_ml_entry: None,
+            ima_ml_count: None,
+            mb_ml_encoding: None,
+            mb_entry: None,
+            mb_ml_count: None,
+            ima_ml_encoding: None,
+            ima_path_filter: None,
+            sign_scheme: None,
+            hash_alg: None,
+            quote_parts: None,
+            schema_version: None,
+        };
+
+        let quote = build_integrity_quote(
+            &IntegrityQuoteParams::parse(&param, &quotedata)
+                .expect("invalid parameters"),
+            &quotedata,
+        )
+        .await
+        .expect("unable to build integrity quote");
+
+        let mb_ml = read(&measuredboot_ml_path).unwrap(); //#[allow_ci]
+        assert_eq!(quote.mb_measurement_list, Some(mb_ml));
+        assert_eq!(quote.mb_measurement_list_available, Some(true));
+        assert_eq!(
+            quote.boot_aggregate.as_ref().map(hex::encode).as_deref(),
+            Some(
+                "e72aae27d2edc003778c0671859e21f32bc462fa04b6130258bee30817c6ba59"
+            )
         );
-        return HttpResponse::BadRequest().json(JsonWrapper::error(
-            400,
-            format!(
-                "Nonce is too long (max size: {}): {}",
-                tpm::MAX_NONCE_SIZE,
-                param.nonce.len()
-            ),
-        ));
+
+        let ima_ml = read_to_string(&quotedata.ima_ml_path).unwrap(); //#[allow_ci]
+        assert_eq!(quote.ima_measurement_list, Some(ima_ml));
+        assert_eq!(quote.ima_measurement_list_entry, Some(0));
     }
 
-    // If partial="0", include the public key in the quote
-    let pubkey = match &param.partial[..] {
-        "0" => {
-            let pubkey = match crypto::pkey_pub_to_pem(&data.pub_key) {
-                Ok(pubkey) => pubkey,
-                Err(e) => {
-                    debug!("Unable to retrieve public key: {:?}", e);
-                    return HttpResponse::InternalServerError().json(
-                        JsonWrapper::error(
-                            500,
-                            "Unable to retrieve public key".to_string(),
-                        ),
-                    );
-                }
-            };
-            Some(pubkey)
-        }
-        "1" => None,
-        _ => {
-            warn!("Get quote returning 400 response. uri must contain key 'partial' and value '0' or '1'");
-            return HttpResponse::BadRequest().json(JsonWrapper::error(
-                400,
-                "uri must contain key 'partial' and value '0' or '1'"
-                    .to_string(),
-            ));
-        }
-    };
+    #[actix_rt::test]
+    async fn test_read_quote_logs() {
+        let measuredboot_ml_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/measured_boot/binary_bios_measurements");
+        let quotedata = web::Data::new(QuoteData {
+            measuredboot_ml_path: measuredboot_ml_path.clone(),
+            ..QuoteData::fixture().unwrap() //#[allow_ci]
+        });
+        // PCRs 0 and 10, so that both logs are read
+        let param = Integ {
+            nonce: "1234567890ABCDEFHIJ".to_string(),
+            nonce_encoding: None,
+            mask: "0x401".to_string(),
+            extra_pcrs: None,
+            partial: "1".to_string(),
+            ima_ml_entry: None,
+            ima_ml_count: None,
+            mb_ml_encoding: None,
+            mb_entry: None,
+            mb_ml_count: None,
+            ima_ml_encoding: None,
+            ima_path_filter: None,
+            sign_scheme: None,
+            hash_alg: None,
+            quote_parts: None,
+            schema_version: None,
+        };
+        let params = IntegrityQuoteParams::parse(&param, &quotedata)
+            .expect("invalid parameters");
 
-    debug!(
-        "Calling Integrity Quote with nonce: {}, mask: {}",
-        param.nonce, param.mask
-    );
+        let logs = read_quote_logs(&params, &quotedata)
+            .await
+            .expect("unable to read the quote logs");
+        let mb_ml = read(&measuredboot_ml_path).unwrap(); //#[allow_ci]
+        assert_eq!(logs.mb_measurement_list, Some(mb_ml));
+        assert_eq!(logs.mb_measurement_list_available, Some(true));
+        assert!(logs.boot_aggregate.is_some());
+        let ima_ml = read_to_string(&quotedata.ima_ml_path).unwrap(); //#[allow_ci]
+        assert_eq!(logs.ima_measurement_list, Some(ima_ml));
+        assert_eq!(logs.ima_measurement_list_entry, Some(0));
 
-    // If an index was provided, the request is for the entries starting from the given index
-    // (iterative attestation). Otherwise the request is for the whole list.
-    let nth_entry = match &param.ima_ml_entry {
-        None => 0,
-        Some(idx) => idx.parse::<u64>().unwrap_or(0),
-    };
+        // An error reading either log fails the whole read, here the IMA
+        // list whose first entry is larger than allowed
+        drop(quotedata);
+        let quotedata = web::Data::new(QuoteData {
+            measuredboot_ml_path,
+            ima_ml_max_bytes: 1,
+            ..QuoteData::fixture().unwrap() //#[allow_ci]
+        });
+        assert!(read_quote_logs(&params, &quotedata).await.is_err());
+    }
 
-    // Generate the ID quote.
-    let id_quote = match tpm::quote(
-        param.nonce.as_bytes(),
-        Some(&param.mask),
-        data.clone(),
-    ) {
-        Ok(id_quote) => id_quote,
-        Err(e) => {
-            debug!("Unable to retrieve quote: {:?}", e);
-            return HttpResponse::InternalServerError().json(
-                JsonWrapper::error(
-                    500,
-                    "Unable to retrieve quote".to_string(),
-                ),
-            );
-        }
-    };
+    #[actix_rt::test]
+    async fn test_build_integrity_quote_mb_entry() {
+        let measuredboot_ml_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/measured_boot/binary_bios_measurements");
+        let quotedata = web::Data::new(QuoteData {
+            measuredboot_ml_path: measuredboot_ml_path.clone(),
+            ..QuoteData::fixture().unwrap() //#[allow_ci]
+        });
+        let mb_ml = read(&measuredboot_ml_path).unwrap(); //#[allow_ci]
+        let param = |mb_entry: &str, mb_ml_count| Integ {
+            nonce: "1234567890ABCDEFHIJ".to_string(),
+            nonce_encoding: None,
+            mask: "0x1".to_string(),
+            extra_pcrs: None,
+            partial: "1".to_string(),
+            ima_ml_entry: None,
+            ima_ml_count: None,
+            mb_ml_encoding: None,
+            mb_entry: Some(mb_entry.to_string()),
+            mb_ml_count,
+            ima_ml_encoding: None,
+            ima_path_filter: None,
+            sign_scheme: None,
+            hash_alg: None,
+            quote_parts: None,
+            schema_version: None,
+        };
 
-    // If PCR 0 is included in the mask, obtain the measured boot
-    let mut mb_measurement_list = None;
-    match tpm::check_mask(&param.mask, &PcrSlot::Slot0) {
-        Ok(true) => {
-            let measuredboot_ml = read(&data.measuredboot_ml_path);
-            mb_measurement_list = match measuredboot_ml {
-                Ok(ml) => Some(ml),
-                Err(e) => {
-                    warn!(
-                        "TPM2 event log not available: {}",
-                        data.measuredboot_ml_path.display()
-                    );
-                    None
-                }
-            }
-        }
-        Err(e) => {
-            debug!("Unable to check PCR mask: {:?}", e);
-            return HttpResponse::InternalServerError().json(
-                JsonWrapper::error(
-                    500,
-                    "Unable to retrieve quote".to_string(),
-                ),
+        // Two events from the third one, ending at an event boundary
+        let quote = build_integrity_quote(
+            &IntegrityQuoteParams::parse(&param("2", Some(2)), &quotedata)
+                .expect("invalid parameters"),
+            &quotedata,
+        )
+        .await
+        .expect("unable to build integrity quote");
+        let (events, _) = measured_boot::event_range(&mb_ml, 2, 2).unwrap(); //#[allow_ci]
+        assert_eq!(quote.mb_measurement_list.as_deref(), Some(events));
+        assert_eq!(quote.mb_measurement_list_entry, Some(2));
+        assert_eq!(quote.mb_num_entries, Some(2));
+        // The boot aggregate still covers the whole log
+        assert_eq!(
+            quote.boot_aggregate.as_ref().map(hex::encode).as_deref(),
+            Some(
+                "e72aae27d2edc003778c0671859e21f32bc462fa04b6130258bee30817c6ba59"
+            )
+        );
+
+        // The rest of the log, from the cursor returned
+        let quote = build_integrity_quote(
+            &IntegrityQuoteParams::parse(&param("4", None), &quotedata)
+                .expect("invalid parameters"),
+            &quotedata,
+        )
+        .await
+        .expect("unable to build integrity quote");
+        let (head, _) = measured_boot::event_range(&mb_ml, 0, 4).unwrap(); //#[allow_ci]
+        let tail = quote.mb_measurement_list.unwrap(); //#[allow_ci]
+        assert_eq!([head, tail.as_slice()].concat(), mb_ml);
+
+        // Past the end of the log
+        let quote = build_integrity_quote(
+            &IntegrityQuoteParams::parse(&param("100000", None), &quotedata)
+                .expect("invalid parameters"),
+            &quotedata,
+        )
+        .await
+        .expect("unable to build integrity quote");
+        assert_eq!(quote.mb_measurement_list, Some(Vec::new()));
+        assert_eq!(quote.mb_num_entries, Some(0));
+    }
+
+    #[actix_rt::test]
+    async fn test_quote_schema_version() {
+        assert_eq!(
+            quote_schema_version(None).ok(),
+            Some(QUOTE_SCHEMA_VERSION)
+        );
+        assert_eq!(quote_schema_version(Some(1)).ok(), Some(1));
+        for version in [0, QUOTE_SCHEMA_VERSION + 1] {
+            assert_eq!(
+                quote_schema_version(Some(version))
+                    .err()
+                    .and_then(|e| e.error_code()),
+                Some("unsupported_schema_version")
             );
         }
-        _ => (),
+
+        // Errors of the parameter checks get the code of the parameter
+        let e = quote_includes_parts(Some(true), 10)
+            .map_err(invalid_param("invalid_quote_parts"))
+            .err();
+        assert_eq!(
+            e.and_then(|e| e.error_code()),
+            Some("invalid_quote_parts")
+        );
+        let e = quote_includes_pubkey(Some("2"))
+            .map_err(invalid_param("invalid_quote_parts"))
+            .err();
+        assert_eq!(e.and_then(|e| e.error_code()), Some("partial_invalid"));
     }
 
-    // Generate the measurement list
-    let ima_ml_path = &data.ima_ml_path;
-    let (ima_measurement_list, ima_measurement_list_entry, num_entries) =
-        match read_measurement_list(
-            &mut data.ima_ml.lock().unwrap(), //#[allow_ci]
-            ima_ml_path,
-            nth_entry,
-        ) {
-            Ok(result) => result,
-            Err(e) => {
-                debug!("Unable to read measurement list: {:?}", e);
-                return HttpResponse::InternalServerError().json(
-                    JsonWrapper::error(
-                        500,
-                        "Unable to retrieve quote".to_string(),
-                    ),
-                );
-            }
+    #[actix_rt::test]
+    async fn test_quote_mb_entry() {
+        assert_eq!(quote_mb_entry(None, 9).unwrap(), None); //#[allow_ci]
+        assert_eq!(quote_mb_entry(Some("3"), 10).unwrap(), Some(3)); //#[allow_ci]
+        assert!(quote_mb_entry(Some("-1"), 10).is_err());
+        // The range is not flagged in older schema versions
+        assert!(quote_mb_entry(Some("3"), 9).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_build_integrity_quote_measured_boot_gzip() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let measuredboot_ml_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/measured_boot/binary_bios_measurements");
+        let quotedata = web::Data::new(QuoteData {
+            measuredboot_ml_path: measuredboot_ml_path.clone(),
+            ..QuoteData::fixture().unwrap() //#[allow_ci]
+        });
+        let param = Integ {
+            nonce: "1234567890ABCDEFHIJ".to_string(),
+            nonce_encoding: None,
+            mask: "0x401".to_string(),
+            extra_pcrs: None,
+            partial: "1".to_string(),
+            ima_ml_entry: None,
+            ima_ml_count: None,
+            mb_ml_encoding: Some("gzip".to_string()),
+            mb_entry: None,
+            mb_ml_count: None,
+            ima_ml_encoding: None,
+            ima_path_filter: None,
+            sign_scheme: None,
+            hash_alg: None,
+            quote_parts: None,
+            schema_version: None,
         };
 
-    // Generate the final quote based on the ID quote
-    let quote = KeylimeQuote {
-        pubkey,
-        ima_measurement_list,
-        mb_measurement_list,
-        ima_measurement_list_entry,
-        ..id_quote
-    };
+        let quote = build_integrity_quote(
+            &IntegrityQuoteParams::parse(&param, &quotedata)
+                .expect("invalid parameters"),
+            &quotedata,
+        )
+        .await
+        .expect("unable to build integrity quote");
+        assert_eq!(
+            quote.mb_measurement_list_encoding.as_deref(),
+            Some(ML_ENCODING_GZIP)
+        );
 
-    let response = JsonWrapper::success(quote);
-    info!("GET integrity quote returning 200 response");
-    HttpResponse::Ok().json(response)
-}
+        let mb_ml = read(&measuredboot_ml_path).unwrap(); //#[allow_ci]
+        let compressed = quote.mb_measurement_list.unwrap(); //#[allow_ci]
+        assert!(compressed.len() < mb_ml.len());
 
-#[cfg(feature = "testing")]
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::{common::API_VERSION, crypto::testing::pkey_pub_from_pem};
-    use actix_web::{test, web, App};
+        let mut decompressed = Vec::new();
+        let _ = GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap(); //#[allow_ci]
+        assert_eq!(decompressed, mb_ml);
+
+        // The boot aggregate is still computed from the log
+        assert_eq!(
+            quote.boot_aggregate.as_ref().map(hex::encode).as_deref(),
+            Some(
+                "e72aae27d2edc003778c0671859e21f32bc462fa04b6130258bee30817c6ba59"
+            )
+        );
+
+        assert!(quote_mb_ml_encoding(Some("lzma")).is_err());
+    }
 
     #[actix_rt::test]
-    async fn test_identity() {
-        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+    async fn test_mb_ml_encoding() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let mb_ml = read(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("test-data/measured_boot/binary_bios_measurements"),
+        )
+        .expect("unable to read the event log");
+
+        for (name, reported) in [
+            (None, None),
+            (Some(ML_ENCODING_NONE), None),
+            (Some(ML_ENCODING_GZIP), Some(ML_ENCODING_GZIP)),
+            (Some(ML_ENCODING_ZSTD), Some(ML_ENCODING_ZSTD)),
+        ] {
+            let encoding =
+                quote_mb_ml_encoding(name).expect("unsupported encoding");
+            assert_eq!(encoding.name(), reported);
+
+            let encoded = encoding
+                .encode(mb_ml.clone())
+                .expect("unable to encode the event log");
+            let decoded = match encoding {
+                MlEncoding::None => encoded,
+                MlEncoding::Gzip => {
+                    assert!(encoded.len() < mb_ml.len());
+                    let mut decoded = Vec::new();
+                    let _ = GzDecoder::new(encoded.as_slice())
+                        .read_to_end(&mut decoded)
+                        .expect("unable to decode gzip");
+                    decoded
+                }
+                MlEncoding::Zstd => {
+                    assert!(encoded.len() < mb_ml.len());
+                    zstd::decode_all(encoded.as_slice())
+                        .expect("unable to decode zstd")
+                }
+            };
+            assert_eq!(decoded, mb_ml);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_identity_sign_scheme() {
+        let quotedata = QuoteData::fixture().unwrap(); //#[allow_ci]
+
+        // The AK is restricted to the scheme it was created with, so use one
+        // created for RSAPSS while the configured default is RSASSA
+        let (ak_handle, ak_sign_algs) = {
+            let mut context = tpm::lock_context(&quotedata).unwrap(); //#[allow_ci]
+            let (ek_handle, _, _) =
+                tpm::create_ek(&mut context, quotedata.enc_alg.into())
+                    .unwrap(); //#[allow_ci]
+            let (ak_handle, _, _) = tpm::create_ak(
+                &mut context,
+                ek_handle,
+                quotedata.hash_alg.into(),
+                SignAlgorithm::RsaPss.into(),
+            )
+            .unwrap(); //#[allow_ci]
+            let ak_sign_algs =
+                tpm::ak_sign_algs(&mut context, ak_handle).unwrap(); //#[allow_ci]
+            (ak_handle, ak_sign_algs)
+        };
+        assert_eq!(ak_sign_algs, [SignAlgorithm::RsaPss]);
+        let quotedata = web::Data::new(QuoteData {
+            ak_handle,
+            ak_sign_algs,
+            ..quotedata
+        });
+
         let mut app =
             test::init_service(App::new().app_data(quotedata.clone()).route(
                 &format!("/{}/quotes/identity", API_VERSION),
@@ -288,7 +4174,7 @@ mod tests {
 
         let req = test::TestRequest::get()
             .uri(&format!(
-                "/{}/quotes/identity?nonce=1234567890ABCDEFHIJ",
+                "/{}/quotes/identity?nonce=1234567890ABCDEFHIJ&sign_scheme=rsapss",
                 API_VERSION,
             ))
             .to_request();
@@ -298,17 +4184,9 @@ mod tests {
 
         let result: JsonWrapper<KeylimeQuote> =
             test::read_body_json(resp).await;
-        assert_eq!(result.results.hash_alg.as_str(), "sha256");
-        assert_eq!(result.results.enc_alg.as_str(), "rsa");
-        assert_eq!(result.results.sign_alg.as_str(), "rsassa");
-        assert!(
-            pkey_pub_from_pem(&result.results.pubkey.unwrap()) //#[allow_ci]
-                .unwrap() //#[allow_ci]
-                .public_eq(&quotedata.pub_key)
-        );
-        assert!(result.results.quote.starts_with('r'));
+        assert_eq!(result.results.sign_alg.as_str(), "rsapss");
 
-        let mut context = quotedata.tpmcontext.lock().unwrap(); //#[allow_ci]
+        let mut context = tpm::lock_context(&quotedata).unwrap(); //#[allow_ci]
         tpm::testing::check_quote(
             &mut context,
             quotedata.ak_handle,
@@ -316,98 +4194,192 @@ mod tests {
             b"1234567890ABCDEFHIJ",
         )
         .expect("unable to verify quote");
+        drop(context);
+
+        // Schemes the AK cannot sign with are rejected before the TPM is
+        // asked for a quote
+        for scheme in ["ecdsa", "rsassa"] {
+            let req = test::TestRequest::get()
+                .uri(&format!(
+                    "/{}/quotes/identity?nonce=1234567890ABCDEFHIJ&sign_scheme={}",
+                    API_VERSION, scheme,
+                ))
+                .to_request();
+
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status().as_u16(), 400);
+            let result: JsonWrapper<serde_json::Value> =
+                test::read_body_json(resp).await;
+            assert_eq!(
+                result.error_code.as_deref(),
+                Some("invalid_sign_scheme")
+            );
+        }
     }
 
     #[actix_rt::test]
-    async fn test_integrity_pre() {
+    async fn test_batch() {
         let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
         let mut app =
             test::init_service(App::new().app_data(quotedata.clone()).route(
-                &format!("/{}/quotes/integrity", API_VERSION),
-                web::get().to(integrity),
+                &format!("/{}/quotes/batch", API_VERSION),
+                web::post().to(batch),
             ))
             .await;
 
-        let req = test::TestRequest::get()
-            .uri(&format!(
-                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408000&vmask=0x808000&partial=0",
-                API_VERSION,
-            ))
+        let req = test::TestRequest::post()
+            .uri(&format!("/{}/quotes/batch", API_VERSION))
+            .set_json(&serde_json::json!([
+                {"nonce": "1234567890ABCDEFHIJ"},
+                {"nonce": "not-alphanumeric"},
+                {"nonce": "0987654321", "mask": "0x408000", "partial": "1"},
+                {"mask": "0x408000"},
+            ]))
             .to_request();
 
         let resp = test::call_service(&app, req).await;
         assert!(resp.status().is_success());
 
-        let result: JsonWrapper<KeylimeQuote> =
+        let result: JsonWrapper<Vec<BatchQuoteResult>> =
             test::read_body_json(resp).await;
-        assert_eq!(result.results.hash_alg.as_str(), "sha256");
-        assert_eq!(result.results.enc_alg.as_str(), "rsa");
-        assert_eq!(result.results.sign_alg.as_str(), "rsassa");
-        assert!(
-            pkey_pub_from_pem(&result.results.pubkey.unwrap()) //#[allow_ci]
-                .unwrap() //#[allow_ci]
-                .public_eq(&quotedata.pub_key)
-        );
+        assert_eq!(result.results.len(), 4);
 
-        let ima_ml_path = &quotedata.ima_ml_path;
-        let ima_ml = read_to_string(ima_ml_path).unwrap(); //#[allow_ci]
-        assert_eq!(
-            result.results.ima_measurement_list.unwrap().as_str(), //#[allow_ci]
-            ima_ml
-        );
-        assert!(result.results.quote.starts_with('r'));
+        let (identity, integrity) = match &result.results[..] {
+            [BatchQuoteResult::Quote(identity), BatchQuoteResult::Error(invalid_nonce), BatchQuoteResult::Quote(integrity), BatchQuoteResult::Error(malformed)] =>
+            {
+                assert_eq!(invalid_nonce.code, ResponseStatus::BadRequest);
+                assert_eq!(
+                    invalid_nonce.error_code.as_deref(),
+                    Some("invalid_nonce")
+                );
+                assert_eq!(malformed.code, ResponseStatus::BadRequest);
+                assert_eq!(
+                    malformed.error_code.as_deref(),
+                    Some("invalid_request")
+                );
+                (identity, integrity)
+            }
+            results => panic!("Unexpected batch results: {:?}", results), //#[allow_ci]
+        };
 
-        let mut context = quotedata.tpmcontext.lock().unwrap(); //#[allow_ci]
-        tpm::testing::check_quote(
-            &mut context,
-            quotedata.ak_handle,
-            &result.results.quote,
-            b"1234567890ABCDEFHIJ",
-        )
-        .expect("unable to verify quote");
+        assert!(identity.pubkey.is_some());
+        assert!(integrity.pubkey.is_none());
+
+        let mut context = tpm::lock_context(&quotedata).unwrap(); //#[allow_ci]
+        for (quote, nonce) in vec![
+            (identity, &b"1234567890ABCDEFHIJ"[..]),
+            (integrity, &b"0987654321"[..]),
+        ] {
+            tpm::testing::check_quote(
+                &mut context,
+                quotedata.ak_handle,
+                &quote.quote,
+                nonce,
+            )
+            .expect("unable to verify quote");
+        }
     }
 
     #[actix_rt::test]
-    async fn test_integrity_post() {
-        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+    async fn test_batch_integrity_logs() {
+        let measuredboot_ml_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/measured_boot/binary_bios_measurements");
+        let quotedata = web::Data::new(QuoteData {
+            measuredboot_ml_path: measuredboot_ml_path.clone(),
+            ..QuoteData::fixture().unwrap() //#[allow_ci]
+        });
         let mut app =
             test::init_service(App::new().app_data(quotedata.clone()).route(
-                &format!("/{}/quotes/integrity", API_VERSION),
-                web::get().to(integrity),
+                &format!("/{}/quotes/batch", API_VERSION),
+                web::post().to(batch),
             ))
             .await;
 
-        let req = test::TestRequest::get()
-            .uri(&format!(
-                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408000&vmask=0x808000&partial=1",
-                API_VERSION,
-            ))
+        // PCRs 0 and 10, so that both logs are included
+        let req = test::TestRequest::post()
+            .uri(&format!("/{}/quotes/batch", API_VERSION))
+            .set_json(&serde_json::json!([
+                {"nonce": "1234567890ABCDEFHIJ", "mask": "0x401"},
+            ]))
             .to_request();
 
         let resp = test::call_service(&app, req).await;
         assert!(resp.status().is_success());
 
-        let result: JsonWrapper<KeylimeQuote> =
+        let result: JsonWrapper<Vec<BatchQuoteResult>> =
             test::read_body_json(resp).await;
-        assert_eq!(result.results.hash_alg.as_str(), "sha256");
-        assert_eq!(result.results.enc_alg.as_str(), "rsa");
-        assert_eq!(result.results.sign_alg.as_str(), "rsassa");
+        let integrity = match &result.results[..] {
+            [BatchQuoteResult::Quote(integrity)] => integrity,
+            results => panic!("Unexpected batch results: {:?}", results), //#[allow_ci]
+        };
 
-        let ima_ml_path = &quotedata.ima_ml_path;
-        let ima_ml = read_to_string(&ima_ml_path).unwrap(); //#[allow_ci]
+        assert!(integrity.pubkey.is_some());
+        let ima_ml = read_to_string(&quotedata.ima_ml_path).unwrap(); //#[allow_ci]
+        assert_eq!(integrity.ima_measurement_list, Some(ima_ml));
+        assert_eq!(integrity.ima_measurement_list_entry, Some(0));
+        let mb_ml = read(&measuredboot_ml_path).unwrap(); //#[allow_ci]
+        assert_eq!(integrity.mb_measurement_list, Some(mb_ml));
+        assert_eq!(integrity.mb_measurement_list_available, Some(true));
         assert_eq!(
-            result.results.ima_measurement_list.unwrap().as_str(), //#[allow_ci]
-            ima_ml
+            integrity.boot_aggregate.as_ref().map(hex::encode).as_deref(),
+            Some(
+                "e72aae27d2edc003778c0671859e21f32bc462fa04b6130258bee30817c6ba59"
+            )
         );
-        assert!(result.results.quote.starts_with('r'));
 
-        let mut context = quotedata.tpmcontext.lock().unwrap(); //#[allow_ci]
+        let mut context = tpm::lock_context(&quotedata).unwrap(); //#[allow_ci]
         tpm::testing::check_quote(
             &mut context,
             quotedata.ak_handle,
-            &result.results.quote,
+            &integrity.quote,
             b"1234567890ABCDEFHIJ",
         )
         .expect("unable to verify quote");
     }
+
+    #[actix_rt::test]
+    async fn test_batch_rate_limit() {
+        let quotedata = web::Data::new(QuoteData {
+            quote_rate_limiter: RateLimiter::new(0.001, 2),
+            ..QuoteData::fixture().unwrap() //#[allow_ci]
+        });
+        let mut app = test::init_service(
+            App::new()
+                .app_data(quotedata.clone())
+                .route(
+                    &format!("/{}/quotes/batch", API_VERSION),
+                    web::post().to(batch),
+                )
+                .route(
+                    &format!("/{}/quotes/identity", API_VERSION),
+                    web::get().to(identity),
+                ),
+        )
+        .await;
+
+        // A batch of 3 quotes is allowed with a full bucket of 2 tokens,
+        // and uses up more than the client burst
+        let client = "192.0.2.1:4242".parse().unwrap(); //#[allow_ci]
+        let req = test::TestRequest::post()
+            .uri(&format!("/{}/quotes/batch", API_VERSION))
+            .peer_addr(client)
+            .set_json(&serde_json::json!([
+                {"nonce": "1234567890A"},
+                {"nonce": "1234567890B"},
+                {"nonce": "1234567890C"},
+            ]))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/identity?nonce=1234567890",
+                API_VERSION
+            ))
+            .peer_addr(client)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::TOO_MANY_REQUESTS);
+    }
 }
@@ -0,0 +1,216 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Maximum number of clients whose request rate is tracked
+pub(crate) const RATE_LIMIT_MAX_CLIENTS: usize = 1024;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Per-client token bucket rate limiter, guarding the quote endpoints so
+/// that a burst of requests cannot monopolize the TPM.
+///
+/// Each client may send up to `burst` requests at once, refilled at `rate`
+/// requests per second. A rate of 0 disables the limiter. Requests
+/// generating several quotes cost one token per quote.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(rate: f64, burst: u32) -> Self {
+        RateLimiter {
+            rate,
+            burst: f64::from(burst.max(1)),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.rate > 0.0
+    }
+
+    /// Takes `cost` tokens for a request from the client. If the client
+    /// exceeded the limit, returns how long it has to wait before it is
+    /// allowed to send the request.
+    ///
+    /// A request costing more than `burst` is allowed once the bucket is
+    /// full, leaving the client in debt of the difference, so that the
+    /// average rate is still enforced.
+    pub(crate) fn check(
+        &self,
+        client: IpAddr,
+        cost: u32,
+    ) -> Result<(), Duration> {
+        self.check_at(client, cost, Instant::now())
+    }
+
+    fn check_at(
+        &self,
+        client: IpAddr,
+        cost: u32,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+
+        // The buckets hold no state that a panic could leave inconsistent,
+        // so a poisoned lock is simply recovered
+        let mut buckets =
+            self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if !buckets.contains_key(&client)
+            && buckets.len() >= RATE_LIMIT_MAX_CLIENTS
+        {
+            self.evict(&mut buckets, now);
+        }
+
+        let (rate, burst) = (self.rate, self.burst);
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        bucket.tokens =
+            (bucket.tokens + elapsed.as_secs_f64() * rate).min(burst);
+        bucket.updated = now;
+
+        let cost = f64::from(cost);
+        let needed = cost.min(burst);
+        if bucket.tokens >= needed {
+            bucket.tokens -= cost;
+            Ok(())
+        } else {
+            Err(wait_secs((needed - bucket.tokens) / rate))
+        }
+    }
+
+    // Forgets the clients whose bucket is full again, as they are in the
+    // same state as a new client. If none is, the least recently seen
+    // client is forgotten.
+    fn evict(&self, buckets: &mut HashMap<IpAddr, Bucket>, now: Instant) {
+        let (rate, burst) = (self.rate, self.burst);
+        buckets.retain(|_, bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated);
+            bucket.tokens + elapsed.as_secs_f64() * rate < burst
+        });
+
+        if buckets.len() >= RATE_LIMIT_MAX_CLIENTS {
+            let oldest = buckets
+                .iter()
+                .min_by_key(|(_, bucket)| bucket.updated)
+                .map(|(client, _)| *client);
+            if let Some(client) = oldest {
+                let _ = buckets.remove(&client);
+            }
+        }
+    }
+}
+
+// Converts a wait in seconds to a Duration. Tiny rates make for waits too
+// long to be represented, which saturate instead of panicking.
+fn wait_secs(secs: f64) -> Duration {
+    if secs.is_finite() && secs < u64::MAX as f64 {
+        Duration::from_secs_f64(secs)
+    } else {
+        Duration::from_secs(u64::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn client(n: u32) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(n))
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(2.0, 3);
+        let now = Instant::now();
+
+        // A burst is allowed, then the client has to wait for a token
+        for _ in 0..3 {
+            assert!(limiter.check_at(client(1), 1, now).is_ok());
+        }
+        assert_eq!(
+            limiter.check_at(client(1), 1, now),
+            Err(Duration::from_millis(500))
+        );
+
+        // Other clients are not limited
+        assert!(limiter.check_at(client(2), 1, now).is_ok());
+
+        // Tokens are refilled at the configured rate
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.check_at(client(1), 1, later).is_ok());
+        assert!(limiter.check_at(client(1), 1, later).is_err());
+    }
+
+    #[test]
+    fn test_rate_limiter_cost() {
+        let limiter = RateLimiter::new(2.0, 4);
+        let now = Instant::now();
+
+        // Each quote of a batch costs a token
+        assert!(limiter.check_at(client(1), 3, now).is_ok());
+        assert_eq!(
+            limiter.check_at(client(1), 3, now),
+            Err(Duration::from_secs(1))
+        );
+
+        // A batch larger than the burst needs a full bucket, and leaves the
+        // client in debt
+        let later = now + Duration::from_millis(1500);
+        assert!(limiter.check_at(client(1), 8, later).is_ok());
+        assert_eq!(
+            limiter.check_at(client(1), 1, later),
+            Err(Duration::from_millis(2500))
+        );
+    }
+
+    #[test]
+    fn test_rate_limiter_tiny_rate() {
+        let limiter = RateLimiter::new(1e-300, 1);
+        let now = Instant::now();
+        assert!(limiter.check_at(client(1), 1, now).is_ok());
+        assert_eq!(
+            limiter.check_at(client(1), 1, now),
+            Err(Duration::from_secs(u64::MAX))
+        );
+    }
+
+    #[test]
+    fn test_rate_limiter_disabled() {
+        let limiter = RateLimiter::new(0.0, 1);
+        for _ in 0..100 {
+            assert!(limiter.check(client(1), 1).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_rate_limiter_bounded() {
+        let limiter = RateLimiter::new(1.0, 1);
+        let now = Instant::now();
+        for n in 0..=RATE_LIMIT_MAX_CLIENTS as u32 {
+            assert!(limiter.check_at(client(n), 1, now).is_ok());
+        }
+        let buckets = limiter.buckets.lock().unwrap(); //#[allow_ci]
+        assert_eq!(buckets.len(), RATE_LIMIT_MAX_CLIENTS);
+        assert!(buckets.contains_key(&client(RATE_LIMIT_MAX_CLIENTS as u32)));
+    }
+}
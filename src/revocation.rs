@@ -4,18 +4,341 @@
 #[macro_use]
 use log::*;
 
-use crate::common::{KeylimeConfig, REV_CERT};
+use crate::algorithms::HashAlgorithm;
+use crate::common::{KeylimeConfig, REV_CERT, REV_CERT_CACHE};
 use crate::crypto;
 use crate::error::*;
+use crate::metrics::REVOCATION_ACTION_METRICS;
+use crate::revocation_history::{RevocationHistory, RevocationStatus};
 use crate::secure_mount;
 
+use openssl::pkey::{PKey, Public};
+use std::collections::HashSet;
 use std::convert::TryInto;
+use std::ffi::{CString, OsStr};
 use std::fs;
-use std::io::{ErrorKind, Write};
-use std::path::{Path, PathBuf};
+use std::future::Future;
+use std::io::{ErrorKind, Read, Write};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
 use std::process::{Child, Command, Output, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::watch;
 
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::str::FromStr;
+use uuid::Uuid;
+
+// How often a running action is checked for completion
+const ACTION_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Period over which repeated revocation service warnings are coalesced
+const WARN_COALESCE_PERIOD: Duration = Duration::from_secs(60);
+
+// First and maximum delay between attempts to connect to the revocation
+// notifier
+const RECONNECT_DELAY_INITIAL: Duration = Duration::from_secs(1);
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(60);
+
+// How long the 0mq socket waits for a message before checking whether the
+// revocation service is shutting down
+#[cfg(feature = "with-zmq")]
+const RECV_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Coalesces repeated warnings so that a flood of bad messages does not
+/// flood the log
+///
+/// The first occurrence is logged immediately. Further occurrences within
+/// the period are only counted. Once the period is over, the count is
+/// logged by `flush` or by the next warning, whichever comes first.
+#[derive(Debug)]
+struct WarnThrottle {
+    period: Duration,
+    window_start: Option<Instant>,
+    suppressed: u64,
+    last_suppressed: String,
+}
+
+impl WarnThrottle {
+    fn new(period: Duration) -> Self {
+        WarnThrottle {
+            period,
+            window_start: None,
+            suppressed: 0,
+            last_suppressed: String::new(),
+        }
+    }
+
+    /// Logs the message unless it is coalesced
+    fn warn(&mut self, message: &str) {
+        self.warn_at(message, Instant::now())
+    }
+
+    /// Logs how many warnings were coalesced if the period is over
+    fn flush(&mut self) {
+        self.flush_at(Instant::now())
+    }
+
+    fn warn_at(&mut self, message: &str, now: Instant) {
+        self.flush_at(now);
+        if self.window_start.is_some() {
+            self.suppressed += 1;
+            self.last_suppressed = message.to_string();
+        } else {
+            warn!("{}", message);
+            self.window_start = Some(now);
+        }
+    }
+
+    fn flush_at(&mut self, now: Instant) {
+        let start = match self.window_start {
+            Some(start) if now.duration_since(start) >= self.period => start,
+            _ => return,
+        };
+        if self.suppressed > 0 {
+            warn!(
+                "{} ({} similar messages in the last {} seconds)",
+                self.last_suppressed,
+                self.suppressed,
+                now.duration_since(start).as_secs()
+            );
+        }
+        self.window_start = None;
+        self.suppressed = 0;
+    }
+}
+
+// Maximum length of a shebang line, as read by the kernel
+const SHEBANG_MAX_LEN: u64 = 256;
+
+/// How a revocation action is executed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ActionKind {
+    /// Executed directly
+    Native,
+    /// Python module executed through the shim.py helper
+    Python,
+    /// Script other than a Python action, executed with the interpreter
+    /// named in its shebang line along with the optional interpreter
+    /// argument (e.g. `#!/usr/bin/env bash`)
+    Shebang(PathBuf, Option<String>),
+}
+
+// Reads the interpreter, and its optional argument, from the shebang line of
+// a script. Returns None if the file does not start with a shebang.
+fn read_shebang(script: &Path) -> Result<Option<(PathBuf, Option<String>)>> {
+    let mut head = Vec::new();
+    let _ = fs::File::open(script)?
+        .take(SHEBANG_MAX_LEN)
+        .read_to_end(&mut head)?;
+
+    let line = match head.strip_prefix(b"#!") {
+        Some(rest) => rest.split(|b| *b == b'\n').next().unwrap_or(rest),
+        None => return Ok(None),
+    };
+    let line = String::from_utf8_lossy(line);
+    let mut parts = line.trim().splitn(2, char::is_whitespace);
+    let interpreter = match parts.next() {
+        Some(i) if !i.is_empty() => PathBuf::from(i),
+        _ => return Ok(None),
+    };
+    let arg = parts
+        .next()
+        .map(str::trim)
+        .filter(|a| !a.is_empty())
+        .map(String::from);
+    Ok(Some((interpreter, arg)))
+}
+
+/// Get the Python shim path according to the python_shim_path entry from
+/// the configuration file
+///
+/// If the python_shim_path entry is empty, None is returned and the shim
+/// from the actions directory is used, see `resolve_python_shim`.
+pub(crate) fn get_python_shim_path(
+    config: &KeylimeConfig,
+) -> Option<PathBuf> {
+    match config.python_shim_path.trim() {
+        "" => None,
+        path => Some(PathBuf::from(path)),
+    }
+}
+
+/// Returns the shim used to run Python actions: the configured one if any,
+/// otherwise shim.py from the pre-installed actions directory
+pub(crate) fn resolve_python_shim(
+    actions_dir: &Path,
+    python_shim: Option<&Path>,
+) -> PathBuf {
+    match python_shim {
+        Some(path) => path.to_path_buf(),
+        None => actions_dir.join("shim.py"),
+    }
+}
+
+/// Get the interpreter running the Python shim according to the
+/// python_interpreter entry from the configuration file
+///
+/// If the python_interpreter entry is empty, None is returned and the shim
+/// is executed directly, run by the interpreter named in its shebang line.
+pub(crate) fn get_python_interpreter(
+    config: &KeylimeConfig,
+) -> Option<PathBuf> {
+    match config.python_interpreter.trim() {
+        "" => None,
+        path => Some(PathBuf::from(path)),
+    }
+}
+
+/// Shim running the Python revocation actions, and the interpreter running
+/// the shim
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct PythonShim {
+    /// Shim to use instead of shim.py from the actions directory
+    pub path: Option<PathBuf>,
+    /// Interpreter to run the shim with instead of its shebang line
+    pub interpreter: Option<PathBuf>,
+}
+
+impl PythonShim {
+    pub(crate) fn from_config(config: &KeylimeConfig) -> Self {
+        PythonShim {
+            path: get_python_shim_path(config),
+            interpreter: get_python_interpreter(config),
+        }
+    }
+}
+
+/// Names of the revocation actions allowed to run
+///
+/// The denylist applies to every action, pre-installed or provided by the
+/// tenant payload. The allowlist only restricts payload actions: when it is
+/// not empty, payload actions not listed are refused.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct ActionPolicy {
+    allowlist: Vec<String>,
+    denylist: Vec<String>,
+}
+
+impl ActionPolicy {
+    /// Creates a policy from comma-separated lists of action names
+    pub(crate) fn new(allowlist: &str, denylist: &str) -> Self {
+        let names = |list: &str| {
+            list.split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(String::from)
+                .collect()
+        };
+        ActionPolicy {
+            allowlist: names(allowlist),
+            denylist: names(denylist),
+        }
+    }
+
+    pub(crate) fn from_config(config: &KeylimeConfig) -> Self {
+        ActionPolicy::new(
+            &config.revocation_actions_allowlist,
+            &config.revocation_actions_denylist,
+        )
+    }
+
+    fn check_denied(&self, action: &str) -> Result<()> {
+        if self.denylist.iter().any(|name| name == action) {
+            return Err(Error::Other(format!(
+                "revocation action {} is listed in revocation_actions_denylist",
+                action
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_payload_allowed(&self, action: &str) -> Result<()> {
+        if !self.allowlist.is_empty()
+            && !self.allowlist.iter().any(|name| name == action)
+        {
+            return Err(Error::Other(format!(
+                "payload revocation action {} is not listed in revocation_actions_allowlist",
+                action
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// When to run a revocation action again after it failed
+///
+/// Only failures with one of `exit_codes` are transient and retried, after
+/// a delay doubled at each attempt. Actions that could not be started, that
+/// timed out or that failed with another exit code are not retried.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RetryPolicy {
+    max_attempts: u32,
+    delay: Duration,
+    exit_codes: Vec<i32>,
+}
+
+impl RetryPolicy {
+    pub(crate) fn new(
+        max_attempts: u32,
+        delay: Duration,
+        exit_codes: Vec<i32>,
+    ) -> Self {
+        RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            delay,
+            exit_codes,
+        }
+    }
+
+    pub(crate) fn from_config(config: &KeylimeConfig) -> Self {
+        RetryPolicy::new(
+            config.revocation_action_max_attempts,
+            config.revocation_action_retry_delay,
+            config.revocation_action_retry_exit_codes.clone(),
+        )
+    }
+
+    // Returns how long to wait before running the action again after the
+    // given attempt failed with the exit code, or None to give up
+    fn backoff(&self, attempt: u32, exit_code: i32) -> Option<Duration> {
+        if attempt >= self.max_attempts
+            || !self.exit_codes.contains(&exit_code)
+        {
+            return None;
+        }
+        Some(self.delay.saturating_mul(1 << (attempt - 1).min(16)))
+    }
+}
+
+impl Default for RetryPolicy {
+    /// Actions are run once
+    fn default() -> Self {
+        RetryPolicy::new(1, Duration::from_secs(1), vec![75])
+    }
+}
+
+// Action names come from the configuration and from the tenant payload
+// action list, and are joined to the actions directories. Only plain file
+// names are accepted, so that an action cannot point outside of them.
+fn check_action_name(action: &str) -> Result<()> {
+    let mut components = Path::new(action).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) if name == action => Ok(()),
+        _ => Err(Error::Other(format!(
+            "revocation action name {:?} is not a plain file name",
+            action
+        ))),
+    }
+}
 
 /// Lookup for the action to be executed and return the command string
 ///
@@ -24,12 +347,25 @@ use serde_json::Value;
 /// 2. Look for the action in the tenant-provided initial payload
 /// 3. Look for pre-installed Python action
 /// 4. Look for the Python action in the tenant-provided initial payload
+///
+/// Python actions are run through the shim, in which case the command is
+/// the shim path instead of the script path. The shim is the one of
+/// `python_shim` if set, otherwise shim.py from `actions_dir`. Other scripts
+/// starting with a shebang line are run with the interpreter it names.
+///
+/// Actions refused by `policy`, or whose name is not a plain file name, are
+/// reported as errors instead.
 fn lookup_action(
     payload_dir: &Path,
     actions_dir: &Path,
+    python_shim: &PythonShim,
     action: &str,
     allow_payload_actions: bool,
-) -> Result<(String, bool, bool)> {
+    policy: &ActionPolicy,
+) -> Result<(String, ActionKind, bool)> {
+    check_action_name(action)?;
+    policy.check_denied(action)?;
+
     let mut py_action = PathBuf::from(action);
     if !py_action.set_extension("py") {
         return Err(Error::Other(format!(
@@ -57,119 +393,666 @@ fn lookup_action(
         .find(|(path, _, _)| path.exists())
     {
         None => {
+            // Only report the directories searched, not the full paths
+            let mut searched = vec![actions_dir.display().to_string()];
+            if allow_payload_actions {
+                searched.push(payload_dir.display().to_string());
+            }
             return Err(Error::Io(std::io::Error::new(
                 ErrorKind::NotFound,
-                format!("Could not find action {}", action),
+                format!(
+                    "Could not find action {} (searched: {})",
+                    action,
+                    searched.join(", ")
+                ),
             )));
         }
         Some((script, is_python, is_payload)) => {
+            if *is_payload {
+                policy.check_payload_allowed(action)?;
+            }
+            let script_command = format!("{}", script.as_path().display());
             // If the script is python, add the shim to the command.  It is expected to be
-            // installed on pre-installed actions directory.
-            let command = if *is_python {
-                let shim = actions_dir.join("shim.py");
-                format!("{}", shim.as_path().display())
+            // installed on pre-installed actions directory, unless configured otherwise.
+            // Python actions are modules imported by the shim, so they go through it even
+            // if they start with a shebang line.
+            let (command, kind) = if *is_python {
+                let shim = resolve_python_shim(
+                    actions_dir,
+                    python_shim.path.as_deref(),
+                );
+                if !shim.exists() {
+                    return Err(Error::Configuration(format!(
+                        "Could not find python shim at {} to run action {}",
+                        shim.display(),
+                        action
+                    )));
+                }
+                (format!("{}", shim.as_path().display()), ActionKind::Python)
             } else {
-                format!("{}", script.as_path().display())
+                match read_shebang(script)? {
+                    Some((interpreter, arg)) => (
+                        script_command,
+                        ActionKind::Shebang(interpreter, arg),
+                    ),
+                    None => (script_command, ActionKind::Native),
+                }
             };
-            Ok((command, *is_python, *is_payload))
+            Ok((command, kind, *is_payload))
+        }
+    }
+}
+
+// Removes the actions listed more than once, keeping the first occurrence so
+// that the actions from the configuration file keep their precedence. The
+// names are compared once trimmed.
+fn dedup_actions(actions: Vec<&str>) -> Vec<&str> {
+    let mut seen = HashSet::new();
+    actions
+        .into_iter()
+        .map(str::trim)
+        .filter(|action| {
+            if seen.insert(*action) {
+                true
+            } else {
+                warn!("Skipping duplicate revocation action {}", action);
+                false
+            }
+        })
+        .collect()
+}
+
+/// Checks if the tenant payload was decrypted and extracted, which is needed
+/// to run payload actions
+///
+/// The payload is considered ready if the unzipped directory exists and is
+/// not empty. A warning is logged otherwise, and payload actions should not
+/// be looked up.
+fn payload_ready(unzipped: &Path) -> bool {
+    let reason = match unzipped.read_dir() {
+        Ok(mut entries) => {
+            if entries.next().is_some() {
+                return true;
+            }
+            "is empty"
+        }
+        Err(_) => "does not exist",
+    };
+    warn!(
+        "Payload actions requested but {} {}, the tenant payload may not be decrypted yet",
+        unzipped.display(),
+        reason
+    );
+    false
+}
+
+/// Checks that a directory actions are run from cannot be modified by other
+/// users
+///
+/// A group or world writable directory would let anyone in the group add or
+/// replace actions.
+pub(crate) fn check_actions_dir_permissions(dir: &Path) -> Result<()> {
+    let mode = fs::metadata(dir)?.permissions().mode();
+    if mode & 0o022 != 0 {
+        return Err(Error::Other(format!(
+            "refusing to run actions from {}: directory is group or world writable (mode {:o})",
+            dir.display(),
+            mode & 0o777
+        )));
+    }
+    Ok(())
+}
+
+/// Waits for a child process to finish, collecting its output
+///
+/// If the child runs for longer than the timeout it is killed and None is
+/// returned.
+fn wait_with_timeout(
+    mut child: Child,
+    timeout: Duration,
+) -> std::io::Result<Option<Output>> {
+    // Drain the pipes while waiting, otherwise a chatty child blocks once
+    // the pipe buffer is full
+    fn drain<R: Read + Send + 'static>(
+        pipe: Option<R>,
+    ) -> Option<thread::JoinHandle<Vec<u8>>> {
+        pipe.map(|mut pipe| {
+            thread::spawn(move || {
+                let mut buf = Vec::new();
+                let _ = pipe.read_to_end(&mut buf);
+                buf
+            })
+        })
+    }
+    fn collect(reader: Option<thread::JoinHandle<Vec<u8>>>) -> Vec<u8> {
+        reader
+            .and_then(|reader| reader.join().ok())
+            .unwrap_or_default()
+    }
+
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            // The readers are not joined: processes spawned by the action
+            // may still hold the pipes open
+            let _ = child.kill();
+            let _ = child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(ACTION_POLL_INTERVAL);
+    };
+
+    Ok(Some(Output {
+        status,
+        stdout: collect(stdout),
+        stderr: collect(stderr),
+    }))
+}
+
+/// Result of a successfully run revocation action
+#[derive(Debug)]
+pub(crate) struct ActionResult {
+    /// The action name, as listed in the configuration or action_list
+    pub name: String,
+    pub output: Output,
+    /// Whether the action was provided by the tenant payload
+    pub was_payload: bool,
+    /// Structured outcome the action wrote to `KEYLIME_ACTION_RESULT`, if
+    /// any
+    pub result: Option<Value>,
+}
+
+// Name of the file, in the scratch directory, actions write their
+// structured result to
+const ACTION_RESULT_FILE: &str = "result.json";
+
+// Largest structured result read from an action
+const ACTION_RESULT_MAX_SIZE: u64 = 64 * 1024;
+
+// Environment variables set for revocation actions, see action_env
+const ACTION_ENV_VARS: &[&str] = &[
+    "KEYLIME_ACTION_NAME",
+    "KEYLIME_WORK_DIR",
+    "KEYLIME_SCRATCH_DIR",
+    "KEYLIME_ACTION_RESULT",
+    "KEYLIME_REVOKED_AGENT_ID",
+    "KEYLIME_REVOCATION_SEVERITY",
+];
+
+/// Environment variables set for every revocation action, so that actions
+/// do not need to parse the JSON argument for the common fields:
+///
+/// * `KEYLIME_ACTION_NAME` - The action name, as listed in the configuration
+///   or action_list
+/// * `KEYLIME_WORK_DIR` - The agent working directory
+/// * `KEYLIME_SCRATCH_DIR` - A directory created for this run of the action,
+///   to write transient data to; see `ScratchCleanup`
+/// * `KEYLIME_ACTION_RESULT` - The file the action can write a JSON document
+///   to, reporting its outcome; see `read_action_result`
+/// * `KEYLIME_REVOKED_AGENT_ID` - The ID of the agent the revocation message
+///   is about, from its agent_id field
+/// * `KEYLIME_REVOCATION_SEVERITY` - The severity of the revocation, from
+///   the severity_label field of the message, if set
+fn action_env(
+    action: &str,
+    json: &Value,
+    work_dir: &Path,
+    scratch_dir: &Path,
+) -> Vec<(&'static str, String)> {
+    let mut env = vec![
+        ("KEYLIME_ACTION_NAME", action.to_string()),
+        ("KEYLIME_WORK_DIR", work_dir.display().to_string()),
+        ("KEYLIME_SCRATCH_DIR", scratch_dir.display().to_string()),
+        (
+            "KEYLIME_ACTION_RESULT",
+            scratch_dir.join(ACTION_RESULT_FILE).display().to_string(),
+        ),
+    ];
+    for (var, field) in &[
+        ("KEYLIME_REVOKED_AGENT_ID", "agent_id"),
+        ("KEYLIME_REVOCATION_SEVERITY", "severity_label"),
+    ] {
+        match &json[field] {
+            Value::String(s) => env.push((var, s.clone())),
+            Value::Null => {}
+            other => env.push((var, other.to_string())),
+        }
+    }
+    env
+}
+
+// Reads the structured result an action wrote to its result file. No file
+// means no result. As the action itself succeeded, a result that cannot be
+// read is only logged. The file is not followed if it is a symbolic link,
+// so that an action cannot have the agent report the content of other
+// files.
+fn read_action_result(action: &str, path: &Path) -> Option<Value> {
+    let file = match fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!(
+                "Unable to open result of revocation action {}: {}",
+                action, e
+            );
+            return None;
+        }
+    };
+
+    let mut data = Vec::new();
+    if let Err(e) =
+        file.take(ACTION_RESULT_MAX_SIZE + 1).read_to_end(&mut data)
+    {
+        warn!(
+            "Unable to read result of revocation action {}: {}",
+            action, e
+        );
+        return None;
+    }
+    if data.len() as u64 > ACTION_RESULT_MAX_SIZE {
+        warn!(
+            "Result of revocation action {} is larger than {} bytes, ignoring it",
+            action, ACTION_RESULT_MAX_SIZE
+        );
+        return None;
+    }
+
+    match serde_json::from_slice(&data) {
+        Ok(result) => Some(result),
+        Err(e) => {
+            warn!(
+                "Result of revocation action {} is not valid JSON: {}",
+                action, e
+            );
+            None
+        }
+    }
+}
+
+/// When the scratch directory of a revocation action is removed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ScratchCleanup {
+    /// Once the action completed
+    Always,
+    /// Once the action completed successfully, keeping the directory of
+    /// failed actions for inspection
+    OnSuccess,
+    /// Never, leaving the directory to the operator
+    Never,
+}
+
+impl ScratchCleanup {
+    fn should_remove(&self, success: bool) -> bool {
+        match self {
+            ScratchCleanup::Always => true,
+            ScratchCleanup::OnSuccess => success,
+            ScratchCleanup::Never => false,
+        }
+    }
+}
+
+impl FromStr for ScratchCleanup {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "always" => Ok(ScratchCleanup::Always),
+            "on_success" => Ok(ScratchCleanup::OnSuccess),
+            "never" => Ok(ScratchCleanup::Never),
+            other => Err(Error::Configuration(format!(
+                "Invalid revocation_action_scratch_cleanup {}: expected always, on_success or never",
+                other
+            ))),
+        }
+    }
+}
+
+/// Settings for checking revocation messages and running their actions,
+/// built once from the configuration
+#[derive(Debug, Clone)]
+pub(crate) struct RevocationOptions {
+    /// Maximum age of a message, 0 not checking it
+    pub max_age: Duration,
+    /// Digest of the message signature
+    pub signature_digest: HashAlgorithm,
+    /// The size of the secure mount
+    pub secure_size: String,
+    /// Actions from the configuration file
+    pub config_actions: String,
+    /// Location of the pre-installed actions
+    pub actions_dir: PathBuf,
+    pub python_shim: PythonShim,
+    pub allow_payload_actions: bool,
+    /// Names of the actions allowed to run
+    pub action_policy: ActionPolicy,
+    pub work_dir: PathBuf,
+    /// Time each action is allowed to run before being killed
+    pub action_timeout: Duration,
+    /// When to run an action again after a transient failure
+    pub retry_policy: RetryPolicy,
+    /// When to remove the scratch directory of each action
+    pub scratch_cleanup: ScratchCleanup,
+    /// Refuse to run actions from group or world writable directories
+    pub check_dir_permissions: bool,
+    /// Run the remaining actions when one fails
+    pub allow_action_failures: bool,
+    /// Maximum number of actions run in parallel; 1 runs them one after
+    /// the other
+    pub max_concurrent_actions: usize,
+    /// Give the actions the signed message instead of its content
+    pub raw_message_to_actions: bool,
+}
+
+impl RevocationOptions {
+    pub(crate) fn from_config(config: &KeylimeConfig) -> Self {
+        RevocationOptions {
+            max_age: config.revocation_max_age,
+            signature_digest: config.revocation_signature_digest,
+            secure_size: config.secure_size.clone(),
+            config_actions: config.revocation_actions.clone(),
+            actions_dir: PathBuf::from(config.revocation_actions_dir.trim()),
+            python_shim: PythonShim::from_config(config),
+            allow_payload_actions: config.allow_payload_revocation_actions,
+            action_policy: ActionPolicy::from_config(config),
+            work_dir: PathBuf::from(&config.work_dir),
+            action_timeout: config.revocation_action_timeout,
+            retry_policy: RetryPolicy::from_config(config),
+            scratch_cleanup: config.revocation_action_scratch_cleanup,
+            check_dir_permissions: config
+                .check_revocation_actions_dir_permissions,
+            allow_action_failures: config.allow_revocation_action_failures,
+            max_concurrent_actions: config.revocation_actions_max_concurrency,
+            raw_message_to_actions: config.revocation_actions_raw_message,
         }
     }
 }
 
+// Prefix of the scratch directories created in the work directory
+const SCRATCH_DIR_PREFIX: &str = "action-scratch.";
+
+// Creates a new scratch directory for a run of the action, only accessible
+// to the agent user. The action name is only kept in the directory name as
+// a hint, with the characters not allowed in a file name replaced.
+fn create_scratch_dir(work_dir: &Path, action: &str) -> Result<PathBuf> {
+    let name = action
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect::<String>();
+    let dir = work_dir.join(format!(
+        "{}{}.{}",
+        SCRATCH_DIR_PREFIX,
+        name,
+        Uuid::new_v4()
+    ));
+    fs::create_dir(&dir)?;
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+    Ok(dir)
+}
+
 /// Runs a script with a json value as argument (used for revocation actions)
+///
+/// The action is given `raw_message` instead of `json` when it is set, e.g.
+/// to receive the signed revocation message rather than its content.
+///
+/// The action gets a new scratch directory, removed once it completed
+/// according to the options. The duration and outcome of the run are
+/// recorded in `REVOCATION_ACTION_METRICS`.
+///
+/// An action failing with one of the retried exit codes is run again,
+/// in the same scratch directory and with the same JSON file, until it
+/// succeeds or the attempts are exhausted.
 pub(crate) fn run_action(
+    options: &RevocationOptions,
     payload_dir: &Path,
-    actions_dir: &Path,
     action: &str,
     json: Value,
+    raw_message: Option<Value>,
     allow_payload_actions: bool,
-    work_dir: &Path,
-) -> Result<Output> {
+) -> Result<ActionResult> {
+    let start = Instant::now();
+    let result = create_scratch_dir(&options.work_dir, action).and_then(
+        |scratch_dir| {
+            let result = execute_action(
+                options,
+                payload_dir,
+                action,
+                json,
+                raw_message,
+                allow_payload_actions,
+                &scratch_dir,
+            );
+            if options.scratch_cleanup.should_remove(result.is_ok()) {
+                remove_scratch_dir(&scratch_dir, action);
+            }
+            result
+        },
+    );
+    REVOCATION_ACTION_METRICS.record(action, start.elapsed(), result.is_ok());
+    result
+}
+
+// Removes the scratch directory of an action. Symbolic links the action
+// left in the directory are removed, not followed.
+fn remove_scratch_dir(scratch_dir: &Path, action: &str) {
+    if let Err(e) = fs::remove_dir_all(scratch_dir) {
+        warn!(
+            "Unable to remove scratch directory {} of action {}: {}",
+            scratch_dir.display(),
+            action,
+            e
+        );
+    }
+}
+
+fn execute_action(
+    options: &RevocationOptions,
+    payload_dir: &Path,
+    action: &str,
+    json: Value,
+    raw_message: Option<Value>,
+    allow_payload_actions: bool,
+    scratch_dir: &Path,
+) -> Result<ActionResult> {
+    let actions_dir = options.actions_dir.as_path();
+    let python_shim = &options.python_shim;
+    let work_dir = options.work_dir.as_path();
+    let retry = &options.retry_policy;
+
     // Lookup for command and get command line
-    let (command, is_python, is_payload) = lookup_action(
+    let (command, kind, is_payload) = lookup_action(
         payload_dir,
         actions_dir,
+        python_shim,
         action,
         allow_payload_actions,
+        &options.action_policy,
     )?;
 
     info!("Executing revocation action {}", action);
 
-    // Write JSON argument to a temporary file
-    let raw_json = serde_json::value::to_raw_value(&json)?;
+    let env = action_env(action, &json, work_dir, scratch_dir);
+
+    // Write JSON argument to a temporary file. The file is created with a
+    // random name and O_EXCL, so concurrent actions never share a file.
+    // The environment is still derived from the message content when the
+    // action is given the raw signed message.
+    let raw_json = serde_json::value::to_raw_value(
+        raw_message.as_ref().unwrap_or(&json),
+    )?;
     let mut json_dump = tempfile::NamedTempFile::new_in(work_dir)?;
     json_dump.write_all(raw_json.get().as_bytes());
 
     //TODO check if it is possible to not keep the file when passing to another process
     let (json_dump, json_path) = json_dump.keep()?;
 
-    let child = if is_python {
-        let python_path = if is_payload { payload_dir } else { actions_dir };
-
-        Command::new(command)
-            .arg(action)
-            .arg(&json_path)
-            .current_dir(work_dir)
-            .env("PYTHONPATH", python_path)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()?
-    } else {
-        Command::new(command)
-            .arg(&json_path)
-            .current_dir(work_dir)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()?
+    let mut cmd = match &kind {
+        ActionKind::Python => {
+            let python_path =
+                if is_payload { payload_dir } else { actions_dir };
+            // The shim is run by its shebang line unless an interpreter is
+            // configured, which does not need the shim to be executable
+            let mut cmd = match &python_shim.interpreter {
+                Some(interpreter) => {
+                    let mut cmd = Command::new(interpreter);
+                    let _ = cmd.arg(command);
+                    cmd
+                }
+                None => Command::new(command),
+            };
+            let _ = cmd.arg(action).env("PYTHONPATH", python_path);
+            cmd
+        }
+        ActionKind::Shebang(interpreter, arg) => {
+            let mut cmd = Command::new(interpreter);
+            let _ = cmd.args(arg).arg(command);
+            cmd
+        }
+        ActionKind::Native => Command::new(command),
+    };
+    // Variables inherited from the agent environment must not reach the
+    // action when the message does not set them
+    for var in ACTION_ENV_VARS {
+        let _ = cmd.env_remove(var);
+    }
+    let _ = cmd
+        .arg(&json_path)
+        .envs(env)
+        .current_dir(work_dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    // The JSON file is kept across attempts and removed once the action
+    // succeeded or gave up. The result file of a failed attempt is not
+    // taken for the one of the next attempt.
+    let result_path = scratch_dir.join(ACTION_RESULT_FILE);
+    let mut attempt = 1;
+    let result = loop {
+        let _ = fs::remove_file(&result_path);
+        let result = spawn_action(&mut cmd, action, options.action_timeout);
+        let delay = match &result {
+            Err(Error::Script(_, Some(code), _)) => {
+                retry.backoff(attempt, *code)
+            }
+            _ => None,
+        };
+        match delay {
+            Some(delay) => {
+                if let Err(e) = &result {
+                    warn!(
+                        "Revocation action {} failed (attempt {} of {}), retrying in {}ms: {}",
+                        action,
+                        attempt,
+                        retry.max_attempts,
+                        delay.as_millis(),
+                        e
+                    );
+                }
+                thread::sleep(delay);
+                attempt += 1;
+            }
+            None => break result,
+        }
     };
+    fs::remove_file(json_path)?;
+    let output = result?;
+
+    info!("INFO: revocation action {} successful", action);
+
+    Ok(ActionResult {
+        name: String::from(action),
+        output,
+        was_payload: is_payload,
+        result: read_action_result(action, &result_path),
+    })
+}
+
+// Runs the action command once, turning a failure to start, a timeout or
+// a failure exit status into an Error::Script
+fn spawn_action(
+    cmd: &mut Command,
+    action: &str,
+    timeout: Duration,
+) -> Result<Output> {
+    let child = cmd.spawn().map_err(|err| {
+        Error::Script(
+            String::from(action),
+            None,
+            format!("failed to start: {}", err),
+        )
+    })?;
 
-    let output = match child.wait_with_output() {
-        Ok(output) => {
-            fs::remove_file(json_path)?;
-            output
+    let output = match wait_with_timeout(child, timeout) {
+        Ok(Some(output)) => output,
+        Ok(None) => {
+            return Err(Error::Script(
+                String::from(action),
+                None,
+                format!("timed out after {}s", timeout.as_secs()),
+            ));
         }
         Err(err) => {
-            fs::remove_file(json_path)?;
-            return Err(err.try_into()?);
+            return Err(Error::Script(
+                String::from(action),
+                None,
+                err.to_string(),
+            ));
         }
     };
 
     if !output.status.success() {
-        return Err(output.try_into()?);
+        let err = Error::from(output);
+        return Err(Error::Script(
+            String::from(action),
+            err.exe_code(),
+            err.stderr(),
+        ));
     }
 
-    info!("INFO: revocation action {} successful", action);
-
     Ok(output)
 }
 
 /// Runs revocation actions received from tenant post-attestation
 ///
-/// An OK result indicates all actions were run successfully.
+/// An OK result indicates all actions were run successfully, and contains
+/// the result of each action in the order they were run.
 /// Otherwise, an Error will be returned from the first action that
-/// did not run successfully.
+/// did not run successfully, and the remaining actions are not run.
+/// When the options allow action failures, failing actions are logged and
+/// the remaining actions are still run; if any failed, an
+/// `Error::RevocationActions` is returned once all actions were attempted,
+/// holding the results of the successful actions and the failures.
 ///
 /// # Arguments
 ///
 /// * `json` - The revocation message content
-/// * `secure_size` - The size of the secure mount
-/// * `config_actions` - Actions from the configuration file
-/// * `actions_dir` - Location of the pre-installed actions
+/// * `raw_message` - The signed revocation message, including its
+///   signature, given to the actions instead of `json` when set
+/// * `options` - Where the actions are and how they are run
 pub(crate) fn run_revocation_actions(
     json: Value,
-    secure_size: &str,
-    config_actions: &str,
-    actions_dir: &Path,
-    allow_payload_actions: bool,
-    work_dir: &Path,
-) -> Result<Vec<Output>> {
-    let mount = secure_mount::mount(work_dir, secure_size)?;
+    raw_message: Option<Value>,
+    options: &RevocationOptions,
+) -> Result<Vec<ActionResult>> {
+    let mount = secure_mount::mount(&options.work_dir, &options.secure_size)?;
 
     // The actions from the configuration file takes precedence over the actions from the
     // actions_list file
-    let mut action_list = config_actions
+    let mut action_list = options
+        .config_actions
         .split(',')
         .map(|script| script.trim())
         .filter(|script| !script.is_empty())
@@ -180,8 +1063,14 @@ pub(crate) fn run_revocation_actions(
     let action_file = unzipped.join("action_list");
 
     if action_file.exists() {
-        action_data = std::fs::read_to_string(&action_file)
-            .expect("unable to read action_list");
+        // The secure mount may be full or unmounted under us: report it
+        // rather than bringing down the revocation service
+        action_data = fs::read_to_string(&action_file).map_err(|source| {
+            Error::ActionList {
+                path: action_file.clone(),
+                source,
+            }
+        })?;
 
         let file_actions = action_data
             .split('\n')
@@ -193,58 +1082,331 @@ pub(crate) fn run_revocation_actions(
         warn!("WARNING: no action_list found in secure directory");
     }
 
-    let mut outputs = Vec::new();
+    let action_list = dedup_actions(action_list);
+
+    let allow_payload_actions =
+        options.allow_payload_actions && payload_ready(&unzipped);
+
+    if options.check_dir_permissions {
+        let mut dirs = vec![options.actions_dir.as_path()];
+        if allow_payload_actions {
+            dirs.push(&unzipped);
+        }
+        for dir in dirs {
+            check_actions_dir_permissions(dir).map_err(|e| {
+                error!("{}", e);
+                e
+            })?;
+        }
+    }
+
+    let mut results = Vec::new();
+    let mut failures = Vec::new();
 
-    if !action_list.is_empty() {
-        for action in action_list {
-            match run_action(
+    if action_list.len() > 1 && options.max_concurrent_actions > 1 {
+        // The actions are run by a pool of up to max_concurrent_actions
+        // threads, and their results are handled in the order the actions
+        // are listed
+        let action_results = run_actions_concurrently(
+            options,
+            &unzipped,
+            &action_list,
+            &json,
+            raw_message.as_ref(),
+            allow_payload_actions,
+        );
+        for (action, result) in action_list.iter().zip(action_results) {
+            handle_action_result(
+                action,
+                result,
+                options.allow_action_failures,
+                &mut results,
+                &mut failures,
+            )?;
+        }
+    } else if !action_list.is_empty() {
+        for action in &action_list {
+            let result = run_action(
+                options,
                 &unzipped,
-                actions_dir,
                 action,
                 json.clone(),
+                raw_message.clone(),
                 allow_payload_actions,
-                work_dir,
-            ) {
-                Ok(output) => {
-                    outputs.push(output);
-                }
-                Err(e) => {
-                    let msg = format!(
-                        "error executing revocation script {}: {:?}",
-                        action, e
-                    );
-                    error!("{}", msg);
-                    return Err(Error::Script(
-                        String::from(action),
-                        e.exe_code()?,
-                        e.stderr()?,
-                    ));
-                }
-            }
+            );
+            handle_action_result(
+                action,
+                result,
+                options.allow_action_failures,
+                &mut results,
+                &mut failures,
+            )?;
         }
     } else {
         warn!("WARNING: no actions found in revocation action list");
     }
 
-    Ok(outputs)
-}
-
-/// Get the revocation certificate path according to the revocation_cert entry
-/// from the configuration file
+    if failures.is_empty() {
+        Ok(results)
+    } else {
+        Err(Error::RevocationActions {
+            total: results.len() + failures.len(),
+            succeeded: results,
+            failures,
+        })
+    }
+}
+
+// Runs the actions on a pool of up to max_concurrent_actions threads, each
+// starting the next action of the list once its previous one is done. The
+// results are returned in the order of the actions. Unless action failures
+// are allowed, no action is started anymore once one failed, and the
+// results end with the first action that was not run.
+fn run_actions_concurrently(
+    options: &RevocationOptions,
+    payload_dir: &Path,
+    actions: &[&str],
+    json: &Value,
+    raw_message: Option<&Value>,
+    allow_payload_actions: bool,
+) -> Vec<Result<ActionResult>> {
+    let actions =
+        Arc::new(actions.iter().map(|a| a.to_string()).collect::<Vec<_>>());
+    let next = Arc::new(AtomicUsize::new(0));
+    let stop = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = mpsc::channel();
+
+    let workers = options.max_concurrent_actions.min(actions.len());
+    let handles = (0..workers)
+        .map(|_| {
+            let options = options.clone();
+            let payload_dir = payload_dir.to_path_buf();
+            let json = json.clone();
+            let raw_message = raw_message.cloned();
+            let (actions, next, stop, tx) =
+                (actions.clone(), next.clone(), stop.clone(), tx.clone());
+            thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    let index = next.fetch_add(1, Ordering::SeqCst);
+                    let action = match actions.get(index) {
+                        Some(action) => action,
+                        None => break,
+                    };
+                    let result = run_action(
+                        &options,
+                        &payload_dir,
+                        action,
+                        json.clone(),
+                        raw_message.clone(),
+                        allow_payload_actions,
+                    )
+                    .map_err(|e| action_failure(action, e));
+                    if result.is_err() && !options.allow_action_failures {
+                        stop.store(true, Ordering::SeqCst);
+                    }
+                    if tx.send((index, result)).is_err() {
+                        break;
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    drop(tx);
+
+    let mut slots = actions.iter().map(|_| None).collect::<Vec<_>>();
+    for (index, result) in rx {
+        slots[index] = Some(result);
+    }
+    let mut panicked = false;
+    for handle in handles {
+        panicked |= handle.join().is_err();
+    }
+
+    // The actions are started in order, so the first one without a result
+    // was either not started, or its thread panicked
+    let mut results = Vec::new();
+    for slot in slots {
+        match slot {
+            Some(result) => {
+                results.push(result.map_err(|(name, code, msg)| {
+                    Error::Script(name, code, msg)
+                }))
+            }
+            None => {
+                if panicked {
+                    results.push(Err(Error::Other(
+                        "revocation action thread panicked".to_string(),
+                    )));
+                }
+                break;
+            }
+        }
+    }
+    results
+}
+
+// Describes a failed action as (name, exit code, message). Unlike Error,
+// this can be sent across threads.
+fn action_failure(action: &str, e: Error) -> (String, Option<i32>, String) {
+    let name = match &e {
+        Error::Script(name, ..) => name.to_owned(),
+        _ => String::from(action),
+    };
+    (name, e.exe_code(), e.stderr())
+}
+
+// Records the result of an action. Failures are returned as an error,
+// unless action failures are allowed.
+fn handle_action_result(
+    action: &str,
+    result: Result<ActionResult>,
+    allow_action_failures: bool,
+    results: &mut Vec<ActionResult>,
+    failures: &mut Vec<Error>,
+) -> Result<()> {
+    match result {
+        Ok(result) => {
+            results.push(result);
+        }
+        Err(e) => {
+            let msg = format!(
+                "error executing revocation script {}: {:?}",
+                action, e
+            );
+            error!("{}", msg);
+            let (name, code, msg) = action_failure(action, e);
+            let e = Error::Script(name, code, msg);
+            if !allow_action_failures {
+                return Err(e);
+            }
+            failures.push(e);
+        }
+    }
+    Ok(())
+}
+
+// Timeout of the requests fetching the revocation certificate
+const REVOCATION_CERT_FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Where a revocation certificate given as an https:// URL is downloaded
+// from, so that the download can be refreshed once it expires
+#[derive(Debug)]
+struct RemoteRevocationCert {
+    url: String,
+    client: reqwest::Client,
+    cache: PathBuf,
+    ttl: Duration,
+}
+
+impl RemoteRevocationCert {
+    // The remote certificate according to the revocation_cert entry from
+    // the configuration file, if it is an https:// URL
+    fn from_config(config: &KeylimeConfig) -> Result<Option<Self>> {
+        let revocation_cert = config.revocation_cert.trim();
+        if revocation_cert.starts_with("http://") {
+            return Err(Error::Configuration(format!(
+                "revocation_cert URL {} must use HTTPS",
+                revocation_cert
+            )));
+        }
+        if !revocation_cert.starts_with("https://") {
+            return Ok(None);
+        }
+
+        Ok(Some(RemoteRevocationCert {
+            url: revocation_cert.to_string(),
+            client: https_client(
+                &config.revocation_cert_ca,
+                REVOCATION_CERT_FETCH_TIMEOUT,
+            )?,
+            cache: Path::new(&config.work_dir).join(REV_CERT_CACHE),
+            ttl: config.revocation_cert_cache_ttl,
+        }))
+    }
+
+    // Downloads the certificate unless the cached download is still fresh.
+    // An expired download is used, with a warning, if the certificate
+    // cannot be downloaded.
+    async fn fetch(&self) -> Result<PathBuf> {
+        match fetch_revocation_cert(
+            &self.client,
+            &self.url,
+            &self.cache,
+            self.ttl,
+        )
+        .await
+        {
+            Err(e) if self.cache.is_file() => {
+                warn!(
+                    "{}; using the expired revocation certificate {}",
+                    e,
+                    self.cache.display()
+                );
+                Ok(self.cache.clone())
+            }
+            result => result,
+        }
+    }
+}
+
+// Downloads the revocation certificate from the URL into the cache file,
+// unless the cache file was downloaded less than ttl ago
+async fn fetch_revocation_cert(
+    client: &reqwest::Client,
+    url: &str,
+    cache: &Path,
+    ttl: Duration,
+) -> Result<PathBuf> {
+    if let Ok(modified) = fs::metadata(cache).and_then(|m| m.modified()) {
+        if modified.elapsed().map(|age| age < ttl).unwrap_or(false) {
+            debug!("Using cached revocation certificate {}", cache.display());
+            return Ok(cache.to_path_buf());
+        }
+    }
+
+    info!("Fetching revocation certificate from {}", url);
+    let fetch_error = |e: String| {
+        Error::Configuration(format!(
+            "Unable to fetch revocation certificate from {}: {}",
+            url, e
+        ))
+    };
+    let resp = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| fetch_error(e.to_string()))?;
+    if !resp.status().is_success() {
+        return Err(fetch_error(format!("received {}", resp.status())));
+    }
+    let cert = resp.bytes().await.map_err(|e| fetch_error(e.to_string()))?;
+
+    // The certificate is checked before it replaces the cached one, which is
+    // replaced at once so that it is never read partially written
+    let cache_dir = cache.parent().unwrap_or_else(|| Path::new("."));
+    let mut cert_file = tempfile::NamedTempFile::new_in(cache_dir)?;
+    cert_file.write_all(&cert)?;
+    let _ = crypto::load_x509(cert_file.path())
+        .map_err(|e| fetch_error(e.to_string()))?;
+    let _ = cert_file.persist(cache)?;
+    Ok(cache.to_path_buf())
+}
+
+/// Get the path of the revocation certificate stored locally, according to
+/// the revocation_cert entry from the configuration file
 ///
 /// If the revocation_cert entry is "default", then use the default path;
 /// If the revocation_cert entry is an absolute path, then use the specified path;
 /// If the revocation_cert entry is a relative path, then expand from the WORK_DIR;
 /// If the revocation_cert is empty, return error.
-pub(crate) fn get_revocation_cert_path(
+pub(crate) fn local_revocation_cert_path(
     config: &KeylimeConfig,
 ) -> Result<PathBuf> {
     let default_path =
         &format!("{}/secure/unzipped/{}", &config.work_dir, REV_CERT);
 
-    // Unlike the python agent we do not attempt lazy loading. We either
-    // have the certificate, or we don't. If we don't have a key or can't load
-    // the key we return a Configuration error as the service will not work.
+    // The certificate itself is only loaded when a revocation message is
+    // received, see RevocationCert.
     let mut cert_path_buf = match config.revocation_cert.trim() {
         "default" => PathBuf::from(&default_path),
         "" => {
@@ -266,460 +1428,3730 @@ pub(crate) fn get_revocation_cert_path(
     Ok(cert_path_buf)
 }
 
-/// Process revocation message received from REST API or 0mq
-pub(crate) fn process_revocation(
-    body: Value,
-    cert_path: &Path,
-    secure_size: &str,
-    config_actions: &str,
-    actions_dir: &Path,
-    allow_payload_revocation_actions: bool,
-    work_dir: &Path,
-) -> Result<()> {
-    // Ensure we have a signature, otherwise continue the loop
-    let signature = match body["signature"].as_str() {
-        Some(v) => v,
-        _ => {
-            warn!("No signature on revocation message from server");
-            return Err(Error::InvalidRequest);
+// Extensions of the files loaded when revocation_cert is a directory
+const REV_CERT_EXTENSIONS: &[&str] = &["pem", "crt"];
+
+// Interval between checks for changes of the revocation certificate when it
+// is watched but cannot be watched with inotify
+const REV_CERT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Whether a file in a revocation certificate directory is a candidate
+// certificate
+fn is_rev_cert_file(name: &OsStr) -> bool {
+    Path::new(name)
+        .extension()
+        .and_then(OsStr::to_str)
+        .map(|ext| {
+            REV_CERT_EXTENSIONS
+                .iter()
+                .any(|e| e.eq_ignore_ascii_case(ext))
+        })
+        .unwrap_or(false)
+}
+
+// Public keys of the revocation certificates along with the paths they were
+// loaded from
+type CachedKeys = Vec<(PathBuf, PKey<Public>)>;
+
+// Revocation certificate public keys along with the certificates they were
+// loaded from and their modification times
+#[derive(Debug)]
+struct CachedCertKeys {
+    modified: Vec<(PathBuf, SystemTime)>,
+    keys: CachedKeys,
+}
+
+/// Revocation certificate whose public key is cached between revocation
+/// messages
+///
+/// The certificate is located according to the revocation_cert entry from
+/// the configuration file, see `local_revocation_cert_path`. If the entry is
+/// an https:// URL, the certificate is downloaded into WORK_DIR instead and
+/// downloaded again by `refresh` once revocation_cert_cache_ttl expires.
+///
+/// The path may also be a directory, in which case every `*.pem` and `*.crt`
+/// certificate in it is a candidate to verify revocation messages, e.g.
+/// while the verifier rotates its signing key.
+///
+/// The keys are loaded on first use, as the certificate may only be
+/// delivered later with the payload, and are reloaded whenever the set of
+/// certificates or their modification times change. `watch_revocation_cert`
+/// can also reload them as soon as a file changes, without waiting for a
+/// revocation message.
+#[derive(Debug)]
+pub(crate) struct RevocationCert {
+    path: PathBuf,
+    remote: Option<RemoteRevocationCert>,
+    cached: Mutex<Option<CachedCertKeys>>,
+}
+
+impl RevocationCert {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        RevocationCert {
+            path,
+            remote: None,
+            cached: Mutex::new(None),
         }
-    };
+    }
 
-    // Ensure we have a msg, otherwise continue the loop
-    let message = match body["msg"].as_str() {
-        Some(v) => v,
-        _ => {
-            warn!("No msg on revocation message from server");
-            return Err(Error::InvalidRequest);
+    /// Locates the revocation certificate according to the configuration,
+    /// downloading it first if it is an https:// URL. A previous download
+    /// is used if the certificate cannot be downloaded.
+    pub(crate) async fn from_config(config: &KeylimeConfig) -> Result<Self> {
+        let remote = RemoteRevocationCert::from_config(config)?;
+        let path = match &remote {
+            Some(remote) => remote.fetch().await?,
+            None => local_revocation_cert_path(config)?,
+        };
+        Ok(RevocationCert {
+            path,
+            remote,
+            cached: Mutex::new(None),
+        })
+    }
+
+    /// Downloads the certificate again if it is an https:// URL and the
+    /// previous download expired. The keys are then reloaded by `keys`, as
+    /// the downloaded file changed.
+    pub(crate) async fn refresh(&self) {
+        if let Some(remote) = &self.remote {
+            if let Err(e) = remote.fetch().await {
+                warn!("{}", e);
+            }
         }
-    };
+    }
 
-    // Canonicalize will fail it the file is not found
-    let cert_absolute_path = cert_path.canonicalize()?;
-    info!(
-        "Loading the revocation certificate from {}",
-        cert_absolute_path.display()
-    );
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
 
-    let cert_key = match crypto::load_x509(&cert_absolute_path) {
-        Ok(v) => v.public_key().map_err(Error::Crypto)?,
-        Err(e) => {
-            return Err(Error::Configuration(String::from(
+    // The candidate certificates, sorted by path, with their modification
+    // times
+    fn cert_files(&self) -> Result<Vec<(PathBuf, SystemTime)>> {
+        let metadata = fs::metadata(&self.path)?;
+        if !metadata.is_dir() {
+            return Ok(vec![(self.path.clone(), metadata.modified()?)]);
+        }
+
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_file() && is_rev_cert_file(&entry.file_name()) {
+                files.push((entry.path(), metadata.modified()?));
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    // Loads the public key of a single certificate
+    fn load_key(path: &Path) -> Result<PKey<Public>> {
+        // Canonicalize will fail it the file is not found
+        let cert_absolute_path = path.canonicalize()?;
+        info!(
+            "Loading the revocation certificate from {}",
+            cert_absolute_path.display()
+        );
+
+        match crypto::load_x509(&cert_absolute_path) {
+            Ok(v) => v.public_key().map_err(Error::Crypto),
+            Err(e) => Err(Error::Configuration(String::from(
                 "Cannot load pubkey from revocation certificate",
-            )))
+            ))),
         }
-    };
+    }
 
-    // Verify the message and signature with our key
-    let mut verified = crypto::asym_verify(&cert_key, message, signature);
-
-    match verified {
-        Ok(true) => {
-            let msg = body["msg"].as_str();
-            let msg_payload: Value = serde_json::from_str(match msg {
-                Some(v) => v,
-                _ => {
-                    warn!("Unable to decode json in msg");
-                    return Err(Error::InvalidRequest);
-                }
-            })?;
-            debug!(
-                "Revocation signature validated for revocation: {}",
-                msg_payload
-            );
-            let outputs = run_revocation_actions(
-                msg_payload,
-                secure_size,
-                config_actions,
-                actions_dir,
-                allow_payload_revocation_actions,
-                work_dir,
-            )?;
+    // Loads and validates the public keys of the certificates
+    fn load(&self) -> Result<CachedCertKeys> {
+        let modified = self.cert_files()?;
+        if !self.path.is_dir() {
+            let keys = modified
+                .iter()
+                .map(|(path, _)| Ok((path.clone(), Self::load_key(path)?)))
+                .collect::<Result<_>>()?;
+            return Ok(CachedCertKeys { modified, keys });
+        }
 
-            for output in outputs {
-                if !output.stdout.is_empty() {
-                    info!(
-                        "Action stdout: {}",
-                        String::from_utf8(output.stdout).unwrap() //#[allow_ci]
-                    );
-                }
-                if !output.stderr.is_empty() {
-                    warn!(
-                        "Action stderr: {}",
-                        String::from_utf8(output.stderr).unwrap() //#[allow_ci])
-                    );
-                }
+        // A certificate that cannot be loaded does not prevent the others
+        // in the directory from being used
+        let mut keys = Vec::new();
+        for (path, _) in &modified {
+            match Self::load_key(path) {
+                Ok(key) => keys.push((path.clone(), key)),
+                Err(e) => warn!(
+                    "Ignoring revocation certificate {}: {}",
+                    path.display(),
+                    e
+                ),
             }
-            Ok(())
         }
-        _ => {
-            error!("Invalid revocation message signature {}", body);
-            Err(Error::InvalidRequest)
+        if keys.is_empty() {
+            return Err(Error::Configuration(format!(
+                "No revocation certificate could be loaded from {}",
+                self.path.display()
+            )));
+        }
+        Ok(CachedCertKeys { modified, keys })
+    }
+
+    /// Returns the public keys of the certificates along with the paths
+    /// they were loaded from, reloading them if the certificates were
+    /// modified since they were cached
+    pub(crate) fn keys(&self) -> Result<Vec<(PathBuf, PKey<Public>)>> {
+        self.cached_keys().map(|(keys, _)| keys)
+    }
+
+    /// Reloads the public keys if the certificates were modified since they
+    /// were cached, returning whether they were reloaded
+    pub(crate) fn reload_if_modified(&self) -> Result<bool> {
+        self.cached_keys().map(|(_, reloaded)| reloaded)
+    }
+
+    // The cached public keys, and whether they were just reloaded
+    fn cached_keys(&self) -> Result<(CachedKeys, bool)> {
+        let modified = self.cert_files()?;
+        let mut cached = self.cached.lock().unwrap(); //#[allow_ci]
+        match &*cached {
+            Some(c) if c.modified == modified => Ok((c.keys.clone(), false)),
+            _ => {
+                let loaded = self.load()?;
+                let keys = loaded.keys.clone();
+                *cached = Some(loaded);
+                Ok((keys, true))
+            }
         }
     }
 }
 
-/// Handles revocation messages via 0mq
-/// See:
-/// - URL: https://github.com/keylime/keylime/blob/master/keylime/revocation_notifier.py
-///   Function: await_notifications
-#[cfg(feature = "with-zmq")]
-pub(crate) async fn run_revocation_service(
-    config: &KeylimeConfig,
-) -> Result<()> {
-    let work_dir = Path::new(&config.work_dir);
-    let mount = secure_mount::mount(work_dir, &config.secure_size)?;
+// Events on the directory holding the revocation certificate that may change
+// the set of certificates or their contents
+const REV_CERT_WATCH_MASK: u32 = libc::IN_CLOSE_WRITE
+    | libc::IN_MOVED_TO
+    | libc::IN_MOVED_FROM
+    | libc::IN_DELETE;
 
-    // Connect to the service via 0mq
-    let context = zmq::Context::new();
-    let mysock = context.socket(zmq::SUB)?;
+// What happened while waiting on a `CertDirWatch`
+#[derive(Debug, PartialEq)]
+enum CertDirEvent {
+    Changed,
+    Timeout,
+    Removed,
+}
 
-    mysock.set_subscribe(b"")?;
+// inotify watch on the directory holding the revocation certificate, so
+// that replacing the certificate by renaming a new file over it is noticed
+struct CertDirWatch {
+    inotify: fs::File,
+}
 
-    let endpoint =
-        format!("tcp://{}:{}", config.revocation_ip, config.revocation_port);
+impl CertDirWatch {
+    fn new(dir: &Path) -> Result<Self> {
+        let dir_c = CString::new(dir.as_os_str().as_bytes())?;
+
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        // Owning the descriptor closes it when the watch ends or fails
+        let inotify = unsafe { fs::File::from_raw_fd(fd) };
+
+        let wd = unsafe {
+            libc::inotify_add_watch(fd, dir_c.as_ptr(), REV_CERT_WATCH_MASK)
+        };
+        if wd < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(CertDirWatch { inotify })
+    }
 
-    info!("Connecting to revocation endpoint at {}...", endpoint);
+    // Waits up to `timeout` for events on the directory
+    fn wait(&mut self, timeout: Duration) -> Result<CertDirEvent> {
+        let mut pollfd = libc::pollfd {
+            fd: self.inotify.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout_ms = timeout.as_millis().try_into().unwrap_or(i32::MAX);
+        let ready = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+        if ready < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == ErrorKind::Interrupted {
+                return Ok(CertDirEvent::Timeout);
+            }
+            return Err(e.into());
+        }
+        if ready == 0 {
+            return Ok(CertDirEvent::Timeout);
+        }
 
-    mysock.connect(endpoint.as_str())?;
+        let mut buf = [0u8; 4096];
+        let n = self.inotify.read(&mut buf)?;
+        if inotify_watch_removed(&buf[..n]) {
+            Ok(CertDirEvent::Removed)
+        } else {
+            Ok(CertDirEvent::Changed)
+        }
+    }
+}
 
-    let revocation_cert = get_revocation_cert_path(config)?;
-    let actions_dir = PathBuf::from(&config.revocation_actions_dir.trim());
+// Whether a buffer of inotify events reports that the watch was removed
+fn inotify_watch_removed(buf: &[u8]) -> bool {
+    let header_len = mem::size_of::<libc::inotify_event>();
+    let mut offset = 0;
+    while offset + header_len <= buf.len() {
+        let field = |at: usize| {
+            let b = &buf[offset + at..offset + at + 4];
+            u32::from_ne_bytes([b[0], b[1], b[2], b[3]])
+        };
+        // struct inotify_event { int wd; uint32_t mask; uint32_t cookie;
+        // uint32_t len; char name[]; }
+        if field(4) & libc::IN_IGNORED != 0 {
+            return true;
+        }
+        offset += header_len + field(12) as usize;
+    }
+    false
+}
 
-    info!("Waiting for revocation messages on 0mq {}", endpoint);
+/// Watches the revocation certificate for changes, reloading the cached
+/// keys whenever the set of certificates or their modification times change
+///
+/// The directory holding the certificate, or the certificate directory
+/// itself, is watched with inotify so that the keys are reloaded as soon as
+/// a file is written or replaced. If the watch cannot be set up, or the
+/// directory is removed later on, the certificate is polled every
+/// `REV_CERT_POLL_INTERVAL` instead. The watch runs in its own thread and
+/// stops once the certificate is dropped.
+pub(crate) fn watch_revocation_cert(
+    cert: &Arc<RevocationCert>,
+) -> Result<thread::JoinHandle<()>> {
+    let dir = if cert.path().is_dir() {
+        cert.path()
+    } else {
+        // The parent of a bare file name is the current directory
+        cert.path()
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."))
+    };
+    let mut dir_watch = match CertDirWatch::new(dir) {
+        Ok(dir_watch) => {
+            info!(
+                "Watching the revocation certificate {} for changes",
+                cert.path().display()
+            );
+            Some(dir_watch)
+        }
+        Err(e) => {
+            warn!(
+                "Cannot watch {} ({}), polling it for changes instead",
+                cert.path().display(),
+                e
+            );
+            None
+        }
+    };
 
-    // Main revocation service loop. If a message is malformed or
-    // can not be verified the loop continues.
-    loop {
-        let mut rawbody = match mysock.recv_string(0) {
-            Ok(v) => match v {
-                Ok(v) => v,
-                _ => {
-                    warn!("Unable to read message from 0mq");
-                    continue;
+    let cert = Arc::downgrade(cert);
+    let handle = thread::Builder::new()
+        .name("revocation-cert-watch".to_string())
+        .spawn(move || loop {
+            // The inotify wait also times out, so that the thread notices
+            // when the certificate is dropped
+            let event = match &mut dir_watch {
+                Some(watch) => watch.wait(REV_CERT_POLL_INTERVAL),
+                None => {
+                    thread::sleep(REV_CERT_POLL_INTERVAL);
+                    Ok(CertDirEvent::Changed)
                 }
-            },
-            Err(e) => {
-                warn!("Unable to read message from 0mq");
-                continue;
+            };
+            let cert = match cert.upgrade() {
+                Some(cert) => cert,
+                None => return,
+            };
+            let fallback = match event {
+                Ok(CertDirEvent::Changed) => None,
+                Ok(CertDirEvent::Timeout) => continue,
+                Ok(CertDirEvent::Removed) => {
+                    Some("directory removed".to_string())
+                }
+                Err(e) => Some(e.to_string()),
+            };
+            if let Some(reason) = fallback {
+                warn!(
+                    "Stopped watching {} ({}), polling it instead",
+                    cert.path().display(),
+                    reason
+                );
+                dir_watch = None;
             }
-        };
+            match cert.reload_if_modified() {
+                Ok(true) => info!(
+                    "Reloaded the revocation certificate {}",
+                    cert.path().display()
+                ),
+                Ok(false) => {}
+                Err(e) => debug!(
+                    "Unable to reload the revocation certificate {}: {}",
+                    cert.path().display(),
+                    e
+                ),
+            }
+        })?;
+    Ok(handle)
+}
 
-        let body: Value = serde_json::from_str(rawbody.as_str())?;
-        let _ = process_revocation(
-            body,
-            &revocation_cert,
-            &config.secure_size,
-            &config.revocation_actions,
-            &actions_dir,
-            config.allow_payload_revocation_actions,
-            work_dir,
-        );
+// Structured record of the output an action wrote to one of its streams,
+// so that log aggregators can parse it. Output that is not valid UTF-8 is
+// decoded lossily and also provided hex encoded.
+fn action_output_record(
+    result: &ActionResult,
+    stream: &str,
+    output: &[u8],
+) -> Value {
+    let origin = if result.was_payload {
+        "payload"
+    } else {
+        "pre-installed"
+    };
+    let mut record = json!({
+        "action": result.name,
+        "origin": origin,
+        "stream": stream,
+        "exit_code": result.output.status.code(),
+        "output": String::from_utf8_lossy(output),
+    });
+    if std::str::from_utf8(output).is_err() {
+        record["output_hex"] = Value::String(hex::encode(output));
     }
-    Ok(())
+    record
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use serde_json::json;
+// Logs the output of the revocation actions that were run
+fn log_action_results(results: &[ActionResult]) {
+    for result in results {
+        if !result.output.stdout.is_empty() {
+            info!(
+                "Revocation action output: {}",
+                action_output_record(result, "stdout", &result.output.stdout)
+            );
+        }
+        if !result.output.stderr.is_empty() {
+            warn!(
+                "Revocation action output: {}",
+                action_output_record(result, "stderr", &result.output.stderr)
+            );
+        }
+    }
+}
 
-    // Used to create symbolic links
-    use std::os::unix::fs::symlink;
+/// Revocation message sent by the verifier, as signed in the "msg" field
+///
+/// Fields not listed here are kept and passed to the actions as they are.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct RevocationMessage {
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub agent_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub severity_label: Option<String>,
+    /// Time the message was sent, in seconds since the epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    /// Number increased by the verifier for each message it sends
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
 
-    #[test]
-    fn revocation_scripts_ok() {
-        let test_config = KeylimeConfig::default();
-        let json_file = concat!(
-            env!("CARGO_MANIFEST_DIR"),
-            "/tests/unzipped/test_ok.json"
-        );
-        let json_str = std::fs::read_to_string(json_file).unwrap(); //#[allow_ci]
-        let json = serde_json::from_str(&json_str).unwrap(); //#[allow_ci]
-        let actions_dir =
-            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions/");
-        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
-        let tmpfs_dir = work_dir.path().join("tmpfs-dev"); //#[allow_ci]
-        fs::create_dir(&tmpfs_dir).unwrap(); //#[allow_ci]
-        let unzipped_dir =
-            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/unzipped");
-        symlink(unzipped_dir, tmpfs_dir.join("unzipped")).unwrap(); //#[allow_ci]
-        let outputs = run_revocation_actions(
-            json,
-            &test_config.secure_size,
-            &test_config.revocation_actions,
-            actions_dir,
-            true,
-            work_dir.path(),
-        );
+impl RevocationMessage {
+    /// Parses the message and checks that the required fields are set,
+    /// returning the JSON passed to the revocation actions
+    fn validate(msg: &str) -> Result<Value> {
+        let message: RevocationMessage = match serde_json::from_str(msg) {
+            Ok(m) => m,
+            Err(e) => {
+                warn!("Invalid revocation message: {}", e);
+                return Err(Error::InvalidRequest);
+            }
+        };
 
-        assert!(outputs.is_ok());
-        let outputs = outputs.unwrap(); //#[allow_ci]
+        for (field, value) in
+            [("type", &message.msg_type), ("agent_id", &message.agent_id)]
+        {
+            if value.trim().is_empty() {
+                warn!("Invalid revocation message: empty field `{}`", field);
+                return Err(Error::InvalidRequest);
+            }
+        }
 
-        assert!(outputs.len() == 4);
+        Ok(serde_json::to_value(message)?)
+    }
+}
 
-        for output in outputs {
-            assert_eq!(
-                String::from_utf8(output.stdout).unwrap(), //#[allow_ci]
-                "there\n"
+/// File in the work directory holding the sequence number of the last
+/// processed revocation message
+pub(crate) const REVOCATION_SEQUENCE_FILE: &str = "revocation_sequence";
+
+// Returns the sequence number of the last processed revocation message, if
+// any message carrying one was processed
+fn last_revocation_sequence(work_dir: &Path) -> Result<Option<u64>> {
+    match fs::read_to_string(work_dir.join(REVOCATION_SEQUENCE_FILE)) {
+        Ok(seq) => Ok(Some(seq.trim().parse()?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn store_revocation_sequence(work_dir: &Path, sequence: u64) -> Result<()> {
+    let mut file = tempfile::NamedTempFile::new_in(work_dir)?;
+    file.write_all(sequence.to_string().as_bytes())?;
+    let _ = file
+        .persist(work_dir.join(REVOCATION_SEQUENCE_FILE))
+        .map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Checks that a validated revocation message is not the replay of an
+/// older one
+///
+/// With a non-zero `max_age`, the message must carry a timestamp within
+/// `max_age` of `now`. A message carrying a sequence number must have one
+/// greater than the last processed message; the sequence number is then
+/// recorded under `work_dir` and in `history`, so that the message cannot
+/// be replayed.
+fn check_revocation_freshness(
+    message: &Value,
+    max_age: Duration,
+    work_dir: &Path,
+    history: &RevocationHistory,
+    now: SystemTime,
+) -> Result<()> {
+    if !max_age.is_zero() {
+        let timestamp = match message["timestamp"].as_u64() {
+            Some(timestamp) => timestamp,
+            None => {
+                warn!("Stale revocation message: no timestamp");
+                return Err(Error::InvalidRequest);
+            }
+        };
+        let sent = SystemTime::UNIX_EPOCH + Duration::from_secs(timestamp);
+        let age = match now.duration_since(sent) {
+            Ok(age) => age,
+            // Sent in the future, allowing for the same clock skew
+            Err(e) => e.duration(),
+        };
+        if age > max_age {
+            warn!(
+                "Stale revocation message: timestamp {} is {}s away from the agent clock (max age {}s)",
+                timestamp,
+                age.as_secs(),
+                max_age.as_secs()
             );
+            return Err(Error::InvalidRequest);
+        }
+    }
+
+    if let Some(sequence) = message["sequence"].as_u64() {
+        let last =
+            last_revocation_sequence(work_dir)?.max(history.sequence());
+        if let Some(last) = last {
+            if sequence <= last {
+                warn!(
+                    "Stale revocation message: sequence {} is not greater than the last processed one {}",
+                    sequence, last
+                );
+                return Err(Error::InvalidRequest);
+            }
+        }
+        store_revocation_sequence(work_dir, sequence)?;
+        history.record_sequence(sequence);
+    }
+
+    Ok(())
+}
+
+/// Process revocation message received from REST API or 0mq
+///
+/// The signature of the message is checked by `verifier` against the keys
+/// of the revocation certificates, `crypto::OpensslVerifier` checking it
+/// locally.
+///
+/// The outcome of the messages whose signature was verified is recorded in
+/// `history`. A message already processed, e.g. received through another
+/// transport, is ignored.
+pub(crate) fn process_revocation(
+    body: Value,
+    cert: &RevocationCert,
+    verifier: &dyn crypto::SignatureVerifier,
+    options: &RevocationOptions,
+    history: &RevocationHistory,
+) -> Result<()> {
+    // Ensure we have a signature, otherwise continue the loop
+    let signature = match body["signature"].as_str() {
+        Some(v) => v,
+        _ => {
+            debug!("No signature on revocation message from server");
+            return Err(Error::InvalidRequest);
+        }
+    };
+
+    // Ensure we have a msg, otherwise continue the loop
+    let message = match body["msg"].as_str() {
+        Some(v) => v,
+        _ => {
+            debug!("No msg on revocation message from server");
+            return Err(Error::InvalidRequest);
+        }
+    };
+
+    let signature = match base64::decode(signature) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("Revocation message signature is not valid base64: {}", e);
+            return Err(Error::InvalidRequest);
+        }
+    };
+
+    // Verify the message and signature with our keys. The signature is
+    // valid if any of the candidate certificates verifies it. The
+    // verification scheme follows the key type of each certificate (RSA or
+    // EC).
+    let mut verified_by = None;
+    let mut tried = Vec::new();
+    for (path, cert_key) in cert.keys()? {
+        debug!(
+            "Revocation certificate {} key type: {:?}",
+            path.display(),
+            cert_key.id()
+        );
+        match verifier.verify(
+            &cert_key,
+            message.as_bytes(),
+            &signature,
+            options.signature_digest,
+        ) {
+            Ok(outcome) => match outcome.alg() {
+                Some(alg) => {
+                    verified_by = Some((path, alg));
+                    break;
+                }
+                None => {
+                    let algs = outcome
+                        .tried
+                        .iter()
+                        .map(|alg| alg.to_string())
+                        .collect::<Vec<String>>();
+                    tried.push(format!(
+                        "{} ({})",
+                        path.display(),
+                        algs.join(", ")
+                    ))
+                }
+            },
+            Err(e) => debug!(
+                "Unable to verify revocation signature with {}: {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+
+    match verified_by {
+        Some((path, alg)) => {
+            info!(
+                "Revocation signature verified with certificate {} ({})",
+                path.display(),
+                alg
+            );
+            // The same message may be received through several transports.
+            // It is claimed before being processed, so that it is not
+            // processed twice at once, and released if processing fails so
+            // that it can be processed when received again.
+            if !history.mark_processed(&signature) {
+                info!("Revocation message already processed, ignoring it");
+                return Ok(());
+            }
+            let result =
+                process_verified_revocation(&body, message, options, history);
+            if result.is_err() {
+                history.forget_processed(&signature);
+            }
+            result
+        }
+        None => {
+            warn!(
+                "Invalid revocation message signature, tried: {}",
+                if tried.is_empty() {
+                    "no usable certificate".to_string()
+                } else {
+                    tried.join(", ")
+                }
+            );
+            debug!("Invalid revocation message signature {}", body);
+            Err(Error::InvalidRequest)
+        }
+    }
+}
+
+// Processes a revocation message whose signature was verified: checks it
+// is fresh, runs the actions and records the outcome in the history
+fn process_verified_revocation(
+    body: &Value,
+    message: &str,
+    options: &RevocationOptions,
+    history: &RevocationHistory,
+) -> Result<()> {
+    let msg_payload = RevocationMessage::validate(message)?;
+    check_revocation_freshness(
+        &msg_payload,
+        options.max_age,
+        &options.work_dir,
+        history,
+        SystemTime::now(),
+    )?;
+    debug!(
+        "Revocation signature validated for revocation: {}",
+        msg_payload
+    );
+    // Actions auditing the revocation may need the signed message
+    let raw_message = if options.raw_message_to_actions {
+        Some(body.clone())
+    } else {
+        None
+    };
+    let result =
+        run_revocation_actions(msg_payload.clone(), raw_message, options);
+    history.record(RevocationStatus::new(
+        &msg_payload,
+        &result,
+        SystemTime::now(),
+    ));
+    match result {
+        Ok(results) => {
+            log_action_results(&results);
+            Ok(())
+        }
+        Err(e) => {
+            // Still report the output of the actions that did run
+            if let Error::RevocationActions { succeeded, .. } = &e {
+                log_action_results(succeeded);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Processes a revocation message with `process_revocation` on a thread
+/// where blocking is allowed, so that running the actions and waiting
+/// before retrying them does not stall the async runtime. The signature is
+/// checked locally, after refreshing a downloaded revocation certificate.
+pub(crate) async fn process_revocation_blocking(
+    body: Value,
+    cert: Arc<RevocationCert>,
+    options: Arc<RevocationOptions>,
+    history: Arc<RevocationHistory>,
+) -> Result<()> {
+    cert.refresh().await;
+    tokio::task::spawn_blocking(move || {
+        process_revocation(
+            body,
+            &cert,
+            &crypto::OpensslVerifier,
+            &options,
+            &history,
+        )
+    })
+    .await?
+}
+
+/// Transport revocation messages are received from, besides the agent REST
+/// API
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RevocationTransport {
+    /// The 0mq revocation notifier
+    ZeroMq,
+    /// Polling revocation_notification_url
+    Webhook,
+}
+
+impl FromStr for RevocationTransport {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "zmq" => Ok(RevocationTransport::ZeroMq),
+            "webhook" => Ok(RevocationTransport::Webhook),
+            other => Err(Error::Configuration(format!(
+                "Invalid revocation transport {}: expected zmq or webhook",
+                other
+            ))),
+        }
+    }
+}
+
+// The transports to run: the configured ones or, if none is configured,
+// the webhook if a notification URL is set and 0mq otherwise
+fn revocation_transports(config: &KeylimeConfig) -> Vec<RevocationTransport> {
+    if !config.revocation_transports.is_empty() {
+        let mut transports = Vec::new();
+        for transport in &config.revocation_transports {
+            if !transports.contains(transport) {
+                transports.push(*transport);
+            }
+        }
+        return transports;
+    }
+    if cfg!(feature = "with-webhook")
+        && !config.revocation_notification_url.is_empty()
+    {
+        vec![RevocationTransport::Webhook]
+    } else if cfg!(feature = "with-zmq") {
+        vec![RevocationTransport::ZeroMq]
+    } else {
+        Vec::new()
+    }
+}
+
+// Revocation service running one of the transports until shutdown
+type RevocationService<'a> = Pin<Box<dyn Future<Output = Result<()>> + 'a>>;
+
+/// Runs the enabled revocation transports concurrently
+///
+/// All the transports verify the messages with the shared
+/// `revocation_cert` and record the revocations in `history`, so that a
+/// message received through several of them is only processed once. The
+/// messages posted to the agent REST API are handled by the HTTP server,
+/// sharing the same history.
+///
+/// The transports run until `true` is sent on `shutdown`, or until one of
+/// them fails.
+pub(crate) async fn run_revocation_transports(
+    config: &KeylimeConfig,
+    revocation_cert: &Arc<RevocationCert>,
+    history: &Arc<RevocationHistory>,
+    shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let services = revocation_transports(config)
+        .into_iter()
+        .map(|transport| -> Result<RevocationService<'_>> {
+            match transport {
+                #[cfg(feature = "with-zmq")]
+                RevocationTransport::ZeroMq => {
+                    Ok(Box::pin(run_revocation_service(
+                        config,
+                        revocation_cert,
+                        history,
+                        shutdown.clone(),
+                    )))
+                }
+                #[cfg(feature = "with-webhook")]
+                RevocationTransport::Webhook => {
+                    Ok(Box::pin(run_revocation_webhook_service(
+                        config,
+                        revocation_cert,
+                        history,
+                        shutdown.clone(),
+                    )))
+                }
+                #[allow(unreachable_patterns)]
+                other => Err(Error::Configuration(format!(
+                    "Revocation transport {:?} not supported by this build",
+                    other
+                ))),
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let _ = futures::future::try_join_all(services).await?;
+    Ok(())
+}
+
+/// Exponential backoff between attempts to connect to the revocation
+/// notifier, or to push a quote to the verifier
+#[derive(Debug)]
+pub(crate) struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub(crate) fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max,
+            next: initial,
+        }
+    }
+
+    /// Returns the delay to wait before the next attempt, doubling it for
+    /// the following one up to the maximum
+    pub(crate) fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+
+    fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
+// Waits until a shutdown of the revocation service is requested. If the
+// sender is dropped without requesting it, no shutdown can happen anymore
+// and this never returns.
+pub(crate) async fn wait_for_shutdown(shutdown: &mut watch::Receiver<bool>) {
+    while !*shutdown.borrow() {
+        if shutdown.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+// Sleeps for the delay, returning early with true if a shutdown of the
+// revocation service is requested meanwhile
+#[cfg(feature = "with-zmq")]
+async fn sleep_or_shutdown(
+    delay: Duration,
+    shutdown: &mut watch::Receiver<bool>,
+) -> bool {
+    tokio::select! {
+        _ = tokio::time::sleep(delay) => false,
+        _ = wait_for_shutdown(shutdown) => true,
+    }
+}
+
+// Creates a socket subscribed to all the revocation messages and connects it
+// to the endpoint. 0mq also reconnects the socket on its own, using the same
+// delays, when the connection drops. Receiving times out after RECV_TIMEOUT,
+// so that a shutdown request is noticed.
+#[cfg(feature = "with-zmq")]
+fn connect_revocation_socket(
+    context: &zmq::Context,
+    endpoint: &str,
+) -> Result<zmq::Socket> {
+    let socket = context.socket(zmq::SUB)?;
+    socket.set_subscribe(b"")?;
+    socket
+        .set_reconnect_ivl(RECONNECT_DELAY_INITIAL.as_millis().try_into()?)?;
+    socket
+        .set_reconnect_ivl_max(RECONNECT_DELAY_MAX.as_millis().try_into()?)?;
+    socket.set_rcvtimeo(RECV_TIMEOUT.as_millis().try_into()?)?;
+    socket.connect(endpoint)?;
+    Ok(socket)
+}
+
+// Receives a message on the socket. Receiving blocks for up to RECV_TIMEOUT,
+// so it is done in a blocking task, which hands the socket back along with
+// the result.
+#[cfg(feature = "with-zmq")]
+async fn recv_revocation_message(
+    socket: zmq::Socket,
+) -> Result<(
+    zmq::Socket,
+    zmq::Result<std::result::Result<String, Vec<u8>>>,
+)> {
+    Ok(tokio::task::spawn_blocking(move || {
+        let received = socket.recv_string(0);
+        (socket, received)
+    })
+    .await?)
+}
+
+/// Handles revocation messages via 0mq
+/// See:
+/// - URL: https://github.com/keylime/keylime/blob/master/keylime/revocation_notifier.py
+///   Function: await_notifications
+///
+/// The service runs until `true` is sent on `shutdown`. A revocation message
+/// being processed is always processed to completion before returning.
+#[cfg(feature = "with-zmq")]
+pub(crate) async fn run_revocation_service(
+    config: &KeylimeConfig,
+    revocation_cert: &Arc<RevocationCert>,
+    history: &Arc<RevocationHistory>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let work_dir = Path::new(&config.work_dir);
+    let mount = secure_mount::mount(work_dir, &config.secure_size)?;
+
+    let options = Arc::new(RevocationOptions::from_config(config));
+
+    let context = zmq::Context::new();
+    let endpoint =
+        format!("tcp://{}:{}", config.revocation_ip, config.revocation_port);
+
+    // Repeated warnings are coalesced so that a flood of bad messages does
+    // not flood the log
+    let mut recv_warnings = WarnThrottle::new(WARN_COALESCE_PERIOD);
+    let mut invalid_warnings = WarnThrottle::new(WARN_COALESCE_PERIOD);
+
+    let mut backoff =
+        Backoff::new(RECONNECT_DELAY_INITIAL, RECONNECT_DELAY_MAX);
+
+    // Connection loop. If the socket fails, a new one is connected after
+    // waiting an increasing delay.
+    'connection: while !*shutdown.borrow() {
+        info!("Connecting to revocation endpoint at {}...", endpoint);
+
+        let mut sock = match connect_revocation_socket(&context, &endpoint) {
+            Ok(s) => s,
+            Err(e) => {
+                let delay = backoff.next_delay();
+                warn!(
+                    "Unable to connect to revocation endpoint {}: {}, retrying in {}s",
+                    endpoint,
+                    e,
+                    delay.as_secs()
+                );
+                if sleep_or_shutdown(delay, &mut shutdown).await {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        info!("Waiting for revocation messages on 0mq {}", endpoint);
+
+        // Main revocation service loop. If a message is malformed or
+        // can not be verified the loop continues.
+        loop {
+            if *shutdown.borrow() {
+                break 'connection;
+            }
+            recv_warnings.flush();
+            invalid_warnings.flush();
+
+            let (socket, received) = recv_revocation_message(sock).await?;
+            sock = socket;
+            let mut rawbody = match received {
+                Ok(v) => match v {
+                    Ok(v) => v,
+                    _ => {
+                        recv_warnings.warn("Unable to read message from 0mq");
+                        continue;
+                    }
+                },
+                // Interrupted, nothing wrong with the socket
+                Err(zmq::Error::EINTR) => continue,
+                // No message yet, check for a shutdown request
+                Err(zmq::Error::EAGAIN) => continue,
+                Err(e) => {
+                    warn!(
+                        "Lost connection to revocation endpoint {}: {}",
+                        endpoint, e
+                    );
+                    break;
+                }
+            };
+
+            // The connection works again, start over with a short delay
+            // the next time it fails
+            backoff.reset();
+
+            let body: Value = match serde_json::from_str(rawbody.as_str()) {
+                Ok(v) => v,
+                Err(e) => {
+                    invalid_warnings.warn(&format!(
+                        "Unable to parse revocation message: {}",
+                        e
+                    ));
+                    continue;
+                }
+            };
+
+            if let Err(e) = process_revocation_blocking(
+                body,
+                revocation_cert.clone(),
+                options.clone(),
+                history.clone(),
+            )
+            .await
+            {
+                warn!("Unable to process revocation message: {}", e);
+            }
+        }
+
+        let delay = backoff.next_delay();
+        info!("Reconnecting in {}s", delay.as_secs());
+        if sleep_or_shutdown(delay, &mut shutdown).await {
+            break;
+        }
+    }
+
+    info!("Revocation service stopped");
+    Ok(())
+}
+
+// Timeout of the requests polling the revocation notification URL
+#[cfg(feature = "with-webhook")]
+const REVOCATION_POLL_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Creates a client for requests to the verifier. Only HTTPS is allowed, the
+// server certificate being verified against the CA certificate at `ca` if
+// set, or the system trusted certificates otherwise.
+pub(crate) fn https_client(
+    ca: &str,
+    timeout: Duration,
+) -> Result<reqwest::Client> {
+    let mut builder =
+        reqwest::Client::builder().https_only(true).timeout(timeout);
+    if !ca.is_empty() {
+        let ca = crypto::load_x509(Path::new(ca))?;
+        builder = builder.add_root_certificate(
+            reqwest::Certificate::from_pem(&ca.to_pem()?)?,
+        );
+    }
+    Ok(builder.build()?)
+}
+
+// Fetches the latest revocation message, if any
+#[cfg(feature = "with-webhook")]
+async fn fetch_revocation(
+    client: &reqwest::Client,
+    url: &str,
+) -> Result<Option<Value>> {
+    let resp = client.get(url).send().await?;
+    if resp.status() == reqwest::StatusCode::NO_CONTENT {
+        return Ok(None);
+    }
+    if !resp.status().is_success() {
+        return Err(Error::Other(format!(
+            "received {} from {}",
+            resp.status(),
+            url
+        )));
+    }
+    Ok(Some(resp.json().await?))
+}
+
+/// Handles revocation messages polled from an HTTPS endpoint
+///
+/// The endpoint returns the latest revocation message, which is verified and
+/// processed the same way as the messages received via 0mq. A message is only
+/// processed once, even if it is returned by several polls.
+///
+/// The service runs until `true` is sent on `shutdown`. A revocation message
+/// being processed is always processed to completion before returning.
+#[cfg(feature = "with-webhook")]
+pub(crate) async fn run_revocation_webhook_service(
+    config: &KeylimeConfig,
+    revocation_cert: &Arc<RevocationCert>,
+    history: &Arc<RevocationHistory>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    // Fail early if the secure mount is not available. Its path is looked
+    // up again when each revocation message is processed.
+    let work_dir = Path::new(&config.work_dir);
+    let _ = secure_mount::mount(work_dir, &config.secure_size)?;
+
+    let options = Arc::new(RevocationOptions::from_config(config));
+
+    let client = https_client(
+        &config.revocation_notification_ca,
+        REVOCATION_POLL_TIMEOUT,
+    )?;
+    let url = &config.revocation_notification_url;
+
+    info!(
+        "Polling revocation messages from {} every {}s",
+        url,
+        config.revocation_poll_interval.as_secs()
+    );
+
+    // Repeated warnings are coalesced so that an unreachable endpoint does
+    // not flood the log
+    let mut poll_warnings = WarnThrottle::new(WARN_COALESCE_PERIOD);
+
+    let mut last_signature: Option<String> = None;
+    let mut interval = tokio::time::interval(config.revocation_poll_interval);
+
+    // Main revocation service loop. If the endpoint can not be reached or a
+    // message can not be verified the loop continues.
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = wait_for_shutdown(&mut shutdown) => break,
+        }
+        poll_warnings.flush();
+
+        let body = match fetch_revocation(&client, url).await {
+            Ok(Some(body)) => body,
+            Ok(None) => continue,
+            Err(e) => {
+                poll_warnings.warn(&format!(
+                    "Unable to poll revocation messages: {}",
+                    e
+                ));
+                continue;
+            }
+        };
+
+        let signature = body["signature"].as_str().map(String::from);
+        if signature.is_some() && signature == last_signature {
+            continue;
+        }
+        last_signature = signature;
+
+        if let Err(e) = process_revocation_blocking(
+            body,
+            revocation_cert.clone(),
+            options.clone(),
+            history.clone(),
+        )
+        .await
+        {
+            warn!("Unable to process revocation message: {}", e);
+        }
+    }
+
+    info!("Revocation service stopped");
+    Ok(())
+}
+
+/// Helpers to run revocation actions in tests without fixture files
+#[cfg(any(test, feature = "testing"))]
+pub mod testing {
+    use super::*;
+    use tempfile::TempDir;
+
+    // Shim running the Python actions, installed with the actions
+    const PYTHON_SHIM: &str = include_str!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/actions/shim.py"
+    ));
+
+    /// Revocation actions written to temporary directories from their
+    /// content.
+    ///
+    /// The pre-installed actions are written to `actions_dir()`, along with
+    /// shim.py, and the payload files to the unzipped directory of the
+    /// secure mount in `work_dir()`, as if extracted from the tenant
+    /// payload. Files starting with a shebang line are made executable, the
+    /// others (Python modules, action_list) are not. The directories are
+    /// removed when the set is dropped.
+    pub(crate) struct ActionSet {
+        dir: TempDir,
+    }
+
+    impl ActionSet {
+        /// Creates the set from (name, content) pairs
+        pub(crate) fn new(
+            actions: &[(&str, &str)],
+            payload: &[(&str, &str)],
+        ) -> Result<Self> {
+            let set = ActionSet {
+                dir: tempfile::tempdir()?,
+            };
+            fs::create_dir(set.actions_dir())?;
+            write_files(&set.actions_dir(), &[("shim.py", PYTHON_SHIM)])?;
+            write_files(&set.actions_dir(), actions)?;
+            if !payload.is_empty() {
+                fs::create_dir_all(set.payload_dir())?;
+                write_files(&set.payload_dir(), payload)?;
+            }
+            Ok(set)
+        }
+
+        pub(crate) fn actions_dir(&self) -> PathBuf {
+            self.dir.path().join("actions")
+        }
+
+        pub(crate) fn work_dir(&self) -> &Path {
+            self.dir.path()
+        }
+
+        pub(crate) fn payload_dir(&self) -> PathBuf {
+            secure_mount::secure_dir(self.work_dir()).join("unzipped")
+        }
+
+        /// Options from `config`, running the actions of the set
+        pub(crate) fn options(
+            &self,
+            config: &KeylimeConfig,
+        ) -> RevocationOptions {
+            RevocationOptions {
+                actions_dir: self.actions_dir(),
+                work_dir: self.work_dir().to_path_buf(),
+                ..RevocationOptions::from_config(config)
+            }
+        }
+
+        /// Runs the actions listed in the configuration and in the payload
+        /// action_list with `run_revocation_actions`, with the settings
+        /// from `config`
+        pub(crate) fn run(
+            &self,
+            json: Value,
+            config: &KeylimeConfig,
+        ) -> Result<Vec<ActionResult>> {
+            run_revocation_actions(json, None, &self.options(config))
+        }
+    }
+
+    fn write_files(dir: &Path, files: &[(&str, &str)]) -> Result<()> {
+        for (name, content) in files {
+            let path = dir.join(name);
+            fs::write(&path, content)?;
+            let mode = if content.starts_with("#!") {
+                0o755
+            } else {
+                0o644
+            };
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::ffi::OsString;
+
+    // Used to create symbolic links
+    use std::os::unix::fs::symlink;
+
+    // Python module printing the hello entry of the revocation message
+    const HELLO_MODULE: &str =
+        "async def execute(revocation):\n    print(revocation['hello'])\n";
+
+    // Script printing the hello entry of the revocation message, failing if
+    // there is none
+    const HELLO_SCRIPT: &str = "#!/usr/bin/python3
+import json, sys
+with open(sys.argv[1]) as f:
+    print(json.load(f)['hello'])
+";
+
+    // Options running the actions from the directories, with the default
+    // settings otherwise
+    fn test_options(
+        actions_dir: &Path,
+        work_dir: &Path,
+    ) -> RevocationOptions {
+        RevocationOptions {
+            actions_dir: actions_dir.to_path_buf(),
+            work_dir: work_dir.to_path_buf(),
+            python_shim: PythonShim::default(),
+            action_policy: ActionPolicy::default(),
+            retry_policy: RetryPolicy::default(),
+            ..RevocationOptions::from_config(&KeylimeConfig::default())
+        }
+    }
+
+    fn hello_actions() -> testing::ActionSet {
+        testing::ActionSet::new(
+            &[("local_action_hello.py", HELLO_MODULE)],
+            &[
+                (
+                    "action_list",
+                    "local_action_rev_script1.py\nlocal_action_rev_script2.py\nlocal_action_payload\nlocal_action_hello\n",
+                ),
+                ("local_action_payload.py", HELLO_MODULE),
+                ("local_action_rev_script1.py", HELLO_SCRIPT),
+                ("local_action_rev_script2.py", HELLO_SCRIPT),
+            ],
+        )
+        .unwrap() //#[allow_ci]
+    }
+
+    #[test]
+    fn revocation_scripts_ok() {
+        let test_config = KeylimeConfig::default();
+        let outputs =
+            hello_actions().run(json!({"hello": "there"}), &test_config);
+
+        assert!(outputs.is_ok());
+        let outputs = outputs.unwrap(); //#[allow_ci]
+
+        assert!(outputs.len() == 4);
+
+        let names = outputs
+            .iter()
+            .map(|result| result.name.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            names,
+            [
+                "local_action_rev_script1.py",
+                "local_action_rev_script2.py",
+                "local_action_payload",
+                "local_action_hello",
+            ]
+        );
+
+        for result in outputs {
+            assert_eq!(
+                result.was_payload,
+                result.name != "local_action_hello"
+            );
+            assert_eq!(
+                String::from_utf8(result.output.stdout).unwrap(), //#[allow_ci]
+                "there\n"
+            );
+        }
+    }
+
+    #[test]
+    fn revocation_scripts_err() {
+        let test_config = KeylimeConfig::default();
+        let outputs =
+            hello_actions().run(json!({"goodbye": "there"}), &test_config);
+        assert!(outputs.is_err());
+    }
+
+    #[test]
+    fn revocation_scripts_unreadable_action_list() {
+        let actions = testing::ActionSet::new(
+            &[("local_action_hello.py", HELLO_MODULE)],
+            &[],
+        )
+        .unwrap(); //#[allow_ci]
+
+        // An action_list that exists but cannot be read as a file
+        let action_list = actions.payload_dir().join("action_list");
+        fs::create_dir_all(&action_list).unwrap(); //#[allow_ci]
+
+        match actions
+            .run(json!({"hello": "there"}), &KeylimeConfig::default())
+        {
+            Err(e @ Error::ActionList { .. }) => {
+                assert!(e.to_string().starts_with(&format!(
+                    "Unable to read revocation action list {}: ",
+                    action_list.display()
+                )));
+            }
+            other => panic!("unexpected result: {:?}", other), //#[allow_ci]
+        }
+    }
+
+    #[test]
+    fn revocation_action_set() {
+        let actions = testing::ActionSet::new(
+            &[("local_action_hello.py", HELLO_MODULE)],
+            &[("local_action_payload.sh", "#!/bin/sh\necho payload\n")],
+        )
+        .unwrap(); //#[allow_ci]
+
+        // Only scripts with a shebang line are executable
+        let mode = |path: PathBuf| {
+            fs::metadata(path).unwrap().permissions().mode() & 0o777 //#[allow_ci]
+        };
+        assert_eq!(mode(actions.actions_dir().join("shim.py")), 0o755);
+        assert_eq!(
+            mode(actions.actions_dir().join("local_action_hello.py")),
+            0o644
+        );
+        assert_eq!(
+            mode(actions.payload_dir().join("local_action_payload.sh")),
+            0o755
+        );
+
+        let test_config = KeylimeConfig {
+            revocation_actions: "local_action_hello, local_action_payload.sh"
+                .to_string(),
+            ..Default::default()
+        };
+        let outputs = actions
+            .run(json!({"hello": "there"}), &test_config)
+            .unwrap(); //#[allow_ci]
+        let stdout = outputs
+            .iter()
+            .map(|result| String::from_utf8_lossy(&result.output.stdout))
+            .collect::<Vec<_>>();
+        assert_eq!(stdout, ["there\n", "payload\n"]);
+    }
+
+    #[test]
+    fn revocation_scripts_from_config() {
+        let mut test_config = KeylimeConfig::default();
+        let json_file = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/unzipped/test_ok.json"
+        );
+        // Duplicates are only run once, even if the whitespace differs
+        test_config.revocation_actions = String::from(
+            "local_action_hello, local_action_payload , local_action_hello ",
+        );
+        let json_str = std::fs::read_to_string(json_file).unwrap(); //#[allow_ci]
+        let json = serde_json::from_str(&json_str).unwrap(); //#[allow_ci]
+        let actions_dir =
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions/");
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let tmpfs_dir = work_dir.path().join("tmpfs-dev"); //#[allow_ci]
+        fs::create_dir(&tmpfs_dir).unwrap(); //#[allow_ci]
+        let unzipped_dir =
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/unzipped");
+        symlink(unzipped_dir, tmpfs_dir.join("unzipped")).unwrap(); //#[allow_ci]
+        let outputs = run_revocation_actions(
+            json,
+            None,
+            &RevocationOptions {
+                config_actions: test_config.revocation_actions.clone(),
+                ..test_options(actions_dir, work_dir.path())
+            },
+        );
+
+        assert!(outputs.is_ok());
+        let outputs = outputs.unwrap(); //#[allow_ci]
+
+        assert!(outputs.len() == 4);
+
+        // Actions from the configuration are run first, and the ones also
+        // listed in action_list are not run again
+        let names = outputs
+            .iter()
+            .map(|result| result.name.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            names,
+            [
+                "local_action_hello",
+                "local_action_payload",
+                "local_action_rev_script1.py",
+                "local_action_rev_script2.py",
+            ]
+        );
+
+        for result in outputs {
+            assert_eq!(
+                String::from_utf8(result.output.stdout).unwrap(), //#[allow_ci]
+                "there\n"
+            );
+        }
+    }
+
+    #[test]
+    fn revocation_scripts_allow_failures() {
+        let mut test_config = KeylimeConfig::default();
+        let json_file = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/unzipped/test_ok.json"
+        );
+        test_config.revocation_actions =
+            String::from("local_action_fail_shell.sh, local_action_hello");
+        let json_str = fs::read_to_string(json_file).unwrap(); //#[allow_ci]
+        let json: Value = serde_json::from_str(&json_str).unwrap(); //#[allow_ci]
+        let actions_dir =
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions/");
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let tmpfs_dir = work_dir.path().join("tmpfs-dev"); //#[allow_ci]
+        fs::create_dir(&tmpfs_dir).unwrap(); //#[allow_ci]
+        let unzipped_dir =
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/unzipped");
+        symlink(unzipped_dir, tmpfs_dir.join("unzipped")).unwrap(); //#[allow_ci]
+
+        // Actions run one after the other or by a pool of threads are
+        // handled alike
+        for max_concurrent_actions in [1, 4] {
+            // By default the first failure stops the execution
+            let outputs = run_revocation_actions(
+                json.clone(),
+                None,
+                &RevocationOptions {
+                    config_actions: test_config.revocation_actions.clone(),
+                    max_concurrent_actions,
+                    ..test_options(actions_dir, work_dir.path())
+                },
+            );
+            assert!(matches!(outputs, Err(Error::Script(..))));
+
+            // Otherwise the failure is recorded and the other actions are run
+            let outputs = run_revocation_actions(
+                json.clone(),
+                None,
+                &RevocationOptions {
+                    config_actions: test_config.revocation_actions.clone(),
+                    allow_action_failures: true,
+                    max_concurrent_actions,
+                    ..test_options(actions_dir, work_dir.path())
+                },
+            );
+            match outputs {
+                Err(Error::RevocationActions {
+                    total,
+                    succeeded,
+                    failures,
+                }) => {
+                    assert_eq!(total, 5);
+                    assert_eq!(succeeded.len(), 4);
+                    assert_eq!(succeeded[0].name, "local_action_hello");
+                    assert_eq!(failures.len(), 1);
+                    assert!(matches!(
+                        &failures[0],
+                        Error::Script(name, Some(1), _)
+                            if name == "local_action_fail_shell.sh"
+                    ));
+                }
+                other => panic!("unexpected result: {:?}", other), //#[allow_ci]
+            }
+        }
+    }
+
+    #[test]
+    fn revocation_scripts_concurrent() {
+        let mut test_config = KeylimeConfig::default();
+        let json_file = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/unzipped/test_ok.json"
+        );
+        test_config.revocation_actions = String::from(
+            "local_action_hello, local_action_hello_shell.sh, local_action_payload_shell.sh",
+        );
+        let json_str = fs::read_to_string(json_file).unwrap(); //#[allow_ci]
+        let json = serde_json::from_str(&json_str).unwrap(); //#[allow_ci]
+        let actions_dir =
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions/");
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let tmpfs_dir = work_dir.path().join("tmpfs-dev"); //#[allow_ci]
+        fs::create_dir(&tmpfs_dir).unwrap(); //#[allow_ci]
+        let unzipped_dir =
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/unzipped");
+        symlink(unzipped_dir, tmpfs_dir.join("unzipped")).unwrap(); //#[allow_ci]
+        let outputs = run_revocation_actions(
+            json,
+            None,
+            &RevocationOptions {
+                config_actions: test_config.revocation_actions.clone(),
+                max_concurrent_actions: 4,
+                ..test_options(actions_dir, work_dir.path())
+            },
+        );
+
+        let outputs = outputs.unwrap(); //#[allow_ci]
+
+        // Results are in the order of the action list
+        let outputs = outputs
+            .iter()
+            .map(|result| {
+                (
+                    result.name.as_str(),
+                    String::from_utf8(result.output.stdout.clone()).unwrap(), //#[allow_ci]
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            outputs,
+            [
+                ("local_action_hello", "there\n".to_string()),
+                (
+                    "local_action_hello_shell.sh",
+                    "Hello from non-python local action!\n".to_string()
+                ),
+                (
+                    "local_action_payload_shell.sh",
+                    "Hello from non-python payload action!\n".to_string()
+                ),
+                ("local_action_rev_script1.py", "there\n".to_string()),
+                ("local_action_rev_script2.py", "there\n".to_string()),
+                ("local_action_payload", "there\n".to_string()),
+            ]
+        );
+
+        // All the temporary JSON files were removed
+        assert_eq!(fs::read_dir(work_dir.path()).unwrap().count(), 1); //#[allow_ci]
+    }
+
+    #[test]
+    fn revocation_action_timeout() {
+        let actions_dir =
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions/");
+        let payload_dir =
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/unzipped/");
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+
+        let start = Instant::now();
+        let result = run_action(
+            &RevocationOptions {
+                action_timeout: Duration::from_secs(1),
+                ..test_options(actions_dir, work_dir.path())
+            },
+            payload_dir,
+            "local_action_sleep_shell.sh",
+            json!({}),
+            None,
+            false,
+        );
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        match result {
+            Err(e @ Error::Script(..)) => {
+                assert!(matches!(
+                    &e,
+                    Error::Script(action, None, _)
+                        if action == "local_action_sleep_shell.sh"
+                ));
+                assert_eq!(e.exe_code(), None);
+                assert_eq!(e.stderr(), "timed out after 1s");
+            }
+            other => panic!("unexpected result: {:?}", other), //#[allow_ci]
+        }
+
+        // The temporary JSON argument file was removed
+        assert_eq!(fs::read_dir(work_dir.path()).unwrap().count(), 0); //#[allow_ci]
+    }
+
+    #[test]
+    fn revocation_action_metrics() {
+        let actions_dir =
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions/");
+        let payload_dir =
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/unzipped/");
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+
+        // Other tests run the same actions concurrently, so the counters
+        // can only be checked to have grown
+        let stats = |action| {
+            REVOCATION_ACTION_METRICS.get(action).unwrap_or_default()
+        };
+        let actions = [
+            ("local_action_hello", true),
+            ("local_action_hello_shell.sh", true),
+            ("local_action_fail_shell.sh", false),
+        ];
+        let before = actions
+            .iter()
+            .map(|(action, _)| stats(action))
+            .collect::<Vec<_>>();
+
+        for (action, success) in &actions {
+            let result = run_action(
+                &RevocationOptions {
+                    action_timeout: Duration::from_secs(10),
+                    ..test_options(actions_dir, work_dir.path())
+                },
+                payload_dir,
+                action,
+                json!({}),
+                None,
+                false,
+            );
+            assert_eq!(result.is_ok(), *success);
+        }
+
+        for ((action, success), before) in actions.iter().zip(before) {
+            let after = stats(action);
+            assert!(after.runs > before.runs);
+            assert!(after.durations.count > before.durations.count);
+            assert!(after.durations.sum > before.durations.sum);
+            if !success {
+                assert!(after.failures > before.failures);
+            }
+        }
+    }
+
+    #[test]
+    fn revocation_action_env() {
+        let actions_dir =
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions/");
+        let payload_dir =
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/unzipped/");
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+
+        let result = run_action(
+            &RevocationOptions {
+                action_timeout: Duration::from_secs(10),
+                ..test_options(actions_dir, work_dir.path())
+            },
+            payload_dir,
+            "local_action_env_shell.sh",
+            json!({
+                "type": "revocation",
+                "agent_id": "d432fbb3-d2f1-4a97-9ef7-75bd81c00000",
+                "severity_label": "high",
+            }),
+            None,
+            false,
+        )
+        .unwrap(); //#[allow_ci]
+        assert_eq!(
+            String::from_utf8_lossy(&result.output.stdout),
+            format!(
+                "local_action_env_shell.sh\n\
+                 d432fbb3-d2f1-4a97-9ef7-75bd81c00000\n\
+                 {}\n\
+                 high\n",
+                work_dir.path().display()
+            )
+        );
+
+        // Fields missing from the message are not set
+        let env = action_env(
+            "action",
+            &json!({}),
+            work_dir.path(),
+            work_dir.path(),
+        );
+        assert_eq!(
+            env.iter().map(|(var, _)| *var).collect::<Vec<_>>(),
+            ACTION_ENV_VARS[..4].to_vec()
+        );
+    }
+
+    #[test]
+    fn revocation_action_result() {
+        let actions_dir =
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions/");
+        let payload_dir =
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/unzipped/");
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+
+        let run = |action| {
+            run_action(
+                &RevocationOptions {
+                    action_timeout: Duration::from_secs(10),
+                    ..test_options(actions_dir, work_dir.path())
+                },
+                payload_dir,
+                action,
+                json!({}),
+                None,
+                false,
+            )
+            .unwrap() //#[allow_ci]
+        };
+
+        let result = run("local_action_result_shell.sh");
+        assert_eq!(
+            result.result,
+            Some(json!({"network_revoked": true, "nodes_affected": 3}))
+        );
+
+        // Actions not writing a result are not an error
+        let result = run("local_action_hello_shell.sh");
+        assert_eq!(result.result, None);
+
+        // Neither are results that cannot be read
+        let scratch_dir = work_dir.path().join("scratch");
+        fs::create_dir(&scratch_dir).unwrap(); //#[allow_ci]
+        let path = scratch_dir.join(ACTION_RESULT_FILE);
+        fs::write(&path, "not json").unwrap(); //#[allow_ci]
+        assert_eq!(read_action_result("action", &path), None);
+        fs::remove_file(&path).unwrap(); //#[allow_ci]
+        symlink(work_dir.path().join("secret"), &path).unwrap(); //#[allow_ci]
+        fs::write(work_dir.path().join("secret"), "{}").unwrap(); //#[allow_ci]
+        assert_eq!(read_action_result("action", &path), None);
+    }
+
+    #[test]
+    fn revocation_action_scratch_dir() {
+        let actions_dir =
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions/");
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+
+        // The action links to this directory from its scratch directory
+        let outside = work_dir.path().join("outside");
+        fs::create_dir(&outside).unwrap(); //#[allow_ci]
+        fs::write(outside.join("keep"), "keep").unwrap(); //#[allow_ci]
+
+        let run = |scratch_cleanup| {
+            let result = run_action(
+                &RevocationOptions {
+                    action_timeout: Duration::from_secs(10),
+                    scratch_cleanup,
+                    ..test_options(actions_dir, work_dir.path())
+                },
+                work_dir.path(),
+                "local_action_scratch_shell.sh",
+                json!({}),
+                None,
+                false,
+            )
+            .unwrap(); //#[allow_ci]
+            PathBuf::from(
+                String::from_utf8_lossy(&result.output.stdout).as_ref(),
+            )
+        };
+
+        // The scratch directory is removed after the action, without
+        // following the symbolic links in it
+        let scratch_dir = run(ScratchCleanup::Always);
+        assert!(scratch_dir.starts_with(work_dir.path()));
+        assert!(!scratch_dir.exists());
+        assert!(outside.join("keep").exists());
+
+        // Unless configured otherwise
+        let scratch_dir = run(ScratchCleanup::Never);
+        assert_eq!(
+            fs::read_to_string(scratch_dir.join("data")).unwrap(), //#[allow_ci]
+            "transient\n"
+        );
+        assert!(ScratchCleanup::OnSuccess.should_remove(true));
+        assert!(!ScratchCleanup::OnSuccess.should_remove(false));
+        assert!(matches!(
+            ScratchCleanup::from_str("sometimes"),
+            Err(Error::Configuration(_))
+        ));
+    }
+
+    #[test]
+    fn revocation_action_spawn_failure() {
+        let actions_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+
+        // A native action without the execute permission cannot be started
+        let action = actions_dir.path().join("local_action_not_executable");
+        fs::write(&action, "not a script").unwrap(); //#[allow_ci]
+        fs::set_permissions(&action, fs::Permissions::from_mode(0o644))
+            .unwrap(); //#[allow_ci]
+
+        let result = run_action(
+            &RevocationOptions {
+                action_timeout: Duration::from_secs(1),
+                ..test_options(actions_dir.path(), work_dir.path())
+            },
+            work_dir.path(),
+            "local_action_not_executable",
+            json!({}),
+            None,
+            false,
+        );
+
+        match result {
+            Err(e @ Error::Script(..)) => {
+                assert!(matches!(
+                    &e,
+                    Error::Script(action, None, _)
+                        if action == "local_action_not_executable"
+                ));
+                assert_eq!(e.exe_code(), None);
+                assert!(e.stderr().starts_with("failed to start"));
+            }
+            other => panic!("unexpected result: {:?}", other), //#[allow_ci]
+        }
+
+        // The temporary JSON argument file was removed
+        assert_eq!(fs::read_dir(work_dir.path()).unwrap().count(), 0); //#[allow_ci]
+    }
+
+    #[test]
+    fn revocation_action_exit_code() {
+        let actions_dir =
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions/");
+        let payload_dir =
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/unzipped/");
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+
+        let result = run_action(
+            &RevocationOptions {
+                action_timeout: Duration::from_secs(10),
+                ..test_options(actions_dir, work_dir.path())
+            },
+            payload_dir,
+            "local_action_fail_shell.sh",
+            json!({}),
+            None,
+            false,
+        );
+
+        match result {
+            Err(e @ Error::Script(..)) => {
+                assert!(matches!(
+                    &e,
+                    Error::Script(action, Some(1), _)
+                        if action == "local_action_fail_shell.sh"
+                ));
+                assert_eq!(e.exe_code(), Some(1));
+                assert_eq!(e.stderr(), "Failing on purpose\n");
+            }
+            other => panic!("unexpected result: {:?}", other), //#[allow_ci]
+        }
+    }
+
+    #[test]
+    fn revocation_action_retry() {
+        let actions_dir =
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions/");
+        let payload_dir =
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/unzipped/");
+        let retry = RetryPolicy::new(3, Duration::from_millis(10), vec![75]);
+        assert_eq!(retry.backoff(1, 75), Some(Duration::from_millis(10)));
+        assert_eq!(retry.backoff(2, 75), Some(Duration::from_millis(20)));
+        assert_eq!(retry.backoff(3, 75), None);
+        assert_eq!(retry.backoff(1, 1), None);
+
+        // The action fails once, then succeeds with the same JSON file
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let result = run_action(
+            &RevocationOptions {
+                action_timeout: Duration::from_secs(10),
+                retry_policy: retry.clone(),
+                ..test_options(actions_dir, work_dir.path())
+            },
+            payload_dir,
+            "local_action_retry_shell.sh",
+            json!({}),
+            None,
+            false,
+        )
+        .unwrap(); //#[allow_ci]
+        assert_eq!(
+            String::from_utf8_lossy(&result.output.stdout),
+            "Succeeded after a retry\n"
+        );
+
+        // The temporary JSON argument file was removed
+        let files = fs::read_dir(work_dir.path())
+            .unwrap() //#[allow_ci]
+            .map(|entry| entry.unwrap().file_name()) //#[allow_ci]
+            .collect::<Vec<_>>();
+        assert_eq!(files, vec![OsString::from("retry_marker")]);
+
+        // Without retries, the first failure is reported
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let result = run_action(
+            &RevocationOptions {
+                action_timeout: Duration::from_secs(10),
+                ..test_options(actions_dir, work_dir.path())
+            },
+            payload_dir,
+            "local_action_retry_shell.sh",
+            json!({}),
+            None,
+            false,
+        );
+        assert!(matches!(
+            result,
+            Err(Error::Script(action, Some(75), _))
+                if action == "local_action_retry_shell.sh"
+        ));
+
+        // Failures with other exit codes are not retried
+        let start = Instant::now();
+        let result = run_action(
+            &RevocationOptions {
+                action_timeout: Duration::from_secs(10),
+                retry_policy: RetryPolicy::new(
+                    3,
+                    Duration::from_secs(10),
+                    vec![75],
+                ),
+                ..test_options(actions_dir, work_dir.path())
+            },
+            payload_dir,
+            "local_action_fail_shell.sh",
+            json!({}),
+            None,
+            false,
+        );
+        assert!(matches!(result, Err(Error::Script(_, Some(1), _))));
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn revocation_scripts_unsafe_dir() {
+        let test_config = KeylimeConfig::default();
+        let json_file = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/unzipped/test_ok.json"
+        );
+        let json_str = fs::read_to_string(json_file).unwrap(); //#[allow_ci]
+        let json = serde_json::from_str(&json_str).unwrap(); //#[allow_ci]
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let tmpfs_dir = work_dir.path().join("tmpfs-dev"); //#[allow_ci]
+        fs::create_dir(&tmpfs_dir).unwrap(); //#[allow_ci]
+        let unzipped_dir =
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/unzipped");
+        symlink(unzipped_dir, tmpfs_dir.join("unzipped")).unwrap(); //#[allow_ci]
+
+        // Use a copy of a pre-installed action in a world writable directory
+        let actions_dir = work_dir.path().join("actions");
+        fs::create_dir(&actions_dir).unwrap(); //#[allow_ci]
+        let _ = fs::copy(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/actions/local_action_hello_shell.sh"),
+            actions_dir.join("local_action_hello_shell.sh"),
+        )
+        .unwrap(); //#[allow_ci]
+        fs::set_permissions(&actions_dir, fs::Permissions::from_mode(0o777))
+            .unwrap(); //#[allow_ci]
+
+        assert!(check_actions_dir_permissions(&actions_dir).is_err());
+
+        let outputs = run_revocation_actions(
+            json,
+            None,
+            &RevocationOptions {
+                config_actions: String::from("local_action_hello_shell.sh"),
+                allow_payload_actions: false,
+                check_dir_permissions: true,
+                ..test_options(&actions_dir, work_dir.path())
+            },
+        );
+        assert!(outputs.is_err());
+
+        fs::set_permissions(&actions_dir, fs::Permissions::from_mode(0o755))
+            .unwrap(); //#[allow_ci]
+        assert!(check_actions_dir_permissions(&actions_dir).is_ok());
+    }
+
+    #[tokio::test]
+    async fn revocation_cert_path_default() {
+        let test_config = KeylimeConfig::default();
+        let revocation_cert_path = RevocationCert::from_config(&test_config)
+            .await
+            .map(|cert| cert.path().to_path_buf())
+            .unwrap(); //#[allow_ci]
+        let mut expected = PathBuf::from(&test_config.work_dir);
+        expected.push("secure/unzipped/");
+        expected.push(REV_CERT);
+        assert_eq!(*revocation_cert_path, expected);
+    }
+
+    #[tokio::test]
+    async fn revocation_cert_path_absolute() {
+        let mut test_config = KeylimeConfig {
+            revocation_cert: String::from("/test/cert.crt"),
+            ..Default::default()
+        };
+        let revocation_cert_path = RevocationCert::from_config(&test_config)
+            .await
+            .map(|cert| cert.path().to_path_buf())
+            .unwrap(); //#[allow_ci]
+        assert_eq!(revocation_cert_path, PathBuf::from("/test/cert.crt"));
+    }
+
+    #[tokio::test]
+    async fn revocation_cert_path_relative() {
+        let mut test_config = KeylimeConfig {
+            revocation_cert: String::from("cert.crt"),
+            ..Default::default()
+        };
+        let revocation_cert_path = RevocationCert::from_config(&test_config)
+            .await
+            .map(|cert| cert.path().to_path_buf())
+            .unwrap(); //#[allow_ci]
+        let mut expected = Path::new(&test_config.work_dir).join("cert.crt");
+        assert_eq!(revocation_cert_path, expected);
+    }
+
+    #[tokio::test]
+    async fn revocation_cert_path_empty() {
+        let mut test_config = KeylimeConfig {
+            revocation_cert: String::from(""),
+            ..Default::default()
+        };
+        assert!(
+            RevocationCert::from_config(&test_config).await.is_err(),
+            "revocation_cert is not set in configuration"
+        );
+    }
+
+    #[tokio::test]
+    async fn revocation_cert_path_http() {
+        let test_config = KeylimeConfig {
+            revocation_cert: String::from("http://127.0.0.1/cert.crt"),
+            ..Default::default()
+        };
+        assert!(matches!(
+            RevocationCert::from_config(&test_config).await,
+            Err(Error::Configuration(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_revocation_cert() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let cert = fs::read(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("test-data/test-cert.pem"),
+        )
+        .unwrap(); //#[allow_ci]
+
+        // The HTTPS only client is not used here, the mock server is plain
+        // HTTP
+        let client = reqwest::Client::new();
+
+        let mock_server = MockServer::start().await;
+        mock_server
+            .register(
+                Mock::given(method("GET"))
+                    .and(path("/cert.crt"))
+                    .respond_with(
+                        ResponseTemplate::new(200)
+                            .set_body_bytes(cert.clone()),
+                    )
+                    .expect(2),
+            )
+            .await;
+        mock_server
+            .register(
+                Mock::given(method("GET"))
+                    .and(path("/invalid.crt"))
+                    .respond_with(
+                        ResponseTemplate::new(200)
+                            .set_body_string("not a certificate"),
+                    ),
+            )
+            .await;
+        let url = format!("{}/cert.crt", mock_server.uri());
+
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let cache = work_dir.path().join(REV_CERT_CACHE);
+        let ttl = Duration::from_secs(3600);
+        let cert_path = fetch_revocation_cert(&client, &url, &cache, ttl)
+            .await
+            .unwrap(); //#[allow_ci]
+        assert_eq!(cert_path, cache);
+        assert_eq!(fs::read(&cert_path).unwrap(), cert); //#[allow_ci]
+        assert!(crypto::load_x509(&cert_path).is_ok());
+
+        // The cached certificate is reused until the TTL expires
+        let cert_path = fetch_revocation_cert(&client, &url, &cache, ttl)
+            .await
+            .unwrap(); //#[allow_ci]
+        assert_eq!(cert_path, cache);
+        let cert_path = fetch_revocation_cert(
+            &client,
+            &url,
+            &cache,
+            Duration::from_secs(0),
+        )
+        .await
+        .unwrap(); //#[allow_ci]
+        assert_eq!(cert_path, cache);
+
+        // Failures are reported with the URL, and do not replace the cached
+        // certificate
+        for url in [
+            format!("{}/invalid.crt", mock_server.uri()),
+            format!("{}/missing.crt", mock_server.uri()),
+        ] {
+            match fetch_revocation_cert(
+                &client,
+                &url,
+                &cache,
+                Duration::from_secs(0),
+            )
+            .await
+            {
+                Err(Error::Configuration(msg)) => assert!(msg.contains(&url)),
+                other => panic!("unexpected result: {:?}", other), //#[allow_ci]
+            }
+        }
+        assert_eq!(fs::read(&cache).unwrap(), cert); //#[allow_ci]
+    }
+
+    #[tokio::test]
+    async fn test_refresh_revocation_cert() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let test_data =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data");
+        let rsa_cert = fs::read(test_data.join("test-cert.pem")).unwrap(); //#[allow_ci]
+        let ec_cert = fs::read(test_data.join("test-cert-ec.pem")).unwrap(); //#[allow_ci]
+
+        let mock_server = MockServer::start().await;
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let remote = RemoteRevocationCert {
+            url: format!("{}/cert.crt", mock_server.uri()),
+            client: reqwest::Client::new(),
+            cache: work_dir.path().join(REV_CERT_CACHE),
+            ttl: Duration::from_secs(0),
+        };
+
+        // Without a previous download, the certificate must be downloaded
+        assert!(remote.fetch().await.is_err());
+
+        mock_server
+            .register(
+                Mock::given(method("GET"))
+                    .and(path("/cert.crt"))
+                    .respond_with(
+                        ResponseTemplate::new(200).set_body_bytes(rsa_cert),
+                    )
+                    .up_to_n_times(1),
+            )
+            .await;
+        let cert = RevocationCert {
+            path: remote.fetch().await.unwrap(), //#[allow_ci]
+            remote: Some(remote),
+            cached: Mutex::new(None),
+        };
+        let rsa_key = cert.keys().unwrap().remove(0).1; //#[allow_ci]
+        assert_eq!(rsa_key.id(), openssl::pkey::Id::RSA);
+
+        // The expired download is kept if the certificate cannot be
+        // downloaded again
+        cert.refresh().await;
+        let key = cert.keys().unwrap().remove(0).1; //#[allow_ci]
+        assert!(key.public_eq(&rsa_key));
+
+        // Once the certificate is downloaded again, the new key is used
+        mock_server
+            .register(
+                Mock::given(method("GET"))
+                    .and(path("/cert.crt"))
+                    .respond_with(
+                        ResponseTemplate::new(200).set_body_bytes(ec_cert),
+                    ),
+            )
+            .await;
+        // The modification time of the download tells the keys apart
+        thread::sleep(Duration::from_millis(10));
+        cert.refresh().await;
+        let key = cert.keys().unwrap().remove(0).1; //#[allow_ci]
+        assert_eq!(key.id(), openssl::pkey::Id::EC);
+    }
+
+    #[test]
+    fn test_lookup_action_python_shim() {
+        let work_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+        let payload_dir = Path::new(&work_dir).join("unzipped/");
+        let actions_dir = Path::new(&work_dir).join("actions/");
+
+        // The shim from the actions directory is used by default
+        let test_config = KeylimeConfig::default();
+        let python_shim = PythonShim::from_config(&test_config);
+        assert_eq!(python_shim, PythonShim::default());
+        assert_eq!(
+            lookup_action(
+                &payload_dir,
+                &actions_dir,
+                &python_shim,
+                "local_action_hello",
+                false,
+                &ActionPolicy::default()
+            )
+            .unwrap() //#[allow_ci]
+            .0,
+            actions_dir.join("shim.py").display().to_string()
+        );
+
+        // A configured shim overrides the default one
+        let shim_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let shim = shim_dir.path().join("keylime-shim.py");
+        let _ = fs::copy(actions_dir.join("shim.py"), &shim).unwrap(); //#[allow_ci]
+        let test_config = KeylimeConfig {
+            python_shim_path: shim.display().to_string(),
+            ..Default::default()
+        };
+        let python_shim = PythonShim::from_config(&test_config);
+        assert_eq!(python_shim.path.as_deref(), Some(shim.as_path()));
+        assert_eq!(
+            lookup_action(
+                &payload_dir,
+                &actions_dir,
+                &python_shim,
+                "local_action_hello",
+                false,
+                &ActionPolicy::default()
+            )
+            .unwrap(), //#[allow_ci]
+            (shim.display().to_string(), ActionKind::Python, false)
+        );
+
+        // A missing shim is reported instead of being run
+        fs::remove_file(&shim).unwrap(); //#[allow_ci]
+        match lookup_action(
+            &payload_dir,
+            &actions_dir,
+            &python_shim,
+            "local_action_hello",
+            false,
+            &ActionPolicy::default(),
+        ) {
+            Err(Error::Configuration(msg)) => assert_eq!(
+                msg,
+                format!(
+                    "Could not find python shim at {} to run action local_action_hello",
+                    shim.display()
+                )
+            ),
+            other => panic!("unexpected result: {:?}", other), //#[allow_ci]
+        }
+    }
+
+    #[test]
+    fn test_run_action_python_interpreter() {
+        let actions_dir =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions/");
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+
+        // The shim cannot be executed directly without the execute
+        // permission
+        let shim_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let shim = shim_dir.path().join("shim.py");
+        let _ = fs::copy(actions_dir.join("shim.py"), &shim).unwrap(); //#[allow_ci]
+        fs::set_permissions(&shim, fs::Permissions::from_mode(0o644))
+            .unwrap(); //#[allow_ci]
+        let mut python_shim = PythonShim {
+            path: Some(shim),
+            interpreter: None,
+        };
+        let run = |python_shim: &PythonShim| {
+            run_action(
+                &RevocationOptions {
+                    python_shim: python_shim.clone(),
+                    action_timeout: Duration::from_secs(10),
+                    ..test_options(&actions_dir, work_dir.path())
+                },
+                work_dir.path(),
+                "local_action_hello",
+                json!({"hello": "there"}),
+                None,
+                false,
+            )
+        };
+        assert!(matches!(
+            run(&python_shim),
+            Err(Error::Script(action, None, _))
+                if action == "local_action_hello"
+        ));
+
+        // The configured interpreter runs it instead
+        let test_config = KeylimeConfig {
+            python_interpreter: "/usr/bin/python3".to_string(),
+            ..Default::default()
+        };
+        python_shim.interpreter = get_python_interpreter(&test_config);
+        let result = run(&python_shim).unwrap(); //#[allow_ci]
+        assert_eq!(
+            String::from_utf8(result.output.stdout).unwrap(), //#[allow_ci]
+            "there\n"
+        );
+    }
+
+    #[test]
+    fn test_action_policy() {
+        let work_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+        let payload_dir = Path::new(&work_dir).join("unzipped/");
+        let actions_dir = Path::new(&work_dir).join("actions/");
+        let policy = ActionPolicy::new(
+            " local_action_payload_shell.sh ",
+            "local_action_hello_shell.sh, local_action_rev_script1",
+        );
+
+        // A payload action in the allowlist
+        let (command, _, is_payload) = lookup_action(
+            &payload_dir,
+            &actions_dir,
+            &PythonShim::default(),
+            "local_action_payload_shell.sh",
+            true,
+            &policy,
+        )
+        .unwrap(); //#[allow_ci]
+        assert!(is_payload);
+        assert_eq!(
+            command,
+            payload_dir
+                .join("local_action_payload_shell.sh")
+                .display()
+                .to_string()
+        );
+
+        // A payload action missing from the allowlist
+        let err = lookup_action(
+            &payload_dir,
+            &actions_dir,
+            &PythonShim::default(),
+            "local_action_rev_script2",
+            true,
+            &policy,
+        )
+        .unwrap_err(); //#[allow_ci]
+        assert_eq!(
+            err.to_string(),
+            "payload revocation action local_action_rev_script2 is not listed in revocation_actions_allowlist"
+        );
+
+        // Denied payload and pre-installed actions
+        for action in
+            &["local_action_rev_script1", "local_action_hello_shell.sh"]
+        {
+            let err = lookup_action(
+                &payload_dir,
+                &actions_dir,
+                &PythonShim::default(),
+                action,
+                true,
+                &policy,
+            )
+            .unwrap_err(); //#[allow_ci]
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "revocation action {} is listed in revocation_actions_denylist",
+                    action
+                )
+            );
+        }
+
+        // The allowlist does not restrict pre-installed actions
+        assert!(lookup_action(
+            &payload_dir,
+            &actions_dir,
+            &PythonShim::default(),
+            "local_action_hello",
+            true,
+            &policy,
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_lookup_action() {
+        let work_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+        let payload_dir = Path::new(&work_dir).join("unzipped/");
+        let actions_dir = Path::new(&work_dir).join("actions/");
+
+        // Test local python action
+        let expected = format!("{}", &actions_dir.join("shim.py").display(),);
+
+        assert_eq!(
+            lookup_action(
+                &payload_dir,
+                &actions_dir,
+                &PythonShim::default(),
+                "local_action_hello",
+                true,
+                &ActionPolicy::default()
+            )
+            .unwrap(), //#[allow_ci]
+            (expected, ActionKind::Python, false)
+        );
+
+        // Test local non-python action
+        let expected = format!(
+            "{}",
+            &actions_dir.join("local_action_hello_shell.sh").display()
+        );
+
+        assert_eq!(
+            lookup_action(
+                &payload_dir,
+                &actions_dir,
+                &PythonShim::default(),
+                "local_action_hello_shell.sh",
+                true,
+                &ActionPolicy::default()
+            )
+            .unwrap(), //#[allow_ci]
+            (
+                expected,
+                ActionKind::Shebang(PathBuf::from("/bin/bash"), None),
+                false
+            )
+        );
+
+        // Test payload python action
+        let expected = format!("{}", &actions_dir.join("shim.py").display(),);
+
+        assert_eq!(
+            lookup_action(
+                &payload_dir,
+                &actions_dir,
+                &PythonShim::default(),
+                "local_action_payload",
+                true,
+                &ActionPolicy::default(),
+            )
+            .unwrap(), //#[allow_ci]
+            (expected, ActionKind::Python, true),
+        );
+
+        // Test payload non-python action
+        let expected = format!(
+            "{}",
+            &payload_dir.join("local_action_payload_shell.sh").display()
+        );
+
+        assert_eq!(
+            lookup_action(
+                &payload_dir,
+                &actions_dir,
+                &PythonShim::default(),
+                "local_action_payload_shell.sh",
+                true,
+                &ActionPolicy::default()
+            )
+            .unwrap(), //#[allow_ci]
+            (
+                expected,
+                ActionKind::Shebang(PathBuf::from("/bin/bash"), None),
+                true
+            )
+        );
+
+        // Test that disallowing payload works
+        let expected: Result<(String, bool)> =
+            Err(Error::Io(std::io::Error::new(
+                ErrorKind::NotFound,
+                "Could not find action local_action_payload_shell.sh"
+                    .to_string(),
+            )));
+
+        assert!(matches!(
+            lookup_action(
+                &payload_dir,
+                &actions_dir,
+                &PythonShim::default(),
+                "local_action_payload_shell.sh",
+                false,
+                &ActionPolicy::default()
+            ),
+            expected,
+        ));
+
+        // Test non-existent action
+        let expected: Result<(String, bool)> =
+            Err(Error::Io(std::io::Error::new(
+                ErrorKind::NotFound,
+                "Could not find action local_action_non_existent".to_string(),
+            )));
+
+        assert!(matches!(
+            lookup_action(
+                &payload_dir,
+                &actions_dir,
+                &PythonShim::default(),
+                "local_action_non_existent",
+                true,
+                &ActionPolicy::default()
+            ),
+            expected,
+        ));
+
+        // The not found error names the action and the searched directories
+        match lookup_action(
+            &payload_dir,
+            &actions_dir,
+            &PythonShim::default(),
+            "local_action_non_existent",
+            false,
+            &ActionPolicy::default(),
+        ) {
+            Err(Error::Io(e)) => {
+                assert_eq!(e.kind(), ErrorKind::NotFound);
+                let msg = e.to_string();
+                assert!(msg.contains("local_action_non_existent"));
+                assert!(msg.contains(&actions_dir.display().to_string()));
+                assert!(!msg.contains(&payload_dir.display().to_string()));
+            }
+            other => panic!("unexpected result: {:?}", other), //#[allow_ci]
         }
     }
 
     #[test]
-    fn revocation_scripts_err() {
+    fn test_lookup_action_name() {
+        let work_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+        let payload_dir = Path::new(&work_dir).join("unzipped/");
+        let actions_dir = Path::new(&work_dir).join("actions/");
+
+        for action in
+            &["../../etc/passwd", "foo/bar", "..", ".", "/bin/sh", ""]
+        {
+            match lookup_action(
+                &payload_dir,
+                &actions_dir,
+                &PythonShim::default(),
+                action,
+                true,
+                &ActionPolicy::default(),
+            ) {
+                Err(Error::Other(msg)) => assert_eq!(
+                    msg,
+                    format!(
+                        "revocation action name {:?} is not a plain file name",
+                        action
+                    )
+                ),
+                other => panic!("unexpected result: {:?}", other), //#[allow_ci]
+            }
+        }
+
+        // Names merely containing dots are plain file names
+        assert!(check_action_name("local_action_hello_shell.sh").is_ok());
+        assert!(check_action_name("..hidden").is_ok());
+    }
+
+    #[test]
+    fn test_dedup_actions() {
+        assert_eq!(
+            dedup_actions(vec![" foo ", "bar", "foo", "baz", " bar"]),
+            ["foo", "bar", "baz"]
+        );
+    }
+
+    #[test]
+    fn test_read_shebang() {
+        let tempdir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let script = tempdir.path().join("action");
+
+        fs::write(&script, "#!/bin/bash\necho hello\n").unwrap(); //#[allow_ci]
+        assert_eq!(
+            read_shebang(&script).unwrap(), //#[allow_ci]
+            Some((PathBuf::from("/bin/bash"), None))
+        );
+
+        fs::write(&script, "#! /usr/bin/env  node \n").unwrap(); //#[allow_ci]
+        assert_eq!(
+            read_shebang(&script).unwrap(), //#[allow_ci]
+            Some((PathBuf::from("/usr/bin/env"), Some("node".to_string())))
+        );
+
+        fs::write(&script, "import sys\n").unwrap(); //#[allow_ci]
+        assert_eq!(read_shebang(&script).unwrap(), None); //#[allow_ci]
+
+        fs::write(&script, "#!\n").unwrap(); //#[allow_ci]
+        assert_eq!(read_shebang(&script).unwrap(), None); //#[allow_ci]
+    }
+
+    #[test]
+    fn revocation_scripts_shebang_payload() {
         let test_config = KeylimeConfig::default();
-        let json_file = concat!(
-            env!("CARGO_MANIFEST_DIR"),
-            "/tests/unzipped/test_err.json"
+        let actions_dir =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions/");
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let payload_dir = work_dir.path().join("unzipped");
+        fs::create_dir(&payload_dir).unwrap(); //#[allow_ci]
+
+        // Payload scripts may not be executable once extracted, the
+        // interpreter from the shebang is used to run them
+        let script = payload_dir.join("local_action_payload_bash");
+        fs::write(&script, "#!/bin/bash\necho \"Hello from $0\"\n").unwrap(); //#[allow_ci]
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o644))
+            .unwrap(); //#[allow_ci]
+
+        assert_eq!(
+            lookup_action(
+                &payload_dir,
+                &actions_dir,
+                &PythonShim::default(),
+                "local_action_payload_bash",
+                true,
+                &ActionPolicy::default()
+            )
+            .unwrap(), //#[allow_ci]
+            (
+                script.display().to_string(),
+                ActionKind::Shebang(PathBuf::from("/bin/bash"), None),
+                true
+            )
         );
-        let json_str = std::fs::read_to_string(json_file).unwrap(); //#[allow_ci]
-        let json = serde_json::from_str(&json_str).unwrap(); //#[allow_ci]
+
+        let result = run_action(
+            &test_options(&actions_dir, work_dir.path()),
+            &payload_dir,
+            "local_action_payload_bash",
+            json!({}),
+            None,
+            true,
+        )
+        .unwrap(); //#[allow_ci]
+        assert!(result.was_payload);
+        assert_eq!(
+            String::from_utf8_lossy(&result.output.stdout),
+            format!("Hello from {}\n", script.display())
+        );
+    }
+
+    #[test]
+    fn test_lookup_action_python_shebang() {
         let actions_dir =
-            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions/");
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions/");
         let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
-        let tmpfs_dir = work_dir.path().join("tmpfs-dev"); //#[allow_ci]
-        fs::create_dir(&tmpfs_dir).unwrap(); //#[allow_ci]
-        let unzipped_dir =
-            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/unzipped");
-        symlink(unzipped_dir, tmpfs_dir.join("unzipped")).unwrap(); //#[allow_ci]
-        let outputs = run_revocation_actions(
-            json,
-            &test_config.secure_size,
-            &test_config.revocation_actions,
-            actions_dir,
+        let payload_dir = work_dir.path().join("unzipped");
+        fs::create_dir(&payload_dir).unwrap(); //#[allow_ci]
+
+        // Python actions are run through the shim even with a shebang line
+        fs::write(
+            payload_dir.join("local_action_payload_env.py"),
+            "#!/usr/bin/env python3\ndef execute(revocation):\n    pass\n",
+        )
+        .unwrap(); //#[allow_ci]
+
+        assert_eq!(
+            lookup_action(
+                &payload_dir,
+                &actions_dir,
+                &PythonShim::default(),
+                "local_action_payload_env",
+                true,
+                &ActionPolicy::default()
+            )
+            .unwrap(), //#[allow_ci]
+            (
+                actions_dir.join("shim.py").display().to_string(),
+                ActionKind::Python,
+                true
+            )
+        );
+    }
+
+    #[test]
+    fn test_action_output_invalid_utf8() {
+        let test_config = KeylimeConfig::default();
+        let actions_dir =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions/");
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let payload_dir = work_dir.path().join("unzipped");
+        fs::create_dir(&payload_dir).unwrap(); //#[allow_ci]
+
+        let script = payload_dir.join("local_action_binary");
+        fs::write(&script, "#!/bin/sh\nprintf 'ok\\377\\376'\n").unwrap(); //#[allow_ci]
+
+        let result = run_action(
+            &test_options(&actions_dir, work_dir.path()),
+            &payload_dir,
+            "local_action_binary",
+            json!({}),
+            None,
             true,
-            work_dir.path(),
+        )
+        .unwrap(); //#[allow_ci]
+        assert_eq!(result.output.stdout, b"ok\xff\xfe");
+
+        // Logging the binary output does not panic
+        log_action_results(std::slice::from_ref(&result));
+
+        let record =
+            action_output_record(&result, "stdout", &result.output.stdout);
+        assert_eq!(
+            record,
+            json!({
+                "action": "local_action_binary",
+                "origin": "payload",
+                "stream": "stdout",
+                "exit_code": 0,
+                "output": "ok\u{fffd}\u{fffd}",
+                "output_hex": "6f6bfffe",
+            })
         );
-        assert!(outputs.is_err());
+
+        // Valid UTF-8 output is not hex encoded
+        let record = action_output_record(&result, "stderr", b"warning");
+        assert_eq!(record["output"], "warning");
+        assert!(record.get("output_hex").is_none());
     }
 
     #[test]
-    fn revocation_scripts_from_config() {
-        let mut test_config = KeylimeConfig::default();
-        let json_file = concat!(
-            env!("CARGO_MANIFEST_DIR"),
-            "/tests/unzipped/test_ok.json"
+    fn test_payload_ready() {
+        let tempdir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let unzipped = tempdir.path().join("unzipped");
+
+        // Missing payload directory
+        assert!(!payload_ready(&unzipped));
+
+        // Empty payload directory: payload actions are skipped and only
+        // pre-installed actions are found
+        fs::create_dir(&unzipped).unwrap(); //#[allow_ci]
+        assert!(!payload_ready(&unzipped));
+
+        let actions_dir =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions/");
+        assert!(lookup_action(
+            &unzipped,
+            &actions_dir,
+            &PythonShim::default(),
+            "local_action_hello",
+            payload_ready(&unzipped),
+            &ActionPolicy::default()
+        )
+        .is_ok());
+
+        let _ = fs::File::create(unzipped.join("action_list")).unwrap(); //#[allow_ci]
+        assert!(payload_ready(&unzipped));
+    }
+
+    // Runs a revocation service, requesting it to shut down after a while,
+    // and returns its result
+    #[cfg(any(feature = "with-zmq", feature = "with-webhook"))]
+    async fn run_until_shutdown<F, Fut>(service: F) -> Result<()>
+    where
+        F: FnOnce(watch::Receiver<bool>) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let shutdown = async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            shutdown_tx.send(true).unwrap(); //#[allow_ci]
+        };
+        let (result, ()) =
+            tokio::time::timeout(Duration::from_secs(10), async move {
+                tokio::join!(service(shutdown_rx), shutdown)
+            })
+            .await
+            .unwrap(); //#[allow_ci]
+        result
+    }
+
+    #[cfg(feature = "with-zmq")]
+    #[tokio::test]
+    async fn test_revocation_service_shutdown() {
+        // Nothing listens on the endpoint, the service keeps waiting for
+        // messages until it is requested to shut down
+        let test_config = KeylimeConfig {
+            work_dir: Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests")
+                .display()
+                .to_string(),
+            revocation_ip: "127.0.0.1".to_string(),
+            revocation_port: "1".to_string(),
+            ..Default::default()
+        };
+
+        let cert = Arc::new(
+            RevocationCert::from_config(&test_config).await.unwrap(), //#[allow_ci]
         );
-        test_config.revocation_actions =
-            String::from("local_action_hello, local_action_payload");
-        let json_str = std::fs::read_to_string(json_file).unwrap(); //#[allow_ci]
-        let json = serde_json::from_str(&json_str).unwrap(); //#[allow_ci]
+        let history = Arc::new(RevocationHistory::default());
+        assert!(run_until_shutdown(|shutdown| run_revocation_service(
+            &test_config,
+            &cert,
+            &history,
+            shutdown
+        ))
+        .await
+        .is_ok());
+    }
+
+    #[cfg(feature = "with-webhook")]
+    #[tokio::test]
+    async fn test_revocation_webhook_service_shutdown() {
+        // The endpoint cannot be reached, the service keeps polling until it
+        // is requested to shut down
+        let test_config = KeylimeConfig {
+            work_dir: Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests")
+                .display()
+                .to_string(),
+            revocation_notification_url: "https://127.0.0.1:1/".to_string(),
+            revocation_poll_interval: Duration::from_secs(60),
+            ..Default::default()
+        };
+
+        let cert = Arc::new(
+            RevocationCert::from_config(&test_config).await.unwrap(), //#[allow_ci]
+        );
+        let history = Arc::new(RevocationHistory::default());
+        assert!(run_until_shutdown(|shutdown| {
+            run_revocation_webhook_service(
+                &test_config,
+                &cert,
+                &history,
+                shutdown,
+            )
+        })
+        .await
+        .is_ok());
+    }
+
+    #[cfg(feature = "with-webhook")]
+    #[tokio::test]
+    async fn test_fetch_revocation() {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let test_data =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data");
+        let signature =
+            fs::read_to_string(test_data.join("revocation.sig")).unwrap(); //#[allow_ci]
+        let message =
+            fs::read_to_string(test_data.join("test_ok.json")).unwrap(); //#[allow_ci]
+
+        // The HTTPS only client is not used here, the mock server is plain
+        // HTTP
+        let client = reqwest::Client::new();
+
+        let mock_server = MockServer::start().await;
+        mock_server
+            .register(Mock::given(method("GET")).respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({
+                    "msg": message,
+                    "signature": signature,
+                })),
+            ))
+            .await;
+
+        let body = fetch_revocation(&client, &mock_server.uri())
+            .await
+            .unwrap() //#[allow_ci]
+            .unwrap(); //#[allow_ci]
+        assert_eq!(body["signature"], signature.as_str());
+
+        // The polled message goes through the same processing
+        let test_config = KeylimeConfig::default();
+        let cert = RevocationCert::new(test_data.join("test-cert.pem"));
+        let actions_dir =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions");
+        let work_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+        assert!(process_revocation(
+            body,
+            &cert,
+            &crypto::OpensslVerifier,
+            &RevocationOptions {
+                config_actions: test_config.revocation_actions.clone(),
+                ..test_options(&actions_dir, &work_dir)
+            },
+            &RevocationHistory::default(),
+        )
+        .is_ok());
+
+        // No pending message
+        mock_server.reset().await;
+        mock_server
+            .register(
+                Mock::given(method("GET"))
+                    .respond_with(ResponseTemplate::new(204)),
+            )
+            .await;
+        assert!(fetch_revocation(&client, &mock_server.uri())
+            .await
+            .unwrap() //#[allow_ci]
+            .is_none());
+
+        // Errors are reported, to be logged by the service
+        mock_server.reset().await;
+        mock_server
+            .register(
+                Mock::given(method("GET"))
+                    .respond_with(ResponseTemplate::new(500)),
+            )
+            .await;
+        assert!(fetch_revocation(&client, &mock_server.uri()).await.is_err());
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let mut backoff =
+            Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+
+        // The delay doubles up to the maximum
+        let delays: Vec<u64> =
+            (0..5).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 5, 5]);
+
+        // Once reset, it starts again from the initial delay
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_warn_throttle() {
+        let mut throttle = WarnThrottle::new(WARN_COALESCE_PERIOD);
+        let start = Instant::now();
+
+        // Only the first of many warnings within the period is logged
+        for _ in 0..1000 {
+            throttle.warn_at("bad message", start);
+        }
+        assert_eq!(throttle.window_start, Some(start));
+        assert_eq!(throttle.suppressed, 999);
+
+        // The count is kept until the period is over
+        throttle.flush_at(start + WARN_COALESCE_PERIOD / 2);
+        assert_eq!(throttle.suppressed, 999);
+
+        // Once the period is over, the count is logged and reset without
+        // waiting for another warning
+        throttle.flush_at(start + WARN_COALESCE_PERIOD);
+        assert_eq!(throttle.window_start, None);
+        assert_eq!(throttle.suppressed, 0);
+
+        // The next warning is logged immediately and starts a new period
+        let later = start + WARN_COALESCE_PERIOD * 3;
+        throttle.warn_at("bad message", later);
+        assert_eq!(throttle.window_start, Some(later));
+        assert_eq!(throttle.suppressed, 0);
+        throttle.warn_at("bad message", later);
+        assert_eq!(throttle.suppressed, 1);
+
+        // A warning after the period also logs the pending count first
+        throttle.warn_at("bad message", later + WARN_COALESCE_PERIOD);
+        assert_eq!(throttle.window_start, Some(later + WARN_COALESCE_PERIOD));
+        assert_eq!(throttle.suppressed, 0);
+    }
+
+    #[test]
+    fn test_process_revocation() {
+        let test_config = KeylimeConfig::default();
+
+        let sig_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/revocation.sig");
+        let signature = fs::read_to_string(sig_path).unwrap(); //#[allow_ci]
+
+        let message_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/test_ok.json");
+        let message = fs::read_to_string(message_path).unwrap(); //#[allow_ci]
+
+        let body = json!({
+            "msg": message,
+            "signature": signature,
+        });
+
+        let cert_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/test-cert.pem");
+
+        let actions_dir =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions");
+
+        let work_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+
+        let cert = RevocationCert::new(cert_path);
+        let history = RevocationHistory::default();
+
+        let result = process_revocation(
+            body,
+            &cert,
+            &crypto::OpensslVerifier,
+            &RevocationOptions {
+                config_actions: test_config.revocation_actions.clone(),
+                ..test_options(&actions_dir, &work_dir)
+            },
+            &history,
+        );
+
+        assert!(result.is_ok());
+
+        // The outcome can be queried once the revocation was processed
+        let status = history.last().unwrap(); //#[allow_ci]
+        assert_eq!(status.msg_type, "revocation");
+        assert_eq!(status.agent_id, "d432fbb3-d2f1-4a97-9ef7-75bd81c00000");
+        assert!(status.success);
+        assert!(status.error.is_none());
+    }
+
+    #[test]
+    fn test_process_revocation_transports() {
+        let test_config = KeylimeConfig::default();
+        let test_data =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data");
+        let signature =
+            fs::read_to_string(test_data.join("revocation.sig")).unwrap(); //#[allow_ci]
+        let message =
+            fs::read_to_string(test_data.join("test_ok.json")).unwrap(); //#[allow_ci]
+        let body = json!({
+            "msg": message,
+            "signature": signature,
+        });
+        let cert =
+            Arc::new(RevocationCert::new(test_data.join("test-cert.pem")));
+        let history = Arc::new(RevocationHistory::default());
+
+        // The same message is received through two transports at once
+        let channels = (0..2)
+            .map(|_| {
+                let (sender, receiver) = mpsc::channel::<Value>();
+                sender.send(body.clone()).unwrap(); //#[allow_ci]
+                let cert = cert.clone();
+                let history = history.clone();
+                let test_config = test_config.clone();
+                thread::spawn(move || {
+                    let body = receiver.recv().unwrap(); //#[allow_ci]
+                    let actions_dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+                        .join("tests/actions");
+                    let work_dir =
+                        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+                    process_revocation(
+                        body,
+                        &cert,
+                        &crypto::OpensslVerifier,
+                        &RevocationOptions {
+                            config_actions: test_config
+                                .revocation_actions
+                                .clone(),
+                            ..test_options(&actions_dir, &work_dir)
+                        },
+                        &history,
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+        for channel in channels {
+            assert!(channel.join().unwrap().is_ok()); //#[allow_ci]
+        }
+
+        // It was only processed once
+        assert_eq!(history.snapshot().len(), 1);
+    }
+
+    #[test]
+    fn test_revocation_transports() {
+        let test_config = KeylimeConfig {
+            revocation_transports: vec![
+                RevocationTransport::ZeroMq,
+                RevocationTransport::Webhook,
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            revocation_transports(&test_config),
+            test_config.revocation_transports
+        );
+
+        // Transports listed more than once are only run once
+        let test_config = KeylimeConfig {
+            revocation_transports: vec![
+                RevocationTransport::ZeroMq,
+                RevocationTransport::Webhook,
+                RevocationTransport::ZeroMq,
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            revocation_transports(&test_config),
+            [RevocationTransport::ZeroMq, RevocationTransport::Webhook]
+        );
+
+        // Without configured transports, 0mq is used unless the webhook is
+        // set up
+        let test_config = KeylimeConfig::default();
+        let expected = if cfg!(feature = "with-zmq") {
+            vec![RevocationTransport::ZeroMq]
+        } else {
+            Vec::new()
+        };
+        assert_eq!(revocation_transports(&test_config), expected);
+
+        assert_eq!(
+            RevocationTransport::from_str(" Webhook").unwrap(), //#[allow_ci]
+            RevocationTransport::Webhook
+        );
+        assert!(RevocationTransport::from_str("rest").is_err());
+    }
+
+    #[test]
+    fn test_process_revocation_ec() {
+        let test_config = KeylimeConfig::default();
+
+        let sig_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/revocation-ec.sig");
+        let signature = fs::read_to_string(sig_path).unwrap(); //#[allow_ci]
+
+        let message_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/test_ok.json");
+        let message = fs::read_to_string(message_path).unwrap(); //#[allow_ci]
+
+        let body = json!({
+            "msg": message,
+            "signature": signature,
+        });
+
+        let cert_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/test-cert-ec.pem");
+
         let actions_dir =
-            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions/");
-        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
-        let tmpfs_dir = work_dir.path().join("tmpfs-dev"); //#[allow_ci]
-        fs::create_dir(&tmpfs_dir).unwrap(); //#[allow_ci]
-        let unzipped_dir =
-            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/unzipped");
-        symlink(unzipped_dir, tmpfs_dir.join("unzipped")).unwrap(); //#[allow_ci]
-        let outputs = run_revocation_actions(
-            json,
-            &test_config.secure_size,
-            &test_config.revocation_actions,
-            actions_dir,
-            true,
-            work_dir.path(),
-        );
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions");
 
-        assert!(outputs.is_ok());
-        let outputs = outputs.unwrap(); //#[allow_ci]
+        let work_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
 
-        assert!(outputs.len() == 6);
+        let cert = RevocationCert::new(cert_path);
 
-        for output in outputs {
-            assert_eq!(
-                String::from_utf8(output.stdout).unwrap(), //#[allow_ci]
-                "there\n"
-            );
-        }
+        let result = process_revocation(
+            body,
+            &cert,
+            &crypto::OpensslVerifier,
+            &RevocationOptions {
+                config_actions: test_config.revocation_actions.clone(),
+                ..test_options(&actions_dir, &work_dir)
+            },
+            &RevocationHistory::default(),
+        );
+
+        assert!(result.is_ok());
     }
 
     #[test]
-    fn get_revocation_cert_path_default() {
+    fn test_process_revocation_missing_agent_id() {
         let test_config = KeylimeConfig::default();
-        let revocation_cert_path =
-            get_revocation_cert_path(&test_config).unwrap(); //#[allow_ci]
-        let mut expected = PathBuf::from(&test_config.work_dir);
-        expected.push("secure/unzipped/");
-        expected.push(REV_CERT);
-        assert_eq!(*revocation_cert_path, expected);
-    }
 
-    #[test]
-    fn get_revocation_cert_path_absolute() {
-        let mut test_config = KeylimeConfig {
-            revocation_cert: String::from("/test/cert.crt"),
-            ..Default::default()
-        };
-        let revocation_cert_path =
-            get_revocation_cert_path(&test_config).unwrap(); //#[allow_ci]
-        assert_eq!(revocation_cert_path, PathBuf::from("/test/cert.crt"));
+        let sig_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/revocation_no_agent_id.sig");
+        let signature = fs::read_to_string(sig_path).unwrap(); //#[allow_ci]
+
+        let message_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/test_no_agent_id.json");
+        let message = fs::read_to_string(message_path).unwrap(); //#[allow_ci]
+
+        let body = json!({
+            "msg": message,
+            "signature": signature,
+        });
+
+        let cert_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data/test-cert.pem");
+
+        let actions_dir =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions");
+
+        let work_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+
+        let cert = RevocationCert::new(cert_path);
+
+        let result = process_revocation(
+            body,
+            &cert,
+            &crypto::OpensslVerifier,
+            &RevocationOptions {
+                config_actions: test_config.revocation_actions.clone(),
+                ..test_options(&actions_dir, &work_dir)
+            },
+            &RevocationHistory::default(),
+        );
+
+        // The signature is valid, but the message is rejected before any
+        // action is run
+        assert!(matches!(result, Err(Error::InvalidRequest)));
     }
 
     #[test]
-    fn get_revocation_cert_path_relative() {
-        let mut test_config = KeylimeConfig {
-            revocation_cert: String::from("cert.crt"),
-            ..Default::default()
-        };
-        let revocation_cert_path =
-            get_revocation_cert_path(&test_config).unwrap(); //#[allow_ci]
-        let mut expected = Path::new(&test_config.work_dir).join("cert.crt");
-        assert_eq!(revocation_cert_path, expected);
+    fn test_revocation_message_validate() {
+        let msg = json!({
+            "type": "revocation",
+            "agent_id": "d432fbb3-d2f1-4a97-9ef7-75bd81c00000",
+            "ip": "127.0.0.1",
+            "event_time": "Fri Jun 17 10:00:00 2022",
+        });
+        let value = RevocationMessage::validate(&msg.to_string()).unwrap(); //#[allow_ci]
+
+        // Unknown fields are passed through, unset optional ones are not
+        // added
+        assert_eq!(value, msg);
+
+        let msg = json!({"type": "revocation", "agent_id": " "});
+        assert!(matches!(
+            RevocationMessage::validate(&msg.to_string()),
+            Err(Error::InvalidRequest)
+        ));
+        assert!(matches!(
+            RevocationMessage::validate("not json"),
+            Err(Error::InvalidRequest)
+        ));
     }
 
     #[test]
-    fn get_revocation_cert_path_empty() {
-        let mut test_config = KeylimeConfig {
-            revocation_cert: String::from(""),
-            ..Default::default()
+    fn test_revocation_freshness() {
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let max_age = Duration::from_secs(60);
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let history = RevocationHistory::default();
+        let check = |message: Value, max_age| {
+            check_revocation_freshness(
+                &message,
+                max_age,
+                work_dir.path(),
+                &history,
+                now,
+            )
         };
-        assert!(
-            get_revocation_cert_path(&test_config).is_err(),
-            "revocation_cert is not set in configuration"
-        );
-    }
 
-    #[test]
-    fn test_lookup_action() {
-        let work_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
-        let payload_dir = Path::new(&work_dir).join("unzipped/");
-        let actions_dir = Path::new(&work_dir).join("actions/");
+        // Timestamps within the window, in the past or the future
+        for timestamp in &[1_000_000, 999_940, 1_000_060] {
+            assert!(check(json!({ "timestamp": timestamp }), max_age).is_ok());
+        }
 
-        // Test local python action
-        let expected = format!("{}", &actions_dir.join("shim.py").display(),);
+        // Old or missing timestamps, unless the age is not checked
+        for message in &[json!({"timestamp": 999_939}), json!({})] {
+            assert!(matches!(
+                check(message.clone(), max_age),
+                Err(Error::InvalidRequest)
+            ));
+            assert!(check(message.clone(), Duration::from_secs(0)).is_ok());
+        }
 
+        // Sequence numbers must increase
+        assert!(check(json!({"sequence": 5}), Duration::from_secs(0)).is_ok());
         assert_eq!(
-            lookup_action(
-                &payload_dir,
-                &actions_dir,
-                "local_action_hello",
-                true
+            fs::read_to_string(
+                work_dir.path().join(REVOCATION_SEQUENCE_FILE)
             )
             .unwrap(), //#[allow_ci]
-            (expected, true, false)
+            "5"
         );
+        for sequence in &[5, 4] {
+            assert!(matches!(
+                check(
+                    json!({ "sequence": sequence }),
+                    Duration::from_secs(0)
+                ),
+                Err(Error::InvalidRequest)
+            ));
+        }
+        assert!(check(json!({"sequence": 6}), Duration::from_secs(0)).is_ok());
+        assert_eq!(history.sequence(), Some(6));
 
-        // Test local non-python action
-        let expected = format!(
-            "{}",
-            &actions_dir.join("local_action_hello_shell.sh").display()
-        );
+        // The sequence number recorded in the history is still enforced
+        fs::remove_file(work_dir.path().join(REVOCATION_SEQUENCE_FILE))
+            .unwrap(); //#[allow_ci]
+        assert!(matches!(
+            check(json!({"sequence": 6}), Duration::from_secs(0)),
+            Err(Error::InvalidRequest)
+        ));
+    }
 
-        assert_eq!(
-            lookup_action(
-                &payload_dir,
-                &actions_dir,
-                "local_action_hello_shell.sh",
-                true
+    #[test]
+    fn test_process_revocation_replay() {
+        let test_config = KeylimeConfig::default();
+        let test_data =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data");
+        let (_, key) =
+            crypto::testing::rsa_import_pair(test_data.join("test-rsa.pem"))
+                .unwrap(); //#[allow_ci]
+        let cert = RevocationCert::new(test_data.join("test-cert.pem"));
+        let actions_dir =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions");
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        fs::create_dir(work_dir.path().join("tmpfs-dev")).unwrap(); //#[allow_ci]
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap() //#[allow_ci]
+            .as_secs();
+        let process = |timestamp: u64, sequence: u64| {
+            let message = json!({
+                "type": "revocation",
+                "agent_id": "d432fbb3-d2f1-4a97-9ef7-75bd81c00000",
+                "timestamp": timestamp,
+                "sequence": sequence,
+            })
+            .to_string();
+            let signature = crypto::asym_sign(&key, &message).unwrap(); //#[allow_ci]
+            process_revocation(
+                json!({"msg": message, "signature": signature}),
+                &cert,
+                &crypto::OpensslVerifier,
+                &RevocationOptions {
+                    max_age: Duration::from_secs(60),
+                    config_actions: String::from(""),
+                    allow_payload_actions: false,
+                    ..test_options(&actions_dir, work_dir.path())
+                },
+                &RevocationHistory::default(),
             )
-            .unwrap(), //#[allow_ci]
-            (expected, false, false)
-        );
+        };
 
-        // Test payload python action
-        let expected = format!("{}", &actions_dir.join("shim.py").display(),);
+        // A fresh message is accepted
+        assert!(process(now, 1).is_ok());
 
-        assert_eq!(
-            lookup_action(
-                &payload_dir,
-                &actions_dir,
-                "local_action_payload",
-                true,
+        // Replaying it, or sending an old one, is rejected
+        assert!(matches!(process(now, 1), Err(Error::InvalidRequest)));
+        assert!(matches!(process(now - 3600, 2), Err(Error::InvalidRequest)));
+
+        assert!(process(now, 2).is_ok());
+    }
+
+    #[test]
+    fn test_process_revocation_failed_again() {
+        let test_data =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data");
+        let (_, key) =
+            crypto::testing::rsa_import_pair(test_data.join("test-rsa.pem"))
+                .unwrap(); //#[allow_ci]
+        let cert = RevocationCert::new(test_data.join("test-cert.pem"));
+        let actions_dir =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions");
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        fs::create_dir(work_dir.path().join("tmpfs-dev")).unwrap(); //#[allow_ci]
+
+        let message = json!({
+            "type": "revocation",
+            "agent_id": "d432fbb3-d2f1-4a97-9ef7-75bd81c00000",
+        })
+        .to_string();
+        let signature = crypto::asym_sign(&key, &message).unwrap(); //#[allow_ci]
+        let history = RevocationHistory::default();
+        let process = || {
+            process_revocation(
+                json!({"msg": message, "signature": signature}),
+                &cert,
+                &crypto::OpensslVerifier,
+                &RevocationOptions {
+                    config_actions: String::from(
+                        "local_action_fail_shell.sh",
+                    ),
+                    allow_payload_actions: false,
+                    ..test_options(&actions_dir, work_dir.path())
+                },
+                &history,
             )
-            .unwrap(), //#[allow_ci]
-            (expected, true, true),
-        );
+        };
 
-        // Test payload non-python action
-        let expected = format!(
-            "{}",
-            &payload_dir.join("local_action_payload_shell.sh").display()
-        );
+        // A message whose actions failed is not marked as processed, so
+        // the actions are run again when it is received again
+        assert!(process().is_err());
+        assert!(process().is_err());
+        assert_eq!(history.snapshot().len(), 2);
+    }
 
-        assert_eq!(
-            lookup_action(
-                &payload_dir,
-                &actions_dir,
-                "local_action_payload_shell.sh",
-                true
+    #[test]
+    fn test_process_revocation_raw_message() {
+        let test_config = KeylimeConfig::default();
+        let test_data =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data");
+        let (_, key) =
+            crypto::testing::rsa_import_pair(test_data.join("test-rsa.pem"))
+                .unwrap(); //#[allow_ci]
+        let cert = RevocationCert::new(test_data.join("test-cert.pem"));
+        let actions_dir =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions");
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        fs::create_dir(work_dir.path().join("tmpfs-dev")).unwrap(); //#[allow_ci]
+
+        let message = json!({
+            "type": "revocation",
+            "agent_id": "d432fbb3-d2f1-4a97-9ef7-75bd81c00000",
+        })
+        .to_string();
+        let signature = crypto::asym_sign(&key, &message).unwrap(); //#[allow_ci]
+
+        // Returns the JSON the action was given
+        let process = |raw_message: bool| {
+            process_revocation(
+                json!({"msg": message, "signature": signature}),
+                &cert,
+                &crypto::OpensslVerifier,
+                &RevocationOptions {
+                    config_actions: String::from(
+                        "local_action_json_shell.sh",
+                    ),
+                    allow_payload_actions: false,
+                    raw_message_to_actions: raw_message,
+                    ..test_options(&actions_dir, work_dir.path())
+                },
+                &RevocationHistory::default(),
             )
-            .unwrap(), //#[allow_ci]
-            (expected, false, true)
-        );
+            .unwrap(); //#[allow_ci]
+            let input =
+                fs::read_to_string(work_dir.path().join("action_input.json"))
+                    .unwrap(); //#[allow_ci]
+            serde_json::from_str::<Value>(&input).unwrap() //#[allow_ci]
+        };
 
-        // Test that disallowing payload works
-        let expected: Result<(String, bool)> =
-            Err(Error::Io(std::io::Error::new(
-                ErrorKind::NotFound,
-                "Could not find action local_action_payload_shell.sh"
-                    .to_string(),
-            )));
+        // By default only the message content is given to the action
+        let input = process(false);
+        assert_eq!(input["agent_id"], "d432fbb3-d2f1-4a97-9ef7-75bd81c00000");
+        assert!(input.get("signature").is_none());
 
-        assert!(matches!(
-            lookup_action(
-                &payload_dir,
-                &actions_dir,
-                "local_action_payload_shell.sh",
-                false
-            ),
-            expected,
-        ));
+        let input = process(true);
+        assert_eq!(input["msg"], message.as_str());
+        assert_eq!(input["signature"], signature.as_str());
+    }
 
-        // Test non-existent action
-        let expected: Result<(String, bool)> =
-            Err(Error::Io(std::io::Error::new(
-                ErrorKind::NotFound,
-                "Could not find action local_action_non_existent".to_string(),
-            )));
+    // Verifier recording the messages and signatures it was asked to check
+    struct MockVerifier {
+        valid: bool,
+        calls: Mutex<Vec<(Vec<u8>, Vec<u8>)>>,
+    }
 
-        assert!(matches!(
-            lookup_action(
-                &payload_dir,
-                &actions_dir,
-                "local_action_non_existent",
-                true
-            ),
-            expected,
-        ));
+    impl crypto::SignatureVerifier for MockVerifier {
+        fn verify(
+            &self,
+            _keypair: &openssl::pkey::PKeyRef<Public>,
+            message: &[u8],
+            signature: &[u8],
+            digest: HashAlgorithm,
+        ) -> Result<crypto::VerifyOutcome> {
+            self.calls
+                .lock()
+                .unwrap() //#[allow_ci]
+                .push((message.to_vec(), signature.to_vec()));
+            Ok(crypto::VerifyOutcome {
+                valid: self.valid,
+                tried: vec![crypto::VerifyAlgorithm {
+                    scheme: crypto::VerifyScheme::RsaPss,
+                    digest,
+                }],
+            })
+        }
     }
 
     #[test]
-    fn test_process_revocation() {
+    fn test_process_revocation_verifier() {
         let test_config = KeylimeConfig::default();
+        let test_data =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data");
+        let cert = RevocationCert::new(test_data.join("test-cert.pem"));
+        let actions_dir =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions");
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        fs::create_dir(work_dir.path().join("tmpfs-dev")).unwrap(); //#[allow_ci]
 
-        let sig_path = Path::new(env!("CARGO_MANIFEST_DIR"))
-            .join("test-data/revocation.sig");
-        let signature = fs::read_to_string(sig_path).unwrap(); //#[allow_ci]
+        // The signature is not checked by OpenSSL, so any will do
+        let message = json!({
+            "type": "revocation",
+            "agent_id": "d432fbb3-d2f1-4a97-9ef7-75bd81c00000",
+        })
+        .to_string();
+        let process = |verifier: &MockVerifier| {
+            process_revocation(
+                json!({"msg": message, "signature": base64::encode("sig")}),
+                &cert,
+                verifier,
+                &RevocationOptions {
+                    config_actions: String::from(""),
+                    allow_payload_actions: false,
+                    ..test_options(&actions_dir, work_dir.path())
+                },
+                &RevocationHistory::default(),
+            )
+        };
 
-        let message_path = Path::new(env!("CARGO_MANIFEST_DIR"))
-            .join("test-data/test_ok.json");
-        let message = fs::read_to_string(message_path).unwrap(); //#[allow_ci]
+        let verifier = MockVerifier {
+            valid: true,
+            calls: Mutex::new(Vec::new()),
+        };
+        assert!(process(&verifier).is_ok());
+        let calls = verifier.calls.lock().unwrap(); //#[allow_ci]
+        assert_eq!(
+            *calls,
+            vec![(message.as_bytes().to_vec(), b"sig".to_vec())]
+        );
+
+        let verifier = MockVerifier {
+            valid: false,
+            calls: Mutex::new(Vec::new()),
+        };
+        assert!(matches!(process(&verifier), Err(Error::InvalidRequest)));
+        assert_eq!(verifier.calls.lock().unwrap().len(), 1); //#[allow_ci]
+    }
+
+    #[test]
+    fn test_process_revocation_cert_dir() {
+        let test_config = KeylimeConfig::default();
+        let test_data =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data");
 
+        let signature =
+            fs::read_to_string(test_data.join("revocation.sig")).unwrap(); //#[allow_ci]
+        let message =
+            fs::read_to_string(test_data.join("test_ok.json")).unwrap(); //#[allow_ci]
         let body = json!({
             "msg": message,
             "signature": signature,
         });
 
-        let cert_path = Path::new(env!("CARGO_MANIFEST_DIR"))
-            .join("test-data/test-cert.pem");
+        // Only the second certificate verifies the signature, files without
+        // a certificate extension are ignored
+        let cert_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let _ = fs::copy(
+            test_data.join("test-cert-ec.pem"),
+            cert_dir.path().join("1-old.pem"),
+        )
+        .unwrap(); //#[allow_ci]
+        let _ = fs::copy(
+            test_data.join("test-cert.pem"),
+            cert_dir.path().join("2-new.crt"),
+        )
+        .unwrap(); //#[allow_ci]
+        fs::write(cert_dir.path().join("README"), "not a certificate")
+            .unwrap(); //#[allow_ci]
+
+        let cert = RevocationCert::new(cert_dir.path().to_path_buf());
+        let keys = cert.keys().unwrap(); //#[allow_ci]
+        assert_eq!(
+            keys.iter()
+                .map(|(path, _)| path.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                cert_dir.path().join("1-old.pem"),
+                cert_dir.path().join("2-new.crt")
+            ]
+        );
 
         let actions_dir =
             Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/actions");
-
         let work_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+        let process = |body: Value, cert: &RevocationCert| {
+            process_revocation(
+                body,
+                cert,
+                &crypto::OpensslVerifier,
+                &RevocationOptions {
+                    config_actions: test_config.revocation_actions.clone(),
+                    ..test_options(&actions_dir, &work_dir)
+                },
+                &RevocationHistory::default(),
+            )
+        };
 
-        let result = process_revocation(
-            body,
-            &cert_path,
-            &test_config.secure_size,
-            &test_config.revocation_actions,
-            &actions_dir,
-            test_config.allow_payload_revocation_actions,
-            &work_dir,
-        );
+        assert!(process(body.clone(), &cert).is_ok());
 
-        assert!(result.is_ok());
+        // Without the matching certificate the signature is rejected
+        fs::remove_file(cert_dir.path().join("2-new.crt")).unwrap(); //#[allow_ci]
+        assert!(matches!(process(body, &cert), Err(Error::InvalidRequest)));
+    }
+
+    // Returns the key type of the first cached revocation certificate key,
+    // if any
+    fn cached_key_id(cert: &RevocationCert) -> Option<openssl::pkey::Id> {
+        cert.cached
+            .lock()
+            .unwrap() //#[allow_ci]
+            .as_ref()
+            .and_then(|c| c.keys.first().map(|(_, key)| key.id()))
+    }
+
+    #[test]
+    fn test_revocation_cert_watch() {
+        let test_data =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data");
+        let temp_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let cert_path = temp_dir.path().join("RevocationNotifier-cert.crt");
+        let _ =
+            fs::copy(test_data.join("test-cert.pem"), &cert_path).unwrap(); //#[allow_ci]
+
+        let cert = Arc::new(RevocationCert::new(cert_path.clone()));
+        let keys = cert.keys().unwrap(); //#[allow_ci]
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].0, cert_path);
+        assert_eq!(keys[0].1.id(), openssl::pkey::Id::RSA);
+
+        let watch = watch_revocation_cert(&cert).unwrap(); //#[allow_ci]
+
+        // Replacing the certificate reloads the cached key without waiting
+        // for the next revocation message
+        let _ =
+            fs::copy(test_data.join("test-cert-ec.pem"), &cert_path).unwrap(); //#[allow_ci]
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while cached_key_id(&cert) != Some(openssl::pkey::Id::EC)
+            && Instant::now() < deadline
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(cached_key_id(&cert), Some(openssl::pkey::Id::EC));
+
+        // The watch stops once the certificate is dropped
+        drop(cert);
+        watch.join().unwrap(); //#[allow_ci]
+    }
+
+    #[test]
+    fn test_revocation_cert_watch_fallback() {
+        let test_data =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data");
+        let temp_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let cert_dir = temp_dir.path().join("unzipped");
+        let cert_path = cert_dir.join("RevocationNotifier-cert.crt");
+
+        // The directory does not exist yet, so it cannot be watched with
+        // inotify and the certificate is polled instead
+        let cert = Arc::new(RevocationCert::new(cert_path.clone()));
+        let watch = watch_revocation_cert(&cert).unwrap(); //#[allow_ci]
+        assert_eq!(cached_key_id(&cert), None);
+
+        fs::create_dir(&cert_dir).unwrap(); //#[allow_ci]
+        let _ =
+            fs::copy(test_data.join("test-cert.pem"), &cert_path).unwrap(); //#[allow_ci]
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while cached_key_id(&cert) != Some(openssl::pkey::Id::RSA)
+            && Instant::now() < deadline
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(cached_key_id(&cert), Some(openssl::pkey::Id::RSA));
+
+        drop(cert);
+        watch.join().unwrap(); //#[allow_ci]
     }
 }
@@ -0,0 +1,31 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+use crate::common::{JsonWrapper, ResponseStatus};
+use crate::QuoteData;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use log::*;
+
+// This is the handler for the GET request for the last revocation processed
+// by the agent, whether received through the REST API or the revocation
+// service
+pub async fn last_revocation(
+    req: HttpRequest,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    info!(
+        "GET invoked from {:?} with uri {}",
+        req.connection_info().peer_addr().unwrap(), //#[allow_ci]
+        req.uri()
+    );
+
+    match data.revocation_history.last() {
+        Some(status) => HttpResponse::Ok().json(JsonWrapper::success(status)),
+        None => {
+            let message = "No revocation processed yet".to_string();
+            info!("GET returning 404 response. {}", message);
+            JsonWrapper::error(ResponseStatus::NotFound, message)
+                .into_response()
+        }
+    }
+}
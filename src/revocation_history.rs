@@ -0,0 +1,402 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+use crate::error::{Error, Result};
+use crate::revocation::ActionResult;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Maximum number of processed revocations kept in the history
+pub(crate) const REVOCATION_HISTORY_SIZE: usize = 16;
+
+/// Maximum number of signatures of processed revocation messages kept to
+/// detect messages received more than once
+pub(crate) const REVOCATION_SEEN_SIZE: usize = 64;
+
+/// File in the work directory where the revocation history is saved when
+/// the agent shuts down
+pub(crate) const REVOCATION_STATE_FILE: &str = "revocation_state.json";
+
+/// Outcome of a revocation action
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ActionStatus {
+    pub name: String,
+    pub success: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Structured outcome reported by the action, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+}
+
+/// Outcome of a revocation message whose signature was verified
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RevocationStatus {
+    /// Time the revocation was processed, in seconds since the epoch
+    pub timestamp: u64,
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub agent_id: String,
+    /// Whether all the actions were run successfully
+    pub success: bool,
+    /// The actions run, in the order they were run. When failing actions
+    /// stop the revocation, only the failing action is known.
+    pub actions: Vec<ActionStatus>,
+    /// Why the revocation failed, if it failed before or while running the
+    /// actions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl RevocationStatus {
+    /// Describes the outcome of running the actions for the revocation
+    /// message content
+    pub(crate) fn new(
+        message: &Value,
+        result: &Result<Vec<ActionResult>>,
+        time: SystemTime,
+    ) -> Self {
+        let succeeded = |results: &[ActionResult]| {
+            results
+                .iter()
+                .map(|result| ActionStatus {
+                    name: result.name.clone(),
+                    success: true,
+                    exit_code: result.output.status.code(),
+                    error: None,
+                    result: result.result.clone(),
+                })
+                .collect::<Vec<ActionStatus>>()
+        };
+        let failed = |e: &Error| match e {
+            Error::Script(name, exit_code, msg) => Some(ActionStatus {
+                name: name.clone(),
+                success: false,
+                exit_code: *exit_code,
+                error: Some(msg.clone()),
+                result: None,
+            }),
+            _ => None,
+        };
+
+        let (actions, error) = match result {
+            Ok(results) => (succeeded(results), None),
+            Err(Error::RevocationActions {
+                succeeded: results,
+                failures,
+                ..
+            }) => {
+                let mut actions = succeeded(results);
+                actions.extend(failures.iter().filter_map(failed));
+                (actions, None)
+            }
+            Err(e) => (failed(e).into_iter().collect(), Some(e.to_string())),
+        };
+
+        RevocationStatus {
+            timestamp: time
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            msg_type: message["type"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            agent_id: message["agent_id"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            success: result.is_ok(),
+            actions,
+            error,
+        }
+    }
+}
+
+// What is saved of the history across restarts
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct RevocationState {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sequence: Option<u64>,
+    #[serde(default)]
+    entries: Vec<RevocationStatus>,
+    /// Base64 encoded signatures of the processed messages
+    #[serde(default)]
+    seen: Vec<String>,
+}
+
+/// The last revocations processed by the agent, most recent last
+///
+/// The history is shared by all the revocation transports, and is also
+/// where a message received through several of them is detected, so that
+/// it is only processed once. It is kept in memory, and saved under the
+/// work directory with `flush` when the agent shuts down.
+#[derive(Debug, Default)]
+pub(crate) struct RevocationHistory {
+    entries: Mutex<VecDeque<RevocationStatus>>,
+    seen: Mutex<VecDeque<Vec<u8>>>,
+    sequence: Mutex<Option<u64>>,
+}
+
+impl RevocationHistory {
+    // The history holds no state that a panic could leave inconsistent, so
+    // a poisoned lock is simply recovered
+    fn entries(&self) -> MutexGuard<'_, VecDeque<RevocationStatus>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Records a processed revocation, forgetting the oldest one when the
+    /// history is full
+    pub(crate) fn record(&self, status: RevocationStatus) {
+        let mut entries = self.entries();
+        if entries.len() >= REVOCATION_HISTORY_SIZE {
+            let _ = entries.pop_front();
+        }
+        entries.push_back(status);
+    }
+
+    /// Returns the last processed revocation, if any
+    pub(crate) fn last(&self) -> Option<RevocationStatus> {
+        self.entries().back().cloned()
+    }
+
+    /// Returns the processed revocations, most recent last
+    pub(crate) fn snapshot(&self) -> Vec<RevocationStatus> {
+        self.entries().iter().cloned().collect()
+    }
+
+    /// Marks the message with the given signature as processed. Returns
+    /// false if it already was, in which case it must not be processed
+    /// again.
+    pub(crate) fn mark_processed(&self, signature: &[u8]) -> bool {
+        let mut seen =
+            self.seen.lock().unwrap_or_else(PoisonError::into_inner);
+        if seen.iter().any(|s| s == signature) {
+            return false;
+        }
+        if seen.len() >= REVOCATION_SEEN_SIZE {
+            let _ = seen.pop_front();
+        }
+        seen.push_back(signature.to_vec());
+        true
+    }
+
+    /// Forgets that the message with the given signature was processed, when
+    /// processing it failed
+    pub(crate) fn forget_processed(&self, signature: &[u8]) {
+        self.seen
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|s| s != signature);
+    }
+
+    /// Returns the sequence number of the last processed revocation
+    /// message, if any message carrying one was processed
+    pub(crate) fn sequence(&self) -> Option<u64> {
+        *self.sequence.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Records the sequence number of a processed revocation message
+    pub(crate) fn record_sequence(&self, sequence: u64) {
+        let mut last =
+            self.sequence.lock().unwrap_or_else(PoisonError::into_inner);
+        *last = (*last).max(Some(sequence));
+    }
+
+    /// Loads the history saved by `flush` under `work_dir`, or returns an
+    /// empty history if none was saved
+    ///
+    /// A saved history which cannot be read is an error rather than being
+    /// ignored: the signatures and sequence number it holds are what keeps
+    /// old revocation messages from being processed again after a restart.
+    /// Removing the file starts the agent with an empty history.
+    pub(crate) fn load(work_dir: &Path) -> Result<Self> {
+        let path = work_dir.join(REVOCATION_STATE_FILE);
+        let invalid = |e: &dyn std::fmt::Display| {
+            Error::Other(format!(
+                "unable to load the revocation history from {}: {} \
+                 (removing the file starts with an empty history)",
+                path.display(),
+                e
+            ))
+        };
+
+        let history = RevocationHistory::default();
+        let state: RevocationState = match fs::read(&path) {
+            Ok(data) => {
+                serde_json::from_slice(&data).map_err(|e| invalid(&e))?
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(history),
+            Err(e) => return Err(invalid(&e)),
+        };
+
+        for status in state.entries {
+            history.record(status);
+        }
+        for signature in state.seen {
+            let signature =
+                base64::decode(signature).map_err(|e| invalid(&e))?;
+            let _ = history.mark_processed(&signature);
+        }
+        if let Some(sequence) = state.sequence {
+            history.record_sequence(sequence);
+        }
+        Ok(history)
+    }
+
+    /// Saves the history under `work_dir`, so that it is loaded back on
+    /// restart
+    ///
+    /// The state is written to a temporary file which then replaces the
+    /// previous one, so that a crash leaves either of them complete. The
+    /// work directory is synced as well, for the replacement itself to
+    /// survive a crash.
+    pub(crate) fn flush(&self, work_dir: &Path) -> Result<()> {
+        let state = RevocationState {
+            sequence: self.sequence(),
+            entries: self.snapshot(),
+            seen: self
+                .seen
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .iter()
+                .map(base64::encode)
+                .collect(),
+        };
+
+        let mut file = tempfile::NamedTempFile::new_in(work_dir)?;
+        file.write_all(&serde_json::to_vec(&state)?)?;
+        file.as_file().sync_all()?;
+        let _ = file
+            .persist(work_dir.join(REVOCATION_STATE_FILE))
+            .map_err(|e| e.error)?;
+        fs::File::open(work_dir)?.sync_all()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn status(msg_type: &str) -> RevocationStatus {
+        RevocationStatus::new(
+            &json!({"type": msg_type, "agent_id": "agent"}),
+            &Err(Error::Script("action".to_string(), Some(1), "".into())),
+            UNIX_EPOCH,
+        )
+    }
+
+    #[test]
+    fn test_revocation_status() {
+        let status = status("revocation");
+        assert_eq!(status.msg_type, "revocation");
+        assert_eq!(status.agent_id, "agent");
+        assert!(!status.success);
+        assert_eq!(
+            status.actions,
+            vec![ActionStatus {
+                name: "action".to_string(),
+                success: false,
+                exit_code: Some(1),
+                error: Some(String::new()),
+                result: None,
+            }]
+        );
+
+        let status = RevocationStatus::new(
+            &json!({"type": "revocation", "agent_id": "agent"}),
+            &Err(Error::InvalidRequest),
+            UNIX_EPOCH,
+        );
+        assert!(status.actions.is_empty());
+        assert!(status.error.is_some());
+    }
+
+    #[test]
+    fn test_revocation_history_bounded() {
+        let history = RevocationHistory::default();
+        assert!(history.last().is_none());
+
+        for i in 0..=REVOCATION_HISTORY_SIZE {
+            history.record(status(&format!("revocation{}", i)));
+        }
+        assert_eq!(history.entries().len(), REVOCATION_HISTORY_SIZE);
+        assert_eq!(history.entries()[0].msg_type, "revocation1");
+        assert_eq!(
+            history.last().unwrap().msg_type, //#[allow_ci]
+            format!("revocation{}", REVOCATION_HISTORY_SIZE)
+        );
+    }
+
+    #[test]
+    fn test_revocation_history_mark_processed() {
+        let history = RevocationHistory::default();
+        assert!(history.mark_processed(b"signature"));
+        assert!(!history.mark_processed(b"signature"));
+        assert!(history.mark_processed(b"other"));
+
+        // A message whose processing failed can be processed again
+        history.forget_processed(b"other");
+        assert!(history.mark_processed(b"other"));
+
+        // Only the last signatures are remembered
+        for i in 0..REVOCATION_SEEN_SIZE {
+            assert!(history.mark_processed(format!("{}", i).as_bytes()));
+        }
+        assert!(history.mark_processed(b"signature"));
+    }
+
+    #[test]
+    fn test_revocation_history_flush() {
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+
+        // Nothing saved yet
+        let history = RevocationHistory::load(work_dir.path()).unwrap(); //#[allow_ci]
+        assert!(history.last().is_none());
+        assert!(history.sequence().is_none());
+
+        history.record(status("revocation"));
+        assert!(history.mark_processed(b"signature"));
+        history.record_sequence(7);
+        history.record_sequence(5);
+        assert_eq!(history.sequence(), Some(7));
+        history.flush(work_dir.path()).unwrap(); //#[allow_ci]
+
+        let loaded = RevocationHistory::load(work_dir.path()).unwrap(); //#[allow_ci]
+        assert_eq!(loaded.sequence(), Some(7));
+        assert_eq!(loaded.snapshot(), history.snapshot());
+        assert!(!loaded.mark_processed(b"signature"));
+
+        // Flushing again replaces the saved state
+        loaded.record_sequence(8);
+        loaded.flush(work_dir.path()).unwrap(); //#[allow_ci]
+        let loaded = RevocationHistory::load(work_dir.path()).unwrap(); //#[allow_ci]
+        assert_eq!(loaded.sequence(), Some(8));
+        assert_eq!(
+            fs::read_dir(work_dir.path()).unwrap().count(), //#[allow_ci]
+            1
+        );
+    }
+
+    #[test]
+    fn test_revocation_history_load_corrupt() {
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = work_dir.path().join(REVOCATION_STATE_FILE);
+
+        for content in [&b"{"[..], br#"{"seen": ["not base64!"]}"#] {
+            fs::write(&path, content).unwrap(); //#[allow_ci]
+            let err = RevocationHistory::load(work_dir.path()).unwrap_err(); //#[allow_ci]
+            assert!(err.to_string().contains(&path.display().to_string()));
+        }
+    }
+}
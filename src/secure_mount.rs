@@ -4,10 +4,166 @@
 use super::*;
 
 use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::ffi::CString;
+use std::fmt;
 use std::fs;
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::PermissionsExt;
 use std::path::PathBuf;
 use std::process::Command;
+use std::str::FromStr;
+
+/// Size of the secure mount, in the format of the tmpfs size option: a
+/// number of bytes with an optional k, m or g suffix, or a percentage of the
+/// memory
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum SecureSize {
+    Bytes(u64),
+    Percent(u64),
+}
+
+impl FromStr for SecureSize {
+    type Err = Error;
+
+    fn from_str(size: &str) -> Result<Self> {
+        let invalid = || {
+            Error::Configuration(format!(
+                "Invalid secure_size {}: expected a size such as 1m, 512k or 50%",
+                size
+            ))
+        };
+
+        let size = size.trim();
+        let (number, multiplier) =
+            match size.char_indices().last().ok_or_else(invalid)? {
+                (i, 'k') | (i, 'K') => (&size[..i], 1 << 10),
+                (i, 'm') | (i, 'M') => (&size[..i], 1 << 20),
+                (i, 'g') | (i, 'G') => (&size[..i], 1 << 30),
+                (i, '%') => {
+                    return match size[..i].parse::<u64>() {
+                        Ok(percent) if percent > 0 => {
+                            Ok(SecureSize::Percent(percent))
+                        }
+                        _ => Err(invalid()),
+                    };
+                }
+                _ => (size, 1),
+            };
+        match number
+            .parse::<u64>()
+            .ok()
+            .and_then(|n| n.checked_mul(multiplier))
+        {
+            Some(bytes) if bytes > 0 => Ok(SecureSize::Bytes(bytes)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Normalized size, as passed to the tmpfs size option: a number of bytes
+/// or a percentage of the memory
+impl fmt::Display for SecureSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecureSize::Bytes(bytes) => write!(f, "{}", bytes),
+            SecureSize::Percent(percent) => write!(f, "{}%", percent),
+        }
+    }
+}
+
+// Entry of the mount table
+#[derive(Debug, PartialEq, Eq)]
+struct MountEntry {
+    device: String,
+    options: Vec<String>,
+}
+
+impl MountEntry {
+    // Size of a tmpfs mount, from its size option
+    fn size(&self) -> Option<SecureSize> {
+        self.options
+            .iter()
+            .find_map(|option| option.strip_prefix("size="))
+            .and_then(|size| SecureSize::from_str(size).ok())
+    }
+}
+
+/*
+ * Input: output of the mount command and secure mount directory
+ * Return: the mount table entry of the directory, if it is mounted
+ *
+ * The lines have the format "<device> on <dir> type <type> (<options>)".
+ */
+fn parse_mount_table(table: &str, secure_dir: &Path) -> Option<MountEntry> {
+    // Check mount list for secure directory
+    for line in table.split('\n') {
+        let tokens: Vec<&str> = line.split(' ').collect();
+
+        if tokens.len() < 3 {
+            continue;
+        }
+
+        if Path::new(tokens[2]) == secure_dir {
+            let options = tokens
+                .get(5)
+                .map(|o| o.trim_start_matches('(').trim_end_matches(')'))
+                .map(|o| o.split(',').map(String::from).collect())
+                .unwrap_or_default();
+            return Some(MountEntry {
+                device: tokens[0].to_string(),
+                options,
+            });
+        }
+    }
+
+    None
+}
+
+/*
+ * Input: secure mount directory
+ * Return: Result wrap the mount table entry of the directory, if it is
+ *         mounted
+ */
+fn find_mount(secure_dir: &Path) -> Result<Option<MountEntry>> {
+    let output = Command::new("mount").output()?;
+
+    let mount_result = String::from_utf8(output.stdout)?;
+
+    Ok(parse_mount_table(&mount_result, secure_dir))
+}
+
+// Size the kernel reports for a tmpfs mount of the given size, which is
+// rounded up to whole pages
+fn page_rounded(bytes: u64) -> u64 {
+    // sysconf has no side effects
+    let page_size = match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as u64,
+        _ => 4096,
+    };
+    match bytes % page_size {
+        0 => bytes,
+        rest => bytes + page_size - rest,
+    }
+}
+
+/*
+ * Input: mount table entry and requested size
+ * Return: whether the mount has the requested size
+ *
+ * Mounts requested as a percentage of the memory are not checked, as the
+ * kernel reports their size in bytes.
+ */
+fn has_size(entry: &MountEntry, secure_size: SecureSize) -> bool {
+    match (secure_size, entry.size()) {
+        (SecureSize::Percent(_), _) => true,
+        (SecureSize::Bytes(requested), Some(SecureSize::Bytes(actual))) => {
+            page_rounded(requested) == actual
+        }
+        _ => false,
+    }
+}
+
 /*
  * Input: secure mount directory
  * Return: Result wrap boolean with error message
@@ -17,38 +173,117 @@ use std::process::Command;
  * Check the mount status of the secure mount directory. Same
  * implementation as the original python version.
  */
-fn check_mount(secure_dir: &Path) -> Result<bool> {
-    let output = Command::new("mount").output()?;
-
-    let mount_result = String::from_utf8(output.stdout)?;
+fn check_mount(secure_dir: &Path, secure_size: SecureSize) -> Result<bool> {
+    match find_mount(secure_dir)? {
+        Some(entry) if entry.device != "tmpfs" => {
+            let msg = format!("secure storage location {:?} already mounted as wrong file system type: {}. Unmount to continue", &secure_dir, entry.device);
+            error!("{}", msg);
+            Err(Error::SecureMount(msg))
+        }
+        Some(entry) if !has_size(&entry, secure_size) => {
+            // Resizing the mount could fail or lose secrets in use, so the
+            // existing mount is kept as it is
+            warn!(
+                "Using existing secure storage tmpfs mount {:?} of size {}, instead of the configured secure_size {}",
+                &secure_dir,
+                entry
+                    .size()
+                    .map(|size| size.to_string())
+                    .unwrap_or_else(|| String::from("unknown")),
+                secure_size
+            );
+            Ok(true)
+        }
+        Some(_) => {
+            info!(
+                "Using existing secure storage tmpsfs mount {:?}",
+                &secure_dir
+            );
+            Ok(true)
+        }
+        None => {
+            info!("secure storage location {:?} not mounted.", &secure_dir);
+            Ok(false)
+        }
+    }
+}
 
-    let lines: Vec<&str> = mount_result.split('\n').collect();
+/*
+ * Input: work directory
+ * Return: secure mount directory
+ *
+ * The directory is /tmpfs-dev in the work directory if the MOUNT_SECURE
+ * flag is not set, for development environments.
+ */
+pub(crate) fn secure_dir(work_dir: &Path) -> PathBuf {
+    if MOUNT_SECURE {
+        work_dir.join("secure")
+    } else {
+        work_dir.join("tmpfs-dev")
+    }
+}
 
-    // Check mount list for secure directory
-    for line in lines {
-        let tokens: Vec<&str> = line.split(' ').collect();
+/// Size and usage of the secure mount, in bytes
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub(crate) struct MountUsage {
+    pub size: u64,
+    pub used: u64,
+    pub available: u64,
+}
 
-        if tokens.len() < 3 {
-            continue;
+/*
+ * Input: secure mount directory
+ * Return: Result wrap the usage of the file system holding the directory
+ */
+// The statvfs fields are only 64 bits wide on some targets
+#[allow(clippy::useless_conversion)]
+fn mount_usage(secure_dir: &Path) -> Result<MountUsage> {
+    let c_path = CString::new(secure_dir.as_os_str().as_bytes())?;
+    // statvfs only writes to the buffer given
+    let stat = unsafe {
+        let mut stat: libc::statvfs = std::mem::zeroed();
+        if libc::statvfs(c_path.as_ptr(), &mut stat) != 0 {
+            return Err(Error::SecureMount(format!(
+                "unable to get usage of secure dir {:?}: {}",
+                secure_dir,
+                std::io::Error::last_os_error()
+            )));
         }
+        stat
+    };
 
-        if Path::new(tokens[2]) == secure_dir {
-            if tokens[0] != "tmpfs" {
-                let msg = format!("secure storage location {:?} already mounted as wrong file system type: {}. Unmount to continue", &secure_dir, tokens[0]);
-                error!("{}", msg);
-                return Err(Error::SecureMount(msg));
-            } else {
-                info!(
-                    "Using existing secure storage tmpsfs mount {:?}",
-                    &secure_dir
-                );
-            }
-            return Ok(true);
-        }
+    let block_size = u64::from(stat.f_frsize);
+    let size = u64::from(stat.f_blocks) * block_size;
+    let available = u64::from(stat.f_bavail) * block_size;
+    Ok(MountUsage {
+        size,
+        used: size - u64::from(stat.f_bfree) * block_size,
+        available,
+    })
+}
+
+/*
+ * Input: work directory
+ * Return: Result wrap the secure mount directory and its usage, if it is
+ *         mounted
+ *
+ * Read-only counterpart of mount(): nothing is created or mounted.
+ */
+pub(crate) fn mount_status(
+    work_dir: &Path,
+) -> Result<Option<(PathBuf, MountUsage)>> {
+    let secure_dir_path = secure_dir(work_dir);
+    let mounted = if MOUNT_SECURE {
+        matches!(find_mount(&secure_dir_path)?, Some(entry) if entry.device == "tmpfs")
+    } else {
+        secure_dir_path.is_dir()
+    };
+    if !mounted {
+        return Ok(None);
     }
 
-    info!("secure storage location {:?} not mounted.", &secure_dir);
-    Ok(false)
+    let usage = mount_usage(&secure_dir_path)?;
+    Ok(Some((secure_dir_path, usage)))
 }
 
 /*
@@ -59,11 +294,13 @@ fn check_mount(secure_dir: &Path) -> Result<bool> {
  * functions are unsafe function in Rust to use.
  */
 pub(crate) fn mount(work_dir: &Path, secure_size: &str) -> Result<PathBuf> {
+    let size = SecureSize::from_str(secure_size)?;
+
     // Use /tmpfs-dev directory if MOUNT_SECURE flag is not set. This
     // is for development environment and does not mount to the system.
     if !MOUNT_SECURE {
         warn!("Using /tmpfs-dev (dev environment)");
-        let secure_dir_path = Path::new(work_dir).join("tmpfs-dev");
+        let secure_dir_path = secure_dir(work_dir);
         if !secure_dir_path.exists() {
             fs::create_dir(&secure_dir_path).map_err(|e| {
                 Error::SecureMount(format!(
@@ -78,11 +315,11 @@ pub(crate) fn mount(work_dir: &Path, secure_size: &str) -> Result<PathBuf> {
     }
 
     // Mount the directory to file system
-    let secure_dir_path = Path::new(work_dir).join("secure");
+    let secure_dir_path = secure_dir(work_dir);
 
     // If the directory is not mount to file system, mount the directory to
     // file system.
-    if !check_mount(&secure_dir_path)? {
+    if !check_mount(&secure_dir_path, size)? {
         // Create directory if the directory is not exist. The
         // directory permission is set to 448.
         if !secure_dir_path.exists() {
@@ -114,12 +351,10 @@ pub(crate) fn mount(work_dir: &Path, secure_size: &str) -> Result<PathBuf> {
                 info!("Changed path {:?} owner to root.", &secure_dir_path);
             }
             Err(e) => {
-                return Err(Error::SecureMount(
-                    format!(
-                        "unable to change secure path dir owner to root: received exit code {}",
-                        e.exe_code()?.unwrap() //#[allow_ci] : because this is an Option
-                    ),
-                ));
+                return Err(Error::SecureMount(format!(
+                    "unable to change secure path dir owner to root: {}",
+                    e
+                )));
             }
         }
 
@@ -129,7 +364,7 @@ pub(crate) fn mount(work_dir: &Path, secure_size: &str) -> Result<PathBuf> {
                 "-t",
                 "tmpfs",
                 "-o",
-                format!("size={},mode=0700", secure_size).as_str(),
+                format!("size={},mode=0700", size).as_str(),
                 "tmpfs",
                 secure_dir_path.to_str().unwrap(), //#[allow_ci]
             ])
@@ -154,3 +389,98 @@ pub(crate) fn mount(work_dir: &Path, secure_size: &str) -> Result<PathBuf> {
 
     Ok(secure_dir_path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secure_size() {
+        for (size, expected) in &[
+            ("1m", SecureSize::Bytes(1 << 20)),
+            ("512K", SecureSize::Bytes(512 << 10)),
+            ("2g", SecureSize::Bytes(2 << 30)),
+            ("4096", SecureSize::Bytes(4096)),
+            ("50%", SecureSize::Percent(50)),
+        ] {
+            assert_eq!(SecureSize::from_str(size).ok(), Some(*expected));
+        }
+
+        // Sizes are normalized to what the tmpfs size option expects
+        for (size, normalized) in
+            &[("1M", "1048576"), ("512k", "524288"), (" 50% ", "50%")]
+        {
+            assert_eq!(
+                SecureSize::from_str(size).unwrap().to_string(), //#[allow_ci]
+                *normalized
+            );
+        }
+
+        for invalid in &[
+            "abc",
+            "10Q",
+            "",
+            "m",
+            "0",
+            "-1m",
+            "-1",
+            "1.5m",
+            "%",
+            "99999999999g",
+        ] {
+            assert!(matches!(
+                SecureSize::from_str(invalid),
+                Err(Error::Configuration(_))
+            ));
+        }
+
+        // Garbage is rejected before anything is mounted
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        assert!(mount(work_dir.path(), "10Q").is_err());
+        assert!(!secure_dir(work_dir.path()).exists());
+    }
+
+    #[test]
+    fn test_already_mounted() {
+        let table = "\
+proc on /proc type proc (rw,nosuid,nodev,noexec,relatime)
+tmpfs on /var/lib/keylime/secure type tmpfs (rw,relatime,size=1024k,mode=700)
+/dev/sda1 on /mnt/secure type ext4 (rw,relatime)
+";
+        let entry =
+            parse_mount_table(table, Path::new("/var/lib/keylime/secure"))
+                .unwrap(); //#[allow_ci]
+        assert_eq!(entry.device, "tmpfs");
+        assert_eq!(entry.size(), Some(SecureSize::Bytes(1 << 20)));
+
+        // An existing mount is reused, with a warning if its size differs
+        assert!(has_size(&entry, SecureSize::from_str("1m").unwrap())); //#[allow_ci]
+        assert!(!has_size(&entry, SecureSize::from_str("2m").unwrap())); //#[allow_ci]
+        assert!(has_size(&entry, SecureSize::Percent(50)));
+
+        let entry =
+            parse_mount_table(table, Path::new("/mnt/secure")).unwrap(); //#[allow_ci]
+        assert_eq!(entry.device, "/dev/sda1");
+        assert_eq!(entry.size(), None);
+
+        assert!(parse_mount_table(table, Path::new("/mnt")).is_none());
+
+        // Mounting again returns the same directory
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let first = mount(work_dir.path(), "1m").unwrap(); //#[allow_ci]
+        let second = mount(work_dir.path(), "1m").unwrap(); //#[allow_ci]
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_mount_status() {
+        let work_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        assert!(mount_status(work_dir.path()).unwrap().is_none()); //#[allow_ci]
+
+        let secure_dir_path = mount(work_dir.path(), "1m").unwrap(); //#[allow_ci]
+        let (path, usage) = mount_status(work_dir.path()).unwrap().unwrap(); //#[allow_ci]
+        assert_eq!(path, secure_dir_path);
+        assert!(usage.size > 0);
+        assert!(usage.used + usage.available <= usage.size);
+    }
+}
@@ -1,14 +1,36 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright 2021 Keylime Authors
 
+use base64::display::Base64Display;
 use serde::{Deserialize, Serialize};
-use serde_json::Number;
+use std::io::{self, Write};
 
 #[derive(Debug, Deserialize)]
 struct WrappedBase64Encoded(
     #[serde(deserialize_with = "deserialize_as_base64")] Vec<u8>,
 );
 
+#[derive(Debug, Deserialize)]
+struct WrappedHexEncoded(
+    #[serde(deserialize_with = "deserialize_as_hex")] Vec<u8>,
+);
+
+// Serializes bytes as base64 encoded with the given configuration.
+//
+// The encoding is written in chunks to serializers able to collect a
+// string in parts, as the JSON one, so that no string holding the whole
+// encoding is allocated for large blobs such as measured boot logs.
+fn collect_base64<S>(
+    bytes: &[u8],
+    config: base64::Config,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.collect_str(&Base64Display::with_config(bytes, config))
+}
+
 pub(crate) fn serialize_as_base64<S>(
     bytes: &[u8],
     serializer: S,
@@ -16,9 +38,11 @@ pub(crate) fn serialize_as_base64<S>(
 where
     S: serde::Serializer,
 {
-    serializer.serialize_str(&base64::encode(bytes))
+    collect_base64(bytes, base64::STANDARD, serializer)
 }
 
+/// Deserializes standard base64, ignoring ASCII whitespace (e.g. the line
+/// breaks of pasted keys) and accepting input with or without padding
 pub(crate) fn deserialize_as_base64<'de, D>(
     deserializer: D,
 ) -> Result<Vec<u8>, D::Error>
@@ -26,10 +50,86 @@ where
     D: serde::Deserializer<'de>,
 {
     String::deserialize(deserializer).and_then(|string| {
-        base64::decode(&string).map_err(serde::de::Error::custom)
+        decode_base64_lenient(&string).map_err(serde::de::Error::custom)
     })
 }
 
+/// Deserializes standard base64, rejecting whitespace and missing padding
+pub(crate) fn deserialize_as_base64_strict<'de, D>(
+    deserializer: D,
+) -> Result<Vec<u8>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    String::deserialize(deserializer).and_then(|string| {
+        decode_base64_strict(&string).map_err(serde::de::Error::custom)
+    })
+}
+
+/// Decodes standard base64, ignoring ASCII whitespace, with or without
+/// padding
+pub(crate) fn decode_base64_lenient(
+    data: &str,
+) -> Result<Vec<u8>, base64::DecodeError> {
+    let data: String =
+        data.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+    base64::decode_config(data.trim_end_matches('='), base64::STANDARD_NO_PAD)
+}
+
+/// Decodes standard base64, which must be padded and free of whitespace
+pub(crate) fn decode_base64_strict(
+    data: &str,
+) -> Result<Vec<u8>, base64::DecodeError> {
+    // The decoder accepts unpadded input, so check the padding here: the
+    // input must be made of whole 4 characters groups
+    if data.len() & 3 != 0 {
+        return Err(base64::DecodeError::InvalidLength);
+    }
+    base64::decode_config(data, base64::STANDARD)
+}
+
+/// Serializes bytes as base64 with the URL-safe alphabet and no padding, so
+/// that the value can be used as is in URLs and file names
+pub(crate) fn serialize_as_base64_url<S>(
+    bytes: &[u8],
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    collect_base64(bytes, base64::URL_SAFE_NO_PAD, serializer)
+}
+
+/// Deserializes base64 in either the URL-safe or the standard alphabet, so
+/// that clients sending the standard encoding keep working
+pub(crate) fn deserialize_as_base64_url<'de, D>(
+    deserializer: D,
+) -> Result<Vec<u8>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    String::deserialize(deserializer).and_then(|string| {
+        decode_base64_any(&string).map_err(serde::de::Error::custom)
+    })
+}
+
+/// Decodes base64 in either the URL-safe or the standard alphabet, with or
+/// without padding
+pub(crate) fn decode_base64_any(
+    data: &str,
+) -> Result<Vec<u8>, base64::DecodeError> {
+    // The alphabets only differ in the characters for 62 and 63
+    let standard: String = data
+        .chars()
+        .map(|c| match c {
+            '-' => '+',
+            '_' => '/',
+            c => c,
+        })
+        .collect();
+    base64::decode_config(standard, base64::STANDARD)
+}
+
 pub(crate) fn serialize_maybe_base64<S>(
     value: &Option<Vec<u8>>,
     serializer: S,
@@ -38,7 +138,7 @@ where
     S: serde::Serializer,
 {
     match *value {
-        Some(ref value) => serializer.serialize_str(&base64::encode(value)),
+        Some(ref value) => serialize_as_base64(value, serializer),
         None => serializer.serialize_none(),
     }
 }
@@ -52,3 +152,348 @@ where
     Option::<WrappedBase64Encoded>::deserialize(deserializer)
         .map(|wrapped| wrapped.map(|wrapped| wrapped.0))
 }
+
+/// Serializes bytes as lowercase hex, as conventionally used for digests
+/// such as PCR values and IMA template hashes
+pub(crate) fn serialize_as_hex<S>(
+    bytes: &[u8],
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.serialize_str(&hex::encode(bytes))
+}
+
+/// Deserializes hex in either case
+pub(crate) fn deserialize_as_hex<'de, D>(
+    deserializer: D,
+) -> Result<Vec<u8>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    String::deserialize(deserializer).and_then(|string| {
+        decode_hex(&string).map_err(serde::de::Error::custom)
+    })
+}
+
+/// Decodes hex in either case, which must have an even number of digits
+pub(crate) fn decode_hex(data: &str) -> Result<Vec<u8>, String> {
+    hex::decode(data).map_err(|e| match e {
+        hex::FromHexError::OddLength => format!(
+            "Invalid hex string: odd number of digits ({})",
+            data.len()
+        ),
+        e => format!("Invalid hex string: {}", e),
+    })
+}
+
+pub(crate) fn serialize_maybe_hex<S>(
+    value: &Option<Vec<u8>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match *value {
+        Some(ref value) => serializer.serialize_str(&hex::encode(value)),
+        None => serializer.serialize_none(),
+    }
+}
+
+pub(crate) fn deserialize_maybe_hex<'de, D>(
+    deserializer: D,
+) -> Result<Option<Vec<u8>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Option::<WrappedHexEncoded>::deserialize(deserializer)
+        .map(|wrapped| wrapped.map(|wrapped| wrapped.0))
+}
+
+// JSON formatter producing the same output as Python's json.dumps with its
+// default arguments: items are separated by ", ", keys by ": ", and
+// non-ASCII characters are escaped
+struct PythonFormatter;
+
+impl serde_json::ser::Formatter for PythonFormatter {
+    fn begin_array_value<W>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> io::Result<()>
+    where
+        W: ?Sized + Write,
+    {
+        if first {
+            Ok(())
+        } else {
+            writer.write_all(b", ")
+        }
+    }
+
+    fn begin_object_key<W>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> io::Result<()>
+    where
+        W: ?Sized + Write,
+    {
+        self.begin_array_value(writer, first)
+    }
+
+    fn begin_object_value<W>(&mut self, writer: &mut W) -> io::Result<()>
+    where
+        W: ?Sized + Write,
+    {
+        writer.write_all(b": ")
+    }
+
+    fn write_string_fragment<W>(
+        &mut self,
+        writer: &mut W,
+        fragment: &str,
+    ) -> io::Result<()>
+    where
+        W: ?Sized + Write,
+    {
+        for part in fragment.split_inclusive(|c: char| !c.is_ascii()) {
+            let mut chars = part.chars();
+            match chars.next_back() {
+                Some(c) if !c.is_ascii() => {
+                    writer.write_all(chars.as_str().as_bytes())?;
+                    for unit in c.encode_utf16(&mut [0; 2]) {
+                        write!(writer, "\\u{:04x}", unit)?;
+                    }
+                }
+                _ => writer.write_all(part.as_bytes())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Serializes the value to JSON exactly as Python's json.dumps would, for
+/// interoperability with the Python Keylime components
+pub(crate) fn to_python_json<T>(value: &T) -> serde_json::Result<String>
+where
+    T: ?Sized + Serialize,
+{
+    let mut output = Vec::new();
+    let mut serializer =
+        serde_json::Serializer::with_formatter(&mut output, PythonFormatter);
+    value.serialize(&mut serializer)?;
+    // Only ASCII is written
+    String::from_utf8(output).map_err(serde::ser::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct MaybeBase64 {
+        #[serde(serialize_with = "serialize_maybe_base64")]
+        data: Option<Vec<u8>>,
+    }
+
+    #[test]
+    fn test_base64_same_output() {
+        // Sizes around the encoder chunks and padding
+        for len in (0..10).chain(1020..1030).chain(3070..3075) {
+            let data = (0..len).map(|i| i as u8).collect::<Vec<u8>>();
+            let value = MaybeBase64 {
+                data: Some(data.clone()),
+            };
+            assert_eq!(
+                serde_json::to_string(&value).unwrap(), //#[allow_ci]
+                format!(r#"{{"data":"{}"}}"#, base64::encode(&data))
+            );
+        }
+
+        let value = MaybeBase64 { data: None };
+        assert_eq!(
+            serde_json::to_string(&value).unwrap(), //#[allow_ci]
+            r#"{"data":null}"#
+        );
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct UrlSafe {
+        #[serde(
+            serialize_with = "serialize_as_base64_url",
+            deserialize_with = "deserialize_as_base64_url"
+        )]
+        data: Vec<u8>,
+    }
+
+    #[test]
+    fn test_base64_url_round_trip() {
+        // Encoded as "+/8=" with the standard alphabet
+        let value = UrlSafe {
+            data: vec![0xfb, 0xff],
+        };
+
+        let json = serde_json::to_string(&value).unwrap(); //#[allow_ci]
+        assert_eq!(json, r#"{"data":"-_8"}"#);
+        let decoded: UrlSafe = serde_json::from_str(&json).unwrap(); //#[allow_ci]
+        assert_eq!(decoded, value);
+
+        // The standard alphabet is accepted too, with or without padding
+        for encoded in ["+/8=", "+/8", "-_8="] {
+            let decoded: UrlSafe =
+                serde_json::from_str(&format!(r#"{{"data":"{}"}}"#, encoded))
+                    .unwrap(); //#[allow_ci]
+            assert_eq!(decoded, value);
+        }
+
+        assert!(
+            serde_json::from_str::<UrlSafe>(r#"{"data":"-_8*"}"#).is_err()
+        );
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct Lenient {
+        #[serde(deserialize_with = "deserialize_as_base64")]
+        data: Vec<u8>,
+        #[serde(deserialize_with = "deserialize_maybe_base64")]
+        maybe: Option<Vec<u8>>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct Strict {
+        #[serde(deserialize_with = "deserialize_as_base64_strict")]
+        data: Vec<u8>,
+    }
+
+    #[test]
+    fn test_base64_lenient() {
+        let expected = b"keylime agent".to_vec();
+
+        // Line breaks and missing padding, as in pasted keys
+        for encoded in [
+            "a2V5bGltZSBhZ2VudA==",
+            "a2V5bGlt\\nZSBhZ2VudA==\\n",
+            "a2V5bGlt\\r\\nZSBh Z2VudA",
+            "a2V5bGltZSBhZ2VudA",
+        ] {
+            let lenient: Lenient = serde_json::from_str(&format!(
+                r#"{{"data":"{0}","maybe":"{0}"}}"#,
+                encoded
+            ))
+            .unwrap(); //#[allow_ci]
+            assert_eq!(lenient.data, expected);
+            assert_eq!(lenient.maybe, Some(expected.clone()));
+        }
+
+        let lenient: Lenient =
+            serde_json::from_str(r#"{"data":"","maybe":null}"#).unwrap(); //#[allow_ci]
+        assert!(lenient.data.is_empty());
+        assert_eq!(lenient.maybe, None);
+
+        assert!(serde_json::from_str::<Lenient>(
+            r#"{"data":"a2V5*","maybe":null}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_base64_strict() {
+        let strict: Strict =
+            serde_json::from_str(r#"{"data":"a2V5bGltZSBhZ2VudA=="}"#)
+                .unwrap(); //#[allow_ci]
+        assert_eq!(strict.data, b"keylime agent".to_vec());
+
+        for encoded in ["a2V5bGlt\\nZSBhZ2VudA==", "a2V5bGltZSBhZ2VudA"] {
+            assert!(serde_json::from_str::<Strict>(&format!(
+                r#"{{"data":"{}"}}"#,
+                encoded
+            ))
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn test_base64_any() {
+        // Encoded as "+/8=" with the standard alphabet
+        let expected = vec![0xfb, 0xff];
+        for encoded in ["+/8=", "+/8", "-_8=", "-_8"] {
+            assert_eq!(decode_base64_any(encoded).unwrap(), expected); //#[allow_ci]
+        }
+
+        assert!(decode_base64_any("-_8*").is_err());
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Hex {
+        #[serde(
+            serialize_with = "serialize_as_hex",
+            deserialize_with = "deserialize_as_hex"
+        )]
+        data: Vec<u8>,
+        #[serde(
+            serialize_with = "serialize_maybe_hex",
+            deserialize_with = "deserialize_maybe_hex"
+        )]
+        maybe: Option<Vec<u8>>,
+    }
+
+    #[test]
+    fn test_hex_round_trip() {
+        let value = Hex {
+            data: vec![0x00, 0xab, 0xcd, 0xef],
+            maybe: Some(vec![0x1f]),
+        };
+        let json = serde_json::to_string(&value).unwrap(); //#[allow_ci]
+        assert_eq!(json, r#"{"data":"00abcdef","maybe":"1f"}"#);
+        let decoded: Hex = serde_json::from_str(&json).unwrap(); //#[allow_ci]
+        assert_eq!(decoded, value);
+
+        // Upper case is accepted too
+        let decoded: Hex =
+            serde_json::from_str(r#"{"data":"00ABcdEF","maybe":"1F"}"#)
+                .unwrap(); //#[allow_ci]
+        assert_eq!(decoded, value);
+
+        let value = Hex {
+            data: Vec::new(),
+            maybe: None,
+        };
+        let json = serde_json::to_string(&value).unwrap(); //#[allow_ci]
+        assert_eq!(json, r#"{"data":"","maybe":null}"#);
+        let decoded: Hex = serde_json::from_str(&json).unwrap(); //#[allow_ci]
+        assert_eq!(decoded, value);
+    }
+
+    #[test]
+    fn test_hex_invalid() {
+        let err =
+            serde_json::from_str::<Hex>(r#"{"data":"abc","maybe":null}"#)
+                .unwrap_err(); //#[allow_ci]
+        assert!(err
+            .to_string()
+            .starts_with("Invalid hex string: odd number of digits (3)"));
+
+        for json in [
+            r#"{"data":"0g","maybe":null}"#,
+            r#"{"data":"","maybe":"abc"}"#,
+        ] {
+            assert!(serde_json::from_str::<Hex>(json).is_err());
+        }
+    }
+
+    #[test]
+    fn test_to_python_json() {
+        let value = serde_json::json!({
+            "list": [1, "two", null, true],
+            "text": "line\n\"quoted\" caf\u{e9} \u{1f600}",
+            "empty": {},
+        });
+        // As printed by json.dumps in Python, keys being sorted here
+        assert_eq!(
+            to_python_json(&value).unwrap(), //#[allow_ci]
+            r#"{"empty": {}, "list": [1, "two", null, true], "text": "line\n\"quoted\" caf\u00e9 \ud83d\ude00"}"#
+        );
+    }
+}
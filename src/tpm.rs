@@ -7,19 +7,24 @@ use log::*;
 use std::convert::{TryFrom, TryInto};
 use std::io::prelude::*;
 use std::str::FromStr;
+use std::sync::{Mutex, MutexGuard, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::{
-    quotes_handler::KeylimeQuote, Error as KeylimeError, QuoteData, Result,
+    algorithms::{EncryptionAlgorithm, HashAlgorithm, SignAlgorithm},
+    quotes_handler::{KeylimeQuote, QuoteBank, QuoteParts},
+    Error as KeylimeError, QuoteData, Result,
 };
 
-use actix_web::web::Data;
-
 use openssl::{
-    hash::{Hasher, MessageDigest},
+    hash::{hash, Hasher, MessageDigest},
     memcmp,
     pkey::{Id, PKeyRef, Public},
 };
 
+use actix_web::web;
+
 use flate2::{write::ZlibEncoder, Compression};
 
 use tss_esapi::{
@@ -34,6 +39,7 @@ use tss_esapi::{
     constants::{
         session_type::SessionType,
         tss::{TPM2_ALG_NULL, TPM2_ST_ATTEST_QUOTE},
+        CapabilityType,
     },
     handles::{AuthHandle, KeyHandle, PcrHandle},
     interface_types::{
@@ -43,9 +49,10 @@ use tss_esapi::{
         session_handles::AuthSession,
     },
     structures::{
-        Attest, AttestInfo, Digest, DigestValues, EncryptedSecret,
-        HashScheme, IdObject, Name, PcrSelectionList,
-        PcrSelectionListBuilder, PcrSlot, Signature, SignatureScheme,
+        Attest, AttestInfo, CapabilityData, Digest, DigestValues, EccScheme,
+        EncryptedSecret, HashScheme, IdObject, Name, PcrSelectionList,
+        PcrSelectionListBuilder, PcrSlot, RsaScheme, Signature,
+        SignatureScheme,
     },
     tcti_ldr::TctiNameConf,
     tss2_esys::{
@@ -62,14 +69,31 @@ use tss_esapi::{
 pub const MAX_NONCE_SIZE: usize = 64;
 
 /*
- * Input: None
+ * Input: TCTI configuration string, e.g. "device:/dev/tpmrm0"
+ * Return: Parsed TCTI configuration
+ *
+ * Returns a configuration error if the string cannot be parsed.
+ */
+pub(crate) fn parse_tcti(tcti: &str) -> Result<TctiNameConf> {
+    TctiNameConf::from_str(tcti).map_err(|e| {
+        KeylimeError::Configuration(format!(
+            "Invalid tpm_tcti {}: {}",
+            tcti, e
+        ))
+    })
+}
+
+/*
+ * Input: TCTI configuration string, or an empty string to use the TCTI
+ *        environment variable or detect the TPM device
  * Return: Connection context
  *
  * Example call:
- * let mut ctx = tpm::get_tpm2_ctx();
+ * let mut ctx = tpm::get_tpm2_ctx(&config.tpm_tcti);
  */
-pub(crate) fn get_tpm2_ctx() -> Result<Context> {
+pub(crate) fn get_tpm2_ctx(tcti: &str) -> Result<Context> {
     let tcti_path = match std::env::var("TCTI") {
+        _ if !tcti.is_empty() => tcti.to_string(),
         Ok(val) => val,
         Err(_) => if std::path::Path::new("/dev/tpmrm0").exists() {
             "device:/dev/tpmrm0"
@@ -79,10 +103,45 @@ pub(crate) fn get_tpm2_ctx() -> Result<Context> {
         .to_string(),
     };
 
-    let tcti = TctiNameConf::from_str(&tcti_path)?;
+    let tcti = parse_tcti(&tcti_path)?;
     Context::new(tcti).map_err(|e| e.into())
 }
 
+// Maximum number of PCR banks reported by the TPM
+const MAX_PCR_BANKS: u32 = 16;
+
+/*
+ * Input: Connection context
+ * Return: Hash algorithms of the PCR banks with PCRs allocated
+ *
+ * Banks using a hash algorithm not supported by Keylime are left out.
+ */
+pub(crate) fn get_pcr_banks(
+    context: &mut Context,
+) -> Result<Vec<HashAlgorithm>> {
+    let (data, _) = context.get_capability(
+        CapabilityType::AssignedPcr,
+        0,
+        MAX_PCR_BANKS,
+    )?;
+    let selections = match data {
+        CapabilityData::AssignedPcr(selections) => selections,
+        _ => {
+            return Err(KeylimeError::Other(
+                "unexpected TPM capability data for PCR banks".to_string(),
+            ))
+        }
+    };
+    Ok(selections
+        .get_selections()
+        .iter()
+        .filter(|selection| !selection.is_empty())
+        .filter_map(|selection| {
+            HashAlgorithm::try_from(selection.hashing_algorithm()).ok()
+        })
+        .collect())
+}
+
 /*
  * Input: Connection context, asymmetric algo (optional)
  * Return: (Key handle, public cert, TPM public object)
@@ -237,6 +296,58 @@ pub(crate) fn create_ak(
     Ok((ak_handle, name, tpm2_pub_vec))
 }
 
+// Signing schemes the agent can quote with
+static SIGN_ALGORITHMS: &[SignAlgorithm] = &[
+    SignAlgorithm::RsaSsa,
+    SignAlgorithm::RsaPss,
+    SignAlgorithm::EcDsa,
+    SignAlgorithm::EcSchnorr,
+];
+
+/// Returns the signing schemes the AK can quote with: the scheme it was
+/// created with, or every scheme usable with its key type if it was created
+/// without one.
+pub(crate) fn ak_sign_algs(
+    ctx: &mut Context,
+    ak_handle: KeyHandle,
+) -> Result<Vec<SignAlgorithm>> {
+    let (ak_public, _, _) = ctx.read_public(ak_handle)?;
+    let enc_alg = match ak_public {
+        tss_esapi::structures::Public::Rsa { parameters, .. } => {
+            match parameters.rsa_scheme() {
+                RsaScheme::RsaSsa(_) => {
+                    return Ok(vec![SignAlgorithm::RsaSsa])
+                }
+                RsaScheme::RsaPss(_) => {
+                    return Ok(vec![SignAlgorithm::RsaPss])
+                }
+                RsaScheme::Null => EncryptionAlgorithm::Rsa,
+                _ => return Ok(Vec::new()),
+            }
+        }
+        tss_esapi::structures::Public::Ecc { parameters, .. } => {
+            match parameters.ecc_scheme() {
+                EccScheme::EcDsa(_) => return Ok(vec![SignAlgorithm::EcDsa]),
+                EccScheme::EcSchnorr(_) => {
+                    return Ok(vec![SignAlgorithm::EcSchnorr])
+                }
+                EccScheme::Null => EncryptionAlgorithm::Ecc,
+                _ => return Ok(Vec::new()),
+            }
+        }
+        _ => {
+            return Err(KeylimeError::Other(
+                "AK is not an RSA or ECC key".to_string(),
+            ))
+        }
+    };
+    Ok(SIGN_ALGORITHMS
+        .iter()
+        .copied()
+        .filter(|alg| alg.is_compatible_with(enc_alg))
+        .collect())
+}
+
 pub(crate) fn store_ak(
     ctx: &mut Context,
     ak_handle: KeyHandle,
@@ -344,16 +455,16 @@ pub(crate) fn activate_credential(
     resp
 }
 
-// Takes a public PKey and returns a DigestValue of it.
-// Note: Currently, this creates a DigestValue including both SHA256 and
-// SHA1 because these banks are checked by Keylime on the Python side.
-pub(crate) fn pubkey_to_tpm_digest(
+// Takes a public PKey and returns its digest with the given hash algorithm,
+// as extended into PCR 16. Algorithms without a digest implementation are
+// refused.
+fn pubkey_digest(
     pubkey: &PKeyRef<Public>,
-) -> Result<DigestValues> {
-    let mut keydigest = DigestValues::new();
-
+    hash_alg: HashingAlgorithm,
+) -> Result<Vec<u8>> {
     let keybytes = match pubkey.id() {
         Id::RSA => pubkey.rsa()?.public_key_to_pem()?,
+        Id::EC => pubkey.ec_key()?.public_key_to_pem()?,
         other_id => {
             return Err(KeylimeError::Other(format!(
             "Converting to digest value for key type {:?} is not yet implemented",
@@ -362,16 +473,24 @@ pub(crate) fn pubkey_to_tpm_digest(
         }
     };
 
-    // SHA256
-    let mut hasher = openssl::sha::Sha256::new();
-    hasher.update(&keybytes);
-    let mut hashvec: Vec<u8> = hasher.finish().into();
-    keydigest.set(HashingAlgorithm::Sha256, Digest::try_from(hashvec)?);
-    // SHA1
-    let mut hasher = openssl::sha::Sha1::new();
-    hasher.update(&keybytes);
-    let mut hashvec: Vec<u8> = hasher.finish().into();
-    keydigest.set(HashingAlgorithm::Sha1, Digest::try_from(hashvec)?);
+    Ok(hash(hash_alg_to_message_digest(hash_alg)?, &keybytes)?.to_vec())
+}
+
+// Takes a public PKey and returns a DigestValue of it, with a digest for
+// each of the given banks, so that PCR 16 binds the key in all the banks
+// quoted. SHA256 and SHA1 are always included because these banks are
+// checked by Keylime on the Python side.
+pub(crate) fn pubkey_to_tpm_digest(
+    pubkey: &PKeyRef<Public>,
+    banks: &[HashingAlgorithm],
+) -> Result<DigestValues> {
+    let mut keydigest = DigestValues::new();
+    for &bank in [HashingAlgorithm::Sha256, HashingAlgorithm::Sha1]
+        .iter()
+        .chain(banks)
+    {
+        keydigest.set(bank, Digest::try_from(pubkey_digest(pubkey, bank)?)?);
+    }
 
     Ok(keydigest)
 }
@@ -389,10 +508,29 @@ pub(crate) fn pubkey_to_tpm_digest(
 // and verifier. The output from this function can be used to call a
 // Quote from the TSS ESAPI.
 //
+// Masks that are not a hexadecimal number, with an optional "0x" prefix,
+// or that select PCRs beyond 23 are rejected with a message describing the
+// problem, as it is returned to the verifier.
+//
 pub(crate) fn read_mask(mask: &str) -> Result<Vec<PcrSlot>> {
-    let mut pcrs = Vec::new();
+    let digits = mask.strip_prefix("0x").unwrap_or(mask);
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(KeylimeError::Other(format!(
+            "malformed mask in integrity quote: {} is not a hexadecimal number",
+            mask
+        )));
+    }
 
-    let num = u32::from_str_radix(mask.trim_start_matches("0x"), 16)?;
+    // Only the PCRs 0-23 exist, so a mask not fitting in 32 bits is out of
+    // range anyway
+    let num = u32::from_str_radix(digits, 16).map_err(|_| {
+        KeylimeError::Other(format!(
+            "malformed mask in integrity quote: only pcrs 0-23 can be included, but mask {} selects higher pcrs",
+            mask
+        ))
+    })?;
+
+    let mut pcrs = Vec::new();
 
     // check which bits are set
     for i in 0..32 {
@@ -432,10 +570,70 @@ pub(crate) fn read_mask(mask: &str) -> Result<Vec<PcrSlot>> {
     Ok(pcrs)
 }
 
-//This checks if a PCR is contained in a mask
-pub(crate) fn check_mask(mask: &str, pcr: &PcrSlot) -> Result<bool> {
-    let selected_pcrs = read_mask(mask)?;
-    Ok(selected_pcrs.contains(pcr))
+// Reads a comma-separated list of PCR indices, e.g. "7,10", checked the
+// same way as a mask
+pub(crate) fn read_pcr_indices(indices: &str) -> Result<Vec<PcrSlot>> {
+    let mut mask = 0u32;
+    for index in indices.split(',').map(str::trim) {
+        match index.parse::<u32>() {
+            Ok(pcr) if pcr < 24 => mask |= 1 << pcr,
+            Ok(pcr) => return Err(KeylimeError::Other(format!(
+                "malformed extra_pcrs in integrity quote: only pcrs 0-23 can be included, but extra_pcrs included pcr {}",
+                pcr
+            ))),
+            Err(_) => return Err(KeylimeError::Other(format!(
+                "malformed extra_pcrs in integrity quote: {:?} is not a PCR index",
+                index
+            ))),
+        }
+    }
+    read_mask(&format!("{:#x}", mask))
+}
+
+// Returns the PCR slot of a PCR index
+pub(crate) fn pcr_slot(index: u32) -> Result<PcrSlot> {
+    (index < 24)
+        .then(|| 1u32 << index)
+        .and_then(|bit| PcrSlot::try_from(bit).ok())
+        .ok_or_else(|| {
+            KeylimeError::Other(format!(
+                "invalid PCR index {}: only pcrs 0-23 exist",
+                index
+            ))
+        })
+}
+
+//This checks if a PCR is contained in the PCRs read from a mask
+pub(crate) fn check_mask(pcrs: &[PcrSlot], pcr: &PcrSlot) -> bool {
+    pcrs.contains(pcr)
+}
+
+// Returns the PCRs from the required list which are not selected by the
+// PCRs read from a mask
+pub(crate) fn missing_pcrs(pcrs: &[PcrSlot], required: &[u32]) -> Vec<u32> {
+    required
+        .iter()
+        .filter(|&&pcr| {
+            !pcrs
+                .iter()
+                .any(|&slot| u32::from(slot).trailing_zeros() == pcr)
+        })
+        .copied()
+        .collect()
+}
+
+// Returns the PCRs read from a mask which are not in the allowed list. All
+// PCRs are allowed if the list is empty. PCR 16 is always allowed, as it
+// is the one binding the NK in every quote.
+pub(crate) fn forbidden_pcrs(pcrs: &[PcrSlot], allowed: &[u32]) -> Vec<u32> {
+    if allowed.is_empty() {
+        return Vec::new();
+    }
+    let data_pcr = u32::from(PcrSlot::Slot16).trailing_zeros();
+    pcrs.iter()
+        .map(|&slot| u32::from(slot).trailing_zeros())
+        .filter(|pcr| *pcr != data_pcr && !allowed.contains(pcr))
+        .collect()
 }
 
 // This encodes a quote string as input to Python Keylime's quote checking functionality.
@@ -457,32 +655,22 @@ pub(crate) fn encode_quote_string(
     let sig_vec = sig_to_vec(sig.try_into()?);
     let pcr_vec = pcrdata_to_vec(pcrs_read, pcr_data);
 
-    // base64 encoding
-    let att_str = base64::encode(att_vec);
-    let sig_str = base64::encode(sig_vec);
-    let pcr_str = base64::encode(pcr_vec);
-
-    // create concatenated string
-    let mut quote = String::new();
-    quote.push('r');
-    quote.push_str(&att_str);
-    quote.push(':');
-    quote.push_str(&sig_str);
-    quote.push(':');
-    quote.push_str(&pcr_str);
-
-    Ok(quote)
+    // base64 encoding, then concatenation into the quote string
+    let parts = QuoteParts {
+        quote_b64: base64::encode(att_vec),
+        sig_b64: base64::encode(sig_vec),
+        pcr_b64: base64::encode(pcr_vec),
+    };
+    Ok(parts.to_quote_string())
 }
 
 // This function extends Pcr16 with the digest, then creates a PcrList
-// from the given mask and pcr16.
-// Note: Currently, this will build the list for both SHA256 and SHA1 as
-// necessary for the Python components of Keylime.
+// from the given mask and pcr16, selecting them in each of the given banks.
 pub(crate) fn build_pcr_list(
     context: &mut Context,
     digest: DigestValues,
-    mask: Option<&str>,
-    hash_alg: HashingAlgorithm,
+    mask: Option<&[PcrSlot]>,
+    banks: &[HashingAlgorithm],
 ) -> Result<PcrSelectionList> {
     // extend digest into pcr16
     context.execute_with_nullauth_session(|ctx| {
@@ -490,9 +678,8 @@ pub(crate) fn build_pcr_list(
         ctx.pcr_extend(PcrHandle::Pcr16, digest.to_owned())
     })?;
 
-    // translate mask to vec of pcrs
     let mut pcrs = match mask {
-        Some(m) => read_mask(m)?,
+        Some(pcrs) => pcrs.to_vec(),
         None => Vec::new(),
     };
 
@@ -503,7 +690,9 @@ pub(crate) fn build_pcr_list(
     }
 
     let mut pcrlist = PcrSelectionListBuilder::new();
-    pcrlist = pcrlist.with_selection(hash_alg, &pcrs);
+    for bank in banks {
+        pcrlist = pcrlist.with_selection(*bank, &pcrs);
+    }
     let pcrlist = pcrlist.build()?;
 
     Ok(pcrlist)
@@ -537,8 +726,10 @@ fn hash_alg_to_message_digest(
     hash_alg: HashingAlgorithm,
 ) -> Result<MessageDigest> {
     match hash_alg {
-        HashingAlgorithm::Sha256 => Ok(MessageDigest::sha256()),
         HashingAlgorithm::Sha1 => Ok(MessageDigest::sha1()),
+        HashingAlgorithm::Sha256 => Ok(MessageDigest::sha256()),
+        HashingAlgorithm::Sha384 => Ok(MessageDigest::sha384()),
+        HashingAlgorithm::Sha512 => Ok(MessageDigest::sha512()),
         other => Err(KeylimeError::Other(format!(
             "Unsupported hashing algorithm: {:?}",
             other
@@ -628,20 +819,112 @@ fn perform_quote_and_pcr_read(
     ))
 }
 
+// Breaks the PCR values read down by bank, so that verifiers can check each
+// bank against its own policy
+fn quote_banks(
+    banks: &[HashAlgorithm],
+    pcr_data: &PcrData,
+) -> Vec<QuoteBank> {
+    banks
+        .iter()
+        .map(|&bank| QuoteBank {
+            hash_alg: bank.to_string(),
+            pcrs: pcr_data
+                .pcr_bank(bank.into())
+                .map(|pcr_bank| {
+                    pcr_bank
+                        .into_iter()
+                        .map(|(slot, digest)| {
+                            (
+                                u32::from(*slot).trailing_zeros(),
+                                hex::encode(digest.value()),
+                            )
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+        })
+        .collect()
+}
+
+// Quotes the PCRs in each of the given banks. The quote reports the first
+// bank as its hash_alg, along with the values of each bank.
 pub(crate) fn quote(
     nonce: &[u8],
-    mask: Option<&str>,
-    data: Data<QuoteData>,
+    mask: Option<&[PcrSlot]>,
+    data: &QuoteData,
+    sign_alg: SignAlgorithm,
+    banks: &[HashAlgorithm],
 ) -> Result<KeylimeQuote> {
-    let nk_digest = pubkey_to_tpm_digest(&data.pub_key)?;
+    let mut context = lock_context(data)?;
+    quote_with_context(&mut context, nonce, mask, data, sign_alg, banks)
+}
 
-    // must unwrap here due to lock mechanism
-    // https://github.com/rust-lang-nursery/failure/issues/192
-    let mut context = data.tpmcontext.lock().unwrap(); //#[allow_ci]
+// Interval between attempts to take the TPM context lock
+const TPM_LOCK_POLL: Duration = Duration::from_millis(10);
 
-    let pcrlist =
-        build_pcr_list(&mut context, nk_digest, mask, data.hash_alg.into())?;
+// Takes the TPM context lock, waiting at most tpm_lock_timeout for the
+// request in progress to release it
+pub(crate) fn lock_context(
+    data: &QuoteData,
+) -> Result<MutexGuard<'_, Context>> {
+    lock_context_timeout(&data.tpmcontext, data.tpm_lock_timeout)
+}
 
+/// Takes the TPM context lock, failing with `Error::TpmBusy` if it is not
+/// released within the timeout. A timeout of 0 waits indefinitely.
+///
+/// std mutexes cannot be waited for with a timeout, so the lock is polled.
+/// A panic while holding the lock does not make the TPM unusable: the
+/// context is recovered, as every command is run to completion or failure
+/// by the TPM anyway.
+pub(crate) fn lock_context_timeout(
+    context: &Mutex<Context>,
+    timeout: Duration,
+) -> Result<MutexGuard<'_, Context>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let result = if timeout.is_zero() {
+            context.lock().map_err(TryLockError::from)
+        } else {
+            context.try_lock()
+        };
+        match result {
+            Ok(guard) => return Ok(guard),
+            Err(TryLockError::Poisoned(poisoned)) => {
+                return Ok(poisoned.into_inner())
+            }
+            Err(TryLockError::WouldBlock) => {
+                if Instant::now() >= deadline {
+                    return Err(KeylimeError::TpmBusy(timeout));
+                }
+                thread::sleep(TPM_LOCK_POLL);
+            }
+        }
+    }
+}
+
+// Same as quote, with the TPM context already locked by the caller
+fn quote_with_context(
+    context: &mut Context,
+    nonce: &[u8],
+    mask: Option<&[PcrSlot]>,
+    data: &QuoteData,
+    sign_alg: SignAlgorithm,
+    banks: &[HashAlgorithm],
+) -> Result<KeylimeQuote> {
+    let hash_alg = *banks.first().ok_or_else(|| {
+        KeylimeError::Other("No PCR bank to quote".to_string())
+    })?;
+    let hashing_algs = banks
+        .iter()
+        .map(|&bank| bank.into())
+        .collect::<Vec<HashingAlgorithm>>();
+    let nk_digest = pubkey_to_tpm_digest(&data.pub_key, &hashing_algs)?;
+    let pcrlist = build_pcr_list(context, nk_digest, mask, &hashing_algs)?;
+
+    // The PCR digest in the quote is computed with the hash algorithm of
+    // the signing scheme, whatever the bank the PCRs are read from
     let (attestation, sig, pcrs_read, pcr_data) = context
         .execute_with_nullauth_session(|ctx| {
             perform_quote_and_pcr_read(
@@ -649,26 +932,90 @@ pub(crate) fn quote(
                 data.ak_handle,
                 nonce,
                 pcrlist,
-                data.sign_alg.to_signature_scheme(data.hash_alg),
+                sign_alg.to_signature_scheme(data.hash_alg),
                 data.hash_alg.into(),
             )
         })?;
 
+    let quote_banks = quote_banks(banks, &pcr_data);
     let tpm_quote =
         encode_quote_string(attestation, sig, pcrs_read, pcr_data)?;
 
+    // The key type is the one of the AK, which signed with sign_alg
     Ok(KeylimeQuote {
         quote: tpm_quote,
-        hash_alg: data.hash_alg.to_string(),
-        enc_alg: data.enc_alg.to_string(),
-        sign_alg: data.sign_alg.to_string(),
+        hash_alg: hash_alg.to_string(),
+        enc_alg: sign_alg.key_algorithm().to_string(),
+        sign_alg: sign_alg.to_string(),
         pubkey: None,
         ima_measurement_list: None,
         mb_measurement_list: None,
         ima_measurement_list_entry: None,
+        boot_aggregate: None,
+        num_entries: None,
+        mb_measurement_list_encoding: None,
+        ima_path_filter: None,
+        ima_measurement_list_encoding: None,
+        banks: Some(quote_banks),
+        mb_measurement_list_available: None,
+        mb_measurement_list_entry: None,
+        mb_num_entries: None,
+        quote_parts: None,
+        ima_signature_failures: None,
     })
 }
 
+// Runs quote on a blocking thread, so that waiting for the TPM does not
+// stall the async runtime. Quotes still access the TPM one at a time,
+// through the context lock.
+pub(crate) async fn quote_async(
+    nonce: Vec<u8>,
+    mask: Option<Vec<PcrSlot>>,
+    data: web::Data<QuoteData>,
+    sign_alg: SignAlgorithm,
+    banks: Vec<HashAlgorithm>,
+) -> Result<KeylimeQuote> {
+    tokio::task::spawn_blocking(move || {
+        quote(&nonce, mask.as_deref(), &data, sign_alg, &banks)
+    })
+    .await?
+}
+
+/// Parameters of one of the quotes generated by `quote_batch_async`
+#[derive(Debug, Clone)]
+pub(crate) struct QuoteRequest {
+    pub nonce: Vec<u8>,
+    pub mask: Option<Vec<PcrSlot>>,
+    pub sign_alg: SignAlgorithm,
+    pub banks: Vec<HashAlgorithm>,
+}
+
+// Generates the quotes one after the other, taking the TPM context lock
+// once for all of them, so that no other quote is interleaved. A failing
+// quote does not prevent the next ones from being generated.
+pub(crate) async fn quote_batch_async(
+    requests: Vec<QuoteRequest>,
+    data: web::Data<QuoteData>,
+) -> Result<Vec<Result<KeylimeQuote>>> {
+    tokio::task::spawn_blocking(move || {
+        let mut context = lock_context(&data)?;
+        Ok(requests
+            .iter()
+            .map(|request| {
+                quote_with_context(
+                    &mut context,
+                    &request.nonce,
+                    request.mask.as_deref(),
+                    &data,
+                    request.sign_alg,
+                    &request.banks,
+                )
+            })
+            .collect())
+    })
+    .await?
+}
+
 #[cfg(test)]
 pub mod testing {
     use super::*;
@@ -730,22 +1077,27 @@ pub mod testing {
             return Err(KeylimeError::InvalidRequest);
         }
         let count = u32::from_le_bytes(count_vec);
-        // Always 1 PCR digest should follow
-        if count != 1 {
+        // At least 1 PCR digest list should follow, and more when the PCRs
+        // do not fit in a single PCR read
+        if count == 0 {
             return Err(KeylimeError::InvalidRequest);
         }
 
-        let mut digest_vec = [0u8; DIGEST_SIZE];
-        let len = reader.read(&mut digest_vec)?;
-        if len != digest_vec.len() {
-            return Err(KeylimeError::InvalidRequest);
-        }
-        let mut digest = unsafe {
-            std::mem::transmute::<[u8; DIGEST_SIZE], TPML_DIGEST>(digest_vec)
-        };
         let mut digest_list = DigestList::new();
-        for i in 0..digest.count {
-            digest_list.add(digest.digests[i as usize].try_into()?);
+        for _ in 0..count {
+            let mut digest_vec = [0u8; DIGEST_SIZE];
+            let len = reader.read(&mut digest_vec)?;
+            if len != digest_vec.len() {
+                return Err(KeylimeError::InvalidRequest);
+            }
+            let digest = unsafe {
+                std::mem::transmute::<[u8; DIGEST_SIZE], TPML_DIGEST>(
+                    digest_vec,
+                )
+            };
+            for i in 0..digest.count {
+                digest_list.add(digest.digests[i as usize].try_into()?);
+            }
         }
 
         let pcrdata = PcrData::create(&pcrlist, &digest_list)?;
@@ -755,19 +1107,14 @@ pub mod testing {
     pub(crate) fn decode_quote_string(
         quote: &str,
     ) -> Result<(AttestBuffer, Signature, PcrSelectionList, PcrData)> {
-        if !quote.starts_with('r') {
-            return Err(KeylimeError::InvalidRequest);
-        }
         // extract components from the concatenated string
-        let mut split = quote[1..].split(':');
-        let att_str = split.next().ok_or(KeylimeError::InvalidRequest)?;
-        let sig_str = split.next().ok_or(KeylimeError::InvalidRequest)?;
-        let pcr_str = split.next().ok_or(KeylimeError::InvalidRequest)?;
+        let parts = QuoteParts::from_quote_string(quote)
+            .or(Err(KeylimeError::InvalidRequest))?;
 
         // base64 decoding
-        let att_comp_finished = base64::decode(att_str)?;
-        let sig_comp_finished = base64::decode(sig_str)?;
-        let pcr_comp_finished = base64::decode(pcr_str)?;
+        let att_comp_finished = base64::decode(parts.quote_b64)?;
+        let sig_comp_finished = base64::decode(parts.sig_b64)?;
+        let pcr_comp_finished = base64::decode(parts.pcr_b64)?;
 
         let sig: Signature = vec_to_sig(&sig_comp_finished)?.try_into()?;
         let (pcrsel, pcrdata) = vec_to_pcrdata(&pcr_comp_finished)?;
@@ -784,6 +1131,20 @@ pub mod testing {
         Ok((att.try_into()?, sig, pcrsel, pcrdata))
     }
 
+    // Returns the hex value PCR 16 has in the given bank once reset and
+    // extended with the digest of the public key, as done for each quote
+    pub(crate) fn expected_pcr16(
+        pubkey: &PKeyRef<Public>,
+        bank: HashAlgorithm,
+    ) -> Result<String> {
+        let bank: HashingAlgorithm = bank.into();
+        let digest = pubkey_digest(pubkey, bank)?;
+        let mut hasher = Hasher::new(hash_alg_to_message_digest(bank)?)?;
+        hasher.update(&vec![0u8; digest.len()])?;
+        hasher.update(&digest)?;
+        Ok(hex::encode(hasher.finish()?))
+    }
+
     // This performs the same checks as in tpm2_checkquote, namely:
     // signature, nonce, and PCR digests from the quote.
     //
@@ -821,13 +1182,18 @@ pub mod testing {
             ));
         }
 
-        // Also ensure digest from quote matches PCR digest
-        let pcrbank =
-            pcrdata.pcr_bank(HashingAlgorithm::Sha256).ok_or_else(|| {
-                KeylimeError::Other("no SHA256 bank".to_string())
-            })?;
+        // Also ensure digest from quote matches PCR digest, which covers
+        // the PCRs of each bank in the order they were selected
         let mut hasher = Hasher::new(MessageDigest::sha256())?;
         for &sel in pcrsel.get_selections() {
+            let pcrbank = pcrdata
+                .pcr_bank(sel.hashing_algorithm())
+                .ok_or_else(|| {
+                    KeylimeError::Other(format!(
+                        "no {:?} bank",
+                        sel.hashing_algorithm()
+                    ))
+                })?;
             for i in &sel.selected() {
                 if let Some(digest) = pcrbank.get_digest(*i) {
                     hasher.update(digest.value())?;
@@ -882,11 +1248,109 @@ fn quote_encode_decode() {
     assert_eq!(encoded, buf);
 }
 
+#[test]
+fn quote_parts() {
+    let quote_path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("test-data")
+        .join("test-quote.txt");
+    let quote = std::fs::read_to_string(quote_path)
+        .expect("unable to read test-quote.txt");
+    let quote = quote.trim_end();
+
+    // The parts recombine into the original quote string
+    let parts =
+        QuoteParts::from_quote_string(quote).expect("unable to split quote");
+    assert_eq!(parts.to_quote_string(), quote);
+    assert_eq!(
+        format!("r{}:{}:{}", parts.quote_b64, parts.sig_b64, parts.pcr_b64),
+        quote
+    );
+    assert!(base64::decode(&parts.quote_b64).is_ok());
+    assert!(base64::decode(&parts.sig_b64).is_ok());
+    assert!(base64::decode(&parts.pcr_b64).is_ok());
+
+    assert!(QuoteParts::from_quote_string("AAAA:BBBB:CCCC").is_err());
+    assert!(QuoteParts::from_quote_string("rAAAA:BBBB").is_err());
+    assert!(QuoteParts::from_quote_string("rAAAA:BBBB:CCCC:DDDD").is_err());
+}
+
 #[ignore] // This will only work as an integration test because it needs keylime.conf
 #[test]
 fn pubkey_to_digest() {
     let (key, _) = crate::crypto::rsa_generate_pair(2048).unwrap(); //#[allow_ci]
-    let digest = pubkey_to_tpm_digest(&key).unwrap(); //#[allow_ci]
+    let digest = pubkey_to_tpm_digest(&key, &[]).unwrap(); //#[allow_ci]
+}
+
+#[test]
+fn pubkey_to_digest_banks() {
+    let (key, _) = crate::crypto::testing::ec_generate_pair().unwrap(); //#[allow_ci]
+    let pem = key.ec_key().unwrap().public_key_to_pem().unwrap(); //#[allow_ci]
+    for (bank, md) in [
+        (HashingAlgorithm::Sha1, MessageDigest::sha1()),
+        (HashingAlgorithm::Sha256, MessageDigest::sha256()),
+        (HashingAlgorithm::Sha384, MessageDigest::sha384()),
+        (HashingAlgorithm::Sha512, MessageDigest::sha512()),
+    ] {
+        assert_eq!(
+            pubkey_digest(&key, bank).unwrap(), //#[allow_ci]
+            hash(md, &pem).unwrap().to_vec()    //#[allow_ci]
+        );
+    }
+    assert!(pubkey_to_tpm_digest(
+        &key,
+        &[HashingAlgorithm::Sha384, HashingAlgorithm::Sha512]
+    )
+    .is_ok());
+
+    // PCR 16 could not bind the key in a bank without a digest
+    assert!(pubkey_to_tpm_digest(&key, &[HashingAlgorithm::Sm3_256]).is_err());
+}
+
+#[test]
+fn pubkey_to_digest_key_types() {
+    let (key, _) = crate::crypto::testing::ec_generate_pair().unwrap(); //#[allow_ci]
+    assert!(pubkey_to_tpm_digest(&key, &[]).is_ok());
+
+    let key = openssl::pkey::PKey::generate_ed25519().unwrap(); //#[allow_ci]
+    let key = openssl::pkey::PKey::public_key_from_pem(
+        &key.public_key_to_pem().unwrap(), //#[allow_ci]
+    )
+    .unwrap(); //#[allow_ci]
+    assert!(pubkey_to_tpm_digest(&key, &[]).is_err());
+}
+
+#[test]
+fn tcti_from_str() {
+    assert_eq!(
+        parse_tcti("device:/dev/tpmrm0").unwrap(), //#[allow_ci]
+        TctiNameConf::Device(
+            tss_esapi::tcti_ldr::DeviceConfig::from_str("/dev/tpmrm0")
+                .unwrap() //#[allow_ci]
+        )
+    );
+    assert!(parse_tcti("swtpm:port=2321").is_ok());
+
+    for invalid in &["", "tpm:/dev/tpm0", "swtpm:port=notaport"] {
+        assert!(matches!(
+            parse_tcti(invalid),
+            Err(KeylimeError::Configuration(_))
+        ));
+    }
+}
+
+// CI runs the software TPM behind the resource manager given in TCTI
+#[cfg(feature = "testing")]
+#[test]
+fn tpm2_ctx_from_tcti() {
+    let tcti = std::env::var("TCTI").unwrap(); //#[allow_ci]
+    let mut ctx = get_tpm2_ctx(&tcti).unwrap(); //#[allow_ci]
+    assert!(tss_esapi::utils::get_tpm_vendor(&mut ctx).is_ok());
+
+    // The configured TCTI takes precedence over the environment
+    assert!(matches!(
+        get_tpm2_ctx("nosuchtcti:"),
+        Err(KeylimeError::Configuration(_))
+    ));
 }
 
 #[test]
@@ -962,4 +1426,53 @@ fn mask() {
     );
 
     assert!(read_mask("0x1ffffff").is_err());
+
+    // Without the prefix, the mask is still read as hexadecimal
+    assert_eq!(read_mask("10").unwrap(), vec![PcrSlot::Slot4]); //#[allow_ci]
+
+    // Malformed masks
+    assert!(read_mask("").is_err());
+    assert!(read_mask("0x").is_err());
+    assert!(read_mask("0x0x1").is_err());
+    assert!(read_mask("0xfg").is_err());
+    assert!(read_mask("0x+1").is_err());
+
+    // PCRs beyond 23, even if not fitting in 32 bits
+    let err = read_mask("0x1000000").unwrap_err(); //#[allow_ci]
+    assert!(err.to_string().contains("only pcrs 0-23"));
+    let err = read_mask("0x100000000").unwrap_err(); //#[allow_ci]
+    assert!(err.to_string().contains("only pcrs 0-23"));
+}
+
+#[test]
+fn missing() {
+    let mask = |mask| read_mask(mask).unwrap(); //#[allow_ci]
+    assert_eq!(missing_pcrs(&mask("0x408000"), &[]), Vec::<u32>::new());
+    assert_eq!(missing_pcrs(&mask("0x401"), &[0, 10]), Vec::<u32>::new());
+    assert_eq!(missing_pcrs(&mask("0x408000"), &[0, 10]), vec![0, 10]);
+    assert_eq!(missing_pcrs(&mask("0x408001"), &[0, 10]), vec![10]);
+    assert!(check_mask(&mask("0x401"), &PcrSlot::Slot0));
+    assert!(!check_mask(&mask("0x408000"), &PcrSlot::Slot0));
+}
+
+#[test]
+fn forbidden() {
+    let mask = |mask| read_mask(mask).unwrap(); //#[allow_ci]
+    assert_eq!(forbidden_pcrs(&mask("0x408001"), &[]), Vec::<u32>::new());
+    assert_eq!(forbidden_pcrs(&mask("0x401"), &[0, 10]), Vec::<u32>::new());
+    assert_eq!(
+        forbidden_pcrs(&mask("0x10401"), &[0, 10]),
+        Vec::<u32>::new()
+    );
+    assert_eq!(forbidden_pcrs(&mask("0x800401"), &[0, 10]), vec![23]);
+    assert_eq!(forbidden_pcrs(&mask("0x408000"), &[0, 10]), vec![15, 22]);
+}
+
+#[test]
+fn pcr_slots() {
+    assert_eq!(pcr_slot(0).unwrap(), PcrSlot::Slot0); //#[allow_ci]
+    assert_eq!(pcr_slot(10).unwrap(), PcrSlot::Slot10); //#[allow_ci]
+    assert_eq!(pcr_slot(23).unwrap(), PcrSlot::Slot23); //#[allow_ci]
+    assert!(pcr_slot(24).is_err());
+    assert!(pcr_slot(32).is_err());
 }
@@ -1,7 +1,8 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright 2022 Keylime Authors
 
-use crate::common::{JsonWrapper, API_VERSION};
+use crate::api_version::current_api_version;
+use crate::common::JsonWrapper;
 use actix_web::{web, HttpRequest, HttpResponse, Responder};
 use log::*;
 use serde::{Deserialize, Serialize};
@@ -20,7 +21,7 @@ pub async fn version(req: HttpRequest) -> impl Responder {
     );
 
     let response = JsonWrapper::success(KeylimeVersion {
-        supported_version: API_VERSION[1..].to_string(),
+        supported_version: current_api_version().number(),
     });
 
     HttpResponse::Ok().json(response)
@@ -46,6 +47,6 @@ mod tests {
 
         let body: JsonWrapper<KeylimeVersion> =
             test::read_body_json(resp).await;
-        assert_eq!(body.results.supported_version, API_VERSION[1..]);
+        assert_eq!(body.results.supported_version, "2.0");
     }
 }
@@ -0,0 +1,9 @@
+#!/bin/bash
+
+#SPDX-License-Identifier: Apache-2.0
+#Copyright 2021 Keylime Authors
+
+echo "$KEYLIME_ACTION_NAME"
+echo "$KEYLIME_REVOKED_AGENT_ID"
+echo "$KEYLIME_WORK_DIR"
+echo "$KEYLIME_REVOCATION_SEVERITY"
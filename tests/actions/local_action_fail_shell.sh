@@ -0,0 +1,8 @@
+#!/bin/bash
+
+#SPDX-License-Identifier: Apache-2.0
+#Copyright 2021 Keylime Authors
+
+# Used to test failing revocation actions
+echo "Failing on purpose" >&2
+exit 1
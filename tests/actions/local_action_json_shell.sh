@@ -0,0 +1,6 @@
+#!/bin/bash
+
+#SPDX-License-Identifier: Apache-2.0
+#Copyright 2022 Keylime Authors
+
+cp "$1" "$KEYLIME_WORK_DIR/action_input.json"
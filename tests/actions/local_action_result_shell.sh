@@ -0,0 +1,6 @@
+#!/bin/bash
+
+#SPDX-License-Identifier: Apache-2.0
+#Copyright 2022 Keylime Authors
+
+echo '{"network_revoked": true, "nodes_affected": 3}' > "$KEYLIME_ACTION_RESULT"
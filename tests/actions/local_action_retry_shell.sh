@@ -0,0 +1,19 @@
+#!/bin/bash
+
+#SPDX-License-Identifier: Apache-2.0
+#Copyright 2022 Keylime Authors
+
+# Used to test retried revocation actions: fails with EX_TEMPFAIL on the
+# first run, then succeeds if given the same JSON file again
+marker="$KEYLIME_WORK_DIR/retry_marker"
+if [ ! -f "$marker" ]; then
+    echo "$1" > "$marker"
+    echo "Temporary failure" >&2
+    exit 75
+fi
+
+if [ "$(cat "$marker")" != "$1" ]; then
+    echo "Given another JSON file" >&2
+    exit 1
+fi
+echo "Succeeded after a retry"
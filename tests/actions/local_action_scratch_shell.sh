@@ -0,0 +1,9 @@
+#!/bin/bash
+
+#SPDX-License-Identifier: Apache-2.0
+#Copyright 2022 Keylime Authors
+
+echo "transient" > "$KEYLIME_SCRATCH_DIR/data"
+mkdir "$KEYLIME_SCRATCH_DIR/nested"
+ln -s "$KEYLIME_WORK_DIR/outside" "$KEYLIME_SCRATCH_DIR/nested/outside"
+echo -n "$KEYLIME_SCRATCH_DIR"
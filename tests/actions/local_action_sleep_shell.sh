@@ -0,0 +1,7 @@
+#!/bin/bash
+
+#SPDX-License-Identifier: Apache-2.0
+#Copyright 2021 Keylime Authors
+
+# Used to test revocation action timeouts
+sleep 10
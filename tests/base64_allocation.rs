@@ -0,0 +1,85 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Measures the memory allocated to serialize large base64 blobs. It needs
+// its own global allocator, so it is kept out of the agent unit tests.
+
+use serde::Serialize;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+#[allow(dead_code)]
+#[path = "../src/serialization.rs"]
+mod serialization;
+
+use serialization::serialize_maybe_base64;
+
+// Allocator recording the peak memory allocated by a thread while it is
+// tracking, so that tests running in parallel do not interfere
+struct TrackingAllocator;
+
+thread_local! {
+    static TRACKING: Cell<bool> = const { Cell::new(false) };
+    static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+    static PEAK: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for TrackingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if TRACKING.try_with(Cell::get).unwrap_or(false) {
+            let allocated =
+                ALLOCATED.with(|a| a.get()).saturating_add(layout.size());
+            ALLOCATED.with(|a| a.set(allocated));
+            PEAK.with(|p| p.set(p.get().max(allocated)));
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if TRACKING.try_with(Cell::get).unwrap_or(false) {
+            ALLOCATED.with(|a| a.set(a.get().saturating_sub(layout.size())));
+        }
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: TrackingAllocator = TrackingAllocator;
+
+// Returns the peak memory allocated by the thread while running f
+fn peak_allocation(f: impl FnOnce()) -> usize {
+    ALLOCATED.with(|a| a.set(0));
+    PEAK.with(|p| p.set(0));
+    TRACKING.with(|t| t.set(true));
+    f();
+    TRACKING.with(|t| t.set(false));
+    PEAK.with(Cell::get)
+}
+
+#[derive(Serialize)]
+struct MaybeBase64 {
+    #[serde(serialize_with = "serialize_maybe_base64")]
+    data: Option<Vec<u8>>,
+}
+
+#[test]
+fn test_base64_large_blob() {
+    let data = (0..4 << 20).map(|i| i as u8).collect::<Vec<u8>>();
+    let expected = format!(r#"{{"data":"{}"}}"#, base64::encode(&data));
+    let value = MaybeBase64 { data: Some(data) };
+
+    // The output buffer is allocated beforehand, so only the memory
+    // allocated for the encoding itself is measured
+    let mut output = Vec::with_capacity(expected.len());
+    let peak = peak_allocation(|| {
+        serde_json::to_writer(&mut output, &value).unwrap() //#[allow_ci]
+    });
+    assert_eq!(output, expected.as_bytes());
+
+    // Encoding to a string first needs as much memory as the encoding
+    let peak_string = peak_allocation(|| {
+        let _ = base64::encode(value.data.as_ref().unwrap()); //#[allow_ci]
+    });
+    assert!(peak_string >= expected.len() - r#"{"data":""}"#.len());
+    assert!(peak < 64 * 1024, "peak allocation: {} bytes", peak);
+}